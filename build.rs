@@ -0,0 +1,549 @@
+//! Generates `Melody` constants from the MIDI files in `melodies/`, plus the
+//! `MELODY_LIST` playlist described by `melodies/playlist.txt`.
+//!
+//! Each playlist line is either the name of a melody defined in
+//! `src/melody.rs`, or a MIDI file followed by optional `key=value` options:
+//!
+//! - `track=N`: track to read, defaults to the first track with notes
+//! - `channel=N`: channel to read, defaults to the first channel with notes
+//! - `name=IDENT`: constant name, defaults to the upper-cased file stem
+//! - `icon=PATH`: icon constant, defaults to `screen::NOTE`
+//! - `waveform=EXPR`: `Waveform` to play it with, defaults to a square wave
+//!
+//! MIDI files that the playlist does not mention are appended to it with the
+//! default options.
+//!
+//! It also hands the linker the firmware's `memory.x`, and tells the firmware
+//! where its `FLASH` ends so that `src/store.rs` can check it stays clear of
+//! the store's region.
+//!
+//! `musicbox-cli` includes this file too, to generate the same melodies for
+//! the firmware modules it shares and to convert MIDI files it uploads with
+//! `convert`, the conversion `generate` writes out as code.
+use std::{
+    env,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+
+const MELODY_DIR: &str = "melodies";
+const PLAYLIST: &str = "playlist.txt";
+
+/// Default MIDI tempo, 120 bpm.
+const DEFAULT_TEMPO_US: u32 = 500_000;
+
+/// Durations in 32nd notes and the `melody!` divisor encoding them, longest
+/// first. Negative divisors are dotted.
+const DURATIONS: &[(u32, i8)] = &[
+    (48, -1),
+    (32, 1),
+    (24, -2),
+    (16, 2),
+    (12, -4),
+    (8, 4),
+    (6, -8),
+    (4, 8),
+    (3, -16),
+    (2, 16),
+    (1, 32),
+];
+
+const PITCH_CLASSES: [&str; 12] = [
+    "C", "CS", "D", "DS", "E", "F", "FS", "G", "GS", "A", "AS", "B",
+];
+
+enum Entry {
+    Builtin(String),
+    Midi(MidiEntry),
+}
+
+#[derive(Default)]
+pub struct MidiEntry {
+    pub path: PathBuf,
+    pub track: Option<usize>,
+    pub channel: Option<u8>,
+    pub name: Option<String>,
+    pub icon: Option<String>,
+    pub waveform: Option<String>,
+}
+
+/// A monophonic note (or rest, when `key` is `None`) in ticks.
+struct Note {
+    key: Option<u8>,
+    start: u64,
+    end: u64,
+}
+
+/// A quantized piece of a note, starting at `start` 32nd notes.
+struct Piece {
+    start: u64,
+    key: Option<u8>,
+    div: i8,
+    tie: bool,
+}
+
+/// A tempo change in microseconds per quarter note, or a time signature.
+enum Marker {
+    Tempo(u32),
+    Time(u8, u8),
+}
+
+/// A MIDI file reduced to the events of a `melody!` with `beat = 4`.
+pub struct Converted {
+    pub name: String,
+    pub track: usize,
+    pub channel: u8,
+    /// Tempo at the start, in quarter notes per minute.
+    pub bpm: u32,
+    pub items: Vec<Item>,
+}
+
+/// An event of a converted MIDI file.
+pub enum Item {
+    /// Tempo change, in quarter notes per minute.
+    Tempo(u32),
+    /// Time signature.
+    Time(u8, u8),
+    /// A note, or a rest when `key` is `None`, as a `melody!` divisor, tied
+    /// to the next one if `tie`.
+    Note { key: Option<u8>, div: i8, tie: bool },
+}
+
+fn main() {
+    write_memory_x();
+    write_melodies(Path::new(""));
+}
+
+/// Puts the firmware's `memory.x`, which leaves the store's region out of
+/// flash, where the linker looks for it ahead of the HAL's, and passes the
+/// end of its `FLASH` on as `MEMORY_X_FLASH_END`.
+fn write_memory_x() {
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap());
+    // the first search path is searched first
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed=memory.x");
+    fs::copy("memory.x", out.join("memory.x")).unwrap();
+
+    let text = fs::read_to_string("memory.x").unwrap();
+    let end = flash_end(&text).unwrap_or_else(|| panic!("memory.x: cannot read FLASH"));
+    println!("cargo:rustc-env=MEMORY_X_FLASH_END={}", end);
+}
+
+/// End of the `FLASH` region of a linker script's `MEMORY`, its `ORIGIN`
+/// plus its `LENGTH`, each a sum of numbers like `0x1000`, `512K` or `1M`.
+fn flash_end(text: &str) -> Option<u64> {
+    let line = text
+        .lines()
+        .map(str::trim)
+        .find(|line| line.starts_with("FLASH"))?;
+    let value = |name: &str| {
+        let (_, rest) = line.split_once(name)?;
+        let rest = rest.trim_start().strip_prefix('=')?;
+        eval(rest.split(',').next()?)
+    };
+    Some(value("ORIGIN")? + value("LENGTH")?)
+}
+
+/// Evaluates additions and subtractions of numbers, see `flash_end`.
+fn eval(expr: &str) -> Option<u64> {
+    let expr = expr.replace('-', "+-");
+    let mut sum = 0i64;
+    for term in expr.split('+').map(str::trim).filter(|t| !t.is_empty()) {
+        let (sign, term) = match term.strip_prefix('-') {
+            Some(term) => (-1, term.trim()),
+            None => (1, term),
+        };
+        let (digits, scale) = match term.as_bytes().last()? {
+            b'K' | b'k' => (&term[..term.len() - 1], 1 << 10),
+            b'M' | b'm' => (&term[..term.len() - 1], 1 << 20),
+            _ => (term, 1),
+        };
+        let n = match digits.strip_prefix("0x") {
+            Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+            None => digits.parse().ok()?,
+        };
+        sum += sign * n * scale;
+    }
+    u64::try_from(sum).ok()
+}
+
+/// Writes `melodies.rs` to `OUT_DIR` from the `melodies/` directory under
+/// `root`.
+pub fn write_melodies(root: &Path) {
+    let dir = root.join(MELODY_DIR);
+    let playlist = dir.join(PLAYLIST);
+    println!("cargo:rerun-if-changed={}", dir.display());
+    println!("cargo:rerun-if-changed={}", playlist.display());
+
+    let mut entries = read_playlist(&playlist, &dir);
+    for path in midi_files(&dir) {
+        println!("cargo:rerun-if-changed={}", path.display());
+        let listed = entries
+            .iter()
+            .any(|e| matches!(e, Entry::Midi(m) if m.path == path));
+        if !listed {
+            entries.push(Entry::Midi(MidiEntry {
+                path,
+                ..Default::default()
+            }));
+        }
+    }
+
+    let mut out = String::from("// @generated by build.rs from the files in melodies/\n");
+    let mut list = Vec::new();
+    for entry in &entries {
+        match entry {
+            Entry::Builtin(name) => list.push(name.clone()),
+            Entry::Midi(midi) => {
+                let (name, code) = generate(midi);
+                out.push_str(&code);
+                list.push(name);
+            }
+        }
+    }
+
+    out.push_str("\npub const MELODY_LIST: &[Melody] = &[\n");
+    for name in &list {
+        writeln!(out, "    {},", name).unwrap();
+    }
+    out.push_str("];\n");
+
+    let dest = Path::new(&env::var("OUT_DIR").unwrap()).join("melodies.rs");
+    fs::write(dest, out).unwrap();
+}
+
+fn read_playlist(path: &Path, dir: &Path) -> Vec<Entry> {
+    let Ok(text) = fs::read_to_string(path) else {
+        return Vec::new();
+    };
+
+    let mut entries = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        let mut words = line.split_whitespace();
+        let Some(first) = words.next() else {
+            continue;
+        };
+
+        if !first.ends_with(".mid") {
+            entries.push(Entry::Builtin(first.to_string()));
+            continue;
+        }
+
+        let mut entry = MidiEntry {
+            path: dir.join(first),
+            ..Default::default()
+        };
+        for word in words {
+            let bad = || -> ! { panic!("{}:{}: bad option `{}`", path.display(), n + 1, word) };
+            let (key, value) = word.split_once('=').unwrap_or_else(|| bad());
+            match key {
+                "track" => entry.track = Some(value.parse().unwrap_or_else(|_| bad())),
+                "channel" => entry.channel = Some(value.parse().unwrap_or_else(|_| bad())),
+                "name" => entry.name = Some(value.to_string()),
+                "icon" => entry.icon = Some(value.to_string()),
+                "waveform" => entry.waveform = Some(value.to_string()),
+                _ => bad(),
+            }
+        }
+        entries.push(Entry::Midi(entry));
+    }
+    entries
+}
+
+fn midi_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<_> = fs::read_dir(dir)
+        .map(|dir| {
+            dir.filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.extension().is_some_and(|ext| ext == "mid"))
+                .collect()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files
+}
+
+/// Returns the constant name and the `melody!` invocation for a MIDI file.
+pub fn generate(entry: &MidiEntry) -> (String, String) {
+    let midi = convert(entry);
+    let icon = entry.icon.as_deref().unwrap_or("screen::NOTE");
+
+    let mut code = String::new();
+    writeln!(
+        code,
+        "\n// {} (track {}, channel {})",
+        entry.path.display(),
+        midi.track,
+        midi.channel
+    )
+    .unwrap();
+    writeln!(code, "melody!(").unwrap();
+    writeln!(
+        code,
+        "    name = {}, tempo = {}, beat = 4,",
+        midi.name, midi.bpm
+    )
+    .unwrap();
+    writeln!(code, "    icon = {},", icon).unwrap();
+    if let Some(waveform) = &entry.waveform {
+        writeln!(code, "    waveform = {},", waveform).unwrap();
+    }
+
+    code.push_str("    [");
+    for (i, item) in midi.items.iter().enumerate() {
+        if i > 0 {
+            code.push_str(if i % 8 == 0 { ",\n    " } else { ", " });
+        }
+        match *item {
+            Item::Tempo(bpm) => write!(code, "TEMPO: {}", bpm),
+            Item::Time(beats, unit) => write!(code, "TIME: {}/{}", beats, unit),
+            Item::Note { key, div, tie } => {
+                let tie = if tie { " tie" } else { "" };
+                write!(code, "{}:{}{}", tone_name(key), div, tie)
+            }
+        }
+        .unwrap();
+    }
+    code.push_str("]\n);\n");
+
+    (midi.name, code)
+}
+
+/// Reads the notes, tempo changes and time signatures of a MIDI file.
+pub fn convert(entry: &MidiEntry) -> Converted {
+    let path = &entry.path;
+    let data = fs::read(path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    let smf = Smf::parse(&data).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+
+    let Timing::Metrical(ppq) = smf.header.timing else {
+        panic!("{}: SMPTE timecode is not supported", path.display());
+    };
+    let ppq = ppq.as_int() as u64;
+
+    let mut markers = markers(&smf);
+    let tempo_us = markers
+        .iter()
+        .find_map(|(_, m)| match m {
+            Marker::Tempo(t) => Some(*t),
+            _ => None,
+        })
+        .unwrap_or(DEFAULT_TEMPO_US);
+    // the first tempo is the melody's own
+    if let Some(first) = markers
+        .iter()
+        .position(|(_, m)| matches!(m, Marker::Tempo(_)))
+    {
+        markers.remove(first);
+    }
+
+    let track = entry
+        .track
+        .or_else(|| smf.tracks.iter().position(|t| first_channel(t).is_some()))
+        .unwrap_or_else(|| panic!("{}: no track with notes", path.display()));
+    let events = smf
+        .tracks
+        .get(track)
+        .unwrap_or_else(|| panic!("{}: no track {}", path.display(), track));
+    let channel = entry
+        .channel
+        .or_else(|| first_channel(events))
+        .unwrap_or_else(|| panic!("{}: no notes in track {}", path.display(), track));
+
+    let notes = extract_notes(events, channel);
+    if notes.is_empty() {
+        panic!(
+            "{}: no notes in track {} channel {}",
+            path.display(),
+            track,
+            channel
+        );
+    }
+
+    let name = entry.name.clone().unwrap_or_else(|| {
+        path.file_stem()
+            .unwrap()
+            .to_string_lossy()
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    });
+    let bpm = |tempo_us: u32| (60_000_000 + tempo_us / 2) / tempo_us;
+
+    let grid = grid(ppq);
+    let mut markers = markers.into_iter().peekable();
+    let mut items = Vec::new();
+    for piece in quantize(&notes, ppq) {
+        // tempo and time signature changes go before the note they reach
+        while let Some((_, marker)) = markers.next_if(|(tick, _)| snap(*tick, grid) <= piece.start)
+        {
+            items.push(match marker {
+                Marker::Tempo(tempo_us) => Item::Tempo(bpm(tempo_us)),
+                Marker::Time(beats, unit) => Item::Time(beats, unit),
+            });
+        }
+        items.push(Item::Note {
+            key: piece.key,
+            div: piece.div,
+            tie: piece.tie,
+        });
+    }
+
+    Converted {
+        name,
+        track,
+        channel,
+        bpm: bpm(tempo_us),
+        items,
+    }
+}
+
+fn first_channel(events: &[midly::TrackEvent]) -> Option<u8> {
+    events.iter().find_map(|e| match e.kind {
+        TrackEventKind::Midi {
+            channel,
+            message: MidiMessage::NoteOn { vel, .. },
+        } if vel > 0 => Some(channel.as_int()),
+        _ => None,
+    })
+}
+
+/// Reduces one channel to a monophonic line where the latest note wins, with
+/// rests filling the gaps.
+fn extract_notes(events: &[midly::TrackEvent], channel: u8) -> Vec<Note> {
+    let mut notes: Vec<Note> = Vec::new();
+    let mut time = 0u64;
+
+    fn push(notes: &mut Vec<Note>, key: Option<u8>, start: u64, end: u64) {
+        if end > start {
+            notes.push(Note { key, start, end });
+        }
+    }
+
+    for event in events {
+        time += event.delta.as_int() as u64;
+        let TrackEventKind::Midi {
+            channel: ch,
+            message,
+        } = event.kind
+        else {
+            continue;
+        };
+        if ch.as_int() != channel {
+            continue;
+        }
+        match message {
+            MidiMessage::NoteOn { key, vel } if vel > 0 => {
+                if let Some(last) = notes.last_mut() {
+                    if last.key.is_some() && last.end > time {
+                        last.end = time;
+                    }
+                }
+                push(&mut notes, Some(key.as_int()), time, u64::MAX);
+            }
+            MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                if let Some(last) = notes.last_mut() {
+                    if last.key == Some(key.as_int()) && last.end > time {
+                        last.end = time;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // notes still held at the end of the track stop there
+    if let Some(last) = notes.last_mut() {
+        if last.end == u64::MAX {
+            last.end = time;
+        }
+    }
+
+    let mut line = Vec::new();
+    let mut cursor = 0;
+    for note in notes {
+        push(&mut line, None, cursor, note.start);
+        cursor = note.end;
+        line.push(note);
+    }
+    push(&mut line, None, cursor, time);
+    line
+}
+
+/// Tempo changes and time signatures of all tracks, by tick.
+fn markers(smf: &Smf) -> Vec<(u64, Marker)> {
+    let mut markers = Vec::new();
+    for track in &smf.tracks {
+        let mut time = 0u64;
+        for event in track {
+            time += event.delta.as_int() as u64;
+            match event.kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(t)) => {
+                    markers.push((time, Marker::Tempo(t.as_int())))
+                }
+                TrackEventKind::Meta(MetaMessage::TimeSignature(beats, unit_log2, ..)) => {
+                    markers.push((time, Marker::Time(beats, 1 << unit_log2)))
+                }
+                _ => {}
+            }
+        }
+    }
+    markers.sort_by_key(|(time, _)| *time);
+    markers
+}
+
+/// Ticks per 32nd note.
+fn grid(ppq: u64) -> u64 {
+    (ppq / 8).max(1)
+}
+
+fn snap(tick: u64, grid: u64) -> u64 {
+    (tick + grid / 2) / grid
+}
+
+/// Snaps note boundaries to a 32nd-note grid and splits each note into
+/// durations `melody!` can express, tied together.
+fn quantize(notes: &[Note], ppq: u64) -> Vec<Piece> {
+    let grid = grid(ppq);
+
+    let mut pieces = Vec::new();
+    for note in notes {
+        let mut start = snap(note.start, grid);
+        let mut units = snap(note.end, grid).saturating_sub(start) as u32;
+        for &(len, div) in DURATIONS {
+            while units >= len {
+                units -= len;
+                pieces.push(Piece {
+                    start,
+                    key: note.key,
+                    div,
+                    tie: note.key.is_some() && units > 0,
+                });
+                start += len as u64;
+            }
+        }
+    }
+    pieces
+}
+
+/// Pitch class (`0` = C) and octave of a MIDI key, folded into the octaves
+/// `Tone` provides.
+pub fn tone_of(key: u8) -> (u8, u8) {
+    (key % 12, (key / 12).saturating_sub(1).clamp(1, 9))
+}
+
+fn tone_name(key: Option<u8>) -> String {
+    let Some(key) = key else {
+        return "REST".to_string();
+    };
+    let (class, octave) = tone_of(key);
+    format!("{}{}", PITCH_CLASSES[class as usize], octave)
+}
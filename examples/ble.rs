@@ -0,0 +1,746 @@
+//! Plays the central against `ble::Peripheral`: advertising, connecting,
+//! following the connection events, discovering and using the music box
+//! service over ATT, link-layer control, BLE-MIDI and losing the connection,
+//! checking every packet that comes back.
+//!
+//! ```text
+//! cargo run --example ble --target <host triple>
+//! ```
+
+// only part of the firmware modules is used here
+#![allow(dead_code, unused_imports, unused_macros)]
+
+#[path = "../src/ble.rs"]
+mod ble;
+#[path = "common/checks.rs"]
+mod checks;
+#[path = "../src/midi.rs"]
+mod midi;
+
+use std::{process::ExitCode, sync::Mutex};
+
+use ble::{Address, Event, Packet, Peripheral, PlayState, Status, MUSICBOX_SERVICE};
+use checks::Checks;
+use midi::Message;
+
+const ACCESS_ADDRESS: u32 = 0x5065_a1d7;
+const CRC_INIT: u32 = 0x12_3456;
+
+/// Connection interval of the CONNECT_IND, in units of 1.25 ms.
+const INTERVAL: u16 = 24;
+/// Channels hopped over at each connection event.
+const HOP: u8 = 7;
+
+static EVENTS: Mutex<Vec<Event>> = Mutex::new(Vec::new());
+
+fn record(event: Event) {
+    EVENTS.lock().unwrap().push(event);
+}
+
+fn take_events() -> Vec<Event> {
+    std::mem::take(&mut EVENTS.lock().unwrap())
+}
+
+/// The payload of a packet.
+fn payload(packet: &Packet) -> &[u8] {
+    &packet[2..2 + packet[1] as usize]
+}
+
+/// A CONNECT_IND from a central to `address`, on all data channels.
+fn connect_ind(address: Address) -> Packet {
+    let mut packet = [0; 2 + ble::MAX_PAYLOAD];
+    packet[0] = 0x05 | 0x40 | 0x80;
+    packet[1] = 34;
+    packet[2..8].copy_from_slice(&[0x11, 0x22, 0x33, 0x44, 0x55, 0xc6]);
+    packet[8..14].copy_from_slice(&address.0);
+    let data = &mut packet[14..36];
+    data[0..4].copy_from_slice(&ACCESS_ADDRESS.to_le_bytes());
+    data[4..7].copy_from_slice(&CRC_INIT.to_le_bytes()[..3]);
+    // transmit window of 2.5 ms, 3.75 ms after the first 1.25 ms
+    data[7] = 2;
+    data[8..10].copy_from_slice(&3u16.to_le_bytes());
+    data[10..12].copy_from_slice(&INTERVAL.to_le_bytes());
+    // supervision timeout of a second
+    data[14..16].copy_from_slice(&100u16.to_le_bytes());
+    data[16..21].copy_from_slice(&[0xff, 0xff, 0xff, 0xff, 0x1f]);
+    // sleep clock accuracy of 31 to 50 ppm
+    data[21] = HOP | 5 << 5;
+    packet
+}
+
+/// A CONNECT_IND to the address of `Address::random_static(0x1234_5678_9abc)`,
+/// byte for byte as it goes over the air.
+const CONNECT_PDU: [u8; 36] = [
+    // CONNECT_IND from a random address to a random address, and the
+    // central's initiator and the peripheral's advertiser address
+    0xc5, 34, 0x3c, 0x4d, 0x5e, 0x6f, 0x70, 0xc1, 0xbc, 0x9a, 0x78, 0x56, 0x34, 0xd2,
+    // access address 0xaf9a8b37, CRC initial value 0x9abcde
+    0x37, 0x8b, 0x9a, 0xaf, 0xde, 0xbc, 0x9a,
+    // transmit window of 3.75 ms 10 ms after the first 1.25 ms, interval of
+    // 45 ms, no latency, supervision timeout of 500 ms
+    3, 8, 0, 36, 0, 0, 0, 50, 0,
+    // channels 0 to 7 and 21 to 36, hop of 9, sleep clock of 251 to 500 ppm
+    0xff, 0x00, 0xe0, 0xff, 0x1f, 0x09,
+];
+
+/// LL_CHANNEL_MAP_IND for channels 1, 12, 17, 30 and 33 from event 7 on.
+const CHANNEL_MAP_PDU: [u8; 8] = [0x01, 0x02, 0x10, 0x02, 0x40, 0x02, 7, 0];
+
+/// LL_CONNECTION_UPDATE_IND for a transmit window of 2.5 ms 5 ms after the
+/// old interval, an interval of 100 ms and a supervision timeout of 3 s from
+/// event 11 on.
+const CONNECTION_UPDATE_PDU: [u8; 12] = [0x00, 2, 4, 0, 80, 0, 0, 0, 44, 1, 11, 0];
+
+/// A packet holding `pdu`.
+fn packet(pdu: &[u8]) -> Packet {
+    let mut packet = [0; 2 + ble::MAX_PAYLOAD];
+    packet[..pdu.len()].copy_from_slice(pdu);
+    packet
+}
+
+/// The central's side of the connection: acknowledgements and the
+/// connection event counter.
+struct Central {
+    sn: bool,
+    nesn: bool,
+    counter: u16,
+    /// Anchor point of the latest connection event.
+    now_us: u32,
+    /// Channel of the latest connection event.
+    channel: u8,
+    /// When and where the peripheral listened at each connection event.
+    timings: Vec<ble::Timing>,
+}
+
+impl Central {
+    /// Follows the peripheral to its first connection event.
+    fn new(peripheral: &mut Peripheral) -> Self {
+        let mut central = Self {
+            sn: false,
+            nesn: false,
+            counter: 0,
+            now_us: 0,
+            channel: 0,
+            timings: Vec::new(),
+        };
+        central.next_event(peripheral);
+        central
+    }
+
+    fn next_event(&mut self, peripheral: &mut Peripheral) -> Option<ble::Timing> {
+        let timing = peripheral.next_event()?;
+        // the central is on time, in the middle of the window if any
+        self.now_us = timing.start_us + timing.window_us / 2;
+        self.channel = timing.channel;
+        self.timings.push(timing);
+        Some(timing)
+    }
+
+    /// Lets the current connection event go by without sending anything, as
+    /// if the peripheral had not heard the central.
+    fn miss(&mut self, peripheral: &mut Peripheral) {
+        self.counter = self.counter.wrapping_add(1);
+        self.next_event(peripheral);
+    }
+
+    /// Sends a data PDU at the current connection event, returning the
+    /// answer and moving on to the next event.
+    fn send(&mut self, peripheral: &mut Peripheral, llid: u8, data: &[u8]) -> Packet {
+        let mut packet = [0; 2 + ble::MAX_PAYLOAD];
+        packet[0] = llid | if self.nesn { 0x04 } else { 0 } | if self.sn { 0x08 } else { 0 };
+        packet[1] = data.len() as u8;
+        packet[2..2 + data.len()].copy_from_slice(data);
+        let reply = *peripheral.exchange(Some(&packet), self.now_us);
+        if (reply[0] & 0x08 != 0) == self.nesn {
+            self.nesn = !self.nesn;
+        }
+        if (reply[0] & 0x04 != 0) != self.sn {
+            self.sn = !self.sn;
+        }
+        peripheral.process();
+        self.counter = self.counter.wrapping_add(1);
+        self.next_event(peripheral);
+        reply
+    }
+
+    /// Sends an L2CAP `payload` on channel `cid`, then empty PDUs until the
+    /// peripheral answers, returning what it said.
+    fn l2cap(&mut self, peripheral: &mut Peripheral, cid: u16, data: &[u8]) -> Vec<u8> {
+        let mut pdu = Vec::from((data.len() as u16).to_le_bytes());
+        pdu.extend_from_slice(&cid.to_le_bytes());
+        pdu.extend_from_slice(data);
+        self.send(peripheral, 0b10, &pdu);
+        self.answer(peripheral, 0b10)
+            .map_or_else(Vec::new, |payload| payload[4..].to_vec())
+    }
+
+    /// Sends a link-layer control PDU, returning the answer.
+    fn control(&mut self, peripheral: &mut Peripheral, pdu: &[u8]) -> Vec<u8> {
+        self.send(peripheral, 0b11, pdu);
+        self.answer(peripheral, 0b11).unwrap_or_default()
+    }
+
+    /// The payload of the next PDU the peripheral sends with `llid`, if it
+    /// sends one within a few connection events.
+    fn answer(&mut self, peripheral: &mut Peripheral, llid: u8) -> Option<Vec<u8>> {
+        (0..4).find_map(|_| {
+            let reply = self.send(peripheral, 0b01, &[]);
+            (reply[0] & 0b11 == llid && reply[1] > 0).then(|| payload(&reply).to_vec())
+        })
+    }
+
+    fn att(&mut self, peripheral: &mut Peripheral, request: &[u8]) -> Vec<u8> {
+        self.l2cap(peripheral, 0x0004, request)
+    }
+}
+
+/// Follows a connection opened by `CONNECT_PDU` through an event missed, a
+/// channel map update and a connection update, checking the channel and the
+/// receive window of every connection event.
+fn check_connection(checks: &mut Checks) {
+    let mut peripheral = Peripheral::new(Address::random_static(0x1234_5678_9abc), false);
+    checks.eq(
+        "connect packet",
+        peripheral.connect(&packet(&CONNECT_PDU), 2_000_000),
+        true,
+    );
+    checks.eq(
+        "connect packet address",
+        peripheral.access_address(),
+        (0xaf9a_8b37, 0x9a_bcde),
+    );
+
+    // the central sends in the middle of each window, so that its anchor
+    // points are 45 ms apart from the first one on
+    let mut central = Central::new(&mut peripheral);
+    central.send(&mut peripheral, 0b01, &[]);
+    central.miss(&mut peripheral);
+    central.send(&mut peripheral, 0b11, &CHANNEL_MAP_PDU);
+    while central.counter != 8 {
+        central.send(&mut peripheral, 0b01, &[]);
+    }
+    central.send(&mut peripheral, 0b11, &CONNECTION_UPDATE_PDU);
+    while central.counter != 12 {
+        central.send(&mut peripheral, 0b01, &[]);
+    }
+    let timings: Vec<_> = central
+        .timings
+        .iter()
+        .map(|t| (t.channel, t.start_us, t.window_us))
+        .collect();
+    // widened by 550 ppm of the time since the last anchor point and 16 us
+    checks.eq(
+        "connection events",
+        timings,
+        vec![
+            // the transmit window from 2.01125 s, anchor point at 2.013125 s
+            (22, 2_011_226, 3_798),
+            // missed, so the next window is widened for two intervals
+            (31, 2_058_085, 80),
+            (27, 2_103_060, 130),
+            (36, 2_148_085, 80),
+            (21, 2_193_085, 80),
+            (30, 2_238_085, 80),
+            (26, 2_283_085, 80),
+            // the new channel map from its instant on
+            (1, 2_328_085, 80),
+            (17, 2_373_085, 80),
+            (12, 2_418_085, 80),
+            (1, 2_463_085, 80),
+            // the new transmit window 50 ms after the last anchor point,
+            // then the new interval
+            (33, 2_513_081, 2_588),
+            (12, 2_614_304, 142),
+        ],
+    );
+
+    // the new supervision timeout of 3 s from event 11, the last heard,
+    // which event 12 and 29 more fit in
+    let mut events = 0;
+    while peripheral.next_event().is_some() {
+        events += 1;
+    }
+    checks.eq("events before the new timeout", events, 29);
+}
+
+/// Sends data PDUs with the given sequence numbers straight to the
+/// peripheral, checking which ones it takes as new and which of its own it
+/// sends again.
+fn check_acknowledgements(checks: &mut Checks) {
+    const SN: u8 = 0x08;
+    const NESN: u8 = 0x04;
+
+    let mut peripheral = Peripheral::new(Address::random_static(0x1234_5678_9abc), false);
+    peripheral.attach_event(record);
+    peripheral.connect(&packet(&CONNECT_PDU), 0);
+    peripheral.next_event();
+    take_events();
+    // an ATT write of the Play characteristic
+    let play = |header: u8| packet(&[header | 0b10, 7, 3, 0, 4, 0, 0x12, 8, 0, 1]);
+    let empty = |header: u8| packet(&[header | 0b01, 0]);
+    let mut exchange = |rx: Option<Packet>| {
+        let tx = *peripheral.exchange(rx.as_ref(), 10_000);
+        peripheral.process();
+        (tx[0] & 0x0f, payload(&tx).to_vec())
+    };
+
+    checks.eq("new packet", exchange(Some(play(0))), (0b01 | NESN, vec![]));
+    // the central did not hear the acknowledgement and sends it again
+    checks.eq(
+        "packet sent again",
+        exchange(Some(play(0))),
+        (0b01 | NESN, vec![]),
+    );
+    checks.eq("played once", take_events(), vec![Event::Play]);
+
+    let response = vec![1, 0, 4, 0, 0x13];
+    checks.eq(
+        "acknowledged",
+        exchange(Some(empty(SN | NESN))),
+        (0b10 | SN, response.clone()),
+    );
+    // a CRC error, then the central not hearing the response: either way
+    // the response goes again, and nothing new is taken
+    checks.eq(
+        "after a CRC error",
+        exchange(None),
+        (0b10 | SN, response.clone()),
+    );
+    checks.eq(
+        "not acknowledged",
+        exchange(Some(empty(SN | NESN))),
+        (0b10 | SN, response),
+    );
+    checks.eq(
+        "response acknowledged",
+        exchange(Some(empty(0))),
+        (0b01 | NESN, vec![]),
+    );
+    checks.eq("no more events", take_events(), vec![]);
+}
+
+fn main() -> ExitCode {
+    let mut checks = Checks::new("ble");
+    let address = Address::random_static(0x1234_5678_9abc);
+    checks.eq("address", address.0, [0xbc, 0x9a, 0x78, 0x56, 0x34, 0xd2]);
+
+    checks.eq(
+        "channel frequencies",
+        [0, 10, 11, 36, 37, 38, 39].map(ble::channel_freq),
+        [4, 24, 28, 78, 2, 26, 80],
+    );
+
+    // advertising
+    let mut peripheral = Peripheral::new(address, false);
+    peripheral.attach_event(record);
+    let mut packet = [0; 2 + ble::MAX_PAYLOAD];
+    peripheral.advertisement(&mut packet);
+    let mut expected = vec![0x40, 37];
+    expected.extend_from_slice(&address.0);
+    expected.extend_from_slice(&[2, 0x01, 0x06, 17, 0x07]);
+    expected.extend_from_slice(&MUSICBOX_SERVICE.to_le_bytes());
+    expected.extend_from_slice(&[9, 0x09]);
+    expected.extend_from_slice(b"musicbox");
+    checks.eq("advertisement", &packet[..39], &expected[..]);
+    checks.eq(
+        "advertising address",
+        peripheral.access_address(),
+        (ble::ADV_ACCESS_ADDRESS, ble::ADV_CRC_INIT),
+    );
+
+    // connecting
+    let connect = connect_ind(address);
+    checks.eq(
+        "connect to another address",
+        peripheral.connect(&connect_ind(Address::random_static(1)), 0),
+        false,
+    );
+    let end_us = 1_000_000;
+    checks.eq("connect", peripheral.connect(&connect, end_us), true);
+    checks.eq("connect events", take_events(), vec![Event::Connect]);
+    checks.eq(
+        "connection address",
+        peripheral.access_address(),
+        (ACCESS_ADDRESS, CRC_INIT),
+    );
+
+    // the first event in the transmit window, widened by 16 us of jitter
+    let timing = peripheral.next_event().unwrap();
+    checks.eq(
+        "first event",
+        (timing.channel, timing.start_us, timing.window_us),
+        (HOP, end_us + 5000 - 16, 2500 + 32),
+    );
+    let mut central = Central {
+        sn: false,
+        nesn: false,
+        counter: 0,
+        now_us: timing.start_us + 100,
+        channel: timing.channel,
+        timings: Vec::new(),
+    };
+    let first_us = central.now_us;
+    let reply = central.send(&mut peripheral, 0b01, &[]);
+    checks.eq("first reply", payload(&reply), &[][..]);
+    checks.eq("first reply header", reply[0], 0b01 | 0x04);
+    // from then on a widening of 100 ppm of the interval
+    checks.eq(
+        "second event",
+        (central.channel, central.now_us),
+        (2 * HOP, first_us + INTERVAL as u32 * 1250),
+    );
+
+    // discovering the services
+    checks.eq(
+        "mtu",
+        central.att(&mut peripheral, &[0x02, 0x00, 0x02]),
+        vec![0x03, 23, 0],
+    );
+    checks.eq(
+        "primary services",
+        central.att(&mut peripheral, &[0x10, 1, 0, 0xff, 0xff, 0x00, 0x28]),
+        vec![0x11, 6, 1, 0, 5, 0, 0x00, 0x18],
+    );
+    let mut expected = vec![0x11, 20, 6, 0, 26, 0];
+    expected.extend_from_slice(&MUSICBOX_SERVICE.to_le_bytes());
+    checks.eq(
+        "music box service",
+        central.att(&mut peripheral, &[0x10, 6, 0, 0xff, 0xff, 0x00, 0x28]),
+        expected,
+    );
+    checks.eq(
+        "no more services",
+        central.att(&mut peripheral, &[0x10, 27, 0, 0xff, 0xff, 0x00, 0x28]),
+        vec![0x01, 0x10, 27, 0, 0x0a],
+    );
+    let mut request = vec![0x06, 1, 0, 0xff, 0xff, 0x00, 0x28];
+    request.extend_from_slice(&MUSICBOX_SERVICE.to_le_bytes());
+    checks.eq(
+        "find the music box service",
+        central.att(&mut peripheral, &request),
+        vec![0x07, 6, 0, 26, 0],
+    );
+    let mut expected = vec![0x09, 21, 7, 0, 0x0c, 8, 0];
+    expected.extend_from_slice(&(MUSICBOX_SERVICE + (1 << 96)).to_le_bytes());
+    checks.eq(
+        "play characteristic",
+        central.att(&mut peripheral, &[0x08, 6, 0, 26, 0, 0x03, 0x28]),
+        expected,
+    );
+    checks.eq(
+        "play description",
+        central.att(&mut peripheral, &[0x04, 9, 0, 9, 0]),
+        vec![0x05, 1, 9, 0, 0x01, 0x29],
+    );
+    checks.eq(
+        "read play description",
+        central.att(&mut peripheral, &[0x0a, 9, 0]),
+        b"\x0bPlay".to_vec(),
+    );
+    checks.eq(
+        "device name",
+        central.att(&mut peripheral, &[0x08, 1, 0, 0xff, 0xff, 0x00, 0x2a]),
+        b"\x09\x0a\x03\x00musicbox".to_vec(),
+    );
+
+    // using the characteristics
+    peripheral.update_status(Status {
+        volume: 40,
+        ..Status::default()
+    });
+    checks.eq(
+        "read volume",
+        central.att(&mut peripheral, &[0x0a, 20, 0]),
+        vec![0x0b, 40],
+    );
+    checks.eq(
+        "play",
+        central.att(&mut peripheral, &[0x12, 8, 0, 1]),
+        vec![0x13],
+    );
+    checks.eq(
+        "set volume",
+        central.att(&mut peripheral, &[0x52, 20, 0, 150]),
+        vec![],
+    );
+    checks.eq(
+        "events",
+        take_events(),
+        vec![Event::Play, Event::Volume(100)],
+    );
+    checks.eq(
+        "write now playing",
+        central.att(&mut peripheral, &[0x12, 24, 0, 1]),
+        vec![0x01, 0x12, 24, 0, 0x03],
+    );
+    checks.eq(
+        "read past the end",
+        central.att(&mut peripheral, &[0x0a, 30, 0]),
+        vec![0x01, 0x0a, 30, 0, 0x01],
+    );
+    checks.eq(
+        "unsupported request",
+        central.att(&mut peripheral, &[0x20, 8, 0]),
+        vec![0x01, 0x20, 0, 0, 0x06],
+    );
+
+    // notifications
+    checks.eq(
+        "subscribe",
+        central.att(&mut peripheral, &[0x12, 25, 0, 1, 0]),
+        vec![0x13],
+    );
+    checks.eq(
+        "read subscription",
+        central.att(&mut peripheral, &[0x0a, 25, 0]),
+        vec![0x0b, 1, 0],
+    );
+    peripheral.update_status(Status {
+        state: PlayState::Playing,
+        index: Some(2),
+        duration_ms: 61_000,
+        volume: 50,
+    });
+    let mut expected = vec![0x1b, 24, 0, 1, 2];
+    expected.extend_from_slice(&61_000u32.to_le_bytes());
+    checks.eq(
+        "now playing notification",
+        central
+            .answer(&mut peripheral, 0b10)
+            .map(|pdu| pdu[4..].to_vec()),
+        Some(expected),
+    );
+    // volume is not subscribed to
+    checks.eq(
+        "no more notifications",
+        central.answer(&mut peripheral, 0b10),
+        None,
+    );
+
+    // link-layer control
+    checks.eq(
+        "features",
+        central.control(&mut peripheral, &[0x08, 0xff, 0, 0, 0, 0, 0, 0, 0]),
+        vec![0x09, 0, 0, 0, 0, 0, 0, 0, 0],
+    );
+    checks.eq(
+        "version",
+        central.control(&mut peripheral, &[0x0c, 0x09, 0x59, 0x00, 0x01, 0x00]),
+        vec![0x0c, 0x09, 0xff, 0xff, 0x00, 0x00],
+    );
+    checks.eq(
+        "ping",
+        central.control(&mut peripheral, &[0x12]),
+        vec![0x13],
+    );
+    checks.eq(
+        "length request",
+        central.control(
+            &mut peripheral,
+            &[0x14, 251, 0, 0x48, 0x08, 251, 0, 0x48, 0x08],
+        ),
+        vec![0x07, 0x14],
+    );
+    checks.eq(
+        "connection parameter request",
+        central.l2cap(
+            &mut peripheral,
+            0x0005,
+            &[0x12, 7, 8, 0, 6, 0, 12, 0, 0, 0, 100, 0],
+        ),
+        vec![0x01, 7, 2, 0, 0, 0],
+    );
+    checks.eq(
+        "pairing",
+        central.l2cap(&mut peripheral, 0x0006, &[0x01, 0x03, 0, 0x01, 16, 0, 0]),
+        vec![0x05, 0x05],
+    );
+
+    // channel map update: channels 1 and 2 only
+    let instant = central.counter.wrapping_add(4);
+    let mut pdu = vec![0x01, 0x06, 0, 0, 0, 0];
+    pdu.extend_from_slice(&instant.to_le_bytes());
+    central.send(&mut peripheral, 0b11, &pdu);
+    while central.counter != instant {
+        central.send(&mut peripheral, 0b01, &[]);
+    }
+    let mut channels = Vec::new();
+    for _ in 0..6 {
+        channels.push(central.channel);
+        central.send(&mut peripheral, 0b01, &[]);
+    }
+    checks.eq(
+        "remapped channels",
+        channels.iter().all(|channel| [1, 2].contains(channel)),
+        true,
+    );
+
+    // connection update: 50 ms interval, 2 s timeout
+    let instant = central.counter.wrapping_add(4);
+    let mut pdu = vec![0x00, 1];
+    pdu.extend_from_slice(&2u16.to_le_bytes());
+    pdu.extend_from_slice(&40u16.to_le_bytes());
+    pdu.extend_from_slice(&0u16.to_le_bytes());
+    pdu.extend_from_slice(&200u16.to_le_bytes());
+    pdu.extend_from_slice(&instant.to_le_bytes());
+    central.send(&mut peripheral, 0b11, &pdu);
+    while central.counter.wrapping_add(1) != instant {
+        central.send(&mut peripheral, 0b01, &[]);
+    }
+    let before_us = central.now_us;
+    let mut packet = [0; 2 + ble::MAX_PAYLOAD];
+    packet[0] = 0b01 | if central.nesn { 0x04 } else { 0 } | if central.sn { 0x08 } else { 0 };
+    peripheral.exchange(Some(&packet), before_us);
+    let timing = peripheral.next_event().unwrap();
+    let widening = (INTERVAL as u32 * 1250 + 2500 + 1250) * 100 / 1_000_000 + 16;
+    checks.eq(
+        "update transmit window",
+        (timing.start_us, timing.window_us),
+        (
+            before_us + INTERVAL as u32 * 1250 + 2500 - widening,
+            1250 + 2 * widening,
+        ),
+    );
+    let anchor_us = timing.start_us + widening;
+    peripheral.exchange(Some(&packet), anchor_us);
+    let timing = peripheral.next_event().unwrap();
+    checks.eq(
+        "updated interval",
+        timing.start_us + 5 + 16,
+        anchor_us + 50_000,
+    );
+
+    // supervision timeout: the central goes quiet
+    let mut events = 1;
+    while peripheral.next_event().is_some() {
+        events += 1;
+    }
+    checks.eq("events before the timeout", events, 40);
+    checks.eq("timeout events", take_events(), vec![Event::Disconnect]);
+    checks.eq("disconnected", peripheral.is_connected(), false);
+
+    // the central terminating the connection
+    peripheral.connect(&connect, 0);
+    let mut central = Central::new(&mut peripheral);
+    central.send(&mut peripheral, 0b11, &[0x02, 0x13]);
+    checks.eq(
+        "terminate events",
+        take_events(),
+        vec![Event::Connect, Event::Disconnect],
+    );
+    checks.eq("terminated", peripheral.is_connected(), false);
+
+    // BLE-MIDI
+    let mut peripheral = Peripheral::new(address, true);
+    peripheral.attach_event(record);
+    let mut packet = [0; 2 + ble::MAX_PAYLOAD];
+    peripheral.advertisement(&mut packet);
+    checks.eq(
+        "midi advertisement",
+        &packet[13..29],
+        &ble::MIDI_SERVICE.to_le_bytes()[..],
+    );
+    peripheral.connect(&connect, 0);
+    let mut central = Central::new(&mut peripheral);
+    let mut expected = vec![0x11, 20, 27, 0, 29, 0];
+    expected.extend_from_slice(&ble::MIDI_SERVICE.to_le_bytes());
+    checks.eq(
+        "midi service",
+        central.att(&mut peripheral, &[0x10, 27, 0, 0xff, 0xff, 0x00, 0x28]),
+        expected,
+    );
+    checks.eq(
+        "play a note",
+        central.att(&mut peripheral, &[0x52, 29, 0, 0x80, 0x80, 0x90, 60, 100]),
+        vec![],
+    );
+    checks.eq(
+        "midi events",
+        take_events(),
+        vec![
+            Event::Connect,
+            Event::Midi(Message::NoteOn {
+                channel: 0,
+                key: 60,
+                velocity: 100,
+            }),
+        ],
+    );
+
+    let messages = |packet: &[u8]| {
+        let mut messages = Vec::new();
+        ble::midi_messages(packet, |message| messages.push(message));
+        messages
+    };
+    checks.eq(
+        "running status",
+        messages(&[0x80, 0x80, 0x91, 60, 100, 64, 90, 0x81, 0x81, 60, 0]),
+        vec![
+            Message::NoteOn {
+                channel: 1,
+                key: 60,
+                velocity: 100,
+            },
+            Message::NoteOn {
+                channel: 1,
+                key: 64,
+                velocity: 90,
+            },
+            Message::NoteOff {
+                channel: 1,
+                key: 60,
+            },
+        ],
+    );
+    checks.eq(
+        "real-time in between",
+        messages(&[0x80, 0x80, 0xb0, 7, 0x81, 0xf8, 64]),
+        vec![Message::ControlChange {
+            channel: 0,
+            controller: 7,
+            value: 64,
+        }],
+    );
+    checks.eq(
+        "system exclusive",
+        messages(&[0x80, 0x80, 0xf0, 1, 2, 3, 0x81, 0xf7, 4, 0x82, 0x80, 62, 0]),
+        vec![Message::NoteOff {
+            channel: 0,
+            key: 62,
+        }],
+    );
+    checks.eq(
+        "pitch bend",
+        messages(&[
+            0x80, 0x80, 0xe2, 0, 0x40, 0x81, 0xe2, 0, 0, 0x82, 0xe2, 0x7f, 0x7f,
+        ]),
+        vec![
+            Message::PitchBend {
+                channel: 2,
+                bend: 0,
+            },
+            Message::PitchBend {
+                channel: 2,
+                bend: -8192,
+            },
+            Message::PitchBend {
+                channel: 2,
+                bend: 8191,
+            },
+        ],
+    );
+    checks.eq(
+        "ignored",
+        messages(&[0x80, 0x80, 0xc0, 5, 0x80, 0xd0, 9]),
+        vec![],
+    );
+    checks.eq(
+        "not BLE-MIDI",
+        messages(&[0x00, 0x80, 0x90, 60, 100]),
+        vec![],
+    );
+
+    check_connection(&mut checks);
+    check_acknowledgements(&mut checks);
+    checks.finish()
+}
@@ -0,0 +1,263 @@
+//! Runs `clap::ClapDetector` over sound made up on the host and over the
+//! recordings in `examples/data/clap/`, checking the events it reports, or
+//! over other recordings to see how it does on them.
+//!
+//! ```text
+//! cargo run --example clap --target <host triple> -- [WAV...]
+//! ```
+//!
+//! Recordings are 16-bit mono WAV files at 8 kHz; the events found in each
+//! one given on the command line are printed with their time.
+
+// only part of the firmware modules is used here
+#![allow(dead_code, unused_imports, unused_macros)]
+
+#[path = "common/checks.rs"]
+mod checks;
+#[path = "../src/clap.rs"]
+mod clap;
+
+extern crate microbit as bsp;
+
+use std::{env, fs, process::ExitCode};
+
+use checks::Checks;
+use clap::{ClapDetector, Event, BLOCK_HZ, BLOCK_LEN, SAMPLE_HZ};
+
+/// Samples per millisecond.
+const PER_MS: usize = (SAMPLE_HZ / 1000) as usize;
+
+const DATA_DIR: &str = "examples/data/clap";
+
+/// Middle of the SAADC's range, where the microphone output sits.
+const BIAS: i16 = 2048;
+
+/// Sound made up sample by sample, at the SAADC's 12-bit scale.
+struct Sound {
+    samples: Vec<i16>,
+    seed: u32,
+}
+
+impl Sound {
+    fn new() -> Self {
+        Self {
+            samples: Vec::new(),
+            seed: 1,
+        }
+    }
+
+    /// White noise between `-amplitude` and `amplitude`.
+    fn noise(&mut self, amplitude: i32) -> i32 {
+        self.seed = self
+            .seed
+            .wrapping_mul(1_664_525)
+            .wrapping_add(1_013_904_223);
+        ((self.seed >> 16) as i32 % (2 * amplitude + 1)) - amplitude
+    }
+
+    /// Background noise of `amplitude` for `ms`.
+    fn room(&mut self, ms: usize, amplitude: i32) -> &mut Self {
+        for _ in 0..ms * PER_MS {
+            let sample = BIAS as i32 + self.noise(amplitude);
+            self.samples.push(sample as i16);
+        }
+        self
+    }
+
+    /// A clap: loud noise dying away within 40 ms, over a quiet room, then
+    /// the room until `ms` in all.
+    fn clap(&mut self, ms: usize) -> &mut Self {
+        let len = 40 * PER_MS;
+        for i in 0..len {
+            let amplitude = 1500 * (len - i) as i32 / len as i32;
+            let sample = BIAS as i32 + self.noise(amplitude.max(20));
+            self.samples.push(sample.clamp(0, 4095) as i16);
+        }
+        self.room(ms - 40, 20)
+    }
+
+    /// A steady square wave of `amplitude` at 500 Hz, for `ms`.
+    fn tone(&mut self, ms: usize, amplitude: i16) -> &mut Self {
+        for i in 0..ms * PER_MS {
+            let high = i % (2 * PER_MS) < PER_MS;
+            self.samples.push(if high {
+                BIAS + amplitude
+            } else {
+                BIAS - amplitude
+            });
+        }
+        self
+    }
+
+    /// The sound made so far, to keep it after building it in place.
+    fn take(&mut self) -> Sound {
+        std::mem::replace(self, Sound::new())
+    }
+}
+
+/// The events a new detector reports for `samples`, with the time they come at
+/// in milliseconds.
+fn detect(samples: &[i16]) -> Vec<(usize, Event)> {
+    let mut detector = ClapDetector::<BLOCK_HZ>::new();
+    samples
+        .chunks_exact(BLOCK_LEN)
+        .enumerate()
+        .filter_map(|(i, block)| {
+            let event = detector.update(clap::level(block))?;
+            Some(((i + 1) * 1000 / BLOCK_HZ as usize, event))
+        })
+        .collect()
+}
+
+/// The events for `samples` if they are the `expected` ones, whatever
+/// their time.
+fn expect(samples: &[i16], expected: &[Event]) -> Result<(), String> {
+    let events = detect(samples);
+    let got: Vec<Event> = events.iter().map(|&(_, event)| event).collect();
+    if got != expected {
+        return Err(format!("got {:?}, expected {:?}", events, expected));
+    }
+    Ok(())
+}
+
+/// Checks the events for the recording `name`, whatever their time.
+fn check_recording(checks: &mut Checks, name: &str, expected: &[Event]) {
+    let path = format!("{}/{}.wav", DATA_DIR, name);
+    let samples = match fs::read(&path) {
+        Ok(data) => read_wav(&data),
+        Err(_) => Err("cannot read it"),
+    };
+    let result = samples
+        .map_err(|e| format!("{}: {}", path, e))
+        .and_then(|samples| expect(&samples, expected));
+    checks.check(name, result);
+}
+
+/// The samples of a 16-bit mono WAV file at `SAMPLE_HZ`, brought down to
+/// the SAADC's 12 bits.
+fn read_wav(data: &[u8]) -> Result<Vec<i16>, &'static str> {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err("not a WAV file");
+    }
+    let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+    let mut format = None;
+    let mut i = 12;
+    while i + 8 <= data.len() {
+        let len = u32_at(i + 4) as usize;
+        let body = i + 8..(i + 8 + len).min(data.len());
+        match &data[i..i + 4] {
+            b"fmt " if len >= 16 => {
+                format = Some((
+                    u16_at(i + 8),
+                    u16_at(i + 10),
+                    u32_at(i + 12),
+                    u16_at(i + 22),
+                ))
+            }
+            b"data" => {
+                if format != Some((1, 1, SAMPLE_HZ, 16)) {
+                    return Err("not 16-bit mono PCM at 8 kHz");
+                }
+                return Ok(data[body]
+                    .chunks_exact(2)
+                    .map(|b| i16::from_le_bytes([b[0], b[1]]) >> 4)
+                    .collect());
+            }
+            _ => {}
+        }
+        // chunks are padded to an even length
+        i += 8 + len + len % 2;
+    }
+    Err("no data chunk")
+}
+
+fn main() -> ExitCode {
+    let files: Vec<String> = env::args().skip(1).collect();
+    if !files.is_empty() {
+        for file in files {
+            let samples = match fs::read(&file) {
+                Ok(data) => read_wav(&data),
+                Err(_) => Err("cannot read it"),
+            };
+            match samples {
+                Ok(samples) => {
+                    println!("{}:", file);
+                    for (ms, event) in detect(&samples) {
+                        println!("{:8} ms {:?}", ms, event);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}: {}", file, e);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    use Event::*;
+    let sounds: [(&str, Sound, &[Event]); 7] = [
+        ("quiet room", Sound::new().room(3000, 20).take(), &[]),
+        (
+            "clap",
+            Sound::new().room(1000, 20).clap(2000).take(),
+            &[Clap],
+        ),
+        (
+            "double clap",
+            Sound::new().room(1000, 20).clap(300).clap(2000).take(),
+            &[DoubleClap],
+        ),
+        (
+            "two claps apart",
+            Sound::new().room(1000, 20).clap(800).clap(2000).take(),
+            &[Clap, Clap],
+        ),
+        (
+            "triple clap",
+            Sound::new()
+                .room(1000, 20)
+                .clap(300)
+                .clap(150)
+                .clap(2000)
+                .take(),
+            &[DoubleClap],
+        ),
+        (
+            "lasting sound",
+            Sound::new()
+                .room(1000, 20)
+                .tone(1000, 1000)
+                .room(2000, 20)
+                .take(),
+            &[],
+        ),
+        (
+            "loud room",
+            Sound::new()
+                .room(1000, 20)
+                .room(4000, 400)
+                .room(8000, 20)
+                .take(),
+            &[Loud, Quiet],
+        ),
+    ];
+    let recordings: [(&str, &[Event]); 4] = [
+        ("clap", &[Clap]),
+        ("double_clap", &[DoubleClap]),
+        // talking next to the board, with a fan on
+        ("speech", &[]),
+        // the board playing staccato melodies through its own speaker, the
+        // notes starting as suddenly as claps
+        ("staccato", &[]),
+    ];
+    let mut checks = Checks::new("clap");
+    for (what, sound, expected) in sounds {
+        checks.check(what, expect(&sound.samples, expected));
+    }
+    for (name, expected) in recordings {
+        check_recording(&mut checks, name, expected);
+    }
+    checks.finish()
+}
@@ -0,0 +1,57 @@
+//! Counts the checks an example makes, reporting those that fail as they
+//! come and all of them at the end.
+//!
+//! Included by the examples with `#[path = "common/checks.rs"]`; cargo only
+//! takes the files directly in `examples/` for examples of their own.
+
+use std::{fmt::Debug, process::ExitCode};
+
+pub struct Checks {
+    name: &'static str,
+    count: usize,
+    failed: usize,
+}
+
+impl Checks {
+    /// Checks made by the example `name`.
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            count: 0,
+            failed: 0,
+        }
+    }
+
+    /// Counts a check, printing what went wrong if it failed.
+    pub fn check(&mut self, what: &str, result: Result<(), String>) -> bool {
+        self.count += 1;
+        if let Err(e) = &result {
+            println!("{}: {}", what, e);
+            self.failed += 1;
+        }
+        result.is_ok()
+    }
+
+    /// Checks that `got` is what was `expected`.
+    pub fn eq<T: Debug + PartialEq>(&mut self, what: &str, got: T, expected: T) -> bool {
+        let result = if got == expected {
+            Ok(())
+        } else {
+            Err(format!("got {:?}, expected {:?}", got, expected))
+        };
+        self.check(what, result)
+    }
+
+    /// Prints how many checks failed, for the example to exit with.
+    pub fn finish(self) -> ExitCode {
+        println!(
+            "{}: {} checks, {} failed",
+            self.name, self.count, self.failed
+        );
+        if self.failed == 0 {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        }
+    }
+}
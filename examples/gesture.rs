@@ -0,0 +1,150 @@
+//! Feeds the accelerometer traces in `examples/data/gesture/` through
+//! `gesture::ShakeDetector` and checks how many shakes it reports, with its
+//! default settings and with the threshold, peak count and refractory period
+//! changed.
+//!
+//! ```text
+//! cargo run --example gesture --target <host triple> -- [TRACE...]
+//! ```
+//!
+//! Traces given on the command line are run with the default settings and
+//! the time of each shake printed. A trace has one `x,y,z` sample in milli-g
+//! per line at 100 Hz, lines starting with `#` being comments; the firmware
+//! logs its samples in that form at the `trace` level.
+
+// only part of the firmware modules is used here
+#![allow(dead_code, unused_imports, unused_macros)]
+
+#[path = "common/checks.rs"]
+mod checks;
+#[path = "../src/gesture.rs"]
+mod gesture;
+
+extern crate microbit as bsp;
+
+use std::{env, fs, process::ExitCode};
+
+use checks::Checks;
+use fugit::ExtU64;
+use gesture::{Detector, Event, Sample, ShakeDetector};
+
+const DATA_DIR: &str = "examples/data/gesture";
+
+/// Sample rate of the traces, the time base of the detector.
+const SAMPLE_HZ: u32 = 100;
+
+type Shakes = ShakeDetector<SAMPLE_HZ>;
+
+/// The samples of a trace.
+fn read_trace(text: &str) -> Result<Vec<Sample>, String> {
+    let mut samples = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let axes: Vec<i32> = line
+            .split(',')
+            .map(|axis| axis.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("line {}: {}", i + 1, e))?;
+        let [x, y, z] = axes[..] else {
+            return Err(format!("line {}: expected x,y,z", i + 1));
+        };
+        samples.push(Sample::new(x, y, z));
+    }
+    Ok(samples)
+}
+
+/// The samples at which `detector` reports a shake.
+fn shakes(samples: &[Sample], mut detector: Shakes) -> Vec<usize> {
+    samples
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &sample)| (detector.update(sample) == Some(Event::Shake)).then_some(i))
+        .collect()
+}
+
+/// Runs the trace `name` through `detector` and checks the number of shakes.
+fn check(checks: &mut Checks, name: &str, detector: Shakes, what: &str, expected: usize) {
+    let path = format!("{}/{}.csv", DATA_DIR, name);
+    let result = fs::read_to_string(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|t| read_trace(&t))
+        .map_err(|e| format!("{}: {}", path, e))
+        .and_then(|samples| {
+            let got = shakes(&samples, detector);
+            if got.len() == expected {
+                Ok(())
+            } else {
+                Err(format!(
+                    "shakes at samples {:?}, expected {}",
+                    got, expected
+                ))
+            }
+        });
+    checks.check(&format!("{} ({})", name, what), result);
+}
+
+fn main() -> ExitCode {
+    let files: Vec<String> = env::args().skip(1).collect();
+    if !files.is_empty() {
+        for file in files {
+            let samples = fs::read_to_string(&file)
+                .map_err(|e| e.to_string())
+                .and_then(|t| read_trace(&t));
+            match samples {
+                Ok(samples) => {
+                    println!("{}:", file);
+                    for i in shakes(&samples, Shakes::new()) {
+                        println!("{:8} ms shake", i * 1000 / SAMPLE_HZ as usize);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}: {}", file, e);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let with = |f: fn(&mut Shakes)| {
+        let mut detector = Shakes::new();
+        f(&mut detector);
+        detector
+    };
+    let mut checks = Checks::new("gesture");
+    check(&mut checks, "shake", Shakes::new(), "default", 1);
+    check(&mut checks, "walking", Shakes::new(), "default", 0);
+    check(&mut checks, "knock", Shakes::new(), "default", 0);
+    // the second shake comes within the refractory period of the first;
+    // with a shorter one, the first shake is also long enough for two
+    check(&mut checks, "shake_refractory", Shakes::new(), "default", 1);
+    check(
+        &mut checks,
+        "shake_refractory",
+        with(|d| d.set_refractory_ms(200.millis())),
+        "refractory 200 ms",
+        3,
+    );
+    // the heel strikes count as peaks once the threshold is low enough,
+    // a shake every three steps
+    check(
+        &mut checks,
+        "walking",
+        with(|d| d.set_threshold_mg(300)),
+        "threshold 300 mg",
+        4,
+    );
+    check(
+        &mut checks,
+        "shake",
+        with(|d| d.set_threshold_mg(4500)),
+        "threshold 4500 mg",
+        0,
+    );
+    // a knock rings for two peaks
+    check(&mut checks, "knock", with(|d| d.set_peaks(2)), "2 peaks", 1);
+    checks.finish()
+}
@@ -0,0 +1,221 @@
+//! Plays MIDI byte streams live the way the firmware's `handle_midi` task
+//! does, through `midi::Parser`, `live::Live` and the `Player`, and checks
+//! what the player's output sounds: last-note priority, note on with
+//! velocity 0 as a note off, running status and real-time bytes in between.
+//!
+//! ```text
+//! cargo run --example live --target <host triple>
+//! ```
+
+// only part of the firmware modules is used here
+#![allow(dead_code, unused_imports, unused_macros)]
+
+#[path = "common/checks.rs"]
+mod checks;
+#[path = "../src/envelope.rs"]
+mod envelope;
+#[path = "../src/live.rs"]
+mod live;
+#[macro_use]
+#[path = "../src/melody.rs"]
+mod melody;
+#[path = "../src/midi.rs"]
+mod midi;
+#[path = "../src/pack.rs"]
+mod pack;
+#[path = "../src/player.rs"]
+mod player;
+#[path = "../src/rtttl.rs"]
+mod rtttl;
+#[path = "../src/screen.rs"]
+mod screen;
+#[path = "../src/shuffle.rs"]
+mod shuffle;
+#[path = "../src/tone.rs"]
+mod tone;
+#[path = "../src/tuning.rs"]
+mod tuning;
+#[path = "../src/waveform.rs"]
+mod waveform;
+
+extern crate microbit as bsp;
+
+use std::{cell::RefCell, process::ExitCode, rc::Rc};
+
+use checks::Checks;
+use live::{Action, Live};
+use melody::Melody;
+use player::{Duration, Instant, Player, PlayerClock, ToneOutput};
+use tone::Pitch;
+use tuning::Tuning;
+
+/// What the player's output was told to do, the frequencies rounded to a
+/// hundredth of a hertz.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Sound {
+    Tone { centihertz: u32, volume: u32 },
+    Move { centihertz: u32 },
+    Release,
+    Stop,
+}
+
+fn centihertz(freq: f32) -> u32 {
+    (freq * 100.0).round() as u32
+}
+
+/// Records what it is told to sound.
+struct Recorder(Rc<RefCell<Vec<Sound>>>);
+
+impl ToneOutput for Recorder {
+    fn tone(&mut self, _voice: usize, freq: f32, volume: u32) {
+        let centihertz = centihertz(freq);
+        self.0.borrow_mut().push(Sound::Tone { centihertz, volume });
+    }
+
+    fn set_freq(&mut self, _voice: usize, freq: f32) {
+        let centihertz = centihertz(freq);
+        self.0.borrow_mut().push(Sound::Move { centihertz });
+    }
+
+    fn release(&mut self, _voice: usize) {
+        self.0.borrow_mut().push(Sound::Release);
+    }
+
+    fn stop(&mut self) {
+        self.0.borrow_mut().push(Sound::Stop);
+    }
+}
+
+/// A clock that stands still, as no melody is played.
+struct Stopped;
+
+impl PlayerClock for Stopped {
+    fn start(&mut self) {}
+    fn stop(&mut self) {}
+
+    fn now(&self) -> Instant {
+        Instant::from_ticks(0)
+    }
+
+    fn set_play_duration(&mut self, _duration: Duration) {}
+    fn set_tick_duration(&mut self, _duration: Duration) {}
+
+    fn check_play(&mut self) -> bool {
+        false
+    }
+
+    fn check_tick(&mut self) -> bool {
+        false
+    }
+}
+
+/// Feeds `bytes` to the parser and the messages it completes to `Live`,
+/// carrying its actions out on a player as `handle_midi` does, and returns
+/// what the player sounded.
+fn play(bytes: &[u8]) -> Vec<Sound> {
+    let sounds = Rc::new(RefCell::new(Vec::new()));
+    let mut player = Player::new(Stopped, Recorder(sounds.clone()), &[] as &[Melody]);
+    let mut parser = midi::Parser::new();
+    let mut live = Live::new();
+    for &byte in bytes {
+        let Some(message) = parser.push(byte) else {
+            continue;
+        };
+        match live.handle(message) {
+            Some(Action::Start { pitch, volume }) => player.note_on(pitch, volume),
+            Some(Action::Move(pitch)) => player.move_note(pitch),
+            Some(Action::Release) => player.note_off(),
+            None => {}
+        }
+    }
+    sounds.take()
+}
+
+fn key_centihertz(key: u8) -> u32 {
+    centihertz(Tuning::DEFAULT.freq(Pitch::Key(key, 0)))
+}
+
+/// A key struck with velocity 100, at the player's full volume.
+fn tone(key: u8) -> Sound {
+    Sound::Tone {
+        centihertz: key_centihertz(key),
+        volume: 100 * 100 / 127,
+    }
+}
+
+fn moved(key: u8) -> Sound {
+    Sound::Move {
+        centihertz: key_centihertz(key),
+    }
+}
+
+/// The latest key held sounds, and releasing it goes back to the one held
+/// before without striking it again.
+fn check_last_note(checks: &mut Checks) {
+    checks.eq(
+        "last-note priority",
+        play(&[
+            0x90, 60, 100, // C4
+            0x90, 64, 100, // E4
+            0x90, 67, 100, // G4
+            0x80, 64, 0, // E4 off, while G4 sounds
+            0x80, 67, 0, // G4 off, back to C4
+            0x80, 60, 0, // C4 off
+        ]),
+        vec![tone(60), tone(64), tone(67), moved(60), Sound::Release],
+    );
+    checks.eq(
+        "key struck again",
+        play(&[
+            0x90, 60, 100, // C4
+            0x90, 64, 100, // E4
+            0x90, 60, 100, // C4 again, now the latest
+            0x80, 60, 0, // back to E4
+            0x80, 64, 0,
+        ]),
+        vec![tone(60), tone(64), tone(60), moved(64), Sound::Release],
+    );
+}
+
+/// A note on with velocity 0 is a note off, with its own status byte or
+/// the running one.
+fn check_velocity_zero(checks: &mut Checks) {
+    checks.eq(
+        "note on with velocity 0",
+        play(&[
+            0x90, 60, 100, // C4
+            0x90, 64, 100, // E4
+            0x90, 64, 0, // E4 off, back to C4
+            0x90, 60, 0, // C4 off
+        ]),
+        vec![tone(60), tone(64), moved(60), Sound::Release],
+    );
+    checks.eq(
+        "velocity 0 in running status",
+        play(&[0x90, 60, 100, 64, 100, 64, 0, 60, 0]),
+        vec![tone(60), tone(64), moved(60), Sound::Release],
+    );
+    checks.eq(
+        "velocity 0 for a key not held",
+        play(&[0x90, 60, 100, 0x90, 62, 0, 0x90, 60, 0]),
+        vec![tone(60), Sound::Release],
+    );
+}
+
+/// Real-time bytes such as the MIDI clock may come in the middle of a
+/// message, and leave it whole.
+fn check_real_time(checks: &mut Checks) {
+    checks.eq(
+        "clock within a note on",
+        play(&[0x90, 0xf8, 60, 0xf8, 100, 0x80, 60, 0xfe, 0]),
+        vec![tone(60), Sound::Release],
+    );
+}
+
+fn main() -> ExitCode {
+    let mut checks = Checks::new("live");
+    check_last_note(&mut checks);
+    check_velocity_zero(&mut checks);
+    check_real_time(&mut checks);
+    checks.finish()
+}
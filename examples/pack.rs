@@ -0,0 +1,94 @@
+//! Reports how much flash packing saves on each melody, against storing its
+//! events as they are, and checks that every voice decodes to as many
+//! events as it was written with.
+//!
+//! ```text
+//! cargo run --example pack --target <host triple>
+//! ```
+//!
+//! That the decoded events are the written ones is already checked when
+//! `melody!` packs them at compile time.
+
+// only part of the firmware modules is used here
+#![allow(dead_code, unused_imports, unused_macros)]
+
+#[path = "../src/envelope.rs"]
+mod envelope;
+#[path = "../src/melody.rs"]
+mod melody;
+#[path = "../src/pack.rs"]
+mod pack;
+#[path = "../src/player.rs"]
+mod player;
+#[path = "../src/rtttl.rs"]
+mod rtttl;
+#[path = "../src/screen.rs"]
+mod screen;
+#[path = "../src/shuffle.rs"]
+mod shuffle;
+#[path = "../src/synth.rs"]
+mod synth;
+#[path = "../src/tone.rs"]
+mod tone;
+#[path = "../src/tuning.rs"]
+mod tuning;
+#[path = "../src/waveform.rs"]
+mod waveform;
+
+extern crate microbit as bsp;
+
+use std::{mem::size_of, process::ExitCode};
+
+use melody::{Event, MELODY_LIST};
+use pack::{Packed, Reader};
+
+/// Number of events `packed` decodes to.
+fn decoded_len(packed: &Packed) -> usize {
+    let mut reader = Reader::new();
+    let mut len = 0;
+    while reader.next(packed).is_some() {
+        len += 1;
+    }
+    len
+}
+
+fn main() -> ExitCode {
+    let mut ok = true;
+    let (mut total_events, mut total_packed) = (0, 0);
+    println!("melody  events  unpacked  packed  saved");
+    for (i, melody) in MELODY_LIST.iter().enumerate() {
+        let (mut events, mut packed) = (0, 0);
+        for voice in melody.voices() {
+            if decoded_len(voice) != voice.len() {
+                println!("melody {}: decodes to {} events, not {}", i, decoded_len(voice), voice.len());
+                ok = false;
+            }
+            events += voice.len();
+            packed += voice.bytes().len();
+        }
+        let unpacked = events * size_of::<Event>();
+        println!(
+            "{:>6}  {:>6}  {:>8}  {:>6}  {:>4.1}%",
+            i,
+            events,
+            unpacked,
+            packed,
+            100.0 * (unpacked - packed) as f32 / unpacked.max(1) as f32,
+        );
+        total_events += events;
+        total_packed += packed;
+    }
+    let total_unpacked = total_events * size_of::<Event>();
+    println!(
+        " total  {:>6}  {:>8}  {:>6}  {:>4.1}%",
+        total_events,
+        total_unpacked,
+        total_packed,
+        100.0 * (total_unpacked - total_packed) as f32 / total_unpacked.max(1) as f32,
+    );
+    if ok {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,786 @@
+//! Drives `Player` with a simulated clock and an output that records every
+//! call made to it, and checks them: for each melody of `MELODY_LIST`
+//! against the sequence kept in `examples/data/player/`, and for playing,
+//! pausing, stopping, `next`/`prev` and the gap between notes against
+//! sequences worked out by hand.
+//!
+//! ```text
+//! cargo run --example player --target <host triple> [-- --bless]
+//! ```
+//!
+//! `--bless` writes the sequences the melodies play now in place of the
+//! kept ones, after a deliberate change to how they sound.
+
+// only part of the firmware modules is used here
+#![allow(dead_code, unused_imports, unused_macros)]
+
+#[path = "common/checks.rs"]
+mod checks;
+#[path = "../src/envelope.rs"]
+mod envelope;
+#[macro_use]
+#[path = "../src/melody.rs"]
+mod melody;
+#[path = "../src/pack.rs"]
+mod pack;
+#[path = "../src/player.rs"]
+mod player;
+#[path = "../src/rtttl.rs"]
+mod rtttl;
+#[path = "../src/screen.rs"]
+mod screen;
+#[path = "../src/shuffle.rs"]
+mod shuffle;
+#[path = "../src/tone.rs"]
+mod tone;
+#[path = "../src/tuning.rs"]
+mod tuning;
+#[path = "../src/waveform.rs"]
+mod waveform;
+
+extern crate microbit as bsp;
+
+use std::{cell::RefCell, env, fmt, fs, path::Path, process::ExitCode, rc::Rc};
+
+use checks::Checks;
+use envelope::Envelope;
+use melody::{Event, Melody, Note, Position, MELODY_LIST};
+use pack::Packed;
+use player::{
+    buzzer_duty, Duration, Instant, PlayMode, Player, PlayerClock, ToneOutput, MAX_VOICES,
+};
+use tone::{Pitch, Tone};
+
+const DATA_DIR: &str = "examples/data/player";
+
+/// The player waits this long before the first note of a melody.
+const LEAD_IN_US: u32 = 1_000_000;
+
+/// Whole note of the melodies below, at 120 beats per minute.
+const WHOLE_MS: u32 = 2000;
+
+/// A quarter, a staccato quarter, a quarter rest, a legato quarter and a
+/// quarter: 90%, 50%, nothing and 100% of the note sounding, then 90%.
+const GAPS: Melody = Melody::new(
+    WHOLE_MS,
+    screen::NOTE,
+    pack!(&[
+        Event::Note(Note::new(Tone::A4, 4)),
+        Event::Note(Note::new(Tone::C5, 4).staccato()),
+        Event::Note(Note::new(Tone::REST, 4)),
+        Event::Note(Note::new(Tone::E5, 4).legato()),
+        Event::Note(Note::new(Tone::A4, 4)),
+    ]),
+);
+
+/// Three melodies of one half note each, told apart by their pitch.
+const LIST: [Melody; 3] = [
+    Melody::new(
+        WHOLE_MS,
+        screen::NOTE,
+        pack!(&[Event::Note(Note::new(Tone::C4, 2))]),
+    ),
+    Melody::new(
+        WHOLE_MS,
+        screen::NOTE,
+        pack!(&[Event::Note(Note::new(Tone::E4, 2))]),
+    ),
+    Melody::new(
+        WHOLE_MS,
+        screen::NOTE,
+        pack!(&[Event::Note(Note::new(Tone::G4, 2))]),
+    ),
+];
+
+/// A half note over a half note of accompaniment, for a one-voice output to
+/// arpeggiate.
+const CHORD: Melody = Melody::new(
+    WHOLE_MS,
+    screen::NOTE,
+    pack!(&[Event::Note(Note::new(Tone::A4, 2))]),
+)
+.with_bass(pack!(&[Event::Note(Note::new(Tone::A3, 2))]));
+
+/// A quarter note tied into another, then rests over a half note of
+/// accompaniment, for a one-voice output.
+const HELD: Melody = Melody::new(
+    WHOLE_MS,
+    screen::NOTE,
+    pack!(&[
+        Event::Note(Note::new(Tone::A4, 4).tie()),
+        Event::Note(Note::new(Tone::A4, 4)),
+        Event::Note(Note::new(Tone::REST, 4)),
+        Event::Note(Note::new(Tone::REST, 4)),
+    ]),
+)
+.with_bass(pack!(&[
+    Event::Note(Note::new(Tone::REST, 2)),
+    Event::Note(Note::new(Tone::A3, 2)),
+]));
+
+/// `|: A [1 B :| [2 C :| D`, in quarter notes: the `:|` after the last
+/// ending repeats that ending.
+const VOLTAS: Melody = Melody::new(
+    WHOLE_MS,
+    screen::NOTE,
+    pack!(&[
+        Event::Repeat,
+        Event::Note(Note::new(Tone::A4, 4)),
+        Event::Volta(1),
+        Event::Note(Note::new(Tone::B4, 4)),
+        Event::EndRepeat(2),
+        Event::Volta(2),
+        Event::Note(Note::new(Tone::C5, 4)),
+        Event::EndRepeat(2),
+        Event::Note(Note::new(Tone::D5, 4)),
+    ]),
+);
+
+/// Three quarter notes in 3/4, a dotted half at twice the speed, then two
+/// bars of 2/4 at that speed: the bars start at 0, 1500, 2250 and 2750 ms.
+const METER: Melody = Melody::new(
+    WHOLE_MS,
+    screen::NOTE,
+    pack!(&[
+        Event::Time(3, 4),
+        Event::Note(Note::new(Tone::A4, 4)),
+        Event::Note(Note::new(Tone::B4, 4)),
+        Event::Note(Note::new(Tone::C5, 4)),
+        Event::Tempo(WHOLE_MS / 2),
+        Event::Note(Note::new(Tone::D5, -2)),
+        Event::Time(2, 4),
+        Event::Note(Note::new(Tone::E5, 4)),
+        Event::Note(Note::new(Tone::E5, 4)),
+        Event::Note(Note::new(Tone::A4, 2)),
+    ]),
+);
+
+/// A call made to the output, at a time in microseconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Call {
+    Tone {
+        voice: usize,
+        freq: f32,
+        volume: u32,
+        at_us: u32,
+    },
+    SetFreq {
+        voice: usize,
+        freq: f32,
+        at_us: u32,
+    },
+    Release {
+        voice: usize,
+        at_us: u32,
+    },
+    Stop {
+        at_us: u32,
+    },
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Call::Tone {
+                voice,
+                freq,
+                volume,
+                at_us,
+            } => write!(f, "{:>10} tone {} {:.2} {}", at_us, voice, freq, volume),
+            Call::SetFreq { voice, freq, at_us } => {
+                write!(f, "{:>10} freq {} {:.2}", at_us, voice, freq)
+            }
+            Call::Release { voice, at_us } => write!(f, "{:>10} release {}", at_us, voice),
+            Call::Stop { at_us } => write!(f, "{:>10} stop", at_us),
+        }
+    }
+}
+
+/// Simulated time and the calls recorded, shared by the clock and the
+/// output handed to the player.
+#[derive(Default)]
+struct Bench {
+    now: u32,
+    play: Option<u32>,
+    tick: Option<u32>,
+    calls: Vec<Call>,
+}
+
+#[derive(Clone)]
+struct TestClock(Rc<RefCell<Bench>>);
+
+impl PlayerClock for TestClock {
+    fn start(&mut self) {}
+
+    fn stop(&mut self) {
+        let mut bench = self.0.borrow_mut();
+        bench.play = None;
+        bench.tick = None;
+    }
+
+    fn now(&self) -> Instant {
+        Instant::from_ticks(self.0.borrow().now)
+    }
+
+    fn set_play_duration(&mut self, duration: Duration) {
+        let mut bench = self.0.borrow_mut();
+        // as with the timer, a compare set to the current count does not
+        // fire until the count wraps around
+        bench.play = (duration.ticks() > 0).then(|| bench.now + duration.ticks());
+    }
+
+    fn set_tick_duration(&mut self, duration: Duration) {
+        let mut bench = self.0.borrow_mut();
+        bench.tick = Some(bench.now + duration.ticks());
+    }
+
+    fn check_play(&mut self) -> bool {
+        let mut bench = self.0.borrow_mut();
+        let now = bench.now;
+        bench.play.take_if(|at| *at <= now).is_some()
+    }
+
+    fn check_tick(&mut self) -> bool {
+        let mut bench = self.0.borrow_mut();
+        let now = bench.now;
+        bench.tick.take_if(|at| *at <= now).is_some()
+    }
+}
+
+/// Records the calls made to it, with `VOICES` voices.
+struct Recorder<const VOICES: usize>(Rc<RefCell<Bench>>);
+
+impl<const VOICES: usize> Recorder<VOICES> {
+    fn record(&self, call: impl FnOnce(u32) -> Call) {
+        let mut bench = self.0.borrow_mut();
+        let at_us = bench.now;
+        bench.calls.push(call(at_us));
+    }
+}
+
+impl<const VOICES: usize> ToneOutput for Recorder<VOICES> {
+    const VOICES: usize = VOICES;
+
+    fn tone(&mut self, voice: usize, freq: f32, volume: u32) {
+        self.record(|at_us| Call::Tone {
+            voice,
+            freq,
+            volume,
+            at_us,
+        });
+    }
+
+    fn set_freq(&mut self, voice: usize, freq: f32) {
+        self.record(|at_us| Call::SetFreq { voice, freq, at_us });
+    }
+
+    fn release(&mut self, voice: usize) {
+        self.record(|at_us| Call::Release { voice, at_us });
+    }
+
+    fn stop(&mut self) {
+        self.record(|at_us| Call::Stop { at_us });
+    }
+}
+
+/// A player on a bench, with the calls to its output recorded.
+struct Test<'a, const VOICES: usize> {
+    bench: Rc<RefCell<Bench>>,
+    player: Player<TestClock, Recorder<VOICES>, &'a [Melody]>,
+}
+
+impl<'a, const VOICES: usize> Test<'a, VOICES> {
+    fn new(list: &'a [Melody]) -> Self {
+        let bench = Rc::new(RefCell::new(Bench::default()));
+        let player = Player::new(TestClock(bench.clone()), Recorder(bench.clone()), list);
+        Self { bench, player }
+    }
+
+    /// Runs the player's interrupt at the next time it asked for, if that is
+    /// no later than `limit_us`.
+    fn step(&mut self, limit_us: u32) -> bool {
+        let next_us = {
+            let bench = self.bench.borrow();
+            [bench.play, bench.tick].into_iter().flatten().min()
+        };
+        match next_us {
+            Some(at_us) if at_us <= limit_us => {
+                self.bench.borrow_mut().now = at_us;
+                self.player.handle_play_event();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Runs the player up to `until_us`, which the clock is left at.
+    fn run_until(&mut self, until_us: u32) {
+        while self.step(until_us) {}
+        self.bench.borrow_mut().now = until_us;
+    }
+
+    /// Runs the player until it stops on its own, for at most `limit_us`.
+    fn run_to_end(&mut self, limit_us: u32) {
+        while self.player.is_playing() && self.step(limit_us) {}
+    }
+
+    /// The calls recorded since the last time.
+    fn take_calls(&mut self) -> Vec<Call> {
+        std::mem::take(&mut self.bench.borrow_mut().calls)
+    }
+}
+
+/// Frequency of `tone` at concert pitch.
+fn freq(tone: Tone) -> f32 {
+    tuning::Tuning::DEFAULT.freq(tone.pitch())
+}
+
+/// The calls on the tune's voice and the stops, leaving out the
+/// accompaniment's, which has nothing to play in the melodies above.
+fn tune(calls: &[Call]) -> Vec<Call> {
+    calls
+        .iter()
+        .filter(|call| match call {
+            Call::Tone { voice, .. }
+            | Call::SetFreq { voice, .. }
+            | Call::Release { voice, .. } => *voice == 0,
+            Call::Stop { .. } => true,
+        })
+        .copied()
+        .collect()
+}
+
+fn tone(freq: f32, at_us: u32) -> Call {
+    Call::Tone {
+        voice: 0,
+        freq,
+        volume: 100,
+        at_us,
+    }
+}
+
+fn release(at_us: u32) -> Call {
+    Call::Release { voice: 0, at_us }
+}
+
+/// Compares calls, the frequencies to a hundredth of a hertz as in the kept
+/// sequences.
+fn check_calls(checks: &mut Checks, what: &str, got: &[Call], expected: &[Call]) {
+    let text = |calls: &[Call]| calls.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+    checks.eq(what, text(got), text(expected));
+}
+
+/// Plays the note gaps melody through, checking how long each note sounds.
+fn check_gaps(checks: &mut Checks) {
+    let quarter_us = WHOLE_MS * 1000 / 4;
+    let at = |quarters: u32, percent: u32| {
+        LEAD_IN_US + quarters * quarter_us + quarter_us * percent / 100
+    };
+    let list = [GAPS];
+    let mut test = Test::<MAX_VOICES>::new(&list);
+    test.player.set_mode(PlayMode::Once);
+    test.player.play();
+    test.run_to_end(10_000_000);
+    check_calls(
+        checks,
+        "note gaps",
+        &tune(&test.take_calls()),
+        &[
+            Call::Stop { at_us: 0 },
+            tone(freq(Tone::A4), at(0, 0)),
+            release(at(0, 90)),
+            tone(freq(Tone::C5), at(1, 0)),
+            release(at(1, 50)),
+            // the rest, released again at the end of its gate
+            release(at(2, 0)),
+            release(at(2, 90)),
+            tone(freq(Tone::E5), at(3, 0)),
+            // legato runs into the next note
+            tone(freq(Tone::A4), at(4, 0)),
+            release(at(4, 90)),
+            Call::Stop { at_us: at(5, 0) },
+        ],
+    );
+    checks.eq("stopped at the end", test.player.is_playing(), false);
+}
+
+/// Pauses within a note and resumes later: the note sounds again for the
+/// rest of it, and the next note comes as late as the pause lasted.
+fn check_pause(checks: &mut Checks) {
+    let list = [GAPS];
+    let mut test = Test::<MAX_VOICES>::new(&list);
+    test.player.play();
+    test.run_until(1_200_000);
+    test.take_calls();
+
+    test.player.pause();
+    checks.eq("paused", test.player.is_paused(), true);
+    test.run_until(5_000_000);
+    check_calls(
+        checks,
+        "pause",
+        &test.take_calls(),
+        &[Call::Stop { at_us: 1_200_000 }],
+    );
+
+    // 250 ms of the A4 were left to sound, then its 50 ms gap
+    test.player.play();
+    test.run_until(5_300_000);
+    check_calls(
+        checks,
+        "resume",
+        &tune(&test.take_calls()),
+        &[
+            tone(freq(Tone::A4), 5_000_000),
+            release(5_250_000),
+            tone(freq(Tone::C5), 5_300_000),
+        ],
+    );
+    checks.eq("playing again", test.player.is_playing(), true);
+}
+
+/// Pauses just as a note is to be released, before the player had the
+/// interrupt for it: resuming releases it right away rather than waiting
+/// for a compare that never comes.
+fn check_pause_when_due(checks: &mut Checks) {
+    let list = [GAPS];
+    let mut test = Test::<MAX_VOICES>::new(&list);
+    test.player.play();
+    test.run_until(1_000_000);
+    test.bench.borrow_mut().now = 1_450_000;
+    test.player.pause();
+    test.take_calls();
+
+    test.run_until(2_000_000);
+    test.player.play();
+    test.run_until(2_100_000);
+    check_calls(
+        checks,
+        "resume when due",
+        &tune(&test.take_calls()),
+        &[release(2_000_000), tone(freq(Tone::C5), 2_050_000)],
+    );
+}
+
+/// Seeks into the middle of a note while paused: the note sounds from
+/// there on when playing resumes.
+fn check_paused_seek(checks: &mut Checks) {
+    let list = [GAPS];
+    let mut test = Test::<MAX_VOICES>::new(&list);
+    test.player.play();
+    test.run_until(1_100_000);
+    test.player.pause();
+    // 100 ms into the legato E5, which sounds to the end of its 500 ms
+    test.player.seek_ms(1_600);
+    checks.eq("paused after seeking", test.player.is_paused(), true);
+    test.run_until(2_000_000);
+    test.take_calls();
+
+    test.player.play();
+    test.run_until(2_500_000);
+    check_calls(
+        checks,
+        "resume after seeking",
+        &tune(&test.take_calls()),
+        &[
+            tone(freq(Tone::E5), 2_000_000),
+            tone(freq(Tone::A4), 2_400_000),
+        ],
+    );
+}
+
+/// Stops within a note: everything falls silent and stays so.
+fn check_stop(checks: &mut Checks) {
+    let list = [GAPS];
+    let mut test = Test::<MAX_VOICES>::new(&list);
+    test.player.play();
+    test.run_until(1_200_000);
+    test.take_calls();
+
+    test.player.stop();
+    test.run_until(10_000_000);
+    check_calls(
+        checks,
+        "stop",
+        &test.take_calls(),
+        &[Call::Stop { at_us: 1_200_000 }],
+    );
+    checks.eq(
+        "stopped",
+        (test.player.is_playing(), test.player.index()),
+        (false, None),
+    );
+
+    // playing again starts the list over
+    test.player.play();
+    test.run_until(11_000_000);
+    check_calls(
+        checks,
+        "play after stop",
+        &tune(&test.take_calls()),
+        &[
+            Call::Stop { at_us: 10_000_000 },
+            tone(freq(Tone::A4), 11_000_000),
+        ],
+    );
+}
+
+/// Moves through the list with `next` and `prev`, wrapping around it.
+fn check_next_prev(checks: &mut Checks) {
+    let mut test = Test::<MAX_VOICES>::new(&LIST);
+    test.player.play();
+    test.run_until(1_000_000);
+    test.player.next();
+    test.run_until(2_000_000);
+    checks.eq("next", test.player.index(), Some(1));
+    check_calls(
+        checks,
+        "next plays",
+        &tune(&test.take_calls()),
+        &[
+            Call::Stop { at_us: 0 },
+            tone(freq(Tone::C4), 1_000_000),
+            Call::Stop { at_us: 1_000_000 },
+            tone(freq(Tone::E4), 2_000_000),
+        ],
+    );
+
+    test.player.prev();
+    test.player.prev();
+    test.run_until(3_000_000);
+    checks.eq("prev wraps", test.player.index(), Some(2));
+    check_calls(
+        checks,
+        "prev plays",
+        &tune(&test.take_calls()),
+        &[
+            Call::Stop { at_us: 2_000_000 },
+            Call::Stop { at_us: 2_000_000 },
+            tone(freq(Tone::G4), 3_000_000),
+        ],
+    );
+
+    test.player.next();
+    checks.eq("next wraps", test.player.index(), Some(0));
+}
+
+/// The pitches of the tune of `melody`, in the order it is played.
+fn pitches(melody: &Melody) -> Vec<Pitch> {
+    let mut pos = Position::default();
+    std::iter::from_fn(|| melody.next_step(0, &mut pos))
+        .map(|step| step.pitch)
+        .collect()
+}
+
+/// Follows the repeat marks of a melody through its endings.
+fn check_repeats(checks: &mut Checks) {
+    checks.eq(
+        "repeat after the last ending",
+        pitches(&VOLTAS),
+        [Tone::A4, Tone::B4, Tone::A4, Tone::C5, Tone::C5, Tone::D5]
+            .map(|t| t.pitch())
+            .to_vec(),
+    );
+}
+
+/// Counts the bars of a melody through its time signatures, and seeks to
+/// one while playing.
+fn check_bars(checks: &mut Checks) {
+    let mut pos = Position::default();
+    let bars: Vec<_> = std::iter::from_fn(|| {
+        let step = METER.next_step(0, &mut pos)?;
+        Some((step.duration_ms, pos.bar(), pos.time_signature()))
+    })
+    .collect();
+    checks.eq(
+        "bars",
+        bars,
+        vec![
+            (500, 0, Some((3, 4))),
+            (500, 0, Some((3, 4))),
+            (500, 0, Some((3, 4))),
+            (750, 1, Some((3, 4))),
+            (250, 2, Some((2, 4))),
+            (250, 2, Some((2, 4))),
+            (500, 3, Some((2, 4))),
+        ],
+    );
+    checks.eq(
+        "bar offsets",
+        (0..5)
+            .map(|bar| METER.bar_offset_ms(bar))
+            .collect::<Vec<_>>(),
+        vec![0, 1500, 2250, 2750, 3250],
+    );
+
+    let list = [METER];
+    let mut test = Test::<MAX_VOICES>::new(&list);
+    test.player.play();
+    test.run_until(LEAD_IN_US + 200_000);
+    checks.eq(
+        "first bar",
+        (test.player.bar(), test.player.time_signature()),
+        (0, Some((3, 4))),
+    );
+    test.take_calls();
+
+    test.player.seek_bar(2);
+    checks.eq(
+        "seek to a bar",
+        (
+            test.player.bar(),
+            test.player.time_signature(),
+            test.player.position(),
+        ),
+        (2, Some((2, 4)), 2250),
+    );
+    test.run_until(LEAD_IN_US + 700_000);
+    check_calls(
+        checks,
+        "play from a bar",
+        &tune(&test.take_calls()),
+        &[
+            Call::Stop { at_us: 1_200_000 },
+            tone(freq(Tone::E5), 1_200_000),
+            release(1_425_000),
+            tone(freq(Tone::E5), 1_450_000),
+            release(1_675_000),
+            tone(freq(Tone::A4), 1_700_000),
+        ],
+    );
+}
+
+/// A one-voice output gets the chord one note at a time, 30 ms each.
+fn check_arpeggio(checks: &mut Checks) {
+    let list = [CHORD];
+    let mut test = Test::<1>::new(&list);
+    test.player.play();
+    test.run_until(LEAD_IN_US + 100_000);
+    let a3 = freq(Tone::A3);
+    let a4 = freq(Tone::A4);
+    check_calls(
+        checks,
+        "arpeggio",
+        &test.take_calls(),
+        &[
+            Call::Stop { at_us: 0 },
+            tone(a3, 1_000_000),
+            tone(a4, 1_030_000),
+            tone(a3, 1_060_000),
+            tone(a4, 1_090_000),
+        ],
+    );
+}
+
+/// A one-voice output is only retoned when the note on it changes: a tied
+/// note keeps sounding, and the rests of another voice leave it alone.
+fn check_held(checks: &mut Checks) {
+    let list = [HELD];
+    let mut test = Test::<1>::new(&list);
+    test.player.play();
+    test.run_until(LEAD_IN_US + 2_000_000);
+    let a3 = freq(Tone::A3);
+    let a4 = freq(Tone::A4);
+    check_calls(
+        checks,
+        "held notes",
+        &test.take_calls(),
+        &[
+            Call::Stop { at_us: 0 },
+            tone(a4, 1_000_000),
+            Call::SetFreq {
+                voice: 0,
+                freq: a4,
+                at_us: 1_500_000,
+            },
+            release(1_950_000),
+            tone(a3, 2_000_000),
+            release(2_900_000),
+            // over again
+            Call::Stop { at_us: 3_000_000 },
+        ],
+    );
+}
+
+/// The plain buzzer holds the sustain level of the envelope, between 20%
+/// and 50% duty.
+fn check_buzzer_duty(checks: &mut Checks) {
+    let duties = [
+        (100, Envelope::GATE),
+        (50, Envelope::GATE),
+        (0, Envelope::GATE),
+        (100, Envelope::DEFAULT),
+        (50, Envelope::PIANO),
+        (100, Envelope::PLUCK),
+    ]
+    .map(|(volume, envelope)| buzzer_duty(1000, volume, &envelope));
+    checks.eq("buzzer duty", duties, [500, 350, 200, 410, 245, 200]);
+}
+
+/// Plays `melody` once through and lists the calls made, one per line.
+fn sequence(melody: &Melody) -> String {
+    let list = std::slice::from_ref(melody);
+    let mut test = Test::<MAX_VOICES>::new(list);
+    test.player.set_mode(PlayMode::Once);
+    test.player.play();
+    let limit_us = LEAD_IN_US + melody.duration_ms() * 1000 * 2;
+    test.run_to_end(limit_us);
+    test.take_calls()
+        .iter()
+        .map(|call| format!("{}\n", call))
+        .collect()
+}
+
+/// Checks each melody of `MELODY_LIST` against its kept sequence, or keeps
+/// the new one when blessing.
+fn check_melodies(checks: &mut Checks, bless: bool) {
+    for (i, melody) in MELODY_LIST.iter().enumerate() {
+        let path = Path::new(DATA_DIR).join(format!("melody-{}.txt", i));
+        let got = sequence(melody);
+        if bless {
+            fs::write(&path, &got).expect("cannot write the sequence");
+            continue;
+        }
+        let what = path.display().to_string();
+        let Ok(expected) = fs::read_to_string(&path) else {
+            checks.check(&what, Err("missing, run with --bless".to_string()));
+            continue;
+        };
+        let diff = got.lines().zip(expected.lines()).position(|(g, e)| g != e);
+        let result = if let Some(line) = diff {
+            Err(format!(
+                "line {}: got `{}`, expected `{}`",
+                line + 1,
+                got.lines().nth(line).unwrap_or_default(),
+                expected.lines().nth(line).unwrap_or_default()
+            ))
+        } else if got.lines().count() != expected.lines().count() {
+            Err(format!(
+                "{} calls, expected {}",
+                got.lines().count(),
+                expected.lines().count()
+            ))
+        } else {
+            Ok(())
+        };
+        checks.check(&what, result);
+    }
+}
+
+fn main() -> ExitCode {
+    let bless = env::args().skip(1).any(|arg| arg == "--bless");
+    let mut checks = Checks::new("player");
+    check_gaps(&mut checks);
+    check_pause(&mut checks);
+    check_pause_when_due(&mut checks);
+    check_paused_seek(&mut checks);
+    check_stop(&mut checks);
+    check_next_prev(&mut checks);
+    check_arpeggio(&mut checks);
+    check_held(&mut checks);
+    check_buzzer_duty(&mut checks);
+    check_repeats(&mut checks);
+    check_bars(&mut checks);
+    check_melodies(&mut checks, bless);
+    checks.finish()
+}
@@ -0,0 +1,212 @@
+//! Renders melodies to WAV files on the host, through the same player and
+//! synthesizer code the board runs, for regression listening.
+//!
+//! ```text
+//! cargo run --example render --target <host triple> -- [INDEX] [DIR]
+//! ```
+//!
+//! Without `INDEX` every melody of `MELODY_LIST` is rendered; files are
+//! written to `DIR` (default `target/wav`) as `melody-<INDEX>.wav`.
+
+// only part of the firmware modules is used here
+#![allow(dead_code, unused_imports, unused_macros)]
+
+#[path = "../src/envelope.rs"]
+mod envelope;
+#[path = "../src/melody.rs"]
+mod melody;
+#[path = "../src/pack.rs"]
+mod pack;
+#[path = "../src/player.rs"]
+mod player;
+#[path = "../src/rtttl.rs"]
+mod rtttl;
+#[path = "../src/screen.rs"]
+mod screen;
+#[path = "../src/shuffle.rs"]
+mod shuffle;
+#[path = "../src/synth.rs"]
+mod synth;
+#[path = "../src/tone.rs"]
+mod tone;
+#[path = "../src/tuning.rs"]
+mod tuning;
+#[path = "../src/waveform.rs"]
+mod waveform;
+
+extern crate microbit as bsp;
+
+use std::{cell::RefCell, env, fs, io, path::Path, rc::Rc};
+
+use envelope::Envelope;
+use melody::{Melody, MELODY_LIST};
+use player::{Duration, Instant, Player, PlayerClock, ToneOutput, MAX_VOICES};
+use synth::{Mixer, COUNTERTOP, SAMPLE_RATE};
+use waveform::Waveform;
+
+/// The player waits this long before the first note.
+const LEAD_IN_US: u64 = 1_000_000;
+const TAIL_US: u64 = 500_000;
+
+/// Simulated time and output, shared by the clock and the output handed to
+/// the player.
+struct Sim {
+    now: u32,
+    play: Option<u32>,
+    tick: Option<u32>,
+    mixer: Mixer<MAX_VOICES>,
+}
+
+#[derive(Clone)]
+struct SimClock(Rc<RefCell<Sim>>);
+
+impl PlayerClock for SimClock {
+    fn start(&mut self) {}
+
+    fn stop(&mut self) {
+        let mut sim = self.0.borrow_mut();
+        sim.play = None;
+        sim.tick = None;
+    }
+
+    fn now(&self) -> Instant {
+        Instant::from_ticks(self.0.borrow().now)
+    }
+
+    fn set_play_duration(&mut self, duration: Duration) {
+        let mut sim = self.0.borrow_mut();
+        sim.play = Some(sim.now + duration.ticks());
+    }
+
+    fn set_tick_duration(&mut self, duration: Duration) {
+        let mut sim = self.0.borrow_mut();
+        sim.tick = Some(sim.now + duration.ticks());
+    }
+
+    fn check_play(&mut self) -> bool {
+        let mut sim = self.0.borrow_mut();
+        let now = sim.now;
+        sim.play.take_if(|at| *at <= now).is_some()
+    }
+
+    fn check_tick(&mut self) -> bool {
+        let mut sim = self.0.borrow_mut();
+        let now = sim.now;
+        sim.tick.take_if(|at| *at <= now).is_some()
+    }
+}
+
+struct SimOutput(Rc<RefCell<Sim>>);
+
+impl ToneOutput for SimOutput {
+    const VOICES: usize = MAX_VOICES;
+
+    fn tone(&mut self, voice: usize, freq: f32, volume: u32) {
+        let mut sim = self.0.borrow_mut();
+        if freq <= 0.0 {
+            sim.mixer.release(voice);
+        } else {
+            sim.mixer.set_voice(voice, freq, volume);
+        }
+    }
+
+    fn set_freq(&mut self, voice: usize, freq: f32) {
+        self.0.borrow_mut().mixer.set_freq(voice, freq);
+    }
+
+    fn release(&mut self, voice: usize) {
+        self.0.borrow_mut().mixer.release(voice);
+    }
+
+    fn stop(&mut self) {
+        self.0.borrow_mut().mixer.silence();
+    }
+
+    fn set_waveform(&mut self, voice: usize, waveform: Waveform) {
+        self.0.borrow_mut().mixer.set_waveform(voice, waveform);
+    }
+
+    fn set_envelope(&mut self, voice: usize, envelope: Envelope) {
+        self.0.borrow_mut().mixer.set_envelope(voice, envelope);
+    }
+}
+
+/// Plays `melody` once and returns the samples sent to the PWM, converted
+/// back to signed 16-bit.
+fn render(melody: &Melody) -> Vec<i16> {
+    let end_us = LEAD_IN_US + melody.duration_ms() as u64 * 1000 + TAIL_US;
+
+    let sim = Rc::new(RefCell::new(Sim {
+        now: 0,
+        play: None,
+        tick: None,
+        mixer: Mixer::new(SAMPLE_RATE),
+    }));
+    let list = std::slice::from_ref(melody);
+    let mut player = Player::new(SimClock(sim.clone()), SimOutput(sim.clone()), list);
+    player.play();
+
+    let mut samples = Vec::new();
+    let mut duty = [0u16; 1];
+    loop {
+        let next_us = {
+            let sim = sim.borrow();
+            [sim.play, sim.tick].into_iter().flatten().min()
+        };
+        let next_us = next_us.map_or(end_us, |at| (at as u64).min(end_us));
+
+        let target = (next_us * SAMPLE_RATE as u64 / 1_000_000) as usize;
+        let mut sim_ref = sim.borrow_mut();
+        while samples.len() < target {
+            sim_ref.mixer.fill(&mut duty, COUNTERTOP);
+            let sample = duty[0] as i32 * 0x1_0000 / COUNTERTOP as i32 - 0x8000;
+            samples.push(sample as i16);
+        }
+        if next_us >= end_us {
+            break;
+        }
+        sim_ref.now = next_us as u32;
+        drop(sim_ref);
+        player.handle_play_event();
+    }
+    samples
+}
+
+fn write_wav(path: &Path, samples: &[i16]) -> io::Result<()> {
+    let data_len = samples.len() as u32 * 2;
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    fs::write(path, wav)
+}
+
+fn main() -> io::Result<()> {
+    let mut args = env::args().skip(1);
+    let index = args.next().map(|arg| arg.parse::<usize>().expect("INDEX must be a number"));
+    let dir = args.next().unwrap_or_else(|| "target/wav".to_string());
+    fs::create_dir_all(&dir)?;
+
+    for (i, melody) in MELODY_LIST.iter().enumerate() {
+        if index.is_some_and(|index| index != i) {
+            continue;
+        }
+        let path = Path::new(&dir).join(format!("melody-{}.wav", i));
+        let samples = render(melody);
+        write_wav(&path, &samples)?;
+        println!("{}: {:.1}s", path.display(), samples.len() as f32 / SAMPLE_RATE as f32);
+    }
+    Ok(())
+}
@@ -0,0 +1,308 @@
+//! Drives `store::Store` over flash simulated in memory, which can lose power
+//! after a given number of writes, and checks what it reads back on the
+//! next start: records torn by a reset or failing their CRC, a full bank,
+//! and compacting into the other bank, whole or interrupted.
+//!
+//! ```text
+//! cargo run --example store --target <host triple>
+//! ```
+
+// only part of the firmware modules is used here
+#![allow(dead_code, unused_imports, unused_macros)]
+
+#[path = "common/checks.rs"]
+mod checks;
+#[path = "../src/envelope.rs"]
+mod envelope;
+#[macro_use]
+#[path = "../src/melody.rs"]
+mod melody;
+#[path = "../src/pack.rs"]
+mod pack;
+#[path = "../src/player.rs"]
+mod player;
+#[path = "../src/rtttl.rs"]
+mod rtttl;
+#[path = "../src/screen.rs"]
+mod screen;
+#[path = "../src/shuffle.rs"]
+mod shuffle;
+#[path = "../src/store.rs"]
+mod store;
+#[path = "../src/tone.rs"]
+mod tone;
+#[path = "../src/tuning.rs"]
+mod tuning;
+#[path = "../src/waveform.rs"]
+mod waveform;
+
+extern crate microbit as bsp;
+
+use std::{process::ExitCode, slice};
+
+use checks::Checks;
+use store::{Entry, Error, Flash, Store, PAGE_SIZE, REGION_LEN};
+
+const BANK_SIZE: usize = REGION_LEN / 2;
+const BANK_MAGIC: u32 = 0x4b42_424d;
+
+/// Payload of the records the checks store, 1008 bytes with the header so
+/// that 16 of them fit in a bank.
+const RECORD_LEN: usize = 1000;
+
+/// Flash simulated in memory, erased to all ones and programmed by clearing
+/// bits like the real one.
+struct RamFlash {
+    mem: *mut u8,
+    /// Erases and writes left before power is lost, the ones after that
+    /// doing nothing.
+    ops: Option<usize>,
+}
+
+impl RamFlash {
+    /// A region erased to all ones.
+    fn erased() -> *mut u8 {
+        Box::leak(vec![0xff; REGION_LEN].into_boxed_slice()).as_mut_ptr()
+    }
+
+    /// The region at `mem`, with power for good.
+    fn new(mem: *mut u8) -> Self {
+        Self { mem, ops: None }
+    }
+
+    /// The region at `mem`, losing power after `ops` erases and writes.
+    fn losing_power(mem: *mut u8, ops: usize) -> Self {
+        Self {
+            mem,
+            ops: Some(ops),
+        }
+    }
+
+    fn powered(&mut self) -> bool {
+        match &mut self.ops {
+            None => true,
+            Some(0) => false,
+            Some(ops) => {
+                *ops -= 1;
+                true
+            }
+        }
+    }
+}
+
+impl Flash for RamFlash {
+    fn region(&self) -> &'static [u8] {
+        // SAFETY: the region is leaked, and like flash it only changes
+        // through `erase_page` and `write_word`, or `clear_bit`
+        unsafe { slice::from_raw_parts(self.mem, REGION_LEN) }
+    }
+
+    fn erase_page(&mut self, offset: usize) {
+        if self.powered() {
+            // SAFETY: the store only erases pages within the region
+            unsafe { self.mem.add(offset).write_bytes(0xff, PAGE_SIZE) };
+        }
+    }
+
+    fn write_word(&mut self, offset: usize, word: u32) {
+        if !self.powered() {
+            return;
+        }
+        for (i, byte) in word.to_le_bytes().into_iter().enumerate() {
+            // SAFETY: the store only writes words within the region
+            unsafe { *self.mem.add(offset + i) &= byte };
+        }
+    }
+}
+
+/// Clears the lowest bit set in the byte at `offset` in the region at `mem`,
+/// as a flash cell going bad would.
+fn clear_bit(mem: *mut u8, offset: usize) {
+    // SAFETY: the checks only clear bits within the region
+    let byte = unsafe { &mut *mem.add(offset) };
+    *byte &= byte.wrapping_sub(1);
+}
+
+/// Sequence number of each bank, if it holds one.
+fn banks(mem: *mut u8) -> [Option<u32>; 2] {
+    let region = RamFlash::new(mem).region();
+    let word = |offset: usize| u32::from_le_bytes(region[offset..offset + 4].try_into().unwrap());
+    [0, BANK_SIZE].map(|bank| (word(bank) == BANK_MAGIC).then(|| word(bank + 4)))
+}
+
+/// A record payload told apart by `fill`.
+fn payload(fill: u8) -> Vec<u8> {
+    vec![fill; RECORD_LEN]
+}
+
+/// The stored melodies, by id and the byte their payload is filled with.
+fn melodies(store: &Store<RamFlash>) -> Vec<(u8, u8)> {
+    store
+        .melodies()
+        .map(|(id, payload)| (id, payload[0]))
+        .collect()
+}
+
+/// Stores and reads back melodies and a playlist across a restart.
+fn check_reopen(checks: &mut Checks) {
+    let mem = RamFlash::erased();
+    let mut store = Store::open(RamFlash::new(mem));
+    checks.eq("formatted", banks(mem), [None, Some(1)]);
+    checks.eq("empty", store.free(), BANK_SIZE - 8);
+
+    checks.eq("add", store.add_melody(&payload(1)), Ok(1));
+    checks.eq("add another", store.add_melody(&payload(2)), Ok(2));
+    checks.eq("replace", store.replace_melody(1, &payload(3)), Ok(()));
+    checks.eq("remove", store.remove_melody(2), Ok(()));
+    checks.eq("remove again", store.remove_melody(2), Err(Error::NotFound));
+    let playlist = [Entry::Stored(1), Entry::BuiltIn(0)];
+    checks.eq("playlist", store.set_playlist(&playlist), Ok(()));
+
+    let store = Store::open(RamFlash::new(mem));
+    checks.eq("reopened melodies", melodies(&store), vec![(1, 3)]);
+    checks.eq(
+        "reopened playlist",
+        store.playlist().map(|entries| entries.collect::<Vec<_>>()),
+        Some(playlist.to_vec()),
+    );
+    checks.eq("stale records kept", store.records().count(), 5);
+}
+
+/// Loses power while a record is written: the next start skips it, and the
+/// records after it are read as before.
+fn check_torn_record(checks: &mut Checks) {
+    let mem = RamFlash::erased();
+    let mut store = Store::open(RamFlash::new(mem));
+    store.add_melody(&payload(1)).unwrap();
+
+    // the header and a few words of the payload, no CRC
+    let mut store = Store::open(RamFlash::losing_power(mem, 4));
+    store.add_melody(&payload(2)).unwrap();
+
+    let mut store = Store::open(RamFlash::new(mem));
+    checks.eq("torn record skipped", melodies(&store), vec![(1, 1)]);
+    checks.eq(
+        "room after the torn record",
+        store.free(),
+        BANK_SIZE - 8 - 2 * (RECORD_LEN + 8),
+    );
+    checks.eq(
+        "add after the torn record",
+        store.add_melody(&payload(3)),
+        Ok(2),
+    );
+
+    let store = Store::open(RamFlash::new(mem));
+    checks.eq(
+        "records after the torn one",
+        melodies(&store),
+        vec![(1, 1), (2, 3)],
+    );
+}
+
+/// A record whose payload no longer matches its CRC is skipped, so the
+/// version it replaced is read again.
+fn check_crc_mismatch(checks: &mut Checks) {
+    let mem = RamFlash::erased();
+    let mut store = Store::open(RamFlash::new(mem));
+    store.add_melody(&payload(1)).unwrap();
+    store.replace_melody(1, &payload(2)).unwrap();
+
+    // in the middle of the payload of the second record, past the bank
+    // header and the first record
+    clear_bit(mem, BANK_SIZE + 8 + (RECORD_LEN + 8) + 8 + 100);
+    let store = Store::open(RamFlash::new(mem));
+    checks.eq("record failing its CRC", melodies(&store), vec![(1, 1)]);
+    checks.eq("valid records", store.records().count(), 1);
+}
+
+/// Fills the bank up, then compacts it into the other one on the next start
+/// once enough of it is stale.
+fn check_full_bank(checks: &mut Checks) {
+    let mem = RamFlash::erased();
+    let mut store = Store::open(RamFlash::new(mem));
+    let big = vec![0; BANK_SIZE];
+    checks.eq("too large", store.add_melody(&big), Err(Error::TooLarge));
+    for id in 1..=16 {
+        store.add_melody(&payload(id)).unwrap();
+    }
+    checks.eq("full", store.add_melody(&payload(17)), Err(Error::Full));
+    checks.eq(
+        "room left",
+        store.free(),
+        BANK_SIZE - 8 - 16 * (RECORD_LEN + 8),
+    );
+
+    // nothing stale yet, so there is nothing to compact
+    let mut store = Store::open(RamFlash::new(mem));
+    checks.eq(
+        "full after restart",
+        store.add_melody(&payload(17)),
+        Err(Error::Full),
+    );
+    checks.eq("not compacted", banks(mem), [None, Some(1)]);
+
+    for id in 1..=8 {
+        store.remove_melody(id).unwrap();
+    }
+    checks.eq(
+        "still full",
+        store.add_melody(&payload(17)),
+        Err(Error::Full),
+    );
+
+    let mut store = Store::open(RamFlash::new(mem));
+    checks.eq("compacted", banks(mem), [Some(2), Some(1)]);
+    checks.eq(
+        "compacted melodies",
+        melodies(&store),
+        (9..=16).map(|id| (id, id)).collect::<Vec<_>>(),
+    );
+    checks.eq("live records only", store.records().count(), 8);
+    checks.eq(
+        "room after compacting",
+        store.free(),
+        BANK_SIZE - 8 - 8 * (RECORD_LEN + 8),
+    );
+    checks.eq(
+        "add after compacting",
+        store.add_melody(&payload(17)),
+        Ok(1),
+    );
+}
+
+/// Loses power while compacting: the next start still reads the old bank,
+/// and compacts it again.
+fn check_interrupted_compaction(checks: &mut Checks) {
+    let mem = RamFlash::erased();
+    let mut store = Store::open(RamFlash::new(mem));
+    for id in 1..=16 {
+        store.add_melody(&payload(id)).unwrap();
+    }
+    for id in 1..=8 {
+        store.remove_melody(id).unwrap();
+    }
+
+    // the other bank erased and a few records copied, no header
+    let store = Store::open(RamFlash::losing_power(mem, 4 + 800));
+    checks.eq("compacted, power lost", melodies(&store).len(), 8);
+    checks.eq("old bank still active", banks(mem), [None, Some(1)]);
+
+    let store = Store::open(RamFlash::new(mem));
+    checks.eq("compacted again", banks(mem), [Some(2), Some(1)]);
+    checks.eq(
+        "melodies after compacting again",
+        melodies(&store),
+        (9..=16).map(|id| (id, id)).collect::<Vec<_>>(),
+    );
+}
+
+fn main() -> ExitCode {
+    let mut checks = Checks::new("store");
+    check_reopen(&mut checks);
+    check_torn_record(&mut checks);
+    check_crc_mismatch(&mut checks);
+    check_full_bank(&mut checks);
+    check_interrupted_compaction(&mut checks);
+    checks.finish()
+}
@@ -0,0 +1,8 @@
+/* Linker script for the nRF52833 - WITHOUT SOFT DEVICE */
+MEMORY
+{
+  /* NOTE K = KiBi = 1024 bytes */
+  /* the top 32K of flash hold the record store, see src/store.rs */
+  FLASH : ORIGIN = 0x00000000, LENGTH = 512K - 32K
+  RAM : ORIGIN = 0x20000000, LENGTH = 128K
+}
@@ -0,0 +1,11 @@
+//! Generates the firmware's melodies for the modules shared with it, from the
+//! `melodies/` directory of the firmware.
+use std::path::Path;
+
+#[allow(dead_code)] // its `main` is the firmware's
+#[path = "../build.rs"]
+mod firmware;
+
+fn main() {
+    firmware::write_melodies(Path::new(".."));
+}
@@ -0,0 +1,225 @@
+//! Host companion to the music box: lists, uploads and controls melodies over
+//! the serial protocol of `src/command.rs`.
+//!
+//! ```text
+//! musicbox-cli [--port PATH] list
+//! musicbox-cli [--port PATH] play | pause | next | prev
+//! musicbox-cli [--port PATH] volume <0-100>
+//! musicbox-cli [--port PATH] status [--follow]
+//! musicbox-cli [--port PATH] push <FILE>...
+//! musicbox-cli [--port PATH] remove <ID>
+//! musicbox-cli sim [--flash FILE]
+//! ```
+//!
+//! `push` uploads RTTTL ringtones (one per line), MIDI files (`.mid`) and
+//! `melody!` invocations (`.rs`), see `source`. The port defaults to
+//! `$MUSICBOX_PORT`, then `/dev/ttyACM0`.
+//!
+//! `sim` runs the firmware's player and command handling on the host behind a
+//! pseudo-terminal and prints its path, to use as the port of the other
+//! commands without a board.
+
+// only part of the firmware modules is used here
+#![allow(dead_code, unused_imports, unused_macros)]
+
+#[path = "../../src/command.rs"]
+mod command;
+#[path = "../../src/envelope.rs"]
+mod envelope;
+#[path = "../../src/melody.rs"]
+mod melody;
+#[path = "../../build.rs"]
+mod midi;
+#[path = "../../src/pack.rs"]
+mod pack;
+#[path = "../../src/player.rs"]
+mod player;
+mod port;
+#[path = "../../src/rtttl.rs"]
+mod rtttl;
+#[path = "../../src/screen.rs"]
+mod screen;
+#[path = "../../src/shuffle.rs"]
+mod shuffle;
+mod sim;
+mod source;
+#[path = "../../src/store.rs"]
+mod store;
+#[path = "../../src/tone.rs"]
+mod tone;
+#[path = "../../src/tuning.rs"]
+mod tuning;
+#[path = "../../src/waveform.rs"]
+mod waveform;
+
+extern crate microbit as bsp;
+
+use std::{
+    env, fmt,
+    fmt::Write as _,
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    thread,
+    time::Duration,
+};
+
+use port::Device;
+
+const DEFAULT_PORT: &str = "/dev/ttyACM0";
+
+/// Time between two status lines with `status --follow`.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
+const USAGE: &str = "usage: musicbox-cli [--port PATH] list | play | pause | next | prev
+       | volume <0-100> | status [--follow] | push <FILE>... | remove <ID>
+       musicbox-cli sim [--flash FILE]";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The device answered `error <reason>`.
+    Device(String),
+    /// The device did not answer in time.
+    NoReply,
+    /// A melody source could not be read.
+    Source(String),
+    Usage,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Device(reason) => write!(f, "device: {}", reason),
+            Self::NoReply => write!(f, "no reply from the device"),
+            Self::Source(e) => write!(f, "{}", e),
+            Self::Usage => write!(f, "{}", USAGE),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut port = env::var_os("MUSICBOX_PORT")
+        .map_or_else(|| PathBuf::from(DEFAULT_PORT), PathBuf::from);
+    if args.first().is_some_and(|arg| arg == "--port") {
+        if args.len() < 2 {
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+        port = PathBuf::from(args.remove(1));
+        args.remove(0);
+    }
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let result = match args.as_slice() {
+        ["sim"] => sim::run(None).map_err(Error::Io),
+        ["sim", "--flash", path] => sim::run(Some(Path::new(path))).map_err(Error::Io),
+        [command, rest @ ..] => run(&port, command, rest),
+        [] => Err(Error::Usage),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Error::Usage) => {
+            eprintln!("{}", USAGE);
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("musicbox-cli: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(port: &Path, command: &str, args: &[&str]) -> Result<(), Error> {
+    let open = || {
+        Device::open(port).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", port.display(), e)))
+    };
+    match (command, args) {
+        ("play" | "pause" | "next" | "prev", []) => {
+            open()?.request(command)?;
+        }
+        ("volume", [volume]) => {
+            open()?.request(&format!("volume {}", volume))?;
+        }
+        ("list", []) => {
+            for line in open()?.request("list")? {
+                let fields: Vec<&str> = line.split(' ').collect();
+                match fields.as_slice() {
+                    [index, origin, n, duration_ms] => {
+                        println!("{:>3}  {:<7} {:>3}  {}", index, origin, n, time(duration_ms))
+                    }
+                    _ => println!("{}", line),
+                }
+            }
+        }
+        ("status", []) => println!("{}", status(&mut open()?)?),
+        ("status", ["--follow"]) => {
+            let mut device = open()?;
+            loop {
+                print!("\r{}\x1b[K", status(&mut device)?);
+                io::stdout().flush()?;
+                thread::sleep(FOLLOW_INTERVAL);
+            }
+        }
+        ("push", paths) if !paths.is_empty() => {
+            let mut device = open()?;
+            for path in paths {
+                let melodies = source::read(Path::new(path)).map_err(Error::Source)?;
+                for melody in melodies {
+                    let mut record = [0; command::MAX_RECORD];
+                    let too_long = || Error::Source(format!("{}: melody too long to upload", path));
+                    let len = melody.write_record(&mut record).ok_or_else(too_long)?;
+                    let mut line = String::from("upload ");
+                    for byte in &record[..len] {
+                        write!(line, "{:02x}", byte).unwrap();
+                    }
+                    for reply in device.request(&line)? {
+                        if let Some(id) = reply.strip_prefix("stored ") {
+                            println!("{}: stored as {}", path, id);
+                        }
+                    }
+                }
+            }
+        }
+        ("remove", [id]) => {
+            open()?.request(&format!("remove {}", id))?;
+        }
+        _ => return Err(Error::Usage),
+    }
+    Ok(())
+}
+
+/// The device's status line, made readable.
+fn status(device: &mut Device) -> Result<String, Error> {
+    let reply = device.request("status")?;
+    let line = reply.first().ok_or(Error::NoReply)?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    Ok(match fields.as_slice() {
+        ["status", state, index, position_ms, duration_ms, volume, mode] => format!(
+            "{} #{}  {} / {}  volume {}%  {}",
+            state,
+            index,
+            time(position_ms),
+            time(duration_ms),
+            volume,
+            mode
+        ),
+        _ => line.clone(),
+    })
+}
+
+/// Milliseconds as `m:ss`.
+fn time(ms: &str) -> String {
+    match ms.parse::<u32>() {
+        Ok(ms) => format!("{}:{:02}", ms / 60_000, ms / 1000 % 60),
+        Err(_) => ms.to_string(),
+    }
+}
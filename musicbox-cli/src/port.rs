@@ -0,0 +1,61 @@
+//! The host end of the serial protocol, see `src/command.rs` of the firmware.
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+};
+
+use nix::sys::termios::{self, BaudRate, SetArg, SpecialCharacterIndices};
+
+use crate::Error;
+
+/// How long to wait for a reply, in tenths of a second.
+const REPLY_TIMEOUT: u8 = 20;
+
+/// A music box on a serial port, or the simulated one on a pseudo-terminal.
+pub struct Device {
+    port: BufReader<File>,
+}
+
+impl Device {
+    /// Opens the port at 115200 baud, raw.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut attrs = termios::tcgetattr(&file)?;
+        termios::cfmakeraw(&mut attrs);
+        termios::cfsetspeed(&mut attrs, BaudRate::B115200)?;
+        // reads give up once the line has been quiet for the timeout
+        attrs.control_chars[SpecialCharacterIndices::VMIN as usize] = 0;
+        attrs.control_chars[SpecialCharacterIndices::VTIME as usize] = REPLY_TIMEOUT;
+        termios::tcsetattr(&file, SetArg::TCSANOW, &attrs)?;
+        termios::tcflush(&file, termios::FlushArg::TCIOFLUSH)?;
+        Ok(Self {
+            port: BufReader::new(file),
+        })
+    }
+
+    /// Sends a command line, returning the lines of the reply before `ok`.
+    pub fn request(&mut self, command: &str) -> Result<Vec<String>, Error> {
+        let port = self.port.get_mut();
+        port.write_all(command.as_bytes())?;
+        port.write_all(b"\n")?;
+        port.flush()?;
+
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            self.port.read_line(&mut line)?;
+            if !line.ends_with('\n') {
+                return Err(Error::NoReply);
+            }
+            let line = line.trim_end();
+            if line == "ok" {
+                return Ok(lines);
+            }
+            if let Some(reason) = line.strip_prefix("error ") {
+                return Err(Error::Device(reason.to_string()));
+            }
+            lines.push(line.to_string());
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! A music box simulated on the host: the firmware's player, store and
+//! command handling, served on a pseudo-terminal in place of the USB serial
+//! port. Nothing is sounded; the player's events are printed instead.
+use std::{
+    cell::RefCell,
+    fs::{self, File},
+    io::{self, Read, Write},
+    os::fd::AsFd,
+    path::Path,
+    rc::Rc,
+    slice, time,
+};
+
+use nix::{
+    poll::{self, PollFd, PollFlags, PollTimeout},
+    pty,
+    sys::termios::{self, SetArg},
+    unistd,
+};
+
+use crate::{
+    command::{self, LineBuffer, MAX_LINE, MAX_RECORD},
+    melody::MELODY_LIST,
+    player::{self, Duration, Instant, PlayerClock, Playlist, ToneOutput, MAX_VOICES},
+    screen::Screen,
+    store::{self, Flash, Store, PAGE_SIZE, REGION_LEN},
+};
+
+/// Longest wait for input between two looks at the player's clock.
+const MAX_POLL_MS: u16 = 1000;
+
+type Player = player::Player<HostClock, Silence, &'static mut Playlist>;
+
+/// The compares of the player's clock, shared with the serving loop so that
+/// it knows how long it may wait for input.
+struct Compares {
+    start: time::Instant,
+    play: Option<u32>,
+    tick: Option<u32>,
+}
+
+impl Compares {
+    /// Microseconds since the simulation started.
+    fn now(&self) -> u32 {
+        self.start.elapsed().as_micros() as u32
+    }
+
+    /// Microseconds until the next compare fires, if one is set.
+    fn next_due_us(&self) -> Option<u32> {
+        let now = self.now();
+        let until = |at: u32| (at.wrapping_sub(now) as i32).max(0) as u32;
+        [self.play, self.tick]
+            .into_iter()
+            .flatten()
+            .map(until)
+            .min()
+    }
+}
+
+#[derive(Clone)]
+struct HostClock(Rc<RefCell<Compares>>);
+
+impl HostClock {
+    fn check(&mut self, compare: impl FnOnce(&mut Compares) -> &mut Option<u32>) -> bool {
+        let mut compares = self.0.borrow_mut();
+        let now = compares.now();
+        compare(&mut compares)
+            .take_if(|at| now.wrapping_sub(*at) as i32 >= 0)
+            .is_some()
+    }
+}
+
+impl PlayerClock for HostClock {
+    fn start(&mut self) {}
+
+    fn stop(&mut self) {
+        let mut compares = self.0.borrow_mut();
+        compares.play = None;
+        compares.tick = None;
+    }
+
+    fn now(&self) -> Instant {
+        Instant::from_ticks(self.0.borrow().now())
+    }
+
+    fn set_play_duration(&mut self, duration: Duration) {
+        let mut compares = self.0.borrow_mut();
+        compares.play = Some(compares.now().wrapping_add(duration.ticks()));
+    }
+
+    fn set_tick_duration(&mut self, duration: Duration) {
+        let mut compares = self.0.borrow_mut();
+        compares.tick = Some(compares.now().wrapping_add(duration.ticks()));
+    }
+
+    fn check_play(&mut self) -> bool {
+        self.check(|compares| &mut compares.play)
+    }
+
+    fn check_tick(&mut self) -> bool {
+        self.check(|compares| &mut compares.tick)
+    }
+}
+
+/// An output with as many voices as the synthesizer, which sounds nothing.
+struct Silence;
+
+impl ToneOutput for Silence {
+    const VOICES: usize = MAX_VOICES;
+
+    fn tone(&mut self, _voice: usize, _freq: f32, _volume: u32) {}
+    fn set_freq(&mut self, _voice: usize, _freq: f32) {}
+    fn release(&mut self, _voice: usize) {}
+    fn stop(&mut self) {}
+}
+
+/// Flash simulated in memory, erased to all ones and programmed by clearing
+/// bits like the real one.
+struct RamFlash(*mut u8);
+
+impl RamFlash {
+    fn new(contents: Vec<u8>) -> Self {
+        Self(Box::leak(contents.into_boxed_slice()).as_mut_ptr())
+    }
+}
+
+impl Flash for RamFlash {
+    fn region(&self) -> &'static [u8] {
+        // SAFETY: the region is leaked, and like flash it only changes
+        // through `erase_page` and `write_word`
+        unsafe { slice::from_raw_parts(self.0, REGION_LEN) }
+    }
+
+    fn erase_page(&mut self, offset: usize) {
+        // SAFETY: the store only erases pages within the region
+        unsafe { self.0.add(offset).write_bytes(0xff, PAGE_SIZE) };
+    }
+
+    fn write_word(&mut self, offset: usize, word: u32) {
+        for (i, byte) in word.to_le_bytes().into_iter().enumerate() {
+            // SAFETY: the store only writes words within the region
+            unsafe { *self.0.add(offset + i) &= byte };
+        }
+    }
+}
+
+/// Serves the simulated device until interrupted. The store starts from
+/// `flash_path` and is saved back to it after each change, if given.
+pub fn run(flash_path: Option<&Path>) -> io::Result<()> {
+    let contents = match flash_path.map(fs::read) {
+        Some(Ok(contents)) if contents.len() == REGION_LEN => contents,
+        Some(Ok(_)) => return Err(io::Error::other("flash file has the wrong size")),
+        Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => vec![0xff; REGION_LEN],
+    };
+    let flash = RamFlash::new(contents);
+    let region = flash.region();
+    let mut store = Store::open(flash);
+
+    let list = Box::leak(Box::new(Playlist::new()));
+    store.load_list(MELODY_LIST, list);
+    let clock = HostClock(Rc::new(RefCell::new(Compares {
+        start: time::Instant::now(),
+        play: None,
+        tick: None,
+    })));
+    let mut ply = Player::new(clock.clone(), Silence, list);
+    ply.attach_event(|event| println!("player: {:?}", event));
+    ply.play();
+    let mut screen = Screen::<100>::new();
+
+    let pty = pty::openpty(None, None)?;
+    let mut attrs = termios::tcgetattr(&pty.slave)?;
+    termios::cfmakeraw(&mut attrs);
+    termios::tcsetattr(&pty.slave, SetArg::TCSANOW, &attrs)?;
+    // the slave stays open so that the master survives clients closing it
+    println!(
+        "serving on {}",
+        unistd::ttyname(pty.slave.as_fd())?.display()
+    );
+    let mut port = File::from(pty.master);
+
+    let mut lines = LineBuffer::<MAX_LINE>::new();
+    let mut record = [0; MAX_RECORD];
+    let mut buf = [0; 256];
+    loop {
+        let due_us = clock.0.borrow().next_due_us();
+        let wait_ms = due_us.map_or(MAX_POLL_MS, |us| (us / 1000).min(MAX_POLL_MS as u32) as u16);
+        let mut fds = [PollFd::new(port.as_fd(), PollFlags::POLLIN)];
+        poll::poll(&mut fds, PollTimeout::from(wait_ms))?;
+        if fds[0].any().unwrap_or(false) {
+            let len = port.read(&mut buf)?;
+            for &byte in &buf[..len] {
+                let Some(line) = lines.push(byte) else {
+                    continue;
+                };
+                let command = line.and_then(command::parse);
+                let mut reply = String::new();
+                let result = match command {
+                    Ok(command) => command::run(
+                        command,
+                        |f| f(&mut ply, &mut screen),
+                        &mut store,
+                        &mut record,
+                        &mut reply,
+                    ),
+                    Err(error) => Err(error.as_str()),
+                };
+                match result {
+                    Ok(()) => reply.push_str("ok\n"),
+                    Err(reason) => reply.push_str(&format!("error {}\n", reason)),
+                }
+                // saved before replying, so a change the host saw is kept
+                // even if the sim is stopped right after
+                if let (Some(path), Ok(command::Command::Upload(_) | command::Command::Remove(_))) =
+                    (flash_path, command)
+                {
+                    fs::write(path, region)?;
+                }
+                port.write_all(reply.as_bytes())?;
+            }
+        }
+        ply.handle_play_event();
+    }
+}
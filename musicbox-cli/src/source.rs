@@ -0,0 +1,510 @@
+//! Turns melody sources into `Melody`s to upload: RTTTL ringtones, MIDI files
+//! and `melody!` invocations written as in `src/melody.rs`.
+//!
+//! `melody!` is read at run time here rather than expanded by the compiler,
+//! so only literal values are understood: `icon` is `screen::NOTE` or a 5x5
+//! array, and waveforms, envelopes and articulations are named as in the
+//! firmware, e.g. `Waveform::Square(25)` or `Envelope::new(5, 60, 70, 40)`.
+//! Sections cannot be uploaded, so `PLAY(..)` is refused. `tests/source.rs`
+//! checks that the firmware's own melodies read the same as it compiles them.
+use std::{fs, path::Path};
+
+use crate::{
+    envelope::Envelope,
+    melody::{Articulation, Curve, Event, Melody, Note},
+    midi,
+    pack::{self, Packed},
+    rtttl,
+    screen::{self, Icon},
+    tone::{Pitch, Tone},
+    waveform::Waveform,
+};
+
+/// Pitch classes as spelled in `Tone` names, from C.
+const PITCH_CLASSES: [&str; 12] = [
+    "C", "CS", "D", "DS", "E", "F", "FS", "G", "GS", "A", "AS", "B",
+];
+
+/// Reads every melody of the file at `path`, picking the format from its
+/// extension: `.mid` for MIDI, `.rs` for `melody!` and anything else for
+/// RTTTL, one ringtone per line.
+pub fn read(path: &Path) -> Result<Vec<Melody>, String> {
+    let error = |e: std::io::Error| format!("{}: {}", path.display(), e);
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("mid") => Ok(vec![from_midi(path)?]),
+        Some("rs") => from_source(&fs::read_to_string(path).map_err(error)?),
+        _ => fs::read_to_string(path)
+            .map_err(error)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(from_rtttl)
+            .collect(),
+    }
+}
+
+/// Parses an RTTTL ringtone such as `Nokia:d=4,o=5,b=225:8e6,8d6,f#`.
+pub fn from_rtttl(src: &str) -> Result<Melody, String> {
+    let error = |e: rtttl::Error| format!("rtttl:{}:{}: {:?}", e.line, e.column, e.kind);
+    let parser = rtttl::Parser::new(src.trim().as_bytes()).map_err(error)?;
+    let whole_ms = parser.header().whole_note_delay_ms();
+    let events = parser
+        .map(|note| note.map(|(tone, div)| Event::Note(Note::new(tone, div))))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(error)?;
+    Ok(Melody::new(whole_ms, screen::NOTE, packed(&events)?))
+}
+
+/// Converts a MIDI file the way the firmware's build script does, from the
+/// first track and channel with notes.
+pub fn from_midi(path: &Path) -> Result<Melody, String> {
+    let midi = midi::convert(&midi::MidiEntry {
+        path: path.to_path_buf(),
+        ..Default::default()
+    });
+    // the build script writes it as a `melody!` with `beat = 4`
+    let whole_ms = |bpm: u32| match bpm {
+        0 => Err(format!("{}: tempo cannot be 0", path.display())),
+        bpm => Ok(60_000 * 4 / bpm),
+    };
+    let events = midi
+        .items
+        .iter()
+        .map(|item| match *item {
+            midi::Item::Tempo(bpm) => Ok(Event::Tempo(whole_ms(bpm)?)),
+            midi::Item::Time(beats, unit) => Ok(Event::Time(beats, unit)),
+            midi::Item::Note { key, div, tie } => {
+                let tone = match key.map(midi::tone_of) {
+                    Some((class, octave)) => Tone::from_note(class, octave).unwrap(),
+                    None => Tone::REST,
+                };
+                let note = Note::new(tone, div);
+                Ok(Event::Note(if tie { note.tie() } else { note }))
+            }
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let whole_ms = whole_ms(midi.bpm)?;
+    Ok(Melody::new(whole_ms, screen::NOTE, packed(&events)?))
+}
+
+/// Parses every `melody!` invocation in `src`.
+pub fn from_source(src: &str) -> Result<Vec<Melody>, String> {
+    let mut parser = Parser::new(src)?;
+    let mut melodies = Vec::new();
+    while let Some(token) = parser.next() {
+        if token.kind == Kind::Ident("melody") && parser.eat('!') {
+            parser.expect('(')?;
+            melodies.push(parser.melody()?);
+        }
+    }
+    if melodies.is_empty() {
+        return Err("no `melody!` found".to_string());
+    }
+    Ok(melodies)
+}
+
+/// Packs the events of a voice, leaking them as the firmware's melodies are
+/// `'static`.
+fn packed(events: &[Event]) -> Result<Packed, String> {
+    let mut bytes = vec![0; pack::packed_len(events)];
+    pack::pack_into(events, &mut bytes);
+    let packed = Packed::new(Box::leak(bytes.into_boxed_slice()), &[], events.len());
+    if !pack::round_trips(events, &packed) {
+        return Err("melody cannot be packed".to_string());
+    }
+    Ok(packed)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind<'a> {
+    Ident(&'a str),
+    Number(&'a str),
+    /// `::` in paths.
+    PathSep,
+    Punct(char),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    kind: Kind<'a>,
+    line: usize,
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(src: &'a str) -> Result<Self, String> {
+        let mut tokens = Vec::new();
+        for (n, line) in src.lines().enumerate() {
+            let line_no = n + 1;
+            let code = line.split("//").next().unwrap();
+            let mut rest = code;
+            while let Some(c) = rest.chars().next() {
+                let len = if c.is_whitespace() {
+                    c.len_utf8()
+                } else if c.is_ascii_alphabetic() || c == '_' {
+                    let len = rest
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                        .unwrap_or(rest.len());
+                    tokens.push(Token {
+                        kind: Kind::Ident(&rest[..len]),
+                        line: line_no,
+                    });
+                    len
+                } else if c.is_ascii_digit()
+                    || (c == '-' && rest[1..].starts_with(|c: char| c.is_ascii_digit()))
+                {
+                    let len = 1 + rest[1..]
+                        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                        .unwrap_or(rest.len() - 1);
+                    tokens.push(Token {
+                        kind: Kind::Number(&rest[..len]),
+                        line: line_no,
+                    });
+                    len
+                } else if rest.starts_with("::") {
+                    tokens.push(Token {
+                        kind: Kind::PathSep,
+                        line: line_no,
+                    });
+                    2
+                } else if "()[],:;/=!".contains(c) {
+                    tokens.push(Token {
+                        kind: Kind::Punct(c),
+                        line: line_no,
+                    });
+                    1
+                } else {
+                    return Err(format!("line {}: unexpected `{}`", line_no, c));
+                };
+                rest = &rest[len..];
+            }
+        }
+        Ok(Self { tokens, pos: 0 })
+    }
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let token = self.tokens.get(self.pos).copied();
+        self.pos += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<Kind<'a>> {
+        self.tokens.get(self.pos).map(|t| t.kind)
+    }
+
+    fn error(&self, message: &str) -> String {
+        match self
+            .tokens
+            .get(self.pos.min(self.tokens.len()).saturating_sub(1))
+        {
+            Some(token) => format!("line {}: {}", token.line, message),
+            None => message.to_string(),
+        }
+    }
+
+    /// Skips `c` if it comes next.
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(Kind::Punct(c));
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        match self.next().map(|t| t.kind) {
+            Some(Kind::Punct(found)) if found == c => Ok(()),
+            _ => Err(self.error(&format!("expected `{}`", c))),
+        }
+    }
+
+    fn ident(&mut self) -> Result<&'a str, String> {
+        match self.next().map(|t| t.kind) {
+            Some(Kind::Ident(ident)) => Ok(ident),
+            _ => Err(self.error("expected a name")),
+        }
+    }
+
+    fn number<T: std::str::FromStr>(&mut self) -> Result<T, String> {
+        match self.next().map(|t| t.kind) {
+            Some(Kind::Number(number)) => number.parse().map_err(|_| self.error("bad number")),
+            _ => Err(self.error("expected a number")),
+        }
+    }
+
+    /// A path such as `Waveform::Sine`, joined back with `::`.
+    fn path(&mut self) -> Result<String, String> {
+        let mut path = self.ident()?.to_string();
+        while self.peek() == Some(Kind::PathSep) {
+            self.pos += 1;
+            path.push_str("::");
+            path.push_str(self.ident()?);
+        }
+        Ok(path)
+    }
+
+    /// The arguments of a call, after its `(`.
+    fn numbers<T: std::str::FromStr, const N: usize>(&mut self) -> Result<[T; N], String> {
+        let mut values = Vec::with_capacity(N);
+        for i in 0..N {
+            if i > 0 {
+                self.expect(',')?;
+            }
+            values.push(self.number()?);
+        }
+        self.expect(')')?;
+        values.try_into().map_err(|_| unreachable!())
+    }
+
+    /// The arguments of `melody!`, after its `(`.
+    fn melody(&mut self) -> Result<Melody, String> {
+        let mut tempo = None;
+        let mut beat = None;
+        let mut icon = screen::NOTE;
+        let mut waveforms = [Waveform::SQUARE; 2];
+        let mut envelopes = [Envelope::DEFAULT; 2];
+        let mut articulation = Articulation::Normal;
+        let mut transpose = 0;
+        let mut notes = Vec::new();
+        let mut bass = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Kind::Punct(')')) => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(Kind::Punct(',')) => self.pos += 1,
+                Some(Kind::Punct('[')) => {
+                    self.pos += 1;
+                    self.bar(beat.unwrap_or(4), &mut notes)?;
+                }
+                Some(Kind::Ident(_)) => {
+                    let key = self.ident()?;
+                    self.expect('=')?;
+                    match key {
+                        "name" => {
+                            self.ident()?;
+                        }
+                        "tempo" => tempo = Some(self.number::<u32>()?),
+                        "beat" => beat = Some(self.number::<u32>()?),
+                        "icon" => icon = self.icon()?,
+                        "waveform" => waveforms[0] = self.waveform()?,
+                        "envelope" => envelopes[0] = self.envelope()?,
+                        "articulation" => articulation = self.articulation()?,
+                        "transpose" => transpose = self.number()?,
+                        "bass" => {
+                            self.expect('[')?;
+                            while !self.eat(']') {
+                                if !self.eat(',') {
+                                    self.expect('[')?;
+                                    self.bar(beat.unwrap_or(4), &mut bass)?;
+                                }
+                            }
+                        }
+                        "bass_waveform" => waveforms[1] = self.waveform()?,
+                        "bass_envelope" => envelopes[1] = self.envelope()?,
+                        _ => return Err(self.error(&format!("unknown `{}`", key))),
+                    }
+                }
+                _ => return Err(self.error("expected `name = value` or notes")),
+            }
+        }
+
+        let (Some(tempo), Some(beat)) = (tempo, beat) else {
+            return Err(self.error("`tempo` and `beat` are required"));
+        };
+        if tempo == 0 {
+            return Err(self.error("`tempo` cannot be 0"));
+        }
+        Ok(Melody::new(60_000 * beat / tempo, icon, packed(&notes)?)
+            .with_bass(packed(&bass)?)
+            .with_waveform(0, waveforms[0])
+            .with_waveform(1, waveforms[1])
+            .with_envelope(0, envelopes[0])
+            .with_envelope(1, envelopes[1])
+            .with_articulation(articulation)
+            .with_transpose(transpose))
+    }
+
+    /// The events of a `[..]` group, after its `[`.
+    fn bar(&mut self, beat: u32, events: &mut Vec<Event>) -> Result<(), String> {
+        while !self.eat(']') {
+            if !self.eat(',') {
+                events.push(self.event(beat)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// One event, as the `@event` rules of `melody!` read it.
+    fn event(&mut self, beat: u32) -> Result<Event, String> {
+        let name = self.ident()?;
+        let event = match name {
+            "TEMPO" => {
+                self.expect(':')?;
+                let bpm: u32 = self.number()?;
+                if bpm == 0 {
+                    return Err(self.error("tempo cannot be 0"));
+                }
+                return Ok(Event::Tempo(60_000 * beat / bpm));
+            }
+            "TIME" => {
+                self.expect(':')?;
+                let beats = self.number()?;
+                self.expect('/')?;
+                return Ok(Event::Time(beats, self.number()?));
+            }
+            "REPEAT" => Event::Repeat,
+            "END_REPEAT" if self.eat(':') => Event::EndRepeat(self.number()?),
+            "END_REPEAT" => Event::EndRepeat(2),
+            "VOLTA" => {
+                self.expect(':')?;
+                Event::Volta(self.number()?)
+            }
+            "SEGNO" => Event::Segno,
+            "CODA" => Event::Coda,
+            "TO_CODA" => Event::ToCoda,
+            "FINE" => Event::Fine,
+            "DC" => Event::DaCapo,
+            "DS" => Event::DalSegno,
+            "PLAY" => return Err(self.error("sections cannot be uploaded")),
+            _ => {
+                let pitch = if name == "HZ" {
+                    self.expect('(')?;
+                    let [hz] = self.numbers::<f32, 1>()?;
+                    Pitch::Hz(hz)
+                } else {
+                    let tone = tone(name)
+                        .ok_or_else(|| self.error(&format!("unknown note `{}`", name)))?;
+                    if self.eat('(') {
+                        let [cents] = self.numbers()?;
+                        tone.pitch().detune(cents)
+                    } else {
+                        tone.pitch()
+                    }
+                };
+                self.expect(':')?;
+                let mut note = Note::pitched(pitch, self.number()?);
+                while let Some(Kind::Ident(mark)) = self.peek() {
+                    self.pos += 1;
+                    note = match mark {
+                        "tie" => note.tie(),
+                        "ddot" => note.ddot(),
+                        "triplet" => note.triplet(),
+                        "staccato" => note.staccato(),
+                        "legato" => note.legato(),
+                        "fermata" => note.fermata(),
+                        "glide" => note.glide(),
+                        "vibrato" => {
+                            self.expect('(')?;
+                            let [rate_hz, depth_cents] = self.numbers()?;
+                            note.vibrato(rate_hz, depth_cents)
+                        }
+                        "bend" => {
+                            self.expect('(')?;
+                            let cents = self.number()?;
+                            self.expect(',')?;
+                            let curve = match self.path()?.as_str() {
+                                "Curve::Linear" => Curve::Linear,
+                                "Curve::EaseIn" => Curve::EaseIn,
+                                "Curve::EaseOut" => Curve::EaseOut,
+                                curve => {
+                                    return Err(self.error(&format!("unknown curve `{}`", curve)))
+                                }
+                            };
+                            self.expect(')')?;
+                            note.bend(cents, curve)
+                        }
+                        _ => return Err(self.error(&format!("unknown mark `{}`", mark))),
+                    };
+                }
+                return Ok(Event::Note(note));
+            }
+        };
+        Ok(event)
+    }
+
+    fn icon(&mut self) -> Result<Icon, String> {
+        if !self.eat('[') {
+            return match self.path()?.as_str() {
+                "screen::NOTE" | "NOTE" => Ok(screen::NOTE),
+                icon => Err(self.error(&format!("unknown icon `{}`, write it out", icon))),
+            };
+        }
+        let mut icon = [[0; 5]; 5];
+        for (y, row) in icon.iter_mut().enumerate() {
+            if y > 0 {
+                self.expect(',')?;
+            }
+            self.expect('[')?;
+            for (x, pixel) in row.iter_mut().enumerate() {
+                if x > 0 {
+                    self.expect(',')?;
+                }
+                *pixel = self.number()?;
+            }
+            self.expect(']')?;
+        }
+        self.eat(',');
+        self.expect(']')?;
+        Ok(icon)
+    }
+
+    fn waveform(&mut self) -> Result<Waveform, String> {
+        Ok(match self.path()?.as_str() {
+            "Waveform::SQUARE" => Waveform::SQUARE,
+            "Waveform::Square" => {
+                self.expect('(')?;
+                let [duty] = self.numbers()?;
+                Waveform::Square(duty)
+            }
+            "Waveform::Triangle" => Waveform::Triangle,
+            "Waveform::Sawtooth" => Waveform::Sawtooth,
+            "Waveform::Sine" => Waveform::Sine,
+            "Waveform::Noise" => Waveform::Noise,
+            waveform => return Err(self.error(&format!("unknown waveform `{}`", waveform))),
+        })
+    }
+
+    fn envelope(&mut self) -> Result<Envelope, String> {
+        Ok(match self.path()?.as_str() {
+            "Envelope::DEFAULT" => Envelope::DEFAULT,
+            "Envelope::GATE" => Envelope::GATE,
+            "Envelope::PIANO" => Envelope::PIANO,
+            "Envelope::PLUCK" => Envelope::PLUCK,
+            "Envelope::ORGAN" => Envelope::ORGAN,
+            "Envelope::PAD" => Envelope::PAD,
+            "Envelope::new" => {
+                self.expect('(')?;
+                let [attack_ms, decay_ms, sustain, release_ms] = self.numbers::<u16, 4>()?;
+                let sustain = u8::try_from(sustain).map_err(|_| self.error("bad sustain"))?;
+                Envelope::new(attack_ms, decay_ms, sustain, release_ms)
+            }
+            envelope => return Err(self.error(&format!("unknown envelope `{}`", envelope))),
+        })
+    }
+
+    fn articulation(&mut self) -> Result<Articulation, String> {
+        Ok(match self.path()?.as_str() {
+            "Articulation::Legato" => Articulation::Legato,
+            "Articulation::Normal" => Articulation::Normal,
+            "Articulation::Staccato" => Articulation::Staccato,
+            articulation => {
+                return Err(self.error(&format!("unknown articulation `{}`", articulation)))
+            }
+        })
+    }
+}
+
+/// The `Tone` named like its variant, e.g. `CS4` or `REST`.
+fn tone(name: &str) -> Option<Tone> {
+    if name == "REST" {
+        return Some(Tone::REST);
+    }
+    let (class, octave) = name.split_at(name.len().checked_sub(1)?);
+    let semitone = PITCH_CLASSES.iter().position(|&c| c == class)?;
+    Tone::from_note(semitone as u8, octave.parse().ok()?)
+}
@@ -0,0 +1,95 @@
+//! Checks that the RTTTL parser rejects malformed ringtones received at
+//! runtime, reporting the kind of error and where it is.
+
+// only part of the firmware modules is used here
+#![allow(dead_code, unused_imports, unused_macros)]
+
+#[path = "../../src/rtttl.rs"]
+mod rtttl;
+#[path = "../../src/tone.rs"]
+mod tone;
+#[path = "../../src/tuning.rs"]
+mod tuning;
+
+extern crate microbit as bsp;
+
+use rtttl::{ErrorKind, Parser};
+use tone::Tone;
+
+/// The first error in `src`, as line, column and kind, if there is one.
+fn error(src: &str) -> Option<(u32, u32, ErrorKind)> {
+    let result = Parser::new(src.as_bytes())
+        .and_then(|parser| parser.collect::<Result<Vec<_>, _>>().map(|_| ()));
+    result.err().map(|e| (e.line, e.column, e.kind))
+}
+
+#[test]
+fn valid() {
+    let notes: Result<Vec<_>, _> = Parser::new(b"Test:d=8,o=5,b=120:4c,d#6,p,8e.")
+        .unwrap()
+        .collect();
+    assert_eq!(
+        notes,
+        Ok(vec![
+            (Tone::C5, 4),
+            (Tone::DS6, 8),
+            (Tone::REST, 8),
+            (Tone::E5, -8),
+        ])
+    );
+    assert_eq!(error("Test:d=8,o=5,b=120:"), None);
+}
+
+#[test]
+fn missing_section() {
+    assert_eq!(error("Test"), Some((1, 5, ErrorKind::MissingSection)));
+    assert_eq!(
+        error("Test:d=4,o=5,b=120"),
+        Some((1, 19, ErrorKind::MissingSection))
+    );
+}
+
+#[test]
+fn bad_duration() {
+    assert_eq!(
+        error("Test:d=3,o=5,b=120:c"),
+        Some((1, 8, ErrorKind::BadDuration))
+    );
+    assert_eq!(
+        error("Test:d=4,o=5,b=120:c,128d"),
+        Some((1, 22, ErrorKind::BadDuration))
+    );
+}
+
+#[test]
+fn bad_octave() {
+    assert_eq!(
+        error("Test:d=4,o=0,b=120:c"),
+        Some((1, 12, ErrorKind::BadOctave))
+    );
+    assert_eq!(
+        error("Test:d=4,o=5,b=120:c,\nd12"),
+        Some((2, 2, ErrorKind::BadOctave))
+    );
+}
+
+#[test]
+fn unknown_note() {
+    assert_eq!(
+        error("Test:d=4,o=5,b=120:c,x"),
+        Some((1, 22, ErrorKind::BadNote))
+    );
+    assert_eq!(
+        error("Test:d=4,o=5,b=120:c d"),
+        Some((1, 22, ErrorKind::BadNote))
+    );
+}
+
+#[test]
+fn too_many_notes() {
+    let src = b"Test:d=4,o=5,b=120:c,d,e";
+    assert_eq!(rtttl::count(src), Ok(3));
+    assert!(rtttl::parse_array::<3>(src).is_ok());
+    let error = rtttl::parse_array::<2>(src).err();
+    assert_eq!(error.map(|e| e.kind), Some(ErrorKind::TooManyNotes));
+}
@@ -0,0 +1,162 @@
+//! Drives `musicbox-cli sim` with the other commands, as against a board:
+//! listing, pushing each kind of source, status, volume and removal, and an
+//! upload surviving a restart with the same `--flash` file.
+
+use std::{
+    env, fs,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    process::{self, Child, Command, Stdio},
+    thread,
+};
+
+const CLI: &str = env!("CARGO_BIN_EXE_musicbox-cli");
+
+const RINGTONE: &str = "Nokia:d=4,o=5,b=225:8e6,8d6,f#,g#,8c#6,8b,d,e,8b,8a,c#,e,2a\n";
+
+const MELODY: &str = "melody!(
+    name = SCALE, tempo = 120, beat = 4,
+    icon = screen::NOTE,
+    waveform = Waveform::Triangle,
+    [C4:4, D4:4, E4:4, F4:4 staccato, G4:2 fermata]
+);
+";
+
+/// A simulated device, stopped when dropped.
+struct Sim {
+    child: Child,
+    port: String,
+}
+
+impl Sim {
+    fn start(flash: &Path) -> Self {
+        let mut child = Command::new(CLI)
+            .args(["sim", "--flash"])
+            .arg(flash)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        let port = lines
+            .by_ref()
+            .find_map(|line| line.ok()?.strip_prefix("serving on ").map(String::from))
+            .expect("sim printed no port");
+        // the player's events go on being printed
+        thread::spawn(move || lines.for_each(drop));
+        Self { child, port }
+    }
+
+    /// Runs a command against the device, returning what it printed.
+    fn run(&self, args: &[&str]) -> String {
+        let output = Command::new(CLI)
+            .arg("--port")
+            .arg(&self.port)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{:?}: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    /// Runs a command the device refuses, returning the error printed.
+    fn run_err(&self, args: &[&str]) -> String {
+        let output = Command::new(CLI)
+            .arg("--port")
+            .arg(&self.port)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(!output.status.success(), "{:?} succeeded", args);
+        String::from_utf8(output.stderr).unwrap()
+    }
+
+    /// The `list` entries, as origin and id or melody number.
+    fn list(&self) -> Vec<(String, u8)> {
+        self.run(&["list"])
+            .lines()
+            .map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                let [_, origin, n, _] = fields[..] else {
+                    panic!("bad list line `{}`", line);
+                };
+                (origin.to_string(), n.parse().unwrap())
+            })
+            .collect()
+    }
+}
+
+impl Drop for Sim {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+/// The id `push` printed for `path`.
+fn stored_id(output: &str, path: &Path) -> u8 {
+    let prefix = format!("{}: stored as ", path.display());
+    output
+        .lines()
+        .find_map(|line| line.strip_prefix(&prefix))
+        .unwrap_or_else(|| panic!("{} not stored: {}", path.display(), output))
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn commands() {
+    let dir = env::temp_dir().join(format!("musicbox-cli-sim-{}", process::id()));
+    // a run that failed leaves its files behind
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&dir).unwrap();
+    let flash = dir.join("flash.bin");
+    let ringtone = dir.join("nokia.rtttl");
+    let melody = dir.join("scale.rs");
+    let midi = PathBuf::from("../melodies/tetris.mid");
+    fs::write(&ringtone, RINGTONE).unwrap();
+    fs::write(&melody, MELODY).unwrap();
+
+    let sim = Sim::start(&flash);
+    let builtin = sim.list();
+    assert!(!builtin.is_empty());
+    assert!(builtin.iter().all(|(origin, _)| origin == "builtin"));
+
+    assert!(sim.run(&["status"]).starts_with("playing #0 "));
+    sim.run(&["volume", "40"]);
+    assert!(sim.run(&["status"]).contains(" volume 40% "));
+    assert!(sim.run_err(&["volume", "101"]).contains("bad-number"));
+    sim.run(&["pause"]);
+    assert!(sim.run(&["status"]).starts_with("paused #0 "));
+
+    let output = sim.run(&[
+        "push",
+        ringtone.to_str().unwrap(),
+        midi.to_str().unwrap(),
+        melody.to_str().unwrap(),
+    ]);
+    let ids = [&ringtone, &midi, &melody].map(|path| stored_id(&output, path));
+    let mut list = builtin.clone();
+    list.extend(ids.map(|id| ("stored".to_string(), id)));
+    assert_eq!(sim.list(), list);
+
+    sim.run(&["remove", &ids[0].to_string()]);
+    list.retain(|entry| *entry != ("stored".to_string(), ids[0]));
+    assert_eq!(sim.list(), list);
+    assert!(sim
+        .run_err(&["remove", &ids[0].to_string()])
+        .contains("not-found"));
+
+    // the store is kept in the flash file
+    drop(sim);
+    let sim = Sim::start(&flash);
+    assert_eq!(sim.list(), list);
+    assert!(sim.run(&["status"]).starts_with("playing #0 "));
+
+    drop(sim);
+    fs::remove_dir_all(&dir).ok();
+}
@@ -0,0 +1,101 @@
+//! Checks that `source` reads the shipped melodies as the firmware compiles
+//! them: each `melody!` and `rtttl!` of `src/melody.rs` and each MIDI file of
+//! the playlist must pack to the same bytes as its entry of `MELODY_LIST`.
+
+// only part of the firmware modules is used here
+#![allow(dead_code, unused_imports, unused_macros)]
+
+#[path = "../../src/envelope.rs"]
+mod envelope;
+#[path = "../../src/melody.rs"]
+mod melody;
+#[path = "../../build.rs"]
+mod midi;
+#[path = "../../src/pack.rs"]
+mod pack;
+#[path = "../../src/player.rs"]
+mod player;
+#[path = "../../src/rtttl.rs"]
+mod rtttl;
+#[path = "../../src/screen.rs"]
+mod screen;
+#[path = "../../src/shuffle.rs"]
+mod shuffle;
+#[path = "../src/source.rs"]
+mod source;
+#[path = "../../src/tone.rs"]
+mod tone;
+#[path = "../../src/tuning.rs"]
+mod tuning;
+#[path = "../../src/waveform.rs"]
+mod waveform;
+
+extern crate microbit as bsp;
+
+use std::{fs, path::Path};
+
+use melody::{Melody, MELODY_LIST};
+
+const MELODY_SOURCE: &str = "../src/melody.rs";
+const MELODY_DIR: &str = "../melodies";
+
+/// The invocation of `melody!` or `rtttl!` defining `name` in `src`.
+fn definition<'a>(src: &'a str, name: &str) -> Option<&'a str> {
+    let at = src.find(&format!("name = {},", name))?;
+    let start = ["\nmelody!(", "\nrtttl!("]
+        .iter()
+        .filter_map(|start| src[..at].rfind(start))
+        .max()?;
+    let end = at + src[at..].find("\n);")? + 3;
+    Some(&src[start + 1..end])
+}
+
+/// Reads the melody of the playlist entry `line` from its source.
+fn read(src: &str, line: &str) -> Result<Melody, String> {
+    let first = line.split_whitespace().next().unwrap();
+    if first.ends_with(".mid") {
+        return source::from_midi(&Path::new(MELODY_DIR).join(first));
+    }
+    let definition = definition(src, first).ok_or("not found")?;
+    if definition.starts_with("rtttl!") {
+        let ringtone = definition.split('"').nth(1).ok_or("no ringtone")?;
+        return source::from_rtttl(ringtone);
+    }
+    source::from_source(definition)?
+        .pop()
+        .ok_or_else(|| "no melody".to_string())
+}
+
+/// The packed voices of a melody, with their event counts, and its length.
+fn packed(melody: &Melody) -> ([(&[u8], usize); 2], u32) {
+    let voices = melody.voices().map(|voice| (voice.bytes(), voice.len()));
+    (voices, melody.duration_ms())
+}
+
+#[test]
+fn shipped_melodies() {
+    let src = fs::read_to_string(MELODY_SOURCE).unwrap();
+    let playlist = fs::read_to_string(Path::new(MELODY_DIR).join("playlist.txt")).unwrap();
+    let lines = playlist
+        .lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty());
+
+    let mut checked = 0;
+    for (line, compiled) in lines.zip(MELODY_LIST) {
+        match read(&src, line) {
+            Ok(melody) => {
+                assert!(
+                    packed(&melody) == packed(compiled),
+                    "{}: differs from MELODY_LIST",
+                    line
+                );
+                checked += 1;
+            }
+            // played sections only exist in the firmware
+            Err(e) if e.ends_with("sections cannot be uploaded") => {}
+            Err(e) => panic!("{}: {}", line, e),
+        }
+    }
+    assert!(checked > 0);
+}
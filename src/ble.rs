@@ -0,0 +1,1143 @@
+//! Bluetooth LE remote control: advertising, the link layer of a single
+//! connection as the peripheral, and a GATT server.
+//!
+//! Everything here works on packets in memory, in the layout the radio reads
+//! and writes them (header, length, payload), so that it runs on the host as
+//! well; `radio.rs` sends and receives them and keeps the timing.
+//!
+//! The music box service (`MUSICBOX_SERVICE`) has a characteristic per
+//! action: writing anything to Play, Pause, Next or Previous does that,
+//! Volume reads and writes the volume in percent, and Now Playing reads as
+//! the state (0 stopped, 1 playing, 2 paused), the index of the melody
+//! (0xff for none) and its duration in milliseconds (u32, little endian).
+//! Volume and Now Playing notify their changes.
+//!
+//! A peripheral made with `midi` also has the standard BLE-MIDI service,
+//! advertised instead of the music box one, and passes the messages written
+//! to it on as `Event::Midi`.
+//!
+//! Only what a music box needs is supported: no encryption or pairing, a
+//! 23-byte ATT MTU, 27-byte data PDUs, 1 Mbit/s and channel selection #1.
+use defmt::Format;
+use heapless::{Deque, Vec};
+
+use crate::midi::{Message, Parser};
+
+/// Access address and CRC initial value of advertising packets.
+pub const ADV_ACCESS_ADDRESS: u32 = 0x8e89_bed6;
+pub const ADV_CRC_INIT: u32 = 0x55_5555;
+
+/// CRC polynomial, x^24 + x^10 + x^9 + x^6 + x^4 + x^3 + x + 1.
+pub const CRC_POLY: u32 = 0x00_065b;
+
+/// Channels advertised on, in this order.
+pub const ADV_CHANNELS: [u8; 3] = [37, 38, 39];
+
+/// Longest payload of a PDU: that of an advertising one, as data PDUs
+/// without the data length extension carry at most 27 bytes.
+pub const MAX_PAYLOAD: usize = 37;
+const MAX_DATA_PAYLOAD: usize = 27;
+
+/// A packet as the radio reads and writes it: header, length and payload.
+pub type Packet = [u8; 2 + MAX_PAYLOAD];
+
+pub const DEVICE_NAME: &str = "musicbox";
+
+// the name has to fit in the advertisement next to the address, the flags
+// and a long UUID
+const _: () = assert!(6 + 3 + 18 + 2 + DEVICE_NAME.len() <= MAX_PAYLOAD);
+
+/// Appearance of a generic media player.
+const APPEARANCE: u16 = 0x0840;
+
+pub const MUSICBOX_SERVICE: u128 = 0x9f3b_0001_5a1c_4c6e_9d2b_3e7a_1c0b_6d51;
+const PLAY_UUID: u128 = 0x9f3b_0002_5a1c_4c6e_9d2b_3e7a_1c0b_6d51;
+const PAUSE_UUID: u128 = 0x9f3b_0003_5a1c_4c6e_9d2b_3e7a_1c0b_6d51;
+const NEXT_UUID: u128 = 0x9f3b_0004_5a1c_4c6e_9d2b_3e7a_1c0b_6d51;
+const PREV_UUID: u128 = 0x9f3b_0005_5a1c_4c6e_9d2b_3e7a_1c0b_6d51;
+const VOLUME_UUID: u128 = 0x9f3b_0006_5a1c_4c6e_9d2b_3e7a_1c0b_6d51;
+const NOW_PLAYING_UUID: u128 = 0x9f3b_0007_5a1c_4c6e_9d2b_3e7a_1c0b_6d51;
+
+pub const MIDI_SERVICE: u128 = 0x03b8_0e5a_ede8_4b33_a751_6ce3_4ec4_c700;
+const MIDI_UUID: u128 = 0x7772_e5db_3868_4112_a1a9_f266_9d10_6bf3;
+
+/// Sleep clock accuracy of the peripheral, whose timer runs from the
+/// crystal oscillator.
+const SCA_PPM: u32 = 50;
+
+/// Jitter allowed on either side of a connection event's anchor point.
+const JITTER_US: u32 = 16;
+
+/// Things done over Bluetooth, for the application to carry out.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Connect,
+    /// The central disconnected or went out of range.
+    Disconnect,
+    Play,
+    Pause,
+    Next,
+    Prev,
+    /// The volume was set, in percent.
+    Volume(u8),
+    Midi(Message),
+}
+
+/// What the music box service tells of the player.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Status {
+    pub state: PlayState,
+    /// Index of the melody in the playlist, if one is playing or paused.
+    pub index: Option<usize>,
+    pub duration_ms: u32,
+    pub volume: u32,
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PlayState {
+    #[default]
+    Stopped,
+    Playing,
+    Paused,
+}
+
+/// Radio frequency of `channel`, in MHz above 2400.
+pub const fn channel_freq(channel: u8) -> u8 {
+    match channel {
+        37 => 2,
+        38 => 26,
+        39 => 80,
+        0..=10 => 4 + 2 * channel,
+        _ => 28 + 2 * (channel - 11),
+    }
+}
+
+/// A device address, least significant byte first as it is sent.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Address(pub [u8; 6]);
+
+impl Address {
+    /// A random static address made of the low 46 bits of `id`, e.g. the
+    /// chip's factory-programmed device address.
+    pub const fn random_static(id: u64) -> Self {
+        let b = id.to_le_bytes();
+        Self([b[0], b[1], b[2], b[3], b[4], b[5] | 0xc0])
+    }
+}
+
+/// When the central's packet opening a connection event is due, in ticks of
+/// a microsecond clock, and on which channel.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    pub channel: u8,
+    /// Earliest start of the packet, window widening included.
+    pub start_us: u32,
+    /// How long after `start_us` the packet may start.
+    pub window_us: u32,
+}
+
+// PDU types of the advertising channel, and the header flags for random
+// addresses
+const ADV_IND: u8 = 0x0;
+const CONNECT_IND: u8 = 0x5;
+const TX_ADD: u8 = 0x40;
+const RX_ADD: u8 = 0x80;
+
+// Header bits of data PDUs
+const LLID_CONTINUE: u8 = 0b01;
+const LLID_START: u8 = 0b10;
+const LLID_CONTROL: u8 = 0b11;
+const NESN: u8 = 0x04;
+const SN: u8 = 0x08;
+
+// Link-layer control PDUs
+const LL_CONNECTION_UPDATE_IND: u8 = 0x00;
+const LL_CHANNEL_MAP_IND: u8 = 0x01;
+const LL_TERMINATE_IND: u8 = 0x02;
+const LL_UNKNOWN_RSP: u8 = 0x07;
+const LL_FEATURE_REQ: u8 = 0x08;
+const LL_FEATURE_RSP: u8 = 0x09;
+const LL_VERSION_IND: u8 = 0x0c;
+const LL_PING_REQ: u8 = 0x12;
+const LL_PING_RSP: u8 = 0x13;
+
+/// Bluetooth 5.0, and no company identifier.
+const LL_VERSION: [u8; 5] = [0x09, 0xff, 0xff, 0x00, 0x00];
+
+// L2CAP channels
+const ATT_CID: u16 = 0x0004;
+const SIGNALING_CID: u16 = 0x0005;
+const SMP_CID: u16 = 0x0006;
+
+const SIGNALING_COMMAND_REJECT: u8 = 0x01;
+const SMP_PAIRING_FAILED: u8 = 0x05;
+const SMP_PAIRING_NOT_SUPPORTED: u8 = 0x05;
+
+/// An empty data PDU, sent when there is nothing to say.
+const EMPTY: Packet = {
+    let mut packet = [0; 2 + MAX_PAYLOAD];
+    packet[0] = LLID_CONTINUE;
+    packet
+};
+
+/// Builds a data PDU.
+fn data_pdu(llid: u8, payload: &[u8]) -> Packet {
+    let mut packet = [0; 2 + MAX_PAYLOAD];
+    packet[0] = llid;
+    packet[1] = payload.len() as u8;
+    packet[2..2 + payload.len()].copy_from_slice(payload);
+    packet
+}
+
+/// Builds a data PDU carrying `payload` on the L2CAP channel `cid`.
+fn l2cap_pdu(cid: u16, payload: &[u8]) -> Packet {
+    let mut packet = data_pdu(LLID_START, &[0; 4]);
+    packet[2..4].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+    packet[4..6].copy_from_slice(&cid.to_le_bytes());
+    packet[6..6 + payload.len()].copy_from_slice(payload);
+    packet[1] = 4 + payload.len() as u8;
+    packet
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+/// The data channels in use, one bit per channel.
+#[derive(Clone, Copy)]
+struct ChannelMap(u64);
+
+impl ChannelMap {
+    fn new(bytes: &[u8]) -> Self {
+        let mut map = [0; 8];
+        map[..5].copy_from_slice(&bytes[..5]);
+        Self(u64::from_le_bytes(map) & ((1 << 37) - 1))
+    }
+
+    fn len(self) -> u32 {
+        self.0.count_ones()
+    }
+
+    /// The channel used for `unmapped`, see channel selection algorithm #1.
+    fn remap(self, unmapped: u8) -> u8 {
+        if self.0 & 1 << unmapped != 0 {
+            return unmapped;
+        }
+        let mut index = unmapped as u32 % self.len();
+        for channel in 0..37 {
+            if self.0 & 1 << channel != 0 {
+                if index == 0 {
+                    return channel;
+                }
+                index -= 1;
+            }
+        }
+        unmapped
+    }
+}
+
+/// Parameters that change at a connection event of the central's choosing.
+#[derive(Clone, Copy)]
+struct Update {
+    instant: u16,
+    window_us: u32,
+    offset_us: u32,
+    interval_us: u32,
+    timeout_us: u32,
+}
+
+/// State of the link layer while connected.
+struct Link {
+    access_address: u32,
+    crc_init: u32,
+    interval_us: u32,
+    timeout_us: u32,
+    /// Sleep clock accuracy of the central and the peripheral together.
+    sca_ppm: u32,
+    channels: ChannelMap,
+    hop: u8,
+    unmapped: u8,
+    /// Counter of the next connection event.
+    counter: u16,
+    /// Anchor point of the connection event last heard from the central.
+    anchor_us: u32,
+    /// When the latest connection event started, or was to start.
+    event_us: u32,
+    /// The transmit window of the first event, or of the one after a
+    /// connection update.
+    window: Option<(u32, u32)>,
+    /// When the central was last heard with a valid CRC.
+    heard_us: u32,
+    update: Option<Update>,
+    map_update: Option<(u16, ChannelMap)>,
+    sn: bool,
+    nesn: bool,
+}
+
+impl Link {
+    /// The link opened by a CONNECT_IND payload that ended at `end_us`.
+    fn new(data: &[u8], end_us: u32) -> Option<Self> {
+        const SCA_PPM_TABLE: [u32; 8] = [500, 250, 150, 100, 75, 50, 30, 20];
+
+        let interval = u16_at(data, 10);
+        let timeout = u16_at(data, 14);
+        let channels = ChannelMap::new(&data[16..21]);
+        let hop = data[21] & 0x1f;
+        if !(6..=3200).contains(&interval) || timeout == 0 || channels.len() < 2 || hop < 5 {
+            return None;
+        }
+        let window_us = data[7] as u32 * 1250;
+        let offset_us = 1250 + u16_at(data, 8) as u32 * 1250;
+        Some(Self {
+            access_address: u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+            crc_init: u32::from_le_bytes([data[4], data[5], data[6], 0]),
+            interval_us: interval as u32 * 1250,
+            timeout_us: timeout as u32 * 10_000,
+            sca_ppm: SCA_PPM_TABLE[(data[21] >> 5) as usize] + SCA_PPM,
+            channels,
+            hop,
+            unmapped: 0,
+            counter: 0,
+            anchor_us: end_us,
+            event_us: end_us,
+            window: Some((offset_us, window_us)),
+            heard_us: end_us,
+            update: None,
+            map_update: None,
+            sn: false,
+            nesn: false,
+        })
+    }
+
+    /// Moves on to the next connection event, `None` if the central has
+    /// not been heard for longer than the supervision timeout.
+    fn next_event(&mut self) -> Option<Timing> {
+        let counter = self.counter;
+        self.counter = counter.wrapping_add(1);
+        self.unmapped = (self.unmapped + self.hop) % 37;
+        if let Some((_, map)) = self.map_update.take_if(|(instant, _)| *instant == counter) {
+            self.channels = map;
+        }
+        if let Some(update) = self.update.take_if(|update| update.instant == counter) {
+            // the old interval leads up to the transmit window
+            self.event_us = self.event_us.wrapping_add(self.interval_us);
+            self.window = Some((update.offset_us, update.window_us));
+            self.interval_us = update.interval_us;
+            self.timeout_us = update.timeout_us;
+        }
+        let (offset_us, window_us) = self.window.take().unwrap_or((self.interval_us, 0));
+        self.event_us = self.event_us.wrapping_add(offset_us);
+
+        if self.event_us.wrapping_sub(self.heard_us) > self.timeout_us {
+            return None;
+        }
+        let since_anchor = self.event_us.wrapping_sub(self.anchor_us) + window_us;
+        let widening = (since_anchor as u64 * self.sca_ppm as u64 / 1_000_000) as u32 + JITTER_US;
+        Some(Timing {
+            channel: self.channels.remap(self.unmapped),
+            start_us: self.event_us.wrapping_sub(widening),
+            window_us: window_us + 2 * widening,
+        })
+    }
+}
+
+/// A Bluetooth LE peripheral offering the music box service, see the module
+/// documentation.
+pub struct Peripheral {
+    address: Address,
+    midi: bool,
+    gatt: Gatt,
+    link: Option<Link>,
+    /// The data PDU being sent, until the central acknowledges it.
+    tx: Packet,
+    /// Data PDUs to send after it.
+    queue: Deque<Packet, 4>,
+    /// The latest new data PDU from the central, until it is processed.
+    rx: Option<Packet>,
+    attach_event_fn: Option<fn(Event)>,
+}
+
+impl Peripheral {
+    /// A peripheral at `address`, with the BLE-MIDI service if `midi`.
+    pub fn new(address: Address, midi: bool) -> Self {
+        Self {
+            address,
+            midi,
+            gatt: Gatt::new(midi),
+            link: None,
+            tx: EMPTY,
+            queue: Deque::new(),
+            rx: None,
+            attach_event_fn: None,
+        }
+    }
+
+    /// Calls `f` with what the central asks for, see `Event`.
+    pub fn attach_event(&mut self, f: fn(Event)) {
+        self.attach_event_fn = Some(f);
+    }
+
+    /// Tells the central about the player, notifying what changed.
+    pub fn update_status(&mut self, status: Status) {
+        self.gatt.update_status(status);
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.link.is_some()
+    }
+
+    /// The access address and CRC initial value of the connection, or those
+    /// for advertising.
+    pub fn access_address(&self) -> (u32, u32) {
+        self.link
+            .as_ref()
+            .map_or((ADV_ACCESS_ADDRESS, ADV_CRC_INIT), |link| {
+                (link.access_address, link.crc_init)
+            })
+    }
+
+    /// Writes the ADV_IND to send on each advertising channel: connectable,
+    /// with the device name and the main service.
+    pub fn advertisement(&self, packet: &mut Packet) {
+        let service = if self.midi {
+            MIDI_SERVICE
+        } else {
+            MUSICBOX_SERVICE
+        };
+        let mut payload = Vec::<u8, MAX_PAYLOAD>::new();
+        payload.extend_from_slice(&self.address.0).ok();
+        // general discoverable, LE only
+        payload.extend_from_slice(&[2, 0x01, 0x06]).ok();
+        payload.extend_from_slice(&[17, 0x07]).ok();
+        payload.extend_from_slice(&service.to_le_bytes()).ok();
+        payload
+            .extend_from_slice(&[1 + DEVICE_NAME.len() as u8, 0x09])
+            .ok();
+        payload.extend_from_slice(DEVICE_NAME.as_bytes()).ok();
+        packet[0] = ADV_IND | TX_ADD;
+        packet[1] = payload.len() as u8;
+        packet[2..2 + payload.len()].copy_from_slice(&payload);
+    }
+
+    /// Takes up the connection `packet` asks for, if it is a CONNECT_IND to
+    /// this peripheral. `end_us` is when it ended.
+    pub fn connect(&mut self, packet: &Packet, end_us: u32) -> bool {
+        if packet[0] & 0x0f != CONNECT_IND || packet[0] & RX_ADD == 0 || packet[1] != 34 {
+            return false;
+        }
+        if packet[8..14] != self.address.0 {
+            return false;
+        }
+        let Some(link) = Link::new(&packet[14..36], end_us) else {
+            return false;
+        };
+        defmt::info!("ble: connected, interval {} us", link.interval_us);
+        self.link = Some(link);
+        self.gatt.reset();
+        self.tx = EMPTY;
+        self.queue.clear();
+        self.rx = None;
+        self.emit(Event::Connect);
+        true
+    }
+
+    /// Moves on to the next connection event, returning when and where to
+    /// listen for the central, or `None` once the connection is lost.
+    pub fn next_event(&mut self) -> Option<Timing> {
+        let timing = self.link.as_mut()?.next_event();
+        if timing.is_none() {
+            defmt::info!("ble: supervision timeout");
+            self.disconnect();
+        }
+        timing
+    }
+
+    /// Answers the central's packet, which started at `start_us` and is
+    /// `None` if its CRC failed, returning the packet to send back right
+    /// away. Anything new it brings is dealt with later by `process`.
+    pub fn exchange(&mut self, rx: Option<&Packet>, start_us: u32) -> &Packet {
+        let Some(link) = self.link.as_mut() else {
+            return &self.tx;
+        };
+        link.anchor_us = start_us;
+        link.event_us = start_us;
+        if let Some(rx) = rx {
+            link.heard_us = start_us;
+            if (rx[0] & NESN != 0) != link.sn {
+                // the central has our last packet
+                link.sn = !link.sn;
+                self.tx = match self.queue.pop_front() {
+                    Some(packet) => packet,
+                    None => self.gatt.notification().unwrap_or(EMPTY),
+                };
+            }
+            if (rx[0] & SN != 0) == link.nesn {
+                link.nesn = !link.nesn;
+                if rx[1] > 0 {
+                    self.rx = Some(*rx);
+                }
+            }
+        }
+        self.tx[0] &= 0b11;
+        self.tx[0] |= if link.nesn { NESN } else { 0 } | if link.sn { SN } else { 0 };
+        &self.tx
+    }
+
+    /// Handles the data PDU received last, if it was new.
+    pub fn process(&mut self) {
+        let Some(rx) = self.rx.take() else {
+            return;
+        };
+        let payload = &rx[2..2 + (rx[1] as usize).min(MAX_DATA_PAYLOAD)];
+        match rx[0] & 0b11 {
+            LLID_CONTROL => self.control(payload),
+            LLID_START if payload.len() >= 4 => {
+                let len = u16_at(payload, 0) as usize;
+                // fragmented L2CAP packets are not needed with the default MTU
+                if len == payload.len() - 4 {
+                    self.l2cap(u16_at(payload, 2), &payload[4..]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn control(&mut self, pdu: &[u8]) {
+        let Some((&opcode, data)) = pdu.split_first() else {
+            return;
+        };
+        let Some(link) = self.link.as_mut() else {
+            return;
+        };
+        match opcode {
+            LL_CONNECTION_UPDATE_IND if data.len() >= 11 => {
+                link.update = Some(Update {
+                    instant: u16_at(data, 9),
+                    window_us: data[0] as u32 * 1250,
+                    offset_us: u16_at(data, 1) as u32 * 1250,
+                    interval_us: u16_at(data, 3) as u32 * 1250,
+                    timeout_us: u16_at(data, 7) as u32 * 10_000,
+                });
+            }
+            LL_CHANNEL_MAP_IND if data.len() >= 7 => {
+                link.map_update = Some((u16_at(data, 5), ChannelMap::new(data)));
+            }
+            LL_TERMINATE_IND => {
+                defmt::info!("ble: terminated by the central");
+                self.disconnect();
+            }
+            LL_FEATURE_REQ => self.send(data_pdu(
+                LLID_CONTROL,
+                &[LL_FEATURE_RSP, 0, 0, 0, 0, 0, 0, 0, 0],
+            )),
+            LL_VERSION_IND => {
+                let mut pdu = [LL_VERSION_IND; 6];
+                pdu[1..].copy_from_slice(&LL_VERSION);
+                self.send(data_pdu(LLID_CONTROL, &pdu));
+            }
+            LL_PING_REQ => self.send(data_pdu(LLID_CONTROL, &[LL_PING_RSP])),
+            LL_UNKNOWN_RSP => {}
+            _ => self.send(data_pdu(LLID_CONTROL, &[LL_UNKNOWN_RSP, opcode])),
+        }
+    }
+
+    fn l2cap(&mut self, cid: u16, payload: &[u8]) {
+        match (cid, payload) {
+            (ATT_CID, _) => {
+                let mut response = Vec::new();
+                let emit = self.attach_event_fn;
+                self.gatt.request(payload, &mut response, |event| {
+                    if let Some(f) = emit {
+                        f(event);
+                    }
+                });
+                if !response.is_empty() {
+                    self.send(l2cap_pdu(ATT_CID, &response));
+                }
+            }
+            // connection parameter update requests and the like are not
+            // understood
+            (SIGNALING_CID, &[code, identifier, ..]) if code != SIGNALING_COMMAND_REJECT => {
+                self.send(l2cap_pdu(
+                    SIGNALING_CID,
+                    &[SIGNALING_COMMAND_REJECT, identifier, 2, 0, 0, 0],
+                ));
+            }
+            (SMP_CID, [_, ..]) => {
+                self.send(l2cap_pdu(
+                    SMP_CID,
+                    &[SMP_PAIRING_FAILED, SMP_PAIRING_NOT_SUPPORTED],
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    /// Queues a data PDU, dropping it if too many are waiting.
+    fn send(&mut self, packet: Packet) {
+        if self.queue.push_back(packet).is_err() {
+            defmt::warn!("ble: transmit queue full");
+        }
+    }
+
+    fn disconnect(&mut self) {
+        self.link = None;
+        self.emit(Event::Disconnect);
+    }
+
+    fn emit(&self, event: Event) {
+        if let Some(f) = self.attach_event_fn {
+            f(event);
+        }
+    }
+}
+
+/// ATT_MTU: the default, as larger ones need L2CAP fragmentation.
+const ATT_MTU: usize = 23;
+
+/// Longest attribute value.
+const MAX_VALUE: usize = ATT_MTU - 4;
+
+// ATT opcodes
+const ATT_ERROR_RSP: u8 = 0x01;
+const ATT_EXCHANGE_MTU_REQ: u8 = 0x02;
+const ATT_FIND_INFORMATION_REQ: u8 = 0x04;
+const ATT_FIND_BY_TYPE_VALUE_REQ: u8 = 0x06;
+const ATT_READ_BY_TYPE_REQ: u8 = 0x08;
+const ATT_READ_REQ: u8 = 0x0a;
+const ATT_READ_BLOB_REQ: u8 = 0x0c;
+const ATT_READ_BY_GROUP_TYPE_REQ: u8 = 0x10;
+const ATT_WRITE_REQ: u8 = 0x12;
+const ATT_WRITE_RSP: u8 = 0x13;
+const ATT_HANDLE_VALUE_NTF: u8 = 0x1b;
+const ATT_WRITE_CMD: u8 = 0x52;
+/// Set in the opcodes of commands, which get no response.
+const ATT_COMMAND: u8 = 0x40;
+
+// ATT error codes
+const INVALID_HANDLE: u8 = 0x01;
+const READ_NOT_PERMITTED: u8 = 0x02;
+const WRITE_NOT_PERMITTED: u8 = 0x03;
+const INVALID_PDU: u8 = 0x04;
+const REQUEST_NOT_SUPPORTED: u8 = 0x06;
+const INVALID_OFFSET: u8 = 0x07;
+const ATTRIBUTE_NOT_FOUND: u8 = 0x0a;
+const INVALID_ATTRIBUTE_VALUE_LENGTH: u8 = 0x0d;
+const UNSUPPORTED_GROUP_TYPE: u8 = 0x10;
+
+// Attribute types
+const PRIMARY_SERVICE: u16 = 0x2800;
+const CHARACTERISTIC: u16 = 0x2803;
+const USER_DESCRIPTION: u16 = 0x2901;
+const CLIENT_CONFIGURATION: u16 = 0x2902;
+
+// Characteristic properties
+const READ: u8 = 0x02;
+const WRITE_WITHOUT_RESPONSE: u8 = 0x04;
+const WRITE: u8 = 0x08;
+const NOTIFY: u8 = 0x10;
+
+/// Short UUIDs are shorthand for this one with the 16-bit value at bits
+/// 96-111.
+const BASE_UUID: u128 = 0x0000_0000_0000_1000_8000_0080_5f9b_34fb;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Uuid {
+    Short(u16),
+    Long(u128),
+}
+
+impl Uuid {
+    /// Reads a UUID of 2 or 16 bytes, little endian.
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        match *bytes {
+            [lo, hi] => Some(Self::Short(u16::from_le_bytes([lo, hi]))),
+            _ => {
+                let uuid = u128::from_le_bytes(bytes.try_into().ok()?);
+                let short = (uuid >> 96) as u16;
+                if uuid == BASE_UUID | (short as u128) << 96 {
+                    Some(Self::Short(short))
+                } else {
+                    Some(Self::Long(uuid))
+                }
+            }
+        }
+    }
+
+    fn write<const N: usize>(self, out: &mut Vec<u8, N>) -> Result<(), ()> {
+        match self {
+            Self::Short(uuid) => out.extend_from_slice(&uuid.to_le_bytes()),
+            Self::Long(uuid) => out.extend_from_slice(&uuid.to_le_bytes()),
+        }
+        .map_err(|_| ())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Characteristic {
+    DeviceName,
+    Appearance,
+    Play,
+    Pause,
+    Next,
+    Prev,
+    Volume,
+    NowPlaying,
+    Midi,
+}
+
+impl Characteristic {
+    const fn uuid(self) -> Uuid {
+        match self {
+            Self::DeviceName => Uuid::Short(0x2a00),
+            Self::Appearance => Uuid::Short(0x2a01),
+            Self::Play => Uuid::Long(PLAY_UUID),
+            Self::Pause => Uuid::Long(PAUSE_UUID),
+            Self::Next => Uuid::Long(NEXT_UUID),
+            Self::Prev => Uuid::Long(PREV_UUID),
+            Self::Volume => Uuid::Long(VOLUME_UUID),
+            Self::NowPlaying => Uuid::Long(NOW_PLAYING_UUID),
+            Self::Midi => Uuid::Long(MIDI_UUID),
+        }
+    }
+
+    const fn properties(self) -> u8 {
+        match self {
+            Self::DeviceName | Self::Appearance => READ,
+            Self::Play | Self::Pause | Self::Next | Self::Prev => WRITE | WRITE_WITHOUT_RESPONSE,
+            Self::Volume => READ | WRITE | WRITE_WITHOUT_RESPONSE | NOTIFY,
+            Self::NowPlaying => READ | NOTIFY,
+            Self::Midi => READ | WRITE_WITHOUT_RESPONSE | NOTIFY,
+        }
+    }
+
+    /// Bit of the characteristic in `Gatt::subscribed` and `Gatt::changed`.
+    const fn bit(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+enum Attribute {
+    Service(Uuid),
+    Declaration(Characteristic),
+    Value(Characteristic),
+    /// Client characteristic configuration, to subscribe to notifications.
+    Config(Characteristic),
+    Description(&'static str),
+}
+
+impl Attribute {
+    fn kind(&self) -> Uuid {
+        match self {
+            Self::Service(_) => Uuid::Short(PRIMARY_SERVICE),
+            Self::Declaration(_) => Uuid::Short(CHARACTERISTIC),
+            Self::Value(characteristic) => characteristic.uuid(),
+            Self::Config(_) => Uuid::Short(CLIENT_CONFIGURATION),
+            Self::Description(_) => Uuid::Short(USER_DESCRIPTION),
+        }
+    }
+}
+
+/// Every attribute, its handle being its position plus one. The BLE-MIDI
+/// service comes last so that it can be left out.
+const ATTRIBUTES: [Attribute; 29] = {
+    use Attribute::*;
+    use Characteristic::*;
+    [
+        Service(Uuid::Short(0x1800)),
+        Declaration(DeviceName),
+        Value(DeviceName),
+        Declaration(Appearance),
+        Value(Appearance),
+        Service(Uuid::Long(MUSICBOX_SERVICE)),
+        Declaration(Play),
+        Value(Play),
+        Description("Play"),
+        Declaration(Pause),
+        Value(Pause),
+        Description("Pause"),
+        Declaration(Next),
+        Value(Next),
+        Description("Next"),
+        Declaration(Prev),
+        Value(Prev),
+        Description("Previous"),
+        Declaration(Volume),
+        Value(Volume),
+        Config(Volume),
+        Description("Volume"),
+        Declaration(NowPlaying),
+        Value(NowPlaying),
+        Config(NowPlaying),
+        Description("Now playing"),
+        Service(Uuid::Long(MIDI_SERVICE)),
+        Declaration(Midi),
+        Value(Midi),
+    ]
+};
+
+/// Attributes of the BLE-MIDI service, at the end of `ATTRIBUTES`.
+const MIDI_ATTRIBUTES: usize = 3;
+
+/// The attribute protocol server, holding the state of the characteristics.
+struct Gatt {
+    attributes: &'static [Attribute],
+    status: Status,
+    /// Characteristics the client subscribed to.
+    subscribed: u16,
+    /// Subscribed characteristics that changed since they were notified.
+    changed: u16,
+}
+
+type Value = Vec<u8, MAX_VALUE>;
+type Response = Vec<u8, ATT_MTU>;
+
+impl Gatt {
+    fn new(midi: bool) -> Self {
+        let len = ATTRIBUTES.len() - if midi { 0 } else { MIDI_ATTRIBUTES };
+        Self {
+            attributes: &ATTRIBUTES[..len],
+            status: Status::default(),
+            subscribed: 0,
+            changed: 0,
+        }
+    }
+
+    /// Forgets the subscriptions, for a new connection.
+    fn reset(&mut self) {
+        self.subscribed = 0;
+        self.changed = 0;
+    }
+
+    fn update_status(&mut self, status: Status) {
+        let mut changed = 0;
+        if status.volume != self.status.volume {
+            changed |= Characteristic::Volume.bit();
+        }
+        if (Status {
+            volume: 0,
+            ..status
+        }) != (Status {
+            volume: 0,
+            ..self.status
+        }) {
+            changed |= Characteristic::NowPlaying.bit();
+        }
+        self.changed |= changed & self.subscribed;
+        self.status = status;
+    }
+
+    /// A notification of the next characteristic that changed, if any.
+    fn notification(&mut self) -> Option<Packet> {
+        let attributes = self.attributes;
+        let handle = (1..)
+            .zip(attributes)
+            .find_map(|(handle, attribute)| match attribute {
+                Attribute::Value(characteristic) if self.changed & characteristic.bit() != 0 => {
+                    self.changed &= !characteristic.bit();
+                    Some(handle)
+                }
+                _ => None,
+            })?;
+        let mut pdu = Response::new();
+        pdu.push(ATT_HANDLE_VALUE_NTF).ok();
+        pdu.extend_from_slice(&u16::to_le_bytes(handle)).ok();
+        pdu.extend_from_slice(&self.read(handle).ok()?).ok();
+        Some(l2cap_pdu(ATT_CID, &pdu))
+    }
+
+    fn attribute(&self, handle: u16) -> Option<&Attribute> {
+        self.attributes.get((handle as usize).checked_sub(1)?)
+    }
+
+    /// Handle of the last attribute in the group of the service at `handle`.
+    fn group_end(&self, handle: u16) -> u16 {
+        (handle + 1..)
+            .zip(&self.attributes[handle as usize..])
+            .find(|(_, attribute)| matches!(attribute, Attribute::Service(_)))
+            .map_or(self.attributes.len() as u16, |(next, _)| next - 1)
+    }
+
+    /// Handles in `start..=end` and their attributes.
+    fn range(&self, start: u16, end: u16) -> impl Iterator<Item = (u16, &Attribute)> {
+        (1..)
+            .zip(self.attributes)
+            .skip_while(move |(handle, _)| *handle < start)
+            .take_while(move |(handle, _)| *handle <= end)
+    }
+
+    fn read(&self, handle: u16) -> Result<Value, u8> {
+        let mut value = Value::new();
+        match self.attribute(handle).ok_or(INVALID_HANDLE)? {
+            Attribute::Service(uuid) => uuid.write(&mut value).ok(),
+            Attribute::Declaration(characteristic) => {
+                value.push(characteristic.properties()).ok();
+                value.extend_from_slice(&(handle + 1).to_le_bytes()).ok();
+                characteristic.uuid().write(&mut value).ok()
+            }
+            Attribute::Value(characteristic) => match characteristic {
+                _ if characteristic.properties() & READ == 0 => return Err(READ_NOT_PERMITTED),
+                Characteristic::DeviceName => value.extend_from_slice(DEVICE_NAME.as_bytes()).ok(),
+                Characteristic::Appearance => {
+                    value.extend_from_slice(&APPEARANCE.to_le_bytes()).ok()
+                }
+                Characteristic::Volume => value.push(self.status.volume.min(100) as u8).ok(),
+                Characteristic::NowPlaying => {
+                    value.push(self.status.state as u8).ok();
+                    value
+                        .push(
+                            self.status
+                                .index
+                                .map_or(0xff, |index| index.min(0xfe) as u8),
+                        )
+                        .ok();
+                    value
+                        .extend_from_slice(&self.status.duration_ms.to_le_bytes())
+                        .ok()
+                }
+                // BLE-MIDI reads as empty
+                _ => Some(()),
+            },
+            Attribute::Config(characteristic) => {
+                let notify = self.subscribed & characteristic.bit() != 0;
+                value.extend_from_slice(&[notify as u8, 0]).ok()
+            }
+            Attribute::Description(text) => value.extend_from_slice(text.as_bytes()).ok(),
+        };
+        Ok(value)
+    }
+
+    fn write(
+        &mut self,
+        handle: u16,
+        value: &[u8],
+        command: bool,
+        mut emit: impl FnMut(Event),
+    ) -> Result<(), u8> {
+        match self.attribute(handle).ok_or(INVALID_HANDLE)? {
+            Attribute::Value(characteristic) => {
+                let allowed = if command {
+                    WRITE_WITHOUT_RESPONSE
+                } else {
+                    WRITE
+                };
+                if characteristic.properties() & allowed == 0 {
+                    return Err(WRITE_NOT_PERMITTED);
+                }
+                match characteristic {
+                    Characteristic::Play => emit(Event::Play),
+                    Characteristic::Pause => emit(Event::Pause),
+                    Characteristic::Next => emit(Event::Next),
+                    Characteristic::Prev => emit(Event::Prev),
+                    Characteristic::Volume => match *value {
+                        [volume] => emit(Event::Volume(volume.min(100))),
+                        _ => return Err(INVALID_ATTRIBUTE_VALUE_LENGTH),
+                    },
+                    Characteristic::Midi => {
+                        midi_messages(value, |message| emit(Event::Midi(message)))
+                    }
+                    _ => return Err(WRITE_NOT_PERMITTED),
+                }
+            }
+            Attribute::Config(characteristic) => match *value {
+                [config, _] => {
+                    let bit = characteristic.bit();
+                    if config & 1 != 0 {
+                        self.subscribed |= bit;
+                    } else {
+                        self.subscribed &= !bit;
+                        self.changed &= !bit;
+                    }
+                }
+                _ => return Err(INVALID_ATTRIBUTE_VALUE_LENGTH),
+            },
+            _ => return Err(WRITE_NOT_PERMITTED),
+        }
+        Ok(())
+    }
+
+    /// Answers the ATT PDU `request` into `response`, which stays empty for
+    /// commands.
+    fn request(&mut self, request: &[u8], response: &mut Response, emit: impl FnMut(Event)) {
+        let Some(&opcode) = request.first() else {
+            return;
+        };
+        if let Err((handle, code)) = self.respond(request, response, emit) {
+            response.clear();
+            if opcode & ATT_COMMAND == 0 {
+                response.push(ATT_ERROR_RSP).ok();
+                response.push(opcode).ok();
+                response.extend_from_slice(&handle.to_le_bytes()).ok();
+                response.push(code).ok();
+            }
+        }
+    }
+
+    fn respond(
+        &mut self,
+        request: &[u8],
+        response: &mut Response,
+        emit: impl FnMut(Event),
+    ) -> Result<(), (u16, u8)> {
+        let opcode = request[0];
+        let params = &request[1..];
+        // handle range of the find and read-by requests
+        let range = || -> Result<(u16, u16), (u16, u8)> {
+            if params.len() < 4 {
+                return Err((0, INVALID_PDU));
+            }
+            let (start, end) = (u16_at(params, 0), u16_at(params, 2));
+            if start == 0 || start > end {
+                return Err((start, INVALID_HANDLE));
+            }
+            Ok((start, end))
+        };
+        // response header, then as many entries of the same length as fit
+        let entries = |response: &mut Response, found: &mut dyn Iterator<Item = Response>| {
+            let mut len = None;
+            for entry in found {
+                if *len.get_or_insert(entry.len()) != entry.len()
+                    || response.extend_from_slice(&entry).is_err()
+                {
+                    break;
+                }
+            }
+            len
+        };
+
+        response.push(opcode + 1).ok();
+        match opcode {
+            ATT_EXCHANGE_MTU_REQ => {
+                response
+                    .extend_from_slice(&(ATT_MTU as u16).to_le_bytes())
+                    .ok();
+            }
+            ATT_FIND_INFORMATION_REQ => {
+                let (start, end) = range()?;
+                response.push(0).ok();
+                let len = entries(
+                    response,
+                    &mut self.range(start, end).map(|(handle, attribute)| {
+                        let mut entry = Response::from_slice(&handle.to_le_bytes()).unwrap();
+                        attribute.kind().write(&mut entry).ok();
+                        entry
+                    }),
+                )
+                .ok_or((start, ATTRIBUTE_NOT_FOUND))?;
+                // format 1 for short UUIDs, 2 for long ones
+                response[1] = if len == 4 { 1 } else { 2 };
+            }
+            ATT_FIND_BY_TYPE_VALUE_REQ => {
+                let (start, end) = range()?;
+                if params.len() < 6 {
+                    return Err((start, INVALID_PDU));
+                }
+                let value = &params[6..];
+                if Uuid::parse(&params[4..6]) != Some(Uuid::Short(PRIMARY_SERVICE)) {
+                    return Err((start, ATTRIBUTE_NOT_FOUND));
+                }
+                for (handle, attribute) in self.range(start, end) {
+                    if let Attribute::Service(uuid) = attribute {
+                        if Uuid::parse(value) == Some(*uuid) {
+                            let mut entry = [0; 4];
+                            entry[..2].copy_from_slice(&handle.to_le_bytes());
+                            entry[2..].copy_from_slice(&self.group_end(handle).to_le_bytes());
+                            if response.extend_from_slice(&entry).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+                if response.len() == 1 {
+                    return Err((start, ATTRIBUTE_NOT_FOUND));
+                }
+            }
+            ATT_READ_BY_TYPE_REQ | ATT_READ_BY_GROUP_TYPE_REQ => {
+                let (start, end) = range()?;
+                let kind = Uuid::parse(&params[4..]).ok_or((start, INVALID_PDU))?;
+                let group = opcode == ATT_READ_BY_GROUP_TYPE_REQ;
+                if group && kind != Uuid::Short(PRIMARY_SERVICE) {
+                    return Err((start, UNSUPPORTED_GROUP_TYPE));
+                }
+                let mut found = self
+                    .range(start, end)
+                    .filter(|(_, attribute)| attribute.kind() == kind)
+                    .peekable();
+                if let Some(&(handle, _)) = found.peek() {
+                    self.read(handle).map_err(|code| (handle, code))?;
+                }
+                response.push(0).ok();
+                let len = entries(
+                    response,
+                    &mut found.map_while(|(handle, _)| {
+                        let mut entry = Response::from_slice(&handle.to_le_bytes()).unwrap();
+                        if group {
+                            entry
+                                .extend_from_slice(&self.group_end(handle).to_le_bytes())
+                                .ok();
+                        }
+                        entry.extend_from_slice(&self.read(handle).ok()?).ok()?;
+                        Some(entry)
+                    }),
+                )
+                .ok_or((start, ATTRIBUTE_NOT_FOUND))?;
+                response[1] = len as u8;
+            }
+            ATT_READ_REQ | ATT_READ_BLOB_REQ => {
+                if params.len() < 2 {
+                    return Err((0, INVALID_PDU));
+                }
+                let handle = u16_at(params, 0);
+                let offset = if opcode == ATT_READ_BLOB_REQ && params.len() >= 4 {
+                    u16_at(params, 2) as usize
+                } else {
+                    0
+                };
+                let value = self.read(handle).map_err(|code| (handle, code))?;
+                let value = value.get(offset..).ok_or((handle, INVALID_OFFSET))?;
+                response.extend_from_slice(value).ok();
+            }
+            ATT_WRITE_REQ | ATT_WRITE_CMD => {
+                if params.len() < 2 {
+                    return Err((0, INVALID_PDU));
+                }
+                let handle = u16_at(params, 0);
+                self.write(handle, &params[2..], opcode == ATT_WRITE_CMD, emit)
+                    .map_err(|code| (handle, code))?;
+                response.clear();
+                if opcode == ATT_WRITE_REQ {
+                    response.push(ATT_WRITE_RSP).ok();
+                }
+            }
+            _ if opcode & ATT_COMMAND != 0 => response.clear(),
+            _ => return Err((0, REQUEST_NOT_SUPPORTED)),
+        }
+        Ok(())
+    }
+}
+
+/// Calls `f` with the MIDI messages of a BLE-MIDI packet in order, leaving
+/// out their timestamps, system messages and anything malformed.
+///
+/// Every status byte is preceded by a timestamp byte, both with the top bit
+/// set; data bytes without one carry on with the running status.
+pub fn midi_messages(packet: &[u8], mut f: impl FnMut(Message)) {
+    let Some((&header, body)) = packet.split_first() else {
+        return;
+    };
+    if header & 0xc0 != 0x80 {
+        return;
+    }
+    let mut parser = Parser::new();
+    let mut timestamp = false;
+    for &byte in body {
+        if byte & 0x80 != 0 && !timestamp {
+            timestamp = true;
+            continue;
+        }
+        timestamp = false;
+        if let Some(message) = parser.push(byte) {
+            f(message);
+        }
+    }
+}
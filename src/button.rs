@@ -1,5 +1,3 @@
-use core::fmt::Debug;
-
 use defmt::Format;
 use embedded_hal::digital::v2::InputPin;
 use fugit::{ExtU64, TimerDurationU64, TimerInstantU64};
@@ -14,8 +12,24 @@ pub enum Event {
     LongPressStop,
 }
 
-pub struct Button<PIN, const TIMER_HZ: u32> {
-    pin: PIN,
+/// What a button reads its state from: a pin, or any other sensor telling
+/// whether it is held.
+pub trait Source {
+    /// Whether the button is held at the moment.
+    fn is_active(&mut self) -> bool;
+}
+
+/// A pin is held when low, as with the board's buttons.
+impl<PIN: InputPin> Source for PIN {
+    fn is_active(&mut self) -> bool {
+        self.is_low().unwrap_or(false)
+    }
+}
+
+/// Turns the state of a source, polled once per tick, into clicks and long
+/// presses.
+pub struct Button<S, const TIMER_HZ: u32> {
+    source: S,
     state: State,
     last_state: State,
     cnt_click: u32,
@@ -37,14 +51,10 @@ enum State {
     Pressend = 7,
 }
 
-impl<PIN, E, const TIMER_HZ: u32> Button<PIN, TIMER_HZ>
-where
-    E: Debug,
-    PIN: InputPin<Error = E>,
-{
-    pub fn new(pin: PIN) -> Self {
+impl<S: Source, const TIMER_HZ: u32> Button<S, TIMER_HZ> {
+    pub fn new(source: S) -> Self {
         Self {
-            pin,
+            source,
             state: State::Pending,
             last_state: State::Pending,
             cnt_click: 0,
@@ -73,14 +83,14 @@ where
         self.attach_event_fn = Some(f);
     }
 
-    pub fn free(self) -> PIN {
-        self.pin
+    pub fn free(self) -> S {
+        self.source
     }
 
     pub fn tick(&mut self) {
         use State::*;
 
-        let active = self.pin.is_low().unwrap();
+        let active = self.source.is_active();
         let now = self.now();
         let wait_time = now - self.start_time;
 
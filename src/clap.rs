@@ -0,0 +1,399 @@
+use defmt::Format;
+use fugit::{ExtU64, TimerDurationU64, TimerInstantU64};
+
+pub use self::inner::Microphone;
+
+/// Microphone sample rate.
+pub const SAMPLE_HZ: u32 = 8000;
+/// Samples per block; the detector gets one level per block.
+pub const BLOCK_LEN: usize = 80;
+/// Blocks per second, the time base of `ClapDetector`.
+pub const BLOCK_HZ: u32 = SAMPLE_HZ / BLOCK_LEN as u32;
+
+/// 12-bit microphone samples, as read by the SAADC.
+pub type Block = [i16; BLOCK_LEN];
+
+/// Weight of a new level in the noise floor, as a power of two: the floor
+/// follows the ambient level over about 64 blocks.
+const NOISE_SHIFT: u32 = 6;
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Clap,
+    DoubleClap,
+    /// The room has been loud for a while.
+    Loud,
+    /// The room is quiet again after `Loud`.
+    Quiet,
+}
+
+/// Peak of `samples` around their mean, so that the microphone's bias does
+/// not count.
+pub fn level(samples: &[i16]) -> u32 {
+    if samples.is_empty() {
+        return 0;
+    }
+    let sum: i32 = samples.iter().map(|&s| s as i32).sum();
+    let mean = sum / samples.len() as i32;
+    samples
+        .iter()
+        .map(|&s| (s as i32).abs_diff(mean))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Detects claps as short bursts well above the noise floor that die away
+/// at once, a second one shortly after the first making a double clap.
+/// Sounds lasting longer are part of the ambient noise, and notes, even
+/// short ones, fade out too slowly to be taken for claps; the room being
+/// loud, or quiet again, for a while is reported too.
+///
+/// `update` is called once per block with its `level`, so time is counted
+/// in blocks and the detector can be driven from recorded sound.
+pub struct ClapDetector<const TIMER_HZ: u32> {
+    time: TimerInstantU64<TIMER_HZ>,
+    /// Ambient level, scaled by `1 << NOISE_SHIFT`.
+    noise: u32,
+    burst: Option<Burst<TIMER_HZ>>,
+    /// Start of a clap waiting for a second one.
+    first_clap: Option<TimerInstantU64<TIMER_HZ>>,
+    /// Start of the last clap reported.
+    event_time: Option<TimerInstantU64<TIMER_HZ>>,
+    loud: bool,
+    /// Since when the noise floor has been past the ducking threshold.
+    duck_since: Option<TimerInstantU64<TIMER_HZ>>,
+    ratio: u32,
+    min_level: u32,
+    max_clap_ms: TimerDurationU64<TIMER_HZ>,
+    max_decay_ms: TimerDurationU64<TIMER_HZ>,
+    window_ms: TimerDurationU64<TIMER_HZ>,
+    refractory_ms: TimerDurationU64<TIMER_HZ>,
+    duck_level: u32,
+    loud_ms: TimerDurationU64<TIMER_HZ>,
+    quiet_ms: TimerDurationU64<TIMER_HZ>,
+}
+
+impl<const TIMER_HZ: u32> ClapDetector<TIMER_HZ> {
+    pub fn new() -> Self {
+        Self {
+            time: TimerInstantU64::from_ticks(0),
+            noise: 0,
+            burst: None,
+            first_clap: None,
+            event_time: None,
+            loud: false,
+            duck_since: None,
+            ratio: 4,
+            min_level: 200,
+            max_clap_ms: 120.millis(),
+            max_decay_ms: 30.millis(),
+            window_ms: 500.millis(),
+            refractory_ms: 300.millis(),
+            duck_level: 150,
+            loud_ms: 2000.millis(),
+            quiet_ms: 5000.millis(),
+        }
+    }
+
+    /// How many times the noise floor a burst has to be.
+    pub fn set_ratio(&mut self, ratio: u32) {
+        self.ratio = ratio.max(2);
+    }
+
+    /// Minimum level of a burst, whatever the noise floor.
+    pub fn set_min_level(&mut self, min_level: u32) {
+        self.min_level = min_level;
+    }
+
+    /// Longest burst taken for a clap.
+    pub fn set_max_clap_ms(&mut self, max_clap_ms: TimerDurationU64<TIMER_HZ>) {
+        self.max_clap_ms = max_clap_ms;
+    }
+
+    /// Longest time a clap takes to fall from its peak to a quarter of it.
+    pub fn set_max_decay_ms(&mut self, max_decay_ms: TimerDurationU64<TIMER_HZ>) {
+        self.max_decay_ms = max_decay_ms;
+    }
+
+    /// Time from a clap within which a second one makes a double clap.
+    pub fn set_window_ms(&mut self, window_ms: TimerDurationU64<TIMER_HZ>) {
+        self.window_ms = window_ms;
+    }
+
+    /// Time after a clap reported during which no new clap is counted.
+    pub fn set_refractory_ms(&mut self, refractory_ms: TimerDurationU64<TIMER_HZ>) {
+        self.refractory_ms = refractory_ms;
+    }
+
+    /// Noise floor above which the room is loud, and below half of which it
+    /// is quiet. It should be above what the speaker makes at the
+    /// microphone, or the music ducks itself.
+    pub fn set_duck_level(&mut self, duck_level: u32) {
+        self.duck_level = duck_level;
+    }
+
+    /// How long the room has to be loud before `Loud`, and quiet before
+    /// `Quiet`.
+    pub fn set_duck_ms(
+        &mut self,
+        loud_ms: TimerDurationU64<TIMER_HZ>,
+        quiet_ms: TimerDurationU64<TIMER_HZ>,
+    ) {
+        self.loud_ms = loud_ms;
+        self.quiet_ms = quiet_ms;
+    }
+
+    /// The ambient level.
+    pub fn noise(&self) -> u32 {
+        self.noise >> NOISE_SHIFT
+    }
+
+    pub fn update(&mut self, level: u32) -> Option<Event> {
+        self.time += TimerDurationU64::from_ticks(1);
+        let now = self.time;
+        if now.ticks() == 1 {
+            self.noise = level << NOISE_SHIFT;
+        }
+
+        // a clap left alone once its window is over, unless a burst that
+        // may be the second one is still going on
+        let mut event = None;
+        if let Some(first) = self.first_clap {
+            let waiting = self
+                .burst
+                .is_some_and(|burst| burst.start - first <= self.window_ms);
+            if now - first > self.window_ms && !waiting {
+                self.first_clap = None;
+                self.event_time = Some(first);
+                event = Some(Event::Clap);
+            }
+        }
+
+        let threshold = (self.noise() * self.ratio).max(self.min_level);
+        match self.burst.as_mut() {
+            None if level >= threshold => self.burst = Some(Burst::new(now, level)),
+            None => self.follow_noise(level),
+            Some(burst) if level < threshold / 2 => {
+                let burst = *burst;
+                self.burst = None;
+                let decay = burst.decay.unwrap_or(now - burst.peak_time);
+                if now - burst.start <= self.max_clap_ms && decay <= self.max_decay_ms {
+                    event = event.or(self.clap(burst.start));
+                }
+            }
+            Some(burst) => {
+                burst.update(now, level);
+                // a lasting sound, not a clap
+                if now - burst.start > self.max_clap_ms {
+                    self.follow_noise(level);
+                }
+            }
+        }
+
+        event.or_else(|| self.duck(now))
+    }
+
+    fn follow_noise(&mut self, level: u32) {
+        self.noise = self.noise - (self.noise >> NOISE_SHIFT) + level;
+    }
+
+    /// A clap started at `start` has ended.
+    fn clap(&mut self, start: TimerInstantU64<TIMER_HZ>) -> Option<Event> {
+        if let Some(event_time) = self.event_time {
+            if start - event_time < self.refractory_ms {
+                return None;
+            }
+        }
+        match self.first_clap {
+            Some(first) if start - first <= self.window_ms => {
+                self.first_clap = None;
+                self.event_time = Some(start);
+                Some(Event::DoubleClap)
+            }
+            _ => {
+                self.first_clap = Some(start);
+                None
+            }
+        }
+    }
+
+    fn duck(&mut self, now: TimerInstantU64<TIMER_HZ>) -> Option<Event> {
+        let past = if self.loud {
+            self.noise() < self.duck_level / 2
+        } else {
+            self.noise() > self.duck_level
+        };
+        if !past {
+            self.duck_since = None;
+            return None;
+        }
+        let since = *self.duck_since.get_or_insert(now);
+        let wait = if self.loud {
+            self.quiet_ms
+        } else {
+            self.loud_ms
+        };
+        if now - since < wait {
+            return None;
+        }
+        self.duck_since = None;
+        self.loud = !self.loud;
+        Some(if self.loud { Event::Loud } else { Event::Quiet })
+    }
+}
+
+/// A sound above the clap threshold.
+#[derive(Clone, Copy)]
+struct Burst<const TIMER_HZ: u32> {
+    start: TimerInstantU64<TIMER_HZ>,
+    peak: u32,
+    peak_time: TimerInstantU64<TIMER_HZ>,
+    /// Time from the peak to a quarter of it, once there.
+    decay: Option<TimerDurationU64<TIMER_HZ>>,
+}
+
+impl<const TIMER_HZ: u32> Burst<TIMER_HZ> {
+    fn new(start: TimerInstantU64<TIMER_HZ>, level: u32) -> Self {
+        Self {
+            start,
+            peak: level,
+            peak_time: start,
+            decay: None,
+        }
+    }
+
+    fn update(&mut self, now: TimerInstantU64<TIMER_HZ>, level: u32) {
+        if self.decay.is_some() {
+            return;
+        }
+        if level > self.peak {
+            self.peak = level;
+            self.peak_time = now;
+        } else if level <= self.peak / 4 {
+            self.decay = Some(now - self.peak_time);
+        }
+    }
+}
+
+impl<const TIMER_HZ: u32> Default for ClapDetector<TIMER_HZ> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+mod inner {
+    use bsp::{
+        hal::gpio::{
+            p0::{P0_05, P0_20},
+            Floating, Input, OpenDrain, Output,
+        },
+        pac::SAADC,
+    };
+    use embedded_hal::digital::v2::OutputPin;
+
+    use super::{level, Block, ClapDetector, Event, BLOCK_HZ, BLOCK_LEN, SAMPLE_HZ};
+
+    /// SAADC clock divided for the sample rate.
+    const SAMPLE_CC: u16 = (16_000_000 / SAMPLE_HZ) as u16;
+
+    /// Samples the microphone with the SAADC's own timer into two blocks in
+    /// turn, and feeds the level of each one filled to a clap detector.
+    pub struct Microphone {
+        saadc: SAADC,
+        _mic_in: P0_05<Input<Floating>>,
+        _mic_run: P0_20<Output<OpenDrain>>,
+        blocks: &'static mut [Block; 2],
+        /// The block being filled.
+        current: usize,
+        detector: ClapDetector<BLOCK_HZ>,
+        attach_event_fn: Option<fn(Event)>,
+    }
+
+    impl Microphone {
+        /// Powers the microphone and starts sampling.
+        pub fn new(
+            saadc: SAADC,
+            mic_in: P0_05<Input<Floating>>,
+            mut mic_run: P0_20<Output<OpenDrain>>,
+            blocks: &'static mut [Block; 2],
+            detector: ClapDetector<BLOCK_HZ>,
+        ) -> Self {
+            mic_run.set_high().ok();
+            saadc.enable.write(|w| w.enable().enabled());
+            saadc.resolution.write(|w| w.val()._12bit());
+            saadc.oversample.write(|w| w.oversample().bypass());
+            // the whole supply range, the microphone output sits around half
+            saadc.ch[0].config.write(|w| {
+                w.gain()
+                    .gain1_4()
+                    .refsel()
+                    .vdd1_4()
+                    .tacq()
+                    ._10us()
+                    .mode()
+                    .se()
+                    .resp()
+                    .bypass()
+                    .resn()
+                    .bypass()
+            });
+            saadc.ch[0].pselp.write(|w| w.pselp().analog_input3());
+            saadc.ch[0].pseln.write(|w| w.pseln().nc());
+            saadc
+                .samplerate
+                .write(|w| unsafe { w.mode().timers().cc().bits(SAMPLE_CC) });
+            saadc
+                .result
+                .maxcnt
+                .write(|w| unsafe { w.maxcnt().bits(BLOCK_LEN as u16) });
+            saadc
+                .result
+                .ptr
+                .write(|w| unsafe { w.ptr().bits(blocks[0].as_ptr() as u32) });
+            saadc.intenset.write(|w| w.started().set().end().set());
+            saadc.tasks_start.write(|w| unsafe { w.bits(1) });
+            saadc.tasks_sample.write(|w| unsafe { w.bits(1) });
+            Self {
+                saadc,
+                _mic_in: mic_in,
+                _mic_run: mic_run,
+                blocks,
+                current: 0,
+                detector,
+                attach_event_fn: None,
+            }
+        }
+
+        pub fn attach_event(&mut self, f: fn(Event)) {
+            self.attach_event_fn = Some(f);
+        }
+
+        pub fn detector_mut(&mut self) -> &mut ClapDetector<BLOCK_HZ> {
+            &mut self.detector
+        }
+
+        /// Handles the SAADC interrupt: once a block has started, the next
+        /// one is queued; once it is full, sampling goes on in the next one
+        /// while this one is looked at.
+        pub fn handle_event(&mut self) {
+            if self.saadc.events_started.read().bits() != 0 {
+                self.saadc.events_started.reset();
+                let next = self.blocks[1 - self.current].as_ptr() as u32;
+                self.saadc
+                    .result
+                    .ptr
+                    .write(|w| unsafe { w.ptr().bits(next) });
+            }
+            if self.saadc.events_end.read().bits() != 0 {
+                self.saadc.events_end.reset();
+                self.saadc.tasks_start.write(|w| unsafe { w.bits(1) });
+                let full = self.current;
+                self.current = 1 - full;
+                let event = self.detector.update(level(&self.blocks[full]));
+                if let (Some(event), Some(f)) = (event, self.attach_event_fn) {
+                    f(event);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,420 @@
+//! Line-based command protocol spoken over the USB serial port.
+//!
+//! Each command is a line of ASCII words separated by spaces and ended by
+//! `\n`, a `\r` before it being ignored. The device answers every command,
+//! last with `ok` or `error <reason>`:
+//!
+//! ```text
+//! play | pause | next | prev     ok
+//! volume <0-100>                 ok
+//! list                           <index> <builtin|stored> <n> <duration_ms>
+//!                                per melody of the playlist, then ok
+//! status                         status <playing|paused|stopped> <index>
+//!                                <position_ms> <duration_ms> <volume> <mode>,
+//!                                then ok
+//! upload <hex>                   stored <id>, then ok, storing a melody
+//!                                record (see `Melody::write_record`) sent
+//!                                in hex, and adding it to the playlist
+//! remove <id>                    ok, removing an uploaded melody
+//! ```
+//!
+//! A byte with the top bit set, which no command has, switches the port to
+//! MIDI input (see `midi::Parser`) until a system reset (`0xff`).
+//!
+//! Parsing is `const` and free of hardware, so the examples at the end of
+//! this file are checked at compile time. Running commands is generic over
+//! the player's clock and output and the store's flash, so that a simulated
+//! device on the host runs the same code.
+use core::fmt::{self, Write};
+
+use defmt::Format;
+use heapless::{String, Vec};
+
+use crate::{
+    melody::{Melody, MELODY_LIST},
+    player::{PlayMode, Player, PlayerClock, Playlist, ToneOutput},
+    screen::Screen,
+    store::{self, Entry, Flash, Store},
+};
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command<'a> {
+    Play,
+    Pause,
+    Next,
+    Prev,
+    /// Volume in percent.
+    Volume(u32),
+    List,
+    Status,
+    /// A melody record, still in hex, see `decode_hex`.
+    Upload(&'a [u8]),
+    /// Removes the uploaded melody with this id.
+    Remove(u8),
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    Empty,
+    UnknownCommand,
+    MissingArgument,
+    ExtraArgument,
+    BadNumber,
+    BadHex,
+    /// The line or the record does not fit in its buffer.
+    TooLong,
+}
+
+impl Error {
+    /// The reason given in an `error` reply.
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Empty => "empty",
+            Self::UnknownCommand => "unknown-command",
+            Self::MissingArgument => "missing-argument",
+            Self::ExtraArgument => "extra-argument",
+            Self::BadNumber => "bad-number",
+            Self::BadHex => "bad-hex",
+            Self::TooLong => "too-long",
+        }
+    }
+}
+
+/// Longest melody record that can be uploaded.
+pub const MAX_RECORD: usize = 1024;
+
+/// Longest command line: an upload of the longest record, in hex.
+pub const MAX_LINE: usize = 16 + 2 * MAX_RECORD;
+
+/// Longest reply line made while the player is locked.
+const MAX_REPLY: usize = 64;
+
+// `?` is not available in const fn
+macro_rules! tri {
+    ($e:expr) => {
+        match $e {
+            Ok(v) => v,
+            Err(e) => return Err(e),
+        }
+    };
+}
+
+/// Parses one line, without its line ending.
+pub const fn parse(line: &[u8]) -> Result<Command<'_>, Error> {
+    let (name, rest) = word(line);
+    if name.is_empty() {
+        return Err(Error::Empty);
+    }
+    let (arg, rest) = word(rest);
+    if !word(rest).0.is_empty() {
+        return Err(Error::ExtraArgument);
+    }
+
+    let command = if eq(name, b"play") {
+        Command::Play
+    } else if eq(name, b"pause") {
+        Command::Pause
+    } else if eq(name, b"next") {
+        Command::Next
+    } else if eq(name, b"prev") {
+        Command::Prev
+    } else if eq(name, b"list") {
+        Command::List
+    } else if eq(name, b"status") {
+        Command::Status
+    } else if eq(name, b"volume") {
+        let volume = tri!(number(arg));
+        if volume > 100 {
+            return Err(Error::BadNumber);
+        }
+        return Ok(Command::Volume(volume));
+    } else if eq(name, b"remove") {
+        let id = tri!(number(arg));
+        if id > u8::MAX as u32 {
+            return Err(Error::BadNumber);
+        }
+        return Ok(Command::Remove(id as u8));
+    } else if eq(name, b"upload") {
+        if arg.is_empty() {
+            return Err(Error::MissingArgument);
+        }
+        return Ok(Command::Upload(arg));
+    } else {
+        return Err(Error::UnknownCommand);
+    };
+    if !arg.is_empty() {
+        return Err(Error::ExtraArgument);
+    }
+    Ok(command)
+}
+
+/// Decodes the hex digits of `hex` into `out`, returning the number of bytes.
+pub const fn decode_hex(hex: &[u8], out: &mut [u8]) -> Result<usize, Error> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(Error::BadHex);
+    }
+    if hex.len() / 2 > out.len() {
+        return Err(Error::TooLong);
+    }
+    let mut i = 0;
+    while i < hex.len() / 2 {
+        let high = tri!(digit(hex[2 * i]));
+        let low = tri!(digit(hex[2 * i + 1]));
+        out[i] = (high << 4) | low;
+        i += 1;
+    }
+    Ok(i)
+}
+
+/// Name of a play mode in `status` replies.
+pub const fn mode_name(mode: PlayMode) -> &'static str {
+    match mode {
+        PlayMode::RepeatOne => "repeat-one",
+        PlayMode::RepeatAll => "repeat-all",
+        PlayMode::Once => "once",
+        PlayMode::Shuffle => "shuffle",
+    }
+}
+
+/// Runs a command against the player and the store, writing any reply lines
+/// but the last to `out`. Fails with the reason for an `error` reply.
+/// `record` holds an upload while it is decoded.
+///
+/// The player and the screen are only reached through `lock`, which calls
+/// its argument with them: replies are written to `out` once it has
+/// returned, and the store is read and written outside of it, so that the
+/// player is not held up by a slow port or by the flash.
+pub fn run<'a, C: PlayerClock, B: ToneOutput, F: Flash, const TIMER_HZ: u32>(
+    command: Command,
+    mut lock: impl FnMut(&mut dyn FnMut(&mut Player<C, B, &'a mut Playlist>, &mut Screen<TIMER_HZ>)),
+    store: &mut Store<F>,
+    record: &mut [u8],
+    out: &mut impl fmt::Write,
+) -> Result<(), &'static str> {
+    use Command::*;
+
+    match command {
+        Play => lock(&mut |ply, screen| {
+            ply.play();
+            screen.set_paused(ply.is_paused());
+        }),
+        Pause => lock(&mut |ply, screen| {
+            ply.pause();
+            screen.set_paused(ply.is_paused());
+        }),
+        Next => lock(&mut |ply, screen| {
+            ply.next();
+            screen.set_paused(ply.is_paused());
+            if let Some(melody) = ply.current() {
+                screen.show_icon(melody.icon());
+            }
+        }),
+        Prev => lock(&mut |ply, screen| {
+            ply.prev();
+            screen.set_paused(ply.is_paused());
+            if let Some(melody) = ply.current() {
+                screen.show_icon(melody.icon());
+            }
+        }),
+        Volume(volume) => lock(&mut |ply, screen| {
+            ply.set_volume(volume);
+            screen.show_volume(ply.volume());
+        }),
+        List => {
+            for (index, (entry, melody)) in store.entries(MELODY_LIST).enumerate() {
+                let (origin, n) = match entry {
+                    Entry::BuiltIn(n) => ("builtin", n),
+                    Entry::Stored(id) => ("stored", id),
+                };
+                writeln!(out, "{} {} {} {}", index, origin, n, melody.duration_ms()).ok();
+            }
+        }
+        Status => {
+            let mut reply = String::<MAX_REPLY>::new();
+            lock(&mut |ply, _| {
+                let state = if ply.is_playing() {
+                    "playing"
+                } else if ply.is_paused() {
+                    "paused"
+                } else {
+                    "stopped"
+                };
+                writeln!(
+                    reply,
+                    "status {} {} {} {} {} {}",
+                    state,
+                    ply.index().unwrap_or(0),
+                    ply.position(),
+                    ply.duration(),
+                    ply.volume(),
+                    mode_name(ply.mode()),
+                )
+                .ok();
+            });
+            out.write_str(&reply).ok();
+        }
+        Upload(hex) => {
+            let len = decode_hex(hex, record).map_err(Error::as_str)?;
+            let record = &record[..len];
+            if !Melody::is_record(record) {
+                return Err("bad-record");
+            }
+            let id = store.add_melody(record).map_err(store::Error::as_str)?;
+            if let Some(playlist) = store.playlist() {
+                // a playlist holds at most 256 entries
+                let mut entries: Vec<Entry, 256> = playlist.take(255).collect();
+                entries.push(Entry::Stored(id)).ok();
+                store.set_playlist(&entries).map_err(store::Error::as_str)?;
+            }
+            lock(&mut |ply, _| reload(ply, store));
+            writeln!(out, "stored {}", id).ok();
+        }
+        Remove(id) => {
+            store.remove_melody(id).map_err(store::Error::as_str)?;
+            lock(&mut |ply, _| reload(ply, store));
+        }
+    }
+    Ok(())
+}
+
+/// Reloads the playlist after the store changed, playing on from its
+/// start if a melody was playing.
+fn reload<C: PlayerClock, B: ToneOutput, F: Flash>(
+    ply: &mut Player<C, B, &mut Playlist>,
+    store: &Store<F>,
+) {
+    let playing = ply.is_playing();
+    ply.update_list(|list| store.load_list(MELODY_LIST, list));
+    if playing && !ply.list().is_empty() {
+        ply.play();
+    }
+}
+
+/// Collects received bytes into lines.
+pub struct LineBuffer<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    /// Whether the current line has run past the buffer.
+    overflow: bool,
+}
+
+impl<const N: usize> LineBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Adds a byte, returning the line it completes, if any.
+    pub fn push(&mut self, byte: u8) -> Option<Result<&[u8], Error>> {
+        match byte {
+            b'\n' => {
+                let len = core::mem::take(&mut self.len);
+                if core::mem::take(&mut self.overflow) {
+                    return Some(Err(Error::TooLong));
+                }
+                let line = &self.buf[..len];
+                Some(Ok(line.strip_suffix(b"\r").unwrap_or(line)))
+            }
+            _ if self.len < N => {
+                self.buf[self.len] = byte;
+                self.len += 1;
+                None
+            }
+            _ => {
+                self.overflow = true;
+                None
+            }
+        }
+    }
+}
+
+impl<const N: usize> Default for LineBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Splits off the first word of `s`, skipping spaces before it.
+const fn word(s: &[u8]) -> (&[u8], &[u8]) {
+    let mut start = 0;
+    while start < s.len() && s[start] == b' ' {
+        start += 1;
+    }
+    let mut end = start;
+    while end < s.len() && s[end] != b' ' {
+        end += 1;
+    }
+    let (_, s) = s.split_at(start);
+    s.split_at(end - start)
+}
+
+const fn number(s: &[u8]) -> Result<u32, Error> {
+    if s.is_empty() {
+        return Err(Error::MissingArgument);
+    }
+    let mut value: u32 = 0;
+    let mut i = 0;
+    while i < s.len() {
+        if !s[i].is_ascii_digit() {
+            return Err(Error::BadNumber);
+        }
+        value = match value.checked_mul(10) {
+            Some(v) => match v.checked_add((s[i] - b'0') as u32) {
+                Some(v) => v,
+                None => return Err(Error::BadNumber),
+            },
+            None => return Err(Error::BadNumber),
+        };
+        i += 1;
+    }
+    Ok(value)
+}
+
+const fn digit(c: u8) -> Result<u8, Error> {
+    match c {
+        b'0'..=b'9' => Ok(c - b'0'),
+        b'a'..=b'f' => Ok(c - b'a' + 10),
+        b'A'..=b'F' => Ok(c - b'A' + 10),
+        _ => Err(Error::BadHex),
+    }
+}
+
+const fn eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+const _: () = {
+    assert!(matches!(parse(b"play"), Ok(Command::Play)));
+    assert!(matches!(parse(b"  next  "), Ok(Command::Next)));
+    assert!(matches!(parse(b"volume 35"), Ok(Command::Volume(35))));
+    assert!(matches!(parse(b"volume 101"), Err(Error::BadNumber)));
+    assert!(matches!(parse(b"volume x"), Err(Error::BadNumber)));
+    assert!(matches!(parse(b"volume"), Err(Error::MissingArgument)));
+    assert!(matches!(parse(b"volume 1 2"), Err(Error::ExtraArgument)));
+    assert!(matches!(parse(b"pause now"), Err(Error::ExtraArgument)));
+    assert!(matches!(parse(b"remove 7"), Ok(Command::Remove(7))));
+    assert!(matches!(parse(b"remove 256"), Err(Error::BadNumber)));
+    assert!(matches!(parse(b"upload 0aFF"), Ok(Command::Upload(b"0aFF"))));
+    assert!(matches!(parse(b"dance"), Err(Error::UnknownCommand)));
+    assert!(matches!(parse(b""), Err(Error::Empty)));
+
+    let mut out = [0; 2];
+    assert!(matches!(decode_hex(b"0aFF", &mut out), Ok(2)));
+    assert!(out[0] == 0x0a && out[1] == 0xff);
+    assert!(matches!(decode_hex(b"0g", &mut out), Err(Error::BadHex)));
+    assert!(matches!(decode_hex(b"abc", &mut out), Err(Error::BadHex)));
+    assert!(matches!(decode_hex(b"000000", &mut out), Err(Error::TooLong)));
+};
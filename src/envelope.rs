@@ -0,0 +1,60 @@
+//! Attack/decay/sustain/release amplitude envelopes, applied by the
+//! synthesizer.
+use defmt::Format;
+
+/// Shape of a note's amplitude over time.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Envelope {
+    /// Time to rise from silence to full volume.
+    pub attack_ms: u16,
+    /// Time to fall from full volume to the sustain level.
+    pub decay_ms: u16,
+    /// Level held until the note is released, in percent.
+    pub sustain: u8,
+    /// Time to fade out once the note is released.
+    pub release_ms: u16,
+}
+
+impl Envelope {
+    /// Soft edges around a steady tone, used unless a melody asks otherwise.
+    pub const DEFAULT: Self = Self::new(5, 60, 70, 40);
+    /// Plain on/off, like the bare buzzer.
+    pub const GATE: Self = Self::new(0, 0, 100, 0);
+    pub const PIANO: Self = Self::new(2, 400, 30, 150);
+    pub const PLUCK: Self = Self::new(1, 150, 0, 30);
+    pub const ORGAN: Self = Self::new(10, 0, 100, 60);
+    pub const PAD: Self = Self::new(150, 200, 80, 300);
+
+    pub const fn new(attack_ms: u16, decay_ms: u16, sustain: u8, release_ms: u16) -> Self {
+        Self {
+            attack_ms,
+            decay_ms,
+            sustain,
+            release_ms,
+        }
+    }
+
+    /// Seven bytes for storing the envelope, see `from_bytes`.
+    pub const fn to_bytes(self) -> [u8; 7] {
+        let [a0, a1] = self.attack_ms.to_le_bytes();
+        let [d0, d1] = self.decay_ms.to_le_bytes();
+        let [r0, r1] = self.release_ms.to_le_bytes();
+        [a0, a1, d0, d1, self.sustain, r0, r1]
+    }
+
+    pub const fn from_bytes(bytes: [u8; 7]) -> Self {
+        let [a0, a1, d0, d1, sustain, r0, r1] = bytes;
+        Self::new(
+            u16::from_le_bytes([a0, a1]),
+            u16::from_le_bytes([d0, d1]),
+            sustain,
+            u16::from_le_bytes([r0, r1]),
+        )
+    }
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
@@ -0,0 +1,231 @@
+use defmt::Format;
+use fugit::{ExtU64, TimerDurationU64, TimerInstantU64};
+
+pub use self::inner::Lsm303agr;
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Shake,
+}
+
+/// One accelerometer reading, in milli-g per axis.
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Sample {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+impl Sample {
+    pub const fn new(x: i32, y: i32, z: i32) -> Self {
+        Self { x, y, z }
+    }
+
+    /// Squared magnitude of the difference between two samples.
+    pub fn delta_sq(&self, other: &Sample) -> u32 {
+        let dx = self.x.abs_diff(other.x);
+        let dy = self.y.abs_diff(other.y);
+        let dz = self.z.abs_diff(other.z);
+        (dx * dx).saturating_add(dy * dy).saturating_add(dz * dz)
+    }
+}
+
+/// Source of acceleration samples.
+pub trait Accelerometer {
+    /// Returns the latest sample, or `None` if the sensor could not be read.
+    fn sample(&mut self) -> Option<Sample>;
+}
+
+/// Turns a stream of samples into gesture events.
+///
+/// `update` is called once per sample period, so implementations can count
+/// time in samples. This keeps detectors free of hardware and lets them be
+/// driven from recorded acceleration traces.
+pub trait Detector {
+    fn update(&mut self, sample: Sample) -> Option<Event>;
+}
+
+/// Detects a shake as several sharp changes of acceleration within a short
+/// window, then ignores further movement for a refractory period.
+pub struct ShakeDetector<const TIMER_HZ: u32> {
+    last_sample: Option<Sample>,
+    cnt_peak: u32,
+    time: TimerInstantU64<TIMER_HZ>,
+    start_time: TimerInstantU64<TIMER_HZ>,
+    event_time: Option<TimerInstantU64<TIMER_HZ>>,
+    threshold_mg: u32,
+    peaks: u32,
+    window_ms: TimerDurationU64<TIMER_HZ>,
+    refractory_ms: TimerDurationU64<TIMER_HZ>,
+}
+
+impl<const TIMER_HZ: u32> ShakeDetector<TIMER_HZ> {
+    pub fn new() -> Self {
+        Self {
+            last_sample: None,
+            cnt_peak: 0,
+            time: TimerInstantU64::from_ticks(0),
+            start_time: TimerInstantU64::from_ticks(0),
+            event_time: None,
+            threshold_mg: 900,
+            peaks: 3,
+            window_ms: 600.millis(),
+            refractory_ms: 1000.millis(),
+        }
+    }
+
+    /// Minimum change of acceleration between two samples counted as a peak.
+    pub fn set_threshold_mg(&mut self, threshold_mg: u32) {
+        self.threshold_mg = threshold_mg;
+    }
+
+    /// Number of peaks within the window needed to report a shake.
+    pub fn set_peaks(&mut self, peaks: u32) {
+        self.peaks = peaks.max(1);
+    }
+
+    pub fn set_window_ms(&mut self, window_ms: TimerDurationU64<TIMER_HZ>) {
+        self.window_ms = window_ms;
+    }
+
+    /// Time after a shake during which no new shake is reported.
+    pub fn set_refractory_ms(&mut self, refractory_ms: TimerDurationU64<TIMER_HZ>) {
+        self.refractory_ms = refractory_ms;
+    }
+}
+
+impl<const TIMER_HZ: u32> Default for ShakeDetector<TIMER_HZ> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const TIMER_HZ: u32> Detector for ShakeDetector<TIMER_HZ> {
+    fn update(&mut self, sample: Sample) -> Option<Event> {
+        self.time += TimerDurationU64::from_ticks(1);
+        let now = self.time;
+        let last_sample = self.last_sample.replace(sample)?;
+
+        if let Some(event_time) = self.event_time {
+            if now - event_time < self.refractory_ms {
+                return None;
+            }
+            self.event_time = None;
+        }
+
+        if self.cnt_peak > 0 && now - self.start_time > self.window_ms {
+            self.cnt_peak = 0;
+        }
+
+        let threshold = self.threshold_mg.saturating_mul(self.threshold_mg);
+        if sample.delta_sq(&last_sample) < threshold {
+            return None;
+        }
+
+        if self.cnt_peak == 0 {
+            self.start_time = now;
+        }
+        self.cnt_peak += 1;
+
+        if self.cnt_peak >= self.peaks {
+            self.cnt_peak = 0;
+            self.event_time = Some(now);
+            Some(Event::Shake)
+        } else {
+            None
+        }
+    }
+}
+
+/// Polls an accelerometer and feeds a detector, like `button::Button` does
+/// for a pin.
+pub struct Gesture<A, D> {
+    accel: A,
+    detector: D,
+    attach_event_fn: Option<fn(Event)>,
+}
+
+impl<A: Accelerometer, D: Detector> Gesture<A, D> {
+    pub fn new(accel: A, detector: D) -> Self {
+        Self {
+            accel,
+            detector,
+            attach_event_fn: None,
+        }
+    }
+
+    pub fn attach_event(&mut self, f: fn(Event)) {
+        self.attach_event_fn = Some(f);
+    }
+
+    pub fn detector_mut(&mut self) -> &mut D {
+        &mut self.detector
+    }
+
+    pub fn free(self) -> (A, D) {
+        (self.accel, self.detector)
+    }
+
+    pub fn tick(&mut self) {
+        if let Some(event) = self.accel.sample().and_then(|s| self.detector.update(s)) {
+            if let Some(f) = self.attach_event_fn {
+                f(event);
+            }
+        }
+    }
+}
+
+mod inner {
+    use bsp::hal::twim;
+
+    use super::{Accelerometer, Sample};
+
+    const ACCEL_ADDR: u8 = 0x19;
+    const CTRL_REG1_A: u8 = 0x20;
+    const CTRL_REG4_A: u8 = 0x23;
+    const OUT_X_L_A: u8 = 0x28;
+    /// Sub-address flag for auto-incremented multi-byte reads.
+    const AUTO_INCREMENT: u8 = 0x80;
+
+    /// Register-level driver for the accelerometer half of the LSM303AGR on
+    /// the internal I2C bus.
+    pub struct Lsm303agr<T: twim::Instance>(twim::Twim<T>);
+
+    impl<T: twim::Instance> Lsm303agr<T> {
+        pub fn new(twim: T, pins: twim::Pins) -> Self {
+            let mut i2c = twim::Twim::new(twim, pins, twim::Frequency::K400);
+            // 100Hz, normal mode, X/Y/Z enabled
+            if i2c.write(ACCEL_ADDR, &[CTRL_REG1_A, 0x57]).is_err() {
+                defmt::warn!("lsm303agr: failed to configure CTRL_REG1_A");
+            }
+            // block data update, +/-2g, high resolution (1mg/digit)
+            if i2c.write(ACCEL_ADDR, &[CTRL_REG4_A, 0x88]).is_err() {
+                defmt::warn!("lsm303agr: failed to configure CTRL_REG4_A");
+            }
+            Self(i2c)
+        }
+
+        pub fn free(self) -> T {
+            self.0.free()
+        }
+    }
+
+    impl<T: twim::Instance> Accelerometer for Lsm303agr<T> {
+        fn sample(&mut self) -> Option<Sample> {
+            let mut buf = [0u8; 6];
+            self.0
+                .write_then_read(ACCEL_ADDR, &[OUT_X_L_A | AUTO_INCREMENT], &mut buf)
+                .ok()?;
+            // 12-bit left-justified values
+            let axis = |lo: u8, hi: u8| (i16::from_le_bytes([lo, hi]) >> 4) as i32;
+            let sample = Sample::new(
+                axis(buf[0], buf[1]),
+                axis(buf[2], buf[3]),
+                axis(buf[4], buf[5]),
+            );
+            // in the form of the traces `examples/gesture.rs` reads
+            defmt::trace!("{},{},{}", sample.x, sample.y, sample.z);
+            Some(sample)
+        }
+    }
+}
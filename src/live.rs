@@ -0,0 +1,239 @@
+//! Live play: the music box as a monophonic synth for MIDI input, on all
+//! channels. The latest key held sounds (last-note priority); releasing it
+//! goes back to the one held before, without re-attacking it.
+//!
+//! `Live` only decides what the voice does, the application carries its
+//! `Action`s out on the player. It is `const`, so the examples at the end of
+//! this file are checked at compile time.
+use defmt::Format;
+
+use crate::{midi::Message, tone::Pitch};
+
+/// Keys remembered while held, to go back to; the earliest are forgotten.
+const MAX_HELD: usize = 16;
+
+/// Pitch bend range either way, two semitones as in General MIDI.
+const BEND_RANGE_CENTS: i32 = 200;
+
+// Controllers
+const VOLUME: u8 = 7;
+const ALL_SOUND_OFF: u8 = 120;
+const ALL_NOTES_OFF: u8 = 123;
+
+#[derive(Format, Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// Starts a note at `volume` percent of the player's volume.
+    Start { pitch: Pitch, volume: u32 },
+    /// Moves the sounding note to `pitch` without re-attacking it.
+    Move(Pitch),
+    /// Ends the note.
+    Release,
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Live {
+    /// Keys held down, the latest last.
+    held: [u8; MAX_HELD],
+    len: usize,
+    bend: i16,
+    /// Channel volume (CC7), from 0 to 127.
+    volume: u8,
+}
+
+impl Live {
+    /// Nothing held, no bend, full volume.
+    pub const fn new() -> Self {
+        Self {
+            held: [0; MAX_HELD],
+            len: 0,
+            bend: 0,
+            volume: 127,
+        }
+    }
+
+    /// What to do for `message`, if anything. The channel volume applies
+    /// from the next note on.
+    pub const fn handle(&mut self, message: Message) -> Option<Action> {
+        match message {
+            Message::NoteOn { key, velocity, .. } => {
+                self.remove(key);
+                if self.len == MAX_HELD {
+                    self.remove(self.held[0]);
+                }
+                self.held[self.len] = key;
+                self.len += 1;
+                let volume = velocity as u32 * self.volume as u32 * 100 / (127 * 127);
+                Some(Action::Start {
+                    pitch: self.pitch(key),
+                    volume,
+                })
+            }
+            Message::NoteOff { key, .. } => {
+                let Some(latest) = self.latest() else {
+                    return None;
+                };
+                self.remove(key);
+                if key != latest {
+                    return None;
+                }
+                match self.latest() {
+                    Some(key) => Some(Action::Move(self.pitch(key))),
+                    None => Some(Action::Release),
+                }
+            }
+            Message::PitchBend { bend, .. } => {
+                self.bend = bend;
+                match self.latest() {
+                    Some(key) => Some(Action::Move(self.pitch(key))),
+                    None => None,
+                }
+            }
+            Message::ControlChange {
+                controller: VOLUME,
+                value,
+                ..
+            } => {
+                self.volume = value;
+                None
+            }
+            Message::ControlChange {
+                controller: ALL_SOUND_OFF | ALL_NOTES_OFF,
+                ..
+            } => {
+                let sounding = self.len > 0;
+                self.len = 0;
+                if sounding {
+                    Some(Action::Release)
+                } else {
+                    None
+                }
+            }
+            Message::ControlChange { .. } => None,
+        }
+    }
+
+    /// The key sounding, if any.
+    pub const fn latest(&self) -> Option<u8> {
+        match self.len {
+            0 => None,
+            len => Some(self.held[len - 1]),
+        }
+    }
+
+    /// `key` bent by the current pitch bend.
+    const fn pitch(&self, key: u8) -> Pitch {
+        Pitch::Key(key, (self.bend as i32 * BEND_RANGE_CENTS / 8192) as i16)
+    }
+
+    /// Forgets `key`, if it is held.
+    const fn remove(&mut self, key: u8) {
+        let mut i = 0;
+        while i < self.len {
+            if self.held[i] == key {
+                self.len -= 1;
+                while i < self.len {
+                    self.held[i] = self.held[i + 1];
+                    i += 1;
+                }
+                return;
+            }
+            i += 1;
+        }
+    }
+}
+
+impl Default for Live {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const fn note_on(key: u8, velocity: u8) -> Message {
+    Message::NoteOn {
+        channel: 0,
+        key,
+        velocity,
+    }
+}
+
+const fn note_off(key: u8) -> Message {
+    Message::NoteOff { channel: 0, key }
+}
+
+const _: () = {
+    let mut live = Live::new();
+    assert!(matches!(
+        live.handle(note_on(60, 127)),
+        Some(Action::Start {
+            pitch: Pitch::Key(60, 0),
+            volume: 100
+        })
+    ));
+    assert!(matches!(
+        live.handle(note_on(64, 127)),
+        Some(Action::Start {
+            pitch: Pitch::Key(64, 0),
+            ..
+        })
+    ));
+    assert!(matches!(
+        live.handle(note_on(67, 127)),
+        Some(Action::Start { .. })
+    ));
+    // releasing a key held before the latest changes nothing, releasing the
+    // latest goes back to the one before
+    assert!(live.handle(note_off(64)).is_none());
+    assert!(matches!(
+        live.handle(note_off(67)),
+        Some(Action::Move(Pitch::Key(60, 0)))
+    ));
+    assert!(matches!(
+        live.handle(Message::PitchBend {
+            channel: 0,
+            bend: 4096
+        }),
+        Some(Action::Move(Pitch::Key(60, 100)))
+    ));
+    assert!(matches!(live.handle(note_off(60)), Some(Action::Release)));
+    assert!(live.handle(note_off(60)).is_none());
+    assert!(live
+        .handle(Message::PitchBend {
+            channel: 0,
+            bend: -8192
+        })
+        .is_none());
+
+    assert!(live
+        .handle(Message::ControlChange {
+            channel: 0,
+            controller: VOLUME,
+            value: 64
+        })
+        .is_none());
+    assert!(matches!(
+        live.handle(note_on(72, 127)),
+        Some(Action::Start {
+            pitch: Pitch::Key(72, -200),
+            volume: 50
+        })
+    ));
+    assert!(matches!(
+        live.handle(Message::ControlChange {
+            channel: 0,
+            controller: ALL_NOTES_OFF,
+            value: 0
+        }),
+        Some(Action::Release)
+    ));
+    assert!(live.latest().is_none());
+
+    // the earliest of too many keys held is forgotten
+    let mut key = 0;
+    while key <= MAX_HELD as u8 {
+        live.handle(note_on(key, 100));
+        key += 1;
+    }
+    assert!(live.len == MAX_HELD);
+    assert!(live.handle(note_off(0)).is_none());
+    assert!(matches!(live.latest(), Some(16)));
+};
@@ -6,51 +6,100 @@ extern crate microbit as bsp; // board support package
 use defmt_rtt as _; // global logger
 use panic_probe as _; // panic handler
 
+mod ble;
 mod button;
+mod clap;
+mod command;
+mod envelope;
+mod gesture;
+mod live;
 mod melody;
+mod midi;
 mod mono;
+mod pack;
 mod player;
+mod radio;
+mod rtttl;
+mod screen;
+mod serial;
+mod shuffle;
+mod store;
+#[cfg(feature = "synth")]
+mod synth;
 mod tone;
+mod touch;
+mod tuning;
+mod waveform;
 
 #[rtic::app(device = bsp::pac, peripherals = true, dispatchers = [SWI0_EGU0])]
 mod app {
     use super::*;
 
+    use core::fmt::Write;
+
     use bsp::hal::clocks::Clocks;
     use bsp::hal::gpio::{Input, Pin, PullUp};
+    use bsp::hal::rng::Rng;
     use bsp::hal::rtc::{Rtc, RtcInterrupt};
-    use bsp::pac::{PWM1, RTC0, TIMER1, TIMER2};
+    use bsp::hal::uarte::{self, Baudrate};
+    use bsp::pac::{PWM1, RTC0, TIMER1, TIMER2, TIMER4, TWIM0, UARTE0, UARTE1};
     use bsp::Board;
 
     type Button = button::Button<Pin<Input<PullUp>>, 100>;
-    type Player = player::Player<'static, TIMER1, PWM1>;
+    type Logo = button::Button<touch::Touch, 100>;
+    #[cfg(feature = "synth")]
+    type Buzzer = synth::SynthBuzzer<PWM1, SYNTH_BUFFER_LEN>;
+    #[cfg(not(feature = "synth"))]
+    type Buzzer = player::PlayerBuzzer<PWM1>;
+    type Player = player::Player<player::PlayerTimer<TIMER1>, Buzzer, &'static mut player::Playlist>;
     type Display = bsp::display::nonblocking::Display<TIMER2>;
+    type Screen = screen::Screen<100>;
+    type Gesture = gesture::Gesture<gesture::Lsm303agr<TWIM0>, gesture::ShakeDetector<100>>;
+    type Serial = serial::Serial<UARTE0>;
+    type MidiIn = serial::Serial<UARTE1>;
+    type Store = store::Store<store::Nvmc>;
+    type Radio = crate::radio::Radio<TIMER4>;
+
+    /// Long-press events (one per 10 ms tick) between two seeks while
+    /// scrubbing.
+    const SCRUB_EVENTS: u32 = 10;
+
+    /// Samples per PWM sequence buffer; two of them are played alternately.
+    const SYNTH_BUFFER_LEN: usize = 128;
 
     #[monotonic(binds = TIMER0, default = true)]
     type Mono = mono::MonoTimer<bsp::pac::TIMER0>;
 
-    const MELODY_LIST: &[melody::Melody] = &[
-        melody::SUPER_MARIOBROS,
-        melody::GAME_OF_THRONES,
-        melody::MERRY_CHRISTMAS,
-        melody::HAPPY_BIRTHDAY,
-        melody::TETRIS,
-    ];
-
     #[shared]
     struct Shared {
         display: Display,
+        screen: Screen,
         player: Player,
         btn1: Button,
         btn2: Button,
+        radio: Radio,
     }
 
     #[local]
     struct Local {
         rtc0: Rtc<RTC0>,
+        logo: Logo,
+        gesture: Gesture,
+        serial: Serial,
+        midi_in: MidiIn,
+        mic: clap::Microphone,
+        store: Store,
     }
 
-    #[init]
+    #[init(local = [
+        synth_bufs: [[u16; SYNTH_BUFFER_LEN]; 2] = [[0; SYNTH_BUFFER_LEN]; 2],
+        list: player::Playlist = heapless::Vec::new(),
+        serial_tx: [u8; 64] = [0; 64],
+        serial_rx: [u8; 1] = [0; 1],
+        midi_tx: [u8; 1] = [0; 1],
+        midi_rx: [u8; 1] = [0; 1],
+        mic_blocks: [clap::Block; 2] = [[0; clap::BLOCK_LEN]; 2],
+    ])]
     fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
         defmt::info!("init musicbox");
 
@@ -58,7 +107,10 @@ mod app {
         let mono = mono::MonoTimer::new(board.TIMER0);
 
         // Starting the low-frequency clock (needed for RTC to work)
-        Clocks::new(board.CLOCK).start_lfclk();
+        let clocks = Clocks::new(board.CLOCK);
+        // the radio needs the crystal oscillator
+        let clocks = clocks.enable_ext_hfosc();
+        clocks.start_lfclk();
 
         // RTC at 100Hz (32_768 / (327 + 1))
         // 100Hz; 10ms period
@@ -73,13 +125,33 @@ mod app {
             Display::new(board.TIMER2, pins)
         };
 
+        // Playlist, from the flash store if anything was uploaded
+        // SAFETY: nothing else uses the NVMC
+        let store = Store::open(unsafe { store::Nvmc::new() });
+        store.load_list(melody::MELODY_LIST, ctx.local.list);
+        let list = ctx.local.list;
+
+        let mut rng = Rng::new(board.RNG);
+
         // Player
         let player = {
             let pin = board
                 .speaker_pin
                 .into_push_pull_output(bsp::hal::gpio::Level::High)
                 .degrade();
-            let mut ply = Player::new(board.TIMER1, board.PWM1, pin, MELODY_LIST);
+            let timer = player::PlayerTimer::new(board.TIMER1);
+            #[cfg(feature = "synth")]
+            let buzzer = {
+                let [buf0, buf1] = ctx.local.synth_bufs;
+                Buzzer::new(board.PWM1, pin, [buf0, buf1])
+            };
+            #[cfg(not(feature = "synth"))]
+            let buzzer = Buzzer::new(board.PWM1, pin);
+            let mut ply = Player::new(timer, buzzer, list);
+            ply.seed_shuffle(rng.random_u32());
+            ply.attach_event(|event| {
+                handle_player_event::spawn(event).ok();
+            });
             ply.play();
             ply
         };
@@ -104,23 +176,205 @@ mod app {
             btn
         };
 
+        // Touch logo, read as a third button
+        let logo = {
+            let mut logo = Logo::new(touch::Touch::new(board.pins.p1_04.into_floating_input()));
+            logo.attach_event(|event| {
+                handle_logo_event::spawn(event).ok();
+            });
+            logo
+        };
+
+        // Accelerometer
+        let gesture = {
+            let accel = gesture::Lsm303agr::new(board.TWIM0, board.i2c_internal.into());
+            let mut gesture = Gesture::new(accel, gesture::ShakeDetector::new());
+            gesture.attach_event(|event| {
+                handle_gesture_event::spawn(event).ok();
+            });
+            gesture
+        };
+
+        // Microphone
+        let mic = {
+            let pins = board.microphone_pins;
+            let mut mic = clap::Microphone::new(
+                board.SAADC,
+                pins.mic_in,
+                pins.mic_run,
+                ctx.local.mic_blocks,
+                clap::ClapDetector::new(),
+            );
+            mic.attach_event(|event| {
+                handle_clap_event::spawn(event).ok();
+            });
+            mic
+        };
+
+        // Serial port, for commands from the host
+        let serial = Serial::new(
+            board.UARTE0,
+            board.uart.into(),
+            Baudrate::BAUD115200,
+            ctx.local.serial_tx,
+            ctx.local.serial_rx,
+        );
+
+        // MIDI in, at the standard 31250 baud, on ring 1 of the edge connector;
+        // ring 2 is only there because the UARTE needs a pin to send on
+        let midi_in = {
+            let rxd = board.pins.p0_03.into_floating_input();
+            // SAFETY: only pulls up the pin owned by `rxd`, so that nothing is
+            // received without a MIDI in circuit
+            unsafe { &*bsp::pac::P0::ptr() }.pin_cnf[3].modify(|_, w| w.pull().pullup());
+            let pins = uarte::Pins {
+                rxd: rxd.degrade(),
+                txd: board.pins.p0_04.into_push_pull_output(bsp::hal::gpio::Level::High).degrade(),
+                cts: None,
+                rts: None,
+            };
+            MidiIn::new(
+                board.UARTE1,
+                pins,
+                Baudrate::BAUD31250,
+                ctx.local.midi_tx,
+                ctx.local.midi_rx,
+            )
+        };
+
+        // Bluetooth LE
+        let radio = {
+            let ficr = &board.FICR;
+            let id = (ficr.deviceaddr[1].read().bits() as u64) << 32
+                | ficr.deviceaddr[0].read().bits() as u64;
+            let mut peripheral =
+                ble::Peripheral::new(ble::Address::random_static(id), cfg!(feature = "ble-midi"));
+            peripheral.attach_event(|event| {
+                handle_ble_event::spawn(event).ok();
+            });
+            Radio::new(board.RADIO, board.TIMER4, peripheral, rng.random_u32())
+        };
+
         (
             Shared {
                 btn1,
                 btn2,
                 player,
+                radio,
                 display,
+                screen: Screen::new(),
+            },
+            Local {
+                rtc0,
+                logo,
+                gesture,
+                serial,
+                midi_in,
+                mic,
+                store,
             },
-            Local { rtc0 },
             init::Monotonics(mono),
         )
     }
 
-    #[task(priority = 1, binds = RTC0, local = [rtc0], shared = [player, btn1, btn2])]
+    #[task(priority = 1, binds = RTC0, local = [rtc0, logo, gesture], shared = [player, display, screen, btn1, btn2, radio])]
     fn rtc0(mut ctx: rtc0::Context) {
         ctx.local.rtc0.reset_event(RtcInterrupt::Tick);
         ctx.shared.btn1.lock(|btn| btn.tick());
         ctx.shared.btn2.lock(|btn| btn.tick());
+        ctx.local.logo.tick();
+        ctx.local.gesture.tick();
+        if let Some(image) = ctx.shared.screen.lock(|screen| screen.tick()) {
+            ctx.shared.display.lock(|display| display.show(&image));
+        }
+        // a central that just connected gets the status at the next tick
+        let connected = ctx
+            .shared
+            .radio
+            .lock(|radio| radio.peripheral().is_connected());
+        if !connected {
+            return;
+        }
+        let status = ctx.shared.player.lock(|ply| ble::Status {
+            state: if ply.is_playing() {
+                ble::PlayState::Playing
+            } else if ply.is_paused() {
+                ble::PlayState::Paused
+            } else {
+                ble::PlayState::Stopped
+            },
+            index: ply.index(),
+            duration_ms: ply.duration(),
+            volume: ply.volume(),
+        });
+        ctx.shared
+            .radio
+            .lock(|radio| radio.peripheral().update_status(status));
+    }
+
+    #[task(
+        priority = 1,
+        binds = UARTE0_UART0,
+        local = [
+            serial,
+            store,
+            line: command::LineBuffer<{ command::MAX_LINE }> = command::LineBuffer::new(),
+            record: [u8; command::MAX_RECORD] = [0; command::MAX_RECORD],
+            usb_midi: bool = false,
+            usb_parser: midi::Parser = midi::Parser::new(),
+        ],
+        shared = [player, screen],
+    )]
+    fn uarte0(mut ctx: uarte0::Context) {
+        let serial = ctx.local.serial;
+        while let Some(byte) = serial.read() {
+            // commands are ASCII: a status byte switches the port to MIDI,
+            // until a system reset
+            if *ctx.local.usb_midi || byte & 0x80 != 0 {
+                *ctx.local.usb_midi = byte != midi::SYSTEM_RESET;
+                if let Some(message) = ctx.local.usb_parser.push(byte) {
+                    handle_midi::spawn(message).ok();
+                }
+                continue;
+            }
+            let Some(line) = ctx.local.line.push(byte) else {
+                continue;
+            };
+            let command = line.and_then(command::parse);
+            defmt::debug!("serial command: {:?}", command);
+            let reply = match command {
+                Ok(command) => command::run(
+                    command,
+                    |f| {
+                        (&mut ctx.shared.player, &mut ctx.shared.screen)
+                            .lock(|ply, screen| f(ply, screen))
+                    },
+                    ctx.local.store,
+                    ctx.local.record,
+                    serial,
+                ),
+                Err(error) => Err(error.as_str()),
+            };
+            match reply {
+                Ok(()) => writeln!(serial, "ok"),
+                Err(reason) => writeln!(serial, "error {}", reason),
+            }
+            .ok();
+        }
+    }
+
+    #[task(priority = 1, binds = UARTE1, local = [midi_in, parser: midi::Parser = midi::Parser::new()])]
+    fn uarte1(ctx: uarte1::Context) {
+        while let Some(byte) = ctx.local.midi_in.read() {
+            if let Some(message) = ctx.local.parser.push(byte) {
+                handle_midi::spawn(message).ok();
+            }
+        }
+    }
+
+    #[task(priority = 1, binds = SAADC, local = [mic])]
+    fn saadc(ctx: saadc::Context) {
+        ctx.local.mic.handle_event();
     }
 
     #[task(priority = 2, binds = TIMER1, shared = [player])]
@@ -128,6 +382,21 @@ mod app {
         ctx.shared.player.lock(|ply| ply.handle_play_event());
     }
 
+    #[task(priority = 2, binds = PWM1, shared = [player])]
+    fn pwm1(mut ctx: pwm1::Context) {
+        ctx.shared.player.lock(|ply| ply.handle_output_event());
+    }
+
+    #[task(priority = 4, binds = RADIO, shared = [radio])]
+    fn radio(mut ctx: radio::Context) {
+        ctx.shared.radio.lock(|radio| radio.handle_radio_event());
+    }
+
+    #[task(priority = 4, binds = TIMER4, shared = [radio])]
+    fn timer4(mut ctx: timer4::Context) {
+        ctx.shared.radio.lock(|radio| radio.handle_timer_event());
+    }
+
     #[task(priority = 3, binds = TIMER2, shared = [display])]
     fn timer2(mut ctx: timer2::Context) {
         ctx.shared
@@ -135,32 +404,212 @@ mod app {
             .lock(|display| display.handle_display_event());
     }
 
-    #[task(shared = [player, display])]
-    fn handle_btn1_event(mut ctx: handle_btn1_event::Context, event: button::Event) {
+    /// Seeks through the melody while a button is held, at 2, 4 and then 8
+    /// times the playback speed the longer it is held. `held` counts the
+    /// long-press events so far.
+    fn scrub(ply: &mut Player, screen: &mut Screen, held: &mut u32, forward: bool) {
+        *held += 1;
+        if !held.is_multiple_of(SCRUB_EVENTS) {
+            return;
+        }
+        let speed = match *held / 100 {
+            0 => 2,
+            1 => 4,
+            _ => 8,
+        };
+        let step_ms = SCRUB_EVENTS * 10 * speed * ply.tempo() / 100;
+        let position = ply.position();
+        ply.seek_ms(if forward {
+            position + step_ms
+        } else {
+            position.saturating_sub(step_ms)
+        });
+        screen.show_progress(ply.position(), ply.duration());
+    }
+
+    #[task(shared = [player, screen], local = [held: u32 = 0])]
+    fn handle_btn1_event(ctx: handle_btn1_event::Context, event: button::Event) {
         use button::Event::*;
 
         defmt::debug!("btn1 event: {:?}", &event);
-        ctx.shared.player.lock(|ply| match event {
-            Click => ply.volume_sub(10),
-            LongPressStart | LongPressDuring | LongPressStop => ply.volume_sub(1),
-            DoubleClick => ply.prev(),
+        let held = ctx.local.held;
+        (ctx.shared.player, ctx.shared.screen).lock(|ply, screen| match event {
+            Click => {
+                ply.volume_sub(10);
+                screen.show_volume(ply.volume());
+            }
+            LongPressStart => *held = 0,
+            LongPressDuring => scrub(ply, screen, held, false),
+            DoubleClick => {
+                ply.prev();
+                screen.set_paused(ply.is_paused());
+                if let Some(melody) = ply.current() {
+                    screen.show_icon(melody.icon());
+                }
+            }
+            MultiClick(3) => {
+                ply.tempo_sub(10);
+                screen.show_tempo(ply.tempo());
+            }
+            MultiClick(4) => {
+                ply.set_mode(ply.mode().prev());
+                screen.show_mode(ply.mode());
+            }
             _ => {}
         })
     }
 
-    #[task(shared = [player])]
-    fn handle_btn2_event(mut ctx: handle_btn2_event::Context, event: button::Event) {
+    #[task(shared = [player, screen], local = [held: u32 = 0])]
+    fn handle_btn2_event(ctx: handle_btn2_event::Context, event: button::Event) {
         use button::Event::*;
 
         defmt::debug!("btn2 event: {:?}", &event);
-        ctx.shared.player.lock(|ply| match event {
-            Click => ply.volume_add(10),
-            LongPressStart | LongPressDuring | LongPressStop => ply.volume_add(1),
-            DoubleClick => ply.next(),
+        let held = ctx.local.held;
+        (ctx.shared.player, ctx.shared.screen).lock(|ply, screen| match event {
+            Click => {
+                ply.volume_add(10);
+                screen.show_volume(ply.volume());
+            }
+            LongPressStart => *held = 0,
+            LongPressDuring => scrub(ply, screen, held, true),
+            DoubleClick => {
+                ply.next();
+                screen.set_paused(ply.is_paused());
+                if let Some(melody) = ply.current() {
+                    screen.show_icon(melody.icon());
+                }
+            }
+            MultiClick(3) => {
+                ply.tempo_add(10);
+                screen.show_tempo(ply.tempo());
+            }
+            MultiClick(4) => {
+                ply.set_mode(ply.mode().next());
+                screen.show_mode(ply.mode());
+            }
             _ => {}
         })
     }
 
+    #[task(shared = [player, screen])]
+    fn handle_logo_event(ctx: handle_logo_event::Context, event: button::Event) {
+        defmt::debug!("logo event: {:?}", &event);
+        if event == button::Event::Click {
+            (ctx.shared.player, ctx.shared.screen).lock(|ply, screen| {
+                ply.toggle();
+                screen.set_paused(ply.is_paused());
+            })
+        }
+    }
+
+    #[task(shared = [player, screen])]
+    fn handle_gesture_event(ctx: handle_gesture_event::Context, event: gesture::Event) {
+        use gesture::Event::*;
+
+        defmt::debug!("gesture event: {:?}", &event);
+        (ctx.shared.player, ctx.shared.screen).lock(|ply, screen| match event {
+            Shake => {
+                ply.toggle();
+                screen.set_paused(ply.is_paused());
+            }
+        })
+    }
+
+    #[task(shared = [player, screen])]
+    fn handle_clap_event(ctx: handle_clap_event::Context, event: clap::Event) {
+        use clap::Event::*;
+
+        defmt::debug!("clap event: {:?}", &event);
+        (ctx.shared.player, ctx.shared.screen).lock(|ply, screen| match event {
+            Clap => {
+                ply.toggle();
+                screen.set_paused(ply.is_paused());
+            }
+            DoubleClap => {
+                ply.next();
+                screen.set_paused(ply.is_paused());
+                if let Some(melody) = ply.current() {
+                    screen.show_icon(melody.icon());
+                }
+            }
+            // ducking is optional, the room may be loud with the music itself
+            Loud if cfg!(feature = "duck") => ply.set_duck(50),
+            Quiet => ply.set_duck(100),
+            Loud => {}
+        })
+    }
+
+    #[task(shared = [player, screen], capacity = 8)]
+    fn handle_ble_event(ctx: handle_ble_event::Context, event: ble::Event) {
+        use ble::Event::*;
+
+        defmt::debug!("ble event: {:?}", &event);
+        (ctx.shared.player, ctx.shared.screen).lock(|ply, screen| match event {
+            Connect => screen.show_icon(&screen::BLUETOOTH),
+            Disconnect => {}
+            Play => {
+                ply.play();
+                screen.set_paused(ply.is_paused());
+            }
+            Pause => {
+                ply.pause();
+                screen.set_paused(ply.is_paused());
+            }
+            Next | Prev => {
+                if event == Next {
+                    ply.next();
+                } else {
+                    ply.prev();
+                }
+                screen.set_paused(ply.is_paused());
+                if let Some(melody) = ply.current() {
+                    screen.show_icon(melody.icon());
+                }
+            }
+            Volume(volume) => {
+                ply.set_volume(volume as u32);
+                screen.show_volume(ply.volume());
+            }
+            Midi(message) => {
+                handle_midi::spawn(message).ok();
+            }
+        })
+    }
+
+    /// Plays the notes received over MIDI, pausing the melody.
+    #[task(shared = [player, screen], local = [live: live::Live = live::Live::new()], capacity = 16)]
+    fn handle_midi(ctx: handle_midi::Context, message: midi::Message) {
+        use live::Action::*;
+
+        let Some(action) = ctx.local.live.handle(message) else {
+            return;
+        };
+        defmt::debug!("live: {:?}", &action);
+        (ctx.shared.player, ctx.shared.screen).lock(|ply, screen| match action {
+            Start { pitch, volume } => {
+                ply.note_on(pitch, volume);
+                screen.set_paused(ply.is_paused());
+            }
+            Move(pitch) => ply.move_note(pitch),
+            Release => ply.note_off(),
+        })
+    }
+
+    #[task(shared = [player, screen])]
+    fn handle_player_event(ctx: handle_player_event::Context, event: player::Event) {
+        use player::Event::*;
+
+        defmt::debug!("player event: {:?}", &event);
+        (ctx.shared.player, ctx.shared.screen).lock(|ply, screen| match event {
+            Advance(_) => {
+                if let Some(melody) = ply.current() {
+                    screen.show_icon(melody.icon());
+                }
+            }
+            End => screen.set_paused(true),
+        })
+    }
+
     #[idle]
     fn idle(_ctx: idle::Context) -> ! {
         loop {
@@ -1,50 +1,862 @@
+use core::ops::Range;
+
 use defmt::Format;
 
-use crate::tone::Tone;
+use crate::{
+    envelope::Envelope,
+    pack::{self, Packed, Reader},
+    rtttl,
+    screen::{self, Icon},
+    tone::{Pitch, Tone},
+    waveform::Waveform,
+};
+
+/// How much of its written duration each note sounds for.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Articulation {
+    /// Notes run into each other.
+    Legato,
+    #[default]
+    Normal,
+    /// Short, detached notes.
+    Staccato,
+}
+
+impl Articulation {
+    /// Sounding part of a note, in percent.
+    pub const fn gate(self) -> u32 {
+        match self {
+            Self::Legato => 100,
+            Self::Normal => 90,
+            Self::Staccato => 50,
+        }
+    }
+}
+
+/// Pitch movement over the sounding part of a note, updated on the player's
+/// effect tick.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Effect {
+    #[default]
+    None,
+    /// Slides into the pitch of the next note (portamento).
+    Glide,
+    /// Wobbles `depth_cents` above and below the pitch, `rate_hz` times a
+    /// second.
+    Vibrato { rate_hz: u8, depth_cents: u8 },
+    /// Bends away from the pitch by `cents`, following `curve`.
+    Bend { cents: i16, curve: Curve },
+}
+
+/// How a bend progresses over the note.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Curve {
+    #[default]
+    Linear,
+    /// Starts slowly and speeds up.
+    EaseIn,
+    /// Moves quickly at first and settles.
+    EaseOut,
+}
+
+impl Curve {
+    /// Share of the bend done at `progress` (0.0 to 1.0) through the note.
+    pub fn at(self, progress: f32) -> f32 {
+        match self {
+            Self::Linear => progress,
+            Self::EaseIn => progress * progress,
+            Self::EaseOut => progress * (2.0 - progress),
+        }
+    }
+}
+
+/// Resolution of note lengths: a 64th-note triplet at double dotting is
+/// still a whole number of units.
+const UNITS_PER_WHOLE: u32 = 64 * 4 * 3;
+
+/// A note as written: a pitch, a duration divisor (negative when dotted) and
+/// the markers set with the methods below, e.g. `Note::new(Tone::D4, -1).tie()`.
+#[derive(Format, Debug, Clone, Copy, PartialEq)]
+pub struct Note {
+    pub pitch: Pitch,
+    pub div: i8,
+    pub(crate) marks: u8,
+    pub(crate) effect: Effect,
+}
+
+impl Note {
+    const TIE: u8 = 1 << 0;
+    const DOUBLE_DOT: u8 = 1 << 1;
+    const TRIPLET: u8 = 1 << 2;
+    const STACCATO: u8 = 1 << 3;
+    const LEGATO: u8 = 1 << 4;
+    const FERMATA: u8 = 1 << 5;
+
+    pub const fn new(tone: Tone, div: i8) -> Self {
+        Self::pitched(tone.pitch(), div)
+    }
+
+    /// A note off the `Tone` grid, e.g. `Note::pitched(Pitch::Hz(1000.0), 16)`.
+    pub const fn pitched(pitch: Pitch, div: i8) -> Self {
+        Self {
+            pitch,
+            div,
+            marks: 0,
+            effect: Effect::None,
+        }
+    }
+
+    /// Holds on into the next note without re-attacking it.
+    pub const fn tie(self) -> Self {
+        self.mark(Self::TIE)
+    }
+
+    /// Lasts 7/4 of its divisor.
+    pub const fn ddot(self) -> Self {
+        self.mark(Self::DOUBLE_DOT)
+    }
+
+    /// One of three notes in the time of two.
+    pub const fn triplet(self) -> Self {
+        self.mark(Self::TRIPLET)
+    }
+
+    pub const fn staccato(self) -> Self {
+        self.mark(Self::STACCATO)
+    }
+
+    pub const fn legato(self) -> Self {
+        self.mark(Self::LEGATO)
+    }
+
+    /// Held for twice its length.
+    pub const fn fermata(self) -> Self {
+        self.mark(Self::FERMATA)
+    }
+
+    /// Slides into the next note.
+    pub const fn glide(self) -> Self {
+        self.effect(Effect::Glide)
+    }
+
+    pub const fn vibrato(self, rate_hz: u8, depth_cents: u8) -> Self {
+        self.effect(Effect::Vibrato {
+            rate_hz,
+            depth_cents,
+        })
+    }
+
+    /// Bends by `cents` over the note, e.g. `bend(1200, Curve::EaseIn)` for
+    /// a rising octave sweep.
+    pub const fn bend(self, cents: i16, curve: Curve) -> Self {
+        self.effect(Effect::Bend { cents, curve })
+    }
+
+    pub const fn effect(self, effect: Effect) -> Self {
+        Self { effect, ..self }
+    }
+
+    pub const fn is_tied(&self) -> bool {
+        self.marks & Self::TIE != 0
+    }
+
+    /// Written length in `UNITS_PER_WHOLE` units, i.e. ignoring a fermata.
+    pub fn units(&self) -> u32 {
+        let mut units = UNITS_PER_WHOLE / self.div.unsigned_abs().max(1) as u32;
+        if self.marks & Self::DOUBLE_DOT != 0 {
+            units = units * 7 / 4;
+        } else if self.div < 0 {
+            units = units * 3 / 2;
+        }
+        if self.marks & Self::TRIPLET != 0 {
+            units = units * 2 / 3;
+        }
+        units
+    }
+
+    /// Length in milliseconds for a whole note of `whole_ms`.
+    pub fn duration_ms(&self, whole_ms: u32) -> u32 {
+        let held = if self.marks & Self::FERMATA != 0 { 2 } else { 1 };
+        whole_ms * self.units() * held / UNITS_PER_WHOLE
+    }
+
+    /// The articulation this note overrides the melody's with, if any.
+    fn articulation(&self) -> Option<Articulation> {
+        if self.marks & Self::STACCATO != 0 {
+            Some(Articulation::Staccato)
+        } else if self.marks & Self::LEGATO != 0 {
+            Some(Articulation::Legato)
+        } else {
+            None
+        }
+    }
 
-#[derive(Format, Debug)]
+    const fn mark(self, mark: u8) -> Self {
+        Self {
+            marks: self.marks | mark,
+            ..self
+        }
+    }
+}
+
+/// What a voice of a melody is made of: notes, and changes that apply to
+/// the notes after them.
+#[derive(Format, Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    Note(Note),
+    /// New tempo, as the length of a whole note in milliseconds.
+    Tempo(u32),
+    /// New time signature, beats per bar and beat unit. It also marks the
+    /// start of a bar.
+    Time(u8, u8),
+    /// Start of a repeated passage, `|:`.
+    Repeat,
+    /// End of a repeated passage, `:|`, and how many times it is played in
+    /// all. Without a `Repeat`, it goes back to the start or to the end of
+    /// the previous repeat.
+    EndRepeat(u8),
+    /// Start of the ending played on the given time through a repeat. On a
+    /// D.C. or D.S. the last ending is played. The last ending closes the
+    /// repeat, a later `EndRepeat` goes back no further than its start.
+    Volta(u8),
+    /// Where `DalSegno` goes back to.
+    Segno,
+    /// Where `ToCoda` jumps to.
+    Coda,
+    /// Jumps to the `Coda` after a D.C. or D.S. has been taken.
+    ToCoda,
+    /// Ends the voice after a D.C. or D.S. has been taken.
+    Fine,
+    /// D.C., back to the start once, without taking repeats again.
+    DaCapo,
+    /// D.S., back to the `Segno` once, without taking repeats again.
+    DalSegno,
+    /// Plays a section, a passage shared between melodies or places in one,
+    /// see `section!`. Sections hold notes, tempo and time changes only.
+    Section(&'static [Event]),
+}
+
+impl Event {
+    /// Converts `(tone, divisor)` pairs, as produced by `rtttl`.
+    pub const fn from_pairs<const N: usize>(pairs: [(Tone, i8); N]) -> [Event; N] {
+        let mut events = [Event::Tempo(0); N];
+        let mut i = 0;
+        while i < N {
+            events[i] = Event::Note(Note::new(pairs[i].0, pairs[i].1));
+            i += 1;
+        }
+        events
+    }
+}
+
+/// A note lowered to the timing the player works with.
+#[derive(Format, Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    pub pitch: Pitch,
+    pub effect: Effect,
+    pub duration_ms: u32,
+    /// Sounding part of the duration, in percent.
+    pub gate: u32,
+    /// Whether the note starts afresh, rather than continuing a tied one.
+    pub attack: bool,
+}
+
+/// Where iteration through one voice stands, along with the tempo in effect
+/// there. See `Melody::next_step`.
+#[derive(Format, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Position {
+    reader: Reader,
+    /// Whole note length set by a tempo change, 0 until there is one.
+    whole_ms: u32,
+    /// Pitch of the previous note, if it was tied.
+    tied: Option<Pitch>,
+    /// Where the current repeat goes back to, and how many times it has.
+    repeat_from: Reader,
+    repeats: u8,
+    /// Whether a D.C. or D.S. has been taken.
+    jumped: bool,
+    /// Section being played, and how far into it.
+    section: &'static [Event],
+    section_index: usize,
+    /// Time signature read last, as beats and beat unit.
+    time: Option<(u8, u8)>,
+    /// Bar of the note read last, and how far the notes read reach into it
+    /// in `UNITS_PER_WHOLE` units.
+    bar: u32,
+    into_bar: u32,
+}
+
+impl Position {
+    /// Time signature in effect at the note read last, if the voice has set
+    /// one by then.
+    pub fn time_signature(&self) -> Option<(u8, u8)> {
+        self.time
+    }
+
+    /// Bar of the note read last, counting from 0 in the order the bars are
+    /// played. Bars are in 4/4 until there is a time signature.
+    pub fn bar(&self) -> u32 {
+        self.bar
+    }
+
+    /// Length of a bar in `UNITS_PER_WHOLE` units.
+    fn bar_units(&self) -> u32 {
+        let (beats, unit) = self.time.unwrap_or((4, 4));
+        (UNITS_PER_WHOLE * beats as u32 / unit.max(1) as u32).max(1)
+    }
+}
+
+#[derive(Format, Debug, Clone, Copy)]
 pub struct Melody {
     whole_note_delay_ms: u32,
-    notes: &'static [(Tone, i8)],
+    icon: Icon,
+    notes: Packed,
+    /// Accompaniment played alongside `notes`, with its own rhythm.
+    bass: Packed,
+    /// Timbre of the tune and of the accompaniment.
+    waveforms: [Waveform; 2],
+    envelopes: [Envelope; 2],
+    articulation: Articulation,
+    /// Key shift in semitones.
+    transpose: i8,
 }
 
 impl Melody {
-    pub fn get(&self, pos: usize) -> Option<(Tone, u32)> {
-        self.notes.get(pos).cloned().map(|(note, div)| {
-            let dotted = if div > 0 { false } else { true };
-            let div = div.abs() as f32;
-            let delay_ms = self.whole_note_delay_ms as f32 / div;
-            (note, if dotted { delay_ms * 1.5 } else { delay_ms } as u32)
-        })
+    pub const fn new(whole_note_delay_ms: u32, icon: Icon, notes: Packed) -> Self {
+        Self {
+            whole_note_delay_ms,
+            icon,
+            notes,
+            bass: Packed::EMPTY,
+            waveforms: [Waveform::SQUARE; 2],
+            envelopes: [Envelope::DEFAULT; 2],
+            articulation: Articulation::Normal,
+            transpose: 0,
+        }
+    }
+
+    pub const fn with_bass(self, bass: Packed) -> Self {
+        Self { bass, ..self }
     }
 
+    pub const fn with_waveform(self, voice: usize, waveform: Waveform) -> Self {
+        let mut waveforms = self.waveforms;
+        waveforms[voice] = waveform;
+        Self { waveforms, ..self }
+    }
+
+    pub const fn with_envelope(self, voice: usize, envelope: Envelope) -> Self {
+        let mut envelopes = self.envelopes;
+        envelopes[voice] = envelope;
+        Self { envelopes, ..self }
+    }
+
+    pub const fn with_articulation(self, articulation: Articulation) -> Self {
+        Self {
+            articulation,
+            ..self
+        }
+    }
+
+    pub const fn with_transpose(self, transpose: i8) -> Self {
+        Self { transpose, ..self }
+    }
+
+    /// Reads `voice` (0 is the tune, 1 the accompaniment) from `pos` up to
+    /// and including its next note, applying the tempo changes and following
+    /// the repeats and jumps on the way.
+    pub fn next_step(&self, voice: usize, pos: &mut Position) -> Option<Step> {
+        let events = match voice {
+            0 => &self.notes,
+            1 => &self.bass,
+            _ => &Packed::EMPTY,
+        };
+        loop {
+            let event = match pos.section.get(pos.section_index) {
+                Some(event) => {
+                    pos.section_index += 1;
+                    *event
+                }
+                None => pos.reader.next(events)?,
+            };
+            let note = match event {
+                Event::Note(note) => note,
+                Event::Tempo(whole_ms) => {
+                    pos.whole_ms = whole_ms;
+                    continue;
+                }
+                // a time signature does not change how the notes are played,
+                // only how they are counted into bars, and starts a bar
+                Event::Time(beats, unit) => {
+                    pos.bar += pos.into_bar.div_ceil(pos.bar_units());
+                    pos.into_bar = 0;
+                    pos.time = Some((beats, unit));
+                    continue;
+                }
+                Event::Section(section) => {
+                    pos.section = section;
+                    pos.section_index = 0;
+                    continue;
+                }
+                structure => {
+                    if !Self::follow(events, pos, structure) {
+                        return None;
+                    }
+                    continue;
+                }
+            };
+
+            let whole_ms = match pos.whole_ms {
+                0 => self.whole_note_delay_ms,
+                whole_ms => whole_ms,
+            };
+            let gate = if note.is_tied() {
+                100
+            } else {
+                note.articulation().unwrap_or(self.articulation).gate()
+            };
+            let step = Step {
+                pitch: note.pitch,
+                effect: note.effect,
+                duration_ms: note.duration_ms(whole_ms),
+                gate,
+                attack: pos.tied != Some(note.pitch),
+            };
+
+            let bar_units = pos.bar_units();
+            pos.bar += pos.into_bar / bar_units;
+            pos.into_bar = pos.into_bar % bar_units + note.units();
+            pos.tied = note.is_tied().then_some(note.pitch);
+            return Some(step);
+        }
+    }
+
+    /// Applies a repeat or jump mark read from `events`, returning `false`
+    /// if it ends the voice.
+    fn follow(events: &Packed, pos: &mut Position, event: Event) -> bool {
+        match event {
+            Event::Repeat => {
+                pos.repeat_from = pos.reader;
+                pos.repeats = 0;
+            }
+            Event::EndRepeat(times) => {
+                if !pos.jumped && pos.repeats + 1 < times {
+                    pos.repeats += 1;
+                    pos.reader = pos.repeat_from;
+                } else {
+                    pos.repeats = 0;
+                    pos.repeat_from = pos.reader;
+                }
+            }
+            Event::Volta(n) => {
+                let next = Self::next_volta(events, pos.reader);
+                let play = match next {
+                    Some(_) if pos.jumped => false,
+                    _ => pos.jumped || n == pos.repeats + 1,
+                };
+                match (play, next) {
+                    // go on to the next ending, which decides for itself
+                    (false, Some(next)) => pos.reader = next,
+                    // the last ending is played through rather than left by
+                    // a `:|`, so the repeat ends here
+                    (true, None) => {
+                        pos.repeats = 0;
+                        pos.repeat_from = pos.reader;
+                    }
+                    _ => {}
+                }
+            }
+            Event::ToCoda if pos.jumped => {
+                if let Some(coda) = Self::find(events, pos.reader, Event::Coda) {
+                    pos.reader = coda;
+                }
+            }
+            Event::Fine if pos.jumped => return false,
+            Event::DaCapo | Event::DalSegno if !pos.jumped => {
+                let segno = match event {
+                    Event::DalSegno => Self::find(events, Reader::new(), Event::Segno),
+                    _ => None,
+                };
+                pos.jumped = true;
+                pos.reader = segno.unwrap_or_default();
+                pos.repeat_from = pos.reader;
+                pos.repeats = 0;
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// Reads on from `from` past the first `event`.
+    fn find(events: &Packed, mut from: Reader, event: Event) -> Option<Reader> {
+        while from.next(events)? != event {}
+        Some(from)
+    }
+
+    /// Reads on from `from` up to the next ending of the current repeat.
+    fn next_volta(events: &Packed, mut from: Reader) -> Option<Reader> {
+        loop {
+            let at = from;
+            match from.next(events)? {
+                Event::Volta(_) => return Some(at),
+                Event::Repeat => return None,
+                _ => {}
+            }
+        }
+    }
+
+    /// Number of events in the tune, as written.
     pub fn len(&self) -> usize {
         self.notes.len()
     }
+
+    /// Packed tune and accompaniment.
+    pub fn voices(&self) -> [&Packed; 2] {
+        [&self.notes, &self.bass]
+    }
+
+    /// Written time at which note `index` of the tune starts, in
+    /// milliseconds, or the tune's length if it has fewer notes.
+    pub fn offset_ms(&self, index: usize) -> u32 {
+        let mut pos = Position::default();
+        let mut ms = 0;
+        for _ in 0..index {
+            match self.next_step(0, &mut pos) {
+                Some(step) => ms += step.duration_ms,
+                None => break,
+            }
+        }
+        ms
+    }
+
+    /// Written time at which bar `bar` of the tune starts, in milliseconds,
+    /// or the tune's length if it has fewer bars. See `Position::bar`.
+    pub fn bar_offset_ms(&self, bar: u32) -> u32 {
+        let mut pos = Position::default();
+        let mut ms = 0;
+        while let Some(step) = self.next_step(0, &mut pos) {
+            if pos.bar >= bar {
+                break;
+            }
+            ms += step.duration_ms;
+        }
+        ms
+    }
+
+    /// Written length of the tune in milliseconds, tempo changes included.
+    pub fn duration_ms(&self) -> u32 {
+        self.offset_ms(usize::MAX)
+    }
+
+    pub fn icon(&self) -> &Icon {
+        &self.icon
+    }
+
+    pub fn waveform(&self, voice: usize) -> Waveform {
+        self.waveforms.get(voice).copied().unwrap_or_default()
+    }
+
+    pub fn envelope(&self, voice: usize) -> Envelope {
+        self.envelopes.get(voice).copied().unwrap_or_default()
+    }
+
+    pub fn articulation(&self) -> Articulation {
+        self.articulation
+    }
+
+    pub fn transpose(&self) -> i8 {
+        self.transpose
+    }
+
+    /// Reads a melody written by `write_record`, its voices played from
+    /// `record` in place.
+    pub fn from_record(record: &'static [u8]) -> Option<Self> {
+        let (melody, [notes, bass]) = Self::read_record(record)?;
+        let voice = |(bytes, len): RecordVoice| Packed::new(&record[bytes], &[], len);
+        Some(Self {
+            notes: voice(notes),
+            bass: voice(bass),
+            ..melody
+        })
+    }
+
+    /// Whether `record` holds a melody written by `write_record`.
+    pub fn is_record(record: &[u8]) -> bool {
+        Self::read_record(record).is_some()
+    }
+
+    /// Reads the header of a record, returning the melody without its voices,
+    /// and the bytes and event count of each voice.
+    fn read_record(record: &[u8]) -> Option<(Self, [RecordVoice; 2])> {
+        let header = record.get(..RECORD_HEADER)?;
+        if header[0] != RECORD_VERSION {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]) as usize;
+        let notes = RECORD_HEADER..RECORD_HEADER + u16_at(52);
+        let bass = notes.end..notes.end + u16_at(56);
+        if bass.end > record.len() {
+            return None;
+        }
+        let mut icon = [[0; 5]; 5];
+        for (row, bytes) in icon.iter_mut().zip(header[5..30].chunks(5)) {
+            row.copy_from_slice(bytes);
+        }
+        let envelope = |i: usize| Envelope::from_bytes(header[i..i + 7].try_into().unwrap());
+        let melody = Self {
+            whole_note_delay_ms: u32::from_le_bytes(header[1..5].try_into().unwrap()),
+            icon,
+            notes: Packed::EMPTY,
+            bass: Packed::EMPTY,
+            waveforms: [
+                Waveform::from_bytes([header[30], header[31]])?,
+                Waveform::from_bytes([header[32], header[33]])?,
+            ],
+            envelopes: [envelope(34), envelope(41)],
+            articulation: match header[48] {
+                0 => Articulation::Legato,
+                1 => Articulation::Normal,
+                2 => Articulation::Staccato,
+                _ => return None,
+            },
+            transpose: header[49] as i8,
+        };
+        if melody.whole_note_delay_ms == 0 {
+            return None;
+        }
+        Some((melody, [(notes, u16_at(50)), (bass, u16_at(54))]))
+    }
+
+    /// Writes the melody to `out` as a record for `store::Store`, returning
+    /// its length, or `None` if `out` is too small or a voice plays
+    /// sections.
+    ///
+    /// The record is a version byte, the whole note length, the icon, the
+    /// waveforms, envelopes, articulation and transpose, then the event count
+    /// and packed length of each voice, followed by the packed voices.
+    pub fn write_record(&self, out: &mut [u8]) -> Option<usize> {
+        let (notes, bass) = (self.notes.bytes(), self.bass.bytes());
+        let len = RECORD_HEADER + notes.len() + bass.len();
+        if out.len() < len || !self.notes.sections().is_empty() || !self.bass.sections().is_empty() {
+            return None;
+        }
+        let (header, voices) = out[..len].split_at_mut(RECORD_HEADER);
+        header[0] = RECORD_VERSION;
+        header[1..5].copy_from_slice(&self.whole_note_delay_ms.to_le_bytes());
+        header[5..30].copy_from_slice(self.icon.as_flattened());
+        for voice in 0..2 {
+            header[30 + 2 * voice..32 + 2 * voice].copy_from_slice(&self.waveforms[voice].to_bytes());
+            header[34 + 7 * voice..41 + 7 * voice].copy_from_slice(&self.envelopes[voice].to_bytes());
+        }
+        header[48] = self.articulation as u8;
+        header[49] = self.transpose as u8;
+        let sizes = [self.notes.len(), notes.len(), self.bass.len(), bass.len()];
+        for (i, size) in sizes.into_iter().enumerate() {
+            let size = u16::try_from(size).ok()?;
+            header[50 + 2 * i..52 + 2 * i].copy_from_slice(&size.to_le_bytes());
+        }
+        let (voice0, voice1) = voices.split_at_mut(notes.len());
+        voice0.copy_from_slice(notes);
+        voice1.copy_from_slice(bass);
+        Some(len)
+    }
 }
 
+/// Length of the fixed part of a melody record, see `Melody::write_record`.
+const RECORD_HEADER: usize = 58;
+const RECORD_VERSION: u8 = 1;
+
+/// Where the packed bytes of a voice lie in a record, and its event count.
+type RecordVoice = (Range<usize>, usize);
+
 macro_rules! melody {
     (
         name = $name:ident,
         tempo = $tempo:expr,
         beat = $beat:expr,
-        $([$($note:ident: $duration:expr),*]),*
+        icon = $icon:expr,
+        $(waveform = $waveform:expr,)?
+        $(envelope = $envelope:expr,)?
+        $(articulation = $articulation:expr,)?
+        $(transpose = $transpose:expr,)?
+        $([$($note:ident $(($($arg:tt)*))? $(: $duration:literal $(/ $unit:literal)?)? $($mark:ident $(($($mark_arg:tt)*))?)*),*]),*
+        $(, bass = [$([$($bass_note:ident $(($($bass_arg:tt)*))? $(: $bass_duration:literal $(/ $bass_unit:literal)?)? $($bass_mark:ident $(($($bass_mark_arg:tt)*))?)*),*]),*]
+            $(, bass_waveform = $bass_waveform:expr)?
+            $(, bass_envelope = $bass_envelope:expr)?)?
     ) => {
         pub const $name: Melody = Melody {
             whole_note_delay_ms: (60000 * $beat) / $tempo,
-            notes: &[
+            icon: $icon,
+            notes: pack!(&[
                 $(
-                    $((Tone::$note, $duration),)*
+                    $(melody!(@event $beat, $note $(($($arg)*))? $(: $duration $(/ $unit)?)? $($mark $(($($mark_arg)*))?)*),)*
                 )*
-            ]
+            ]),
+            bass: pack!(&[
+                $($(
+                    $(melody!(@event $beat, $bass_note $(($($bass_arg)*))? $(: $bass_duration $(/ $bass_unit)?)? $($bass_mark $(($($bass_mark_arg)*))?)*),)*
+                )*)?
+            ]),
+            waveforms: [
+                melody!(@waveform $($waveform)?),
+                melody!(@waveform $($($bass_waveform)?)?),
+            ],
+            envelopes: [
+                melody!(@envelope $($envelope)?),
+                melody!(@envelope $($($bass_envelope)?)?),
+            ],
+            articulation: melody!(@articulation $($articulation)?),
+            transpose: melody!(@transpose $($transpose)?),
+        };
+    };
+    (@event $beat:expr, TEMPO: $bpm:literal) => {
+        Event::Tempo((60000 * $beat) / $bpm)
+    };
+    (@event $beat:expr, TIME: $beats:literal / $unit:literal) => {
+        Event::Time($beats, $unit)
+    };
+    (@event $beat:expr, REPEAT) => {
+        Event::Repeat
+    };
+    (@event $beat:expr, END_REPEAT) => {
+        Event::EndRepeat(2)
+    };
+    (@event $beat:expr, END_REPEAT: $times:literal) => {
+        Event::EndRepeat($times)
+    };
+    (@event $beat:expr, VOLTA: $n:literal) => {
+        Event::Volta($n)
+    };
+    (@event $beat:expr, SEGNO) => {
+        Event::Segno
+    };
+    (@event $beat:expr, CODA) => {
+        Event::Coda
+    };
+    (@event $beat:expr, TO_CODA) => {
+        Event::ToCoda
+    };
+    (@event $beat:expr, FINE) => {
+        Event::Fine
+    };
+    (@event $beat:expr, DC) => {
+        Event::DaCapo
+    };
+    (@event $beat:expr, DS) => {
+        Event::DalSegno
+    };
+    (@event $beat:expr, PLAY($section:ident)) => {
+        Event::Section($section)
+    };
+    (@event $beat:expr, HZ($hz:literal): $duration:literal $($mark:ident $(($($arg:tt)*))?)*) => {
+        Event::Note(Note::pitched(Pitch::Hz($hz as f32), $duration)$(.$mark($($($arg)*)?))*)
+    };
+    (@event $beat:expr, $note:ident($cents:literal): $duration:literal $($mark:ident $(($($arg:tt)*))?)*) => {
+        Event::Note(Note::pitched(Tone::$note.pitch().detune($cents), $duration)$(.$mark($($($arg)*)?))*)
+    };
+    (@event $beat:expr, $note:ident: $duration:literal $($mark:ident $(($($arg:tt)*))?)*) => {
+        Event::Note(Note::new(Tone::$note, $duration)$(.$mark($($($arg)*)?))*)
+    };
+    (@waveform) => {
+        Waveform::SQUARE
+    };
+    (@envelope) => {
+        Envelope::DEFAULT
+    };
+    (@articulation) => {
+        Articulation::Normal
+    };
+    (@transpose) => {
+        0
+    };
+    (@$field:ident $value:expr) => {
+        $value
+    };
+}
+
+/// Packs a voice's events at compile time, failing the build if they do not
+/// decode back to the same.
+macro_rules! pack {
+    ($events:expr) => {{
+        const EVENTS: &[Event] = $events;
+        const BYTES: [u8; pack::packed_len(EVENTS)] = pack::pack(EVENTS);
+        const SECTIONS: [&[Event]; pack::section_count(EVENTS)] = pack::sections(EVENTS);
+        const PACKED: Packed = Packed::new(&BYTES, &SECTIONS, EVENTS.len());
+        const _: () = assert!(pack::round_trips(EVENTS, &PACKED), "pack: does not round-trip");
+        PACKED
+    }};
+}
+
+/// Defines a passage in the note syntax of `melody!`, for melodies to play
+/// with `PLAY(NAME)` wherever it recurs.
+macro_rules! section {
+    (
+        name = $name:ident,
+        beat = $beat:expr,
+        $([$($note:ident $(($($arg:tt)*))? $(: $duration:literal $(/ $unit:literal)?)? $($mark:ident $(($($mark_arg:tt)*))?)*),*]),*
+    ) => {
+        pub const $name: &[Event] = &[
+            $(
+                $(melody!(@event $beat, $note $(($($arg)*))? $(: $duration $(/ $unit)?)? $($mark $(($($mark_arg)*))?)*),)*
+            )*
+        ];
+    };
+}
+
+/// Embeds an RTTTL ringtone, failing the build if it does not parse.
+macro_rules! rtttl {
+    (
+        name = $name:ident,
+        icon = $icon:expr,
+        $src:expr
+        $(, waveform = $waveform:expr)?
+        $(, envelope = $envelope:expr)?
+        $(, articulation = $articulation:expr)?
+        $(, transpose = $transpose:expr)?
+    ) => {
+        pub const $name: Melody = {
+            const SRC: &[u8] = $src.as_bytes();
+            const LEN: usize = match rtttl::count(SRC) {
+                Ok(len) => len,
+                Err(e) => e.panic(),
+            };
+            const NOTES: [Event; LEN] = match rtttl::parse_array::<LEN>(SRC) {
+                Ok(notes) => Event::from_pairs(notes),
+                Err(e) => e.panic(),
+            };
+            let header = match rtttl::Parser::new(SRC) {
+                Ok(parser) => *parser.header(),
+                Err(e) => e.panic(),
+            };
+            Melody::new(header.whole_note_delay_ms(), $icon, pack!(&NOTES))
+                $(.with_waveform(0, $waveform))?
+                $(.with_envelope(0, $envelope))?
+                $(.with_articulation($articulation))?
+                $(.with_transpose($transpose))?
         };
     };
 }
 
+// Nokia tune (Gran Vals)
+rtttl!(
+    name = NOKIA,
+    icon = screen::NOTE,
+    "Nokia:d=4,o=5,b=225:8e6,8d6,f#,g#,8c#6,8b,d,e,8b,8a,c#,e,2a",
+    waveform = Waveform::Square(25),
+    envelope = Envelope::PLUCK
+);
+
 // Happy birthday
 // https://musescore.com/user/8221/scores/26906
 melody!(
     name = HAPPY_BIRTHDAY, tempo = 140, beat = 4,
+    icon = [
+        [0, 9, 0, 9, 0],
+        [0, 9, 0, 9, 0],
+        [9, 9, 9, 9, 9],
+        [9, 5, 9, 5, 9],
+        [9, 9, 9, 9, 9],
+    ],
     [C4:4, C4:8, D4:-4, C4:-4, F4:-4, E4:-2],
     [C4:4, C4:8, D4:-4, C4:-4, G4:-4, F4:-2],
     [C4:4, C4:8, C5:-4, A4:-4, F4:-4, E4:-4, D4:-4],
@@ -55,6 +867,13 @@ melody!(
 // https://musescore.com/user/6208766/scores/1497501
 melody!(
     name = MERRY_CHRISTMAS, tempo = 140, beat = 4,
+    icon = [
+        [0, 0, 9, 0, 0],
+        [0, 9, 9, 9, 0],
+        [0, 9, 9, 9, 0],
+        [9, 9, 9, 9, 9],
+        [0, 0, 5, 0, 0],
+    ],
     [C5:4, //1
     F5:4, F5:8, G5:8, F5:8, E5:8,
     D5:4, D5:4, D5:4,
@@ -120,60 +939,43 @@ melody!(
     F5:2, REST:4]
 );
 
-// Tetris Theme (Korobeniki)
+// Tetris Theme (Korobeniki), generated from melodies/tetris.mid
 // https://musescore.com/user/28837378/scores/5144713
-melody!(
-    name = TETRIS, tempo = 149, beat = 4, // bpm found here: https://getsongbpm.com/song/tetris-theme-a-korobeiniki/EqJvoY
-    [E5:4, B5:8, C5:8, D5:4, C5:8, B5:8, // 1st line
-    A5:4, A5:8, C5:8, E5:4, D5:8, C5:8,
-    B5:-4, C5:8, D5:4, E5:4,
-    C5:4, A5:4, A5:4, REST:4],
-    [REST:8, D5:4, F5:8, A6:4, G5:8, F5:8, // 2nd line
-    E5:-4, C5:8, E5:4, D5:8, C5:8,
-    B5:-4, C5:8, D5:4, E5:4,
-    C5:4, A5:4, A5:4, REST:4],
-    [E5:2, C5:2, // 3rd line
-    D5:2, B5:2,
-    C5:2, A5:2,
-    GS5:1],
-    [E5:2, C5:2, // 4th line
-    D5:2, B5:2,
-    C5:4, E5:4, A6:4, A6:4,
-    GS5:1],
-    [E5:4, B5:8, C5:8, D5:4, C5:8, B5:8, // 1st line repeats on 5th
-    A5:4, A5:8, C5:8, E5:4, D5:8, C5:8,
-    B5:-4, C5:8, D5:4, E5:4,
-    C5:4, A5:4, A5:4, REST:4],
-    [REST:8, D5:4, F5:8, A6:4, G5:8, F5:8, // 6th line
-    REST:8, E5:4, C5:8, E5:4, D5:8, C5:8,
-    REST:8, B5:4, C5:8, D5:4, E5:4,
-    REST:8, C5:4, A5:8, A5:4, REST:4]
+const TETRIS_ICON: Icon = [
+    [0, 0, 0, 0, 0],
+    [9, 9, 9, 0, 0],
+    [0, 9, 0, 0, 5],
+    [5, 0, 0, 5, 5],
+    [5, 5, 0, 9, 5],
+];
+
+// Bars 3-6 of the overworld theme, played again from bar 19
+section!(
+    name = OVERWORLD, beat = 4,
+    [C5:-4, G4:8, REST:4, E4:-4,
+    A4:4, B4:4, AS4:8, A4:4,
+    G4:-8, E5:-8, G5:-8, A5:4, F5:8, G5:8,
+    REST:8, E5:4, C5:8, D5:8, B4:-4]
 );
 
 melody!(
     name = SUPER_MARIOBROS, tempo = 200, beat = 4,
+    icon = [
+        [0, 9, 9, 9, 0],
+        [9, 5, 9, 5, 9],
+        [9, 9, 9, 9, 9],
+        [0, 5, 0, 5, 0],
+        [0, 5, 5, 5, 0],
+    ],
     [E5:8, E5:8, REST:8, E5:8, REST:8, C5:8, E5:8, //1
     G5:4, REST:4, G4:8, REST:4],
 
-    [C5:-4, G4:8, REST:4, E4:-4, // 3
-    A4:4, B4:4, AS4:8, A4:4,
-    G4:-8, E5:-8, G5:-8, A5:4, F5:8, G5:8,
-    REST:8, E5:4,C5:8, D5:8, B4:-4],
-
-    [C5:-4, G4:8, REST:4, E4:-4, // repeats from 3
-    A4:4, B4:4, AS4:8, A4:4,
-    G4:-8, E5:-8, G5:-8, A5:4, F5:8, G5:8,
-    REST:8, E5:4,C5:8, D5:8, B4:-4],
-
-    [REST:4, G5:8, FS5:8, F5:8, DS5:4, E5:8,//7
-    REST:8, GS4:8, A4:8, C4:8, REST:8, A4:8, C5:8, D5:8,
-    REST:4, DS5:4, REST:8, D5:-4,
-    C5:2, REST:2],
+    [REPEAT, PLAY(OVERWORLD), END_REPEAT], //3
 
-    [REST:4, G5:8, FS5:8, F5:8, DS5:4, E5:8,//repeats from 7
+    [REPEAT, REST:4, G5:8, FS5:8, F5:8, DS5:4, E5:8,//7
     REST:8, GS4:8, A4:8, C4:8, REST:8, A4:8, C5:8, D5:8,
     REST:4, DS5:4, REST:8, D5:-4,
-    C5:2, REST:2],
+    C5:2, REST:2, END_REPEAT],
 
     [C5:8, C5:4, C5:8, REST:8, C5:8, D5:4,//11
     E5:8, C5:4, A4:8, G4:2],
@@ -185,17 +987,9 @@ melody!(
     E5:8, E5:8, REST:8, E5:8, REST:8, C5:8, E5:4,
     G5:4, REST:4, G4:4, REST:4],
 
-    [C5:-4, G4:8, REST:4, E4:-4, // 19
-    A4:4, B4:4, AS4:8, A4:4,
-    G4:-8, E5:-8, G5:-8, A5:4, F5:8, G5:8,
-    REST:8, E5:4, C5:8, D5:8, B4:-4],
-
-    [C5:-4, G4:8, REST:4, E4:-4, // repeats from 19
-    A4:4, B4:4, AS4:8, A4:4,
-    G4:-8, E5:-8, G5:-8, A5:4, F5:8, G5:8,
-    REST:8, E5:4, C5:8, D5:8, B4:-4],
+    [REPEAT, PLAY(OVERWORLD), END_REPEAT], //19
 
-    [E5:8, C5:4, G4:8, REST:4, GS4:4,//23
+    [REPEAT, E5:8, C5:4, G4:8, REST:4, GS4:4,//23
     A4:8, F5:4, F5:8, A4:2,
     D5:-8, A5:-8, A5:-8, A5:-8, G5:-8, F5:-8],
 
@@ -203,18 +997,9 @@ melody!(
     E5:8, C5:4, G4:8, REST:4, GS4:4,
     A4:8, F5:4, F5:8, A4:2,
     B4:8, F5:4, F5:8, F5:-8, E5:-8, D5:-8,
-    C5:8, E4:4, E4:8, C4:2],
-
-    [E5:8, C5:4, G4:8, REST:4, GS4:4,//repeats from 23
-    A4:8, F5:4, F5:8, A4:2,
-    D5:-8, A5:-8, A5:-8, A5:-8, G5:-8, F5:-8],
+    C5:8, E4:4, E4:8, C4:2, END_REPEAT],
 
-    [E5:8, C5:4, A4:8, G4:2, //26
-    E5:8, C5:4, G4:8, REST:4, GS4:4,
-    A4:8, F5:4, F5:8, A4:2,
-    B4:8, F5:4, F5:8, F5:-8, E5:-8, D5:-8,
-    C5:8, E4:4, E4:8, C4:2,
-    C5:8, C5:4, C5:8, REST:8, C5:8, D5:8, E5:8,
+    [C5:8, C5:4, C5:8, REST:8, C5:8, D5:8, E5:8, //31
     REST:1],
 
     [C5:8, C5:4, C5:8, REST:8, C5:8, D5:4, //33
@@ -239,12 +1024,19 @@ melody!(
 
 melody!(
     name = GAME_OF_THRONES, tempo = 85, beat = 4,
+    icon = [
+        [9, 0, 9, 0, 9],
+        [9, 9, 9, 9, 9],
+        [9, 5, 9, 5, 9],
+        [9, 9, 9, 9, 9],
+        [0, 0, 0, 0, 0],
+    ],
     [G4:8, C4:8, DS4:16, F4:16, G4:8, C4:8, DS4:16, F4:16, //1
     G4:8, C4:8, DS4:16, F4:16, G4:8, C4:8, DS4:16, F4:16,
     G4:8, C4:8, E4:16, F4:16, G4:8, C4:8, E4:16, F4:16,
     G4:8, C4:8, E4:16, F4:16, G4:8, C4:8, E4:16, F4:16],
 
-    [G4:-4, C4:-4],//5
+    [REPEAT, G4:-4, C4:-4],//5
 
     [DS4:16, F4:16, G4:4, C4:4, DS4:16, F4:16], //6
 
@@ -252,19 +1044,9 @@ melody!(
     F4:-4, AS3:-4,
     DS4:16, D4:16, F4:4, AS3:-4],
 
-    [DS4:16, D4:16, C4:-1], //11 and 12
+    [DS4:16, D4:16, C4:-1, END_REPEAT], //11 and 12
 
-    //repeats from 5
-    [G4:-4, C4:-4],//5
-
-    [DS4:16, F4:16, G4:4, C4:4, DS4:16, F4:16], //6
-
-    [D4:-1, //7 and 8
-    F4:-4, AS3:-4,
-    DS4:16, D4:16, F4:4, AS3:-4],
-
-    [DS4:16, D4:16, C4:-1, //11 and 12
-    G4:-4, C4:-4,
+    [G4:-4, C4:-4, //13
     DS4:16, F4:16, G4:4,  C4:4, DS4:16, F4:16],
 
     [D4:-2,//15
@@ -289,5 +1071,36 @@ melody!(
     C5:8, G4:8, GS4:16, AS4:16, C5:8, G4:8, GS4:16, AS4:16,
 
     REST:4, GS5:16, AS5:16, C6:8, G5:8, GS5:16, AS5:16,
-    C6:8, G5:16, GS5:16, AS5:16, C6:8, G5:8, GS5:16, AS5:16]
+    C6:8, G5:16, GS5:16, AS5:16, C6:8, G5:8, GS5:16, AS5:16],
+
+    bass = [
+        [C3:-2, C3:-2, C3:-2, C3:-2], //1
+        [REPEAT, C3:-2, C3:-2, G2:-2, G2:-2, AS2:-2, AS2:-2, C3:-2, C3:-2, //5
+        VOLTA: 1, REST:8, END_REPEAT, VOLTA: 2]
+    ],
+    bass_waveform = Waveform::Triangle,
+    bass_envelope = Envelope::PIANO
+);
+
+// Power-up jingle, chiptune style: each arpeggio slides up into the next,
+// then a shaken note and a falling sweep
+melody!(
+    name = POWER_UP, tempo = 100, beat = 4,
+    icon = [
+        [0, 9, 9, 9, 0],
+        [9, 5, 9, 5, 9],
+        [9, 9, 9, 9, 9],
+        [0, 9, 0, 9, 0],
+        [0, 9, 9, 9, 0],
+    ],
+    waveform = Waveform::Square(25),
+    envelope = Envelope::ORGAN,
+    articulation = Articulation::Legato,
+    [G4:32 glide, B4:32 glide, D5:32 glide, G5:32 glide, B5:32,
+    GS4:32 glide, C5:32 glide, DS5:32 glide, GS5:32 glide, C6:32,
+    AS4:32 glide, D5:32 glide, F5:32 glide, AS5:32 glide, D6:32],
+    [G5:4 vibrato(6, 30), G6:8 bend(-2400, Curve::EaseOut), REST:2]
 );
+
+// MIDI melodies and `MELODY_LIST`, see build.rs
+include!(concat!(env!("OUT_DIR"), "/melodies.rs"));
@@ -0,0 +1,192 @@
+//! MIDI channel messages, as the music box receives them to play live, and
+//! the parser of a MIDI byte stream.
+//!
+//! Parsing is `const`, so the examples at the end of this file are checked at
+//! compile time.
+use defmt::Format;
+
+/// System real-time message resetting the receivers, which also switches
+/// the USB serial port back from MIDI to commands.
+pub const SYSTEM_RESET: u8 = 0xff;
+
+/// The channel messages the music box acts on.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    NoteOff {
+        channel: u8,
+        key: u8,
+    },
+    NoteOn {
+        channel: u8,
+        key: u8,
+        velocity: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    /// Bend from -8192 to 8191, 0 being the written pitch.
+    PitchBend {
+        channel: u8,
+        bend: i16,
+    },
+}
+
+impl Message {
+    /// Number of data bytes after the channel `status` byte.
+    pub const fn data_len(status: u8) -> usize {
+        match status & 0xf0 {
+            0xc0 | 0xd0 => 1,
+            _ => 2,
+        }
+    }
+
+    /// The message of a channel `status` byte and its `data`, `None` for
+    /// those the music box ignores (aftertouch, program change). A note on
+    /// with velocity 0 is a note off.
+    pub const fn parse(status: u8, data: &[u8]) -> Option<Self> {
+        if data.len() < Self::data_len(status) {
+            return None;
+        }
+        let channel = status & 0x0f;
+        Some(match (status & 0xf0, data) {
+            (0x80, &[key, ..]) | (0x90, &[key, 0, ..]) => Self::NoteOff { channel, key },
+            (0x90, &[key, velocity, ..]) => Self::NoteOn {
+                channel,
+                key,
+                velocity,
+            },
+            (0xb0, &[controller, value, ..]) => Self::ControlChange {
+                channel,
+                controller,
+                value,
+            },
+            (0xe0, &[lsb, msb, ..]) => Self::PitchBend {
+                channel,
+                bend: ((msb as i16) << 7 | lsb as i16) - 8192,
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// Turns a stream of MIDI bytes, as sent over a 31250 baud line, into
+/// channel messages. A status byte may be left out while it stays the same
+/// (running status); system real-time messages may come between the bytes of
+/// another message and are ignored, as are system exclusive and common ones.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Parser {
+    status: Option<u8>,
+    data: [u8; 2],
+    len: usize,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            status: None,
+            data: [0; 2],
+            len: 0,
+        }
+    }
+
+    /// Adds a byte, returning the message it completes, if any.
+    pub const fn push(&mut self, byte: u8) -> Option<Message> {
+        match byte {
+            0xf8..=0xff => None,
+            // system exclusive and common messages cancel the running status
+            0xf0..=0xf7 => {
+                self.status = None;
+                None
+            }
+            0x80..=0xef => {
+                self.status = Some(byte);
+                self.len = 0;
+                None
+            }
+            _ => {
+                let Some(status) = self.status else {
+                    return None;
+                };
+                self.data[self.len] = byte;
+                self.len += 1;
+                if self.len < Message::data_len(status) {
+                    return None;
+                }
+                let len = self.len;
+                self.len = 0;
+                Message::parse(status, self.data.split_at(len).0)
+            }
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The message `bytes` ends with, `None` if they do not end one.
+const fn last(parser: &mut Parser, bytes: &[u8]) -> Option<Message> {
+    let mut message = None;
+    let mut i = 0;
+    while i < bytes.len() {
+        message = parser.push(bytes[i]);
+        i += 1;
+    }
+    message
+}
+
+const _: () = {
+    let mut parser = Parser::new();
+    assert!(matches!(
+        last(&mut parser, &[0x91, 60, 100]),
+        Some(Message::NoteOn {
+            channel: 1,
+            key: 60,
+            velocity: 100
+        })
+    ));
+    // running status, and a note on of velocity 0 for a note off
+    assert!(matches!(
+        last(&mut parser, &[64, 0]),
+        Some(Message::NoteOff {
+            channel: 1,
+            key: 64
+        })
+    ));
+    assert!(matches!(
+        last(&mut parser, &[0xb0, 7, 0xf8, 90]),
+        Some(Message::ControlChange {
+            channel: 0,
+            controller: 7,
+            value: 90
+        })
+    ));
+    assert!(matches!(
+        last(&mut parser, &[0xe0, 0, 0x40]),
+        Some(Message::PitchBend {
+            channel: 0,
+            bend: 0
+        })
+    ));
+    assert!(matches!(
+        last(&mut parser, &[0x7f, 0x7f]),
+        Some(Message::PitchBend {
+            channel: 0,
+            bend: 8191
+        })
+    ));
+    assert!(last(&mut parser, &[0xc0, 5, 0xd0, 9]).is_none());
+    // data bytes of a system exclusive message, then without a status
+    assert!(last(&mut parser, &[0xf0, 1, 2, 0xf7, 60, 0]).is_none());
+    assert!(matches!(
+        last(&mut parser, &[0x80, 62, 0]),
+        Some(Message::NoteOff {
+            channel: 0,
+            key: 62
+        })
+    ));
+};
@@ -0,0 +1,525 @@
+//! Compact encoding of the events of a voice, which is how melodies are kept
+//! in flash: about a byte per note instead of the size of an `Event`.
+//!
+//! A note is one byte, with a duration code in the high nibble and the pitch
+//! in the low one, after prefixes for whatever else sets it apart. Pitches
+//! are mostly steps from the key of the previous note:
+//!
+//! ```text
+//! 0x00-0xbf    note of divisor DIVS[b >> 4], pitch b & 0xf:
+//!              0-12 a step of -6 to +6 semitones, 13 a rest,
+//!              14 the key in the next byte, 15 a frequency in the next 4
+//! 0xc0-0xc6    Repeat, Segno, Coda, ToCoda, Fine, DaCapo, DalSegno
+//! 0xc7 n       EndRepeat(n)
+//! 0xc8 n       Volta(n)
+//! 0xc9 b u     Time(b, u)
+//! 0xca t t t t Tempo(t), little-endian
+//! 0xcb i       Section, the i-th of the voice's sections
+//! 0xcc n       the previous note n more times
+//! 0xcd d       prefix: divisor d, for one not in DIVS
+//! 0xdm         prefix: marks m
+//! 0xem         prefix: marks m << 4
+//! 0xf0         prefix: glide
+//! 0xf1 r d     prefix: vibrato of rate r and depth d
+//! 0xf2 c c k   prefix: bend by c cents along curve k
+//! 0xf3 c c     prefix: key detuned by c cents
+//! ```
+//!
+//! Sections stay plain `Event`s, being shared. Encoding is `const`, so the
+//! `melody!` macros pack at compile time and check that the result decodes
+//! to the events they were given.
+use defmt::Format;
+
+use crate::{
+    melody::{Curve, Effect, Event, Note},
+    tone::Pitch,
+};
+
+/// Divisors with a duration code of their own, by code.
+const DIVS: [i8; 12] = [1, 2, 4, 8, 16, 32, -1, -2, -4, -8, -16, -32];
+
+const NOTE_END: u8 = 0xc;
+const STEP_MAX: i32 = 6;
+const PITCH_REST: u8 = 13;
+const PITCH_KEY: u8 = 14;
+const PITCH_HZ: u8 = 15;
+
+const OP_REPEAT: u8 = 0xc0;
+const OP_SEGNO: u8 = 0xc1;
+const OP_CODA: u8 = 0xc2;
+const OP_TO_CODA: u8 = 0xc3;
+const OP_FINE: u8 = 0xc4;
+const OP_DA_CAPO: u8 = 0xc5;
+const OP_DAL_SEGNO: u8 = 0xc6;
+const OP_END_REPEAT: u8 = 0xc7;
+const OP_VOLTA: u8 = 0xc8;
+const OP_TIME: u8 = 0xc9;
+const OP_TEMPO: u8 = 0xca;
+const OP_SECTION: u8 = 0xcb;
+const OP_RUN: u8 = 0xcc;
+const OP_DIV: u8 = 0xcd;
+const OP_MARKS_LOW: u8 = 0xd0;
+const OP_MARKS_HIGH: u8 = 0xe0;
+const OP_GLIDE: u8 = 0xf0;
+const OP_VIBRATO: u8 = 0xf1;
+const OP_BEND: u8 = 0xf2;
+const OP_CENTS: u8 = 0xf3;
+
+/// The events of one voice, packed.
+#[derive(Format, Debug, Clone, Copy, PartialEq)]
+pub struct Packed {
+    bytes: &'static [u8],
+    /// Sections the voice plays, in the order it refers to them.
+    sections: &'static [&'static [Event]],
+    /// Number of events encoded.
+    len: usize,
+}
+
+impl Packed {
+    pub const EMPTY: Self = Self::new(&[], &[], 0);
+
+    pub const fn new(bytes: &'static [u8], sections: &'static [&'static [Event]], len: usize) -> Self {
+        Self {
+            bytes,
+            sections,
+            len,
+        }
+    }
+
+    pub const fn bytes(&self) -> &'static [u8] {
+        self.bytes
+    }
+
+    pub const fn sections(&self) -> &'static [&'static [Event]] {
+        self.sections
+    }
+
+    /// Number of events, as they were before packing.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+// `?` is not available in const fn
+macro_rules! tri {
+    ($e:expr) => {
+        match $e {
+            Some(v) => v,
+            None => return None,
+        }
+    };
+}
+
+/// Where decoding of a `Packed` stands. Copies of it are how the player
+/// jumps back for a repeat, the pitch steps being relative to `key`.
+#[derive(Format, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Reader {
+    offset: usize,
+    /// Key of the previous note.
+    key: u8,
+    /// Last note read, and how many more times it is played.
+    last: Option<Note>,
+    run: u8,
+}
+
+impl Reader {
+    pub const fn new() -> Self {
+        Self {
+            offset: 0,
+            key: 0,
+            last: None,
+            run: 0,
+        }
+    }
+
+    /// Decodes the next event, `None` at the end or on malformed data.
+    pub const fn next(&mut self, packed: &Packed) -> Option<Event> {
+        if self.run > 0 {
+            self.run -= 1;
+            return match self.last {
+                Some(note) => Some(Event::Note(note)),
+                None => None,
+            };
+        }
+
+        let mut note = Note::pitched(Pitch::Rest, 0);
+        let mut div = None;
+        let mut cents = 0;
+        loop {
+            let op = tri!(self.byte(packed));
+            if op >> 4 < NOTE_END {
+                note.div = match div {
+                    Some(div) => div,
+                    None => DIVS[(op >> 4) as usize],
+                };
+                note.pitch = match op & 0xf {
+                    PITCH_REST => Pitch::Rest,
+                    PITCH_KEY => {
+                        self.key = tri!(self.byte(packed));
+                        Pitch::Key(self.key, cents)
+                    }
+                    PITCH_HZ => Pitch::Hz(f32::from_le_bytes(tri!(self.array(packed)))),
+                    step => {
+                        self.key = (self.key as i32 + step as i32 - STEP_MAX) as u8;
+                        Pitch::Key(self.key, cents)
+                    }
+                };
+                self.last = Some(note);
+                return Some(Event::Note(note));
+            }
+
+            let event = match op {
+                OP_REPEAT => Event::Repeat,
+                OP_SEGNO => Event::Segno,
+                OP_CODA => Event::Coda,
+                OP_TO_CODA => Event::ToCoda,
+                OP_FINE => Event::Fine,
+                OP_DA_CAPO => Event::DaCapo,
+                OP_DAL_SEGNO => Event::DalSegno,
+                OP_END_REPEAT => Event::EndRepeat(tri!(self.byte(packed))),
+                OP_VOLTA => Event::Volta(tri!(self.byte(packed))),
+                OP_TIME => {
+                    let [beats, unit] = tri!(self.array(packed));
+                    Event::Time(beats, unit)
+                }
+                OP_TEMPO => Event::Tempo(u32::from_le_bytes(tri!(self.array(packed)))),
+                OP_SECTION => {
+                    let i = tri!(self.byte(packed)) as usize;
+                    if i >= packed.sections.len() {
+                        return None;
+                    }
+                    Event::Section(packed.sections[i])
+                }
+                OP_RUN => {
+                    self.run = tri!(self.byte(packed));
+                    return self.next(packed);
+                }
+                OP_DIV => {
+                    div = Some(tri!(self.byte(packed)) as i8);
+                    continue;
+                }
+                OP_GLIDE => {
+                    note.effect = Effect::Glide;
+                    continue;
+                }
+                OP_VIBRATO => {
+                    let [rate_hz, depth_cents] = tri!(self.array(packed));
+                    note.effect = Effect::Vibrato {
+                        rate_hz,
+                        depth_cents,
+                    };
+                    continue;
+                }
+                OP_BEND => {
+                    let [low, high, curve] = tri!(self.array(packed));
+                    let curve = match curve {
+                        0 => Curve::Linear,
+                        1 => Curve::EaseIn,
+                        _ => Curve::EaseOut,
+                    };
+                    note.effect = Effect::Bend {
+                        cents: i16::from_le_bytes([low, high]),
+                        curve,
+                    };
+                    continue;
+                }
+                OP_CENTS => {
+                    cents = i16::from_le_bytes(tri!(self.array(packed)));
+                    continue;
+                }
+                _ => {
+                    if op & 0xf0 == OP_MARKS_LOW {
+                        note.marks |= op & 0xf;
+                    } else if op & 0xf0 == OP_MARKS_HIGH {
+                        note.marks |= (op & 0xf) << 4;
+                    } else {
+                        return None;
+                    }
+                    continue;
+                }
+            };
+            return Some(event);
+        }
+    }
+
+    const fn byte(&mut self, packed: &Packed) -> Option<u8> {
+        if self.offset >= packed.bytes.len() {
+            return None;
+        }
+        self.offset += 1;
+        Some(packed.bytes[self.offset - 1])
+    }
+
+    const fn array<const N: usize>(&mut self, packed: &Packed) -> Option<[u8; N]> {
+        let mut array = [0; N];
+        let mut i = 0;
+        while i < N {
+            array[i] = tri!(self.byte(packed));
+            i += 1;
+        }
+        Some(array)
+    }
+}
+
+/// Output of the encoder; bytes past the end of `out` are only counted, so
+/// that the same code measures and fills.
+struct Writer<'a> {
+    out: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    const fn put(&mut self, byte: u8) {
+        if self.len < self.out.len() {
+            self.out[self.len] = byte;
+        }
+        self.len += 1;
+    }
+
+    const fn put_all(&mut self, bytes: &[u8]) {
+        let mut i = 0;
+        while i < bytes.len() {
+            self.put(bytes[i]);
+            i += 1;
+        }
+    }
+}
+
+/// Packed size of `events` in bytes.
+pub const fn packed_len(events: &[Event]) -> usize {
+    encode(events, &mut [])
+}
+
+/// Packs `events`, `N` being their `packed_len`.
+pub const fn pack<const N: usize>(events: &[Event]) -> [u8; N] {
+    let mut bytes = [0; N];
+    if pack_into(events, &mut bytes) != N {
+        panic!("pack: wrong length");
+    }
+    bytes
+}
+
+/// Packs `events` into `out`, as far as it goes, returning their
+/// `packed_len`. For melodies built at run time.
+pub const fn pack_into(events: &[Event], out: &mut [u8]) -> usize {
+    encode(events, out)
+}
+
+/// Number of `Event::Section`s in `events`.
+pub const fn section_count(events: &[Event]) -> usize {
+    let mut count = 0;
+    let mut i = 0;
+    while i < events.len() {
+        if let Event::Section(_) = events[i] {
+            count += 1;
+        }
+        i += 1;
+    }
+    count
+}
+
+/// The sections `events` refer to, in order, `N` being their
+/// `section_count`.
+pub const fn sections<const N: usize>(events: &[Event]) -> [&'static [Event]; N] {
+    let mut sections: [&'static [Event]; N] = [&[]; N];
+    let mut count = 0;
+    let mut i = 0;
+    while i < events.len() {
+        if let Event::Section(section) = events[i] {
+            sections[count] = section;
+            count += 1;
+        }
+        i += 1;
+    }
+    sections
+}
+
+/// Whether `packed` decodes to exactly `events`.
+pub const fn round_trips(events: &[Event], packed: &Packed) -> bool {
+    let mut reader = Reader::new();
+    let mut i = 0;
+    while i < events.len() {
+        match reader.next(packed) {
+            Some(event) if same_event(&event, &events[i]) => {}
+            _ => return false,
+        }
+        i += 1;
+    }
+    reader.next(packed).is_none() && packed.len == events.len()
+}
+
+/// Writes `events` to `out` as far as it goes, returning the full length.
+const fn encode(events: &[Event], out: &mut [u8]) -> usize {
+    let mut w = Writer { out, len: 0 };
+    let mut key = 0;
+    let mut sections = 0;
+    let mut i = 0;
+    while i < events.len() {
+        match events[i] {
+            Event::Note(note) => {
+                key = encode_note(&mut w, &note, key);
+                // identical notes that follow make a run
+                let mut run = 0;
+                while run < u8::MAX as usize
+                    && i + 1 + run < events.len()
+                    && same_event(&events[i + 1 + run], &events[i])
+                {
+                    run += 1;
+                }
+                if run >= 2 {
+                    w.put_all(&[OP_RUN, run as u8]);
+                    i += run;
+                }
+            }
+            Event::Tempo(whole_ms) => {
+                w.put(OP_TEMPO);
+                w.put_all(&whole_ms.to_le_bytes());
+            }
+            Event::Time(beats, unit) => w.put_all(&[OP_TIME, beats, unit]),
+            Event::Repeat => w.put(OP_REPEAT),
+            Event::EndRepeat(times) => w.put_all(&[OP_END_REPEAT, times]),
+            Event::Volta(n) => w.put_all(&[OP_VOLTA, n]),
+            Event::Segno => w.put(OP_SEGNO),
+            Event::Coda => w.put(OP_CODA),
+            Event::ToCoda => w.put(OP_TO_CODA),
+            Event::Fine => w.put(OP_FINE),
+            Event::DaCapo => w.put(OP_DA_CAPO),
+            Event::DalSegno => w.put(OP_DAL_SEGNO),
+            Event::Section(_) => {
+                if sections > u8::MAX as usize {
+                    panic!("pack: too many sections");
+                }
+                w.put_all(&[OP_SECTION, sections as u8]);
+                sections += 1;
+            }
+        }
+        i += 1;
+    }
+    w.len
+}
+
+/// Writes `note`, returning the key for the next one to step from.
+const fn encode_note(w: &mut Writer, note: &Note, key: u8) -> u8 {
+    let mut code = 0;
+    while code < DIVS.len() && DIVS[code] != note.div {
+        code += 1;
+    }
+    if code == DIVS.len() {
+        w.put_all(&[OP_DIV, note.div as u8]);
+        code = 0;
+    }
+    if note.marks & 0xf != 0 {
+        w.put(OP_MARKS_LOW | (note.marks & 0xf));
+    }
+    if note.marks >> 4 != 0 {
+        w.put(OP_MARKS_HIGH | (note.marks >> 4));
+    }
+    match note.effect {
+        Effect::None => {}
+        Effect::Glide => w.put(OP_GLIDE),
+        Effect::Vibrato {
+            rate_hz,
+            depth_cents,
+        } => w.put_all(&[OP_VIBRATO, rate_hz, depth_cents]),
+        Effect::Bend { cents, curve } => {
+            let [low, high] = cents.to_le_bytes();
+            w.put_all(&[OP_BEND, low, high, curve as u8]);
+        }
+    }
+
+    let code = (code as u8) << 4;
+    match note.pitch {
+        Pitch::Rest => {
+            w.put(code | PITCH_REST);
+            key
+        }
+        Pitch::Hz(hz) => {
+            w.put(code | PITCH_HZ);
+            w.put_all(&hz.to_le_bytes());
+            key
+        }
+        Pitch::Key(next, cents) => {
+            if cents != 0 {
+                let [low, high] = cents.to_le_bytes();
+                w.put_all(&[OP_CENTS, low, high]);
+            }
+            let step = next as i32 - key as i32;
+            if step >= -STEP_MAX && step <= STEP_MAX {
+                w.put(code | (step + STEP_MAX) as u8);
+            } else {
+                w.put_all(&[code | PITCH_KEY, next]);
+            }
+            next
+        }
+    }
+}
+
+const fn same_event(a: &Event, b: &Event) -> bool {
+    match (*a, *b) {
+        (Event::Note(a), Event::Note(b)) => {
+            same_pitch(a.pitch, b.pitch)
+                && a.div == b.div
+                && a.marks == b.marks
+                && same_effect(a.effect, b.effect)
+        }
+        (Event::Tempo(a), Event::Tempo(b)) => a == b,
+        (Event::Time(a, b), Event::Time(c, d)) => a == c && b == d,
+        (Event::EndRepeat(a), Event::EndRepeat(b)) | (Event::Volta(a), Event::Volta(b)) => a == b,
+        (Event::Section(a), Event::Section(b)) => same_events(a, b),
+        (Event::Repeat, Event::Repeat)
+        | (Event::Segno, Event::Segno)
+        | (Event::Coda, Event::Coda)
+        | (Event::ToCoda, Event::ToCoda)
+        | (Event::Fine, Event::Fine)
+        | (Event::DaCapo, Event::DaCapo)
+        | (Event::DalSegno, Event::DalSegno) => true,
+        _ => false,
+    }
+}
+
+const fn same_events(a: &[Event], b: &[Event]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if !same_event(&a[i], &b[i]) {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+const fn same_pitch(a: Pitch, b: Pitch) -> bool {
+    match (a, b) {
+        (Pitch::Rest, Pitch::Rest) => true,
+        (Pitch::Key(a, c), Pitch::Key(b, d)) => a == b && c == d,
+        (Pitch::Hz(a), Pitch::Hz(b)) => a.to_bits() == b.to_bits(),
+        _ => false,
+    }
+}
+
+const fn same_effect(a: Effect, b: Effect) -> bool {
+    match (a, b) {
+        (Effect::None, Effect::None) | (Effect::Glide, Effect::Glide) => true,
+        (
+            Effect::Vibrato {
+                rate_hz: a,
+                depth_cents: b,
+            },
+            Effect::Vibrato {
+                rate_hz: c,
+                depth_cents: d,
+            },
+        ) => a == c && b == d,
+        (Effect::Bend { cents: a, curve: b }, Effect::Bend { cents: c, curve: d }) => {
+            a == c && b as u8 == d as u8
+        }
+        _ => false,
+    }
+}
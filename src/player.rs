@@ -1,41 +1,262 @@
-use core::cmp;
+use defmt::Format;
+use fugit::ExtU32;
 
-use bsp::hal::{
-    gpio::{Output, Pin, PushPull},
-    pwm, timer,
+pub use self::inner::PlayerTimer;
+#[cfg(not(feature = "synth"))]
+pub use self::inner::PlayerBuzzer;
+use crate::{
+    envelope::Envelope,
+    melody::{Effect, Melody, Position, Step},
+    shuffle::Shuffle,
+    tone::Pitch,
+    tuning::{self, Tuning},
+    waveform::Waveform,
 };
-use fugit::ExtU32;
 
-use self::inner::{PlayerBuzzer, PlayerTimer};
-use crate::{melody::Melody, tone::Tone};
+pub type Instant = fugit::Instant<u32, 1, 1_000_000>;
+pub type Duration = fugit::Duration<u32, 1, 1_000_000>;
+
+/// Voices a melody can have: the tune and its accompaniment.
+pub const MAX_VOICES: usize = 2;
 
-type Instant = fugit::Instant<u32, 1, 1_000_000>;
-type Duration = fugit::Duration<u32, 1, 1_000_000>;
+/// Melodies a `Playlist` can hold, built-in and uploaded ones together.
+pub const MAX_MELODIES: usize = 64;
+
+/// A list of melodies the player can own and have changed, see
+/// `Player::update_list`.
+pub type Playlist = heapless::Vec<Melody, MAX_MELODIES>;
 
 const DEFAULT_PLAY_DURATION: Duration = Duration::from_ticks(1 * 1000 * 1000);
 
+/// Bounds of the tempo scaling, in percent of the written tempo.
+pub const MIN_TEMPO: u32 = 50;
+pub const MAX_TEMPO: u32 = 200;
+
+/// How long each note of a chord sounds when an output with fewer voices
+/// has to arpeggiate it.
+const ARPEGGIO_DURATION: Duration = Duration::from_ticks(30 * 1000);
+
+/// How often glides, vibrato and bends move the pitch.
+const EFFECT_TICK: Duration = Duration::from_ticks(5 * 1000);
+
+/// What the player does at the end of a melody, and how `next` and `prev`
+/// move through the list.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PlayMode {
+    /// Plays the current melody over and over.
+    #[default]
+    RepeatOne,
+    /// Plays the list in order, starting over after the last melody.
+    RepeatAll,
+    /// Plays the list in order and stops after the last melody.
+    Once,
+    /// Plays the list in a random order, every melody once per round.
+    Shuffle,
+}
+
+impl PlayMode {
+    /// The mode after this one, cycling through all of them.
+    pub fn next(self) -> Self {
+        match self {
+            Self::RepeatOne => Self::RepeatAll,
+            Self::RepeatAll => Self::Once,
+            Self::Once => Self::Shuffle,
+            Self::Shuffle => Self::RepeatOne,
+        }
+    }
+
+    pub fn prev(self) -> Self {
+        match self {
+            Self::RepeatOne => Self::Shuffle,
+            Self::RepeatAll => Self::RepeatOne,
+            Self::Once => Self::RepeatAll,
+            Self::Shuffle => Self::Once,
+        }
+    }
+}
+
+/// Changes the player makes on its own, as opposed to those asked of it.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A melody ended and the one at the given position started.
+    Advance(usize),
+    /// The last melody ended in `PlayMode::Once`.
+    End,
+}
+
 enum State {
-    Play { pos: usize, progress: usize },
-    Pause { pos: usize, progress: usize },
+    Play { pos: usize, progress: Progress },
+    Pause { pos: usize, progress: Progress },
     Stop,
 }
 
-pub struct Player<'a, T: timer::Instance, P: pwm::Instance> {
-    list: &'a [Melody],
+#[derive(Clone, Copy, Default)]
+enum Gate {
+    /// Waiting to start the next note.
+    #[default]
+    Attack,
+    /// Sounding `Cursor::step`, waiting to release it.
+    Release,
+    /// No notes left in this voice.
+    Done,
+}
+
+/// Position of one voice within the melody.
+#[derive(Clone, Copy, Default)]
+struct Cursor {
+    pos: Position,
+    step: Option<Step>,
+    wait_us: u32,
+    gate: Gate,
+    /// Written time at which the last step read ends, in milliseconds from
+    /// the start of the melody.
+    end_ms: u32,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Progress {
+    voices: [Cursor; MAX_VOICES],
+    /// Time the clock was last set to wait for, and when.
+    scheduled_us: u32,
+    scheduled_at: u32,
+}
+
+/// What a note event did to a voice, for `Player::update_output`.
+#[derive(Clone, Copy, Default)]
+enum Change {
+    /// The voice carries on as it was.
+    #[default]
+    None,
+    /// A new note starts, at the given volume.
+    Attack(u32),
+    /// A tied note continues the previous one with its own effect.
+    Continue,
+    /// The voice falls silent.
+    Release,
+}
+
+/// Pitch effect of a sounding note, see `Effect`.
+#[derive(Clone, Copy, Default)]
+struct Sweep {
+    effect: Effect,
+    /// Frequency of the note as written.
+    freq: f32,
+    /// For a glide, the interval to the next note in cents.
+    glide_cents: f32,
+    started_at: u32,
+    length_us: u32,
+}
+
+impl Sweep {
+    fn is_active(&self) -> bool {
+        !matches!(self.effect, Effect::None)
+    }
+
+    /// Frequency at `now`, in clock ticks.
+    fn freq(&self, now: u32) -> f32 {
+        let elapsed = now.wrapping_sub(self.started_at);
+        let progress = match self.length_us {
+            0 => 1.0,
+            length => (elapsed as f32 / length as f32).min(1.0),
+        };
+        let cents = match self.effect {
+            Effect::None => 0.0,
+            Effect::Glide => self.glide_cents * progress,
+            Effect::Vibrato {
+                rate_hz,
+                depth_cents,
+            } => {
+                let phase = (elapsed as u64 * rate_hz as u64 * (1 << 32) / 1_000_000) as u32;
+                let sine = Waveform::Sine.sample(phase, 0) as f32 / i16::MAX as f32;
+                depth_cents as f32 * sine
+            }
+            Effect::Bend { cents, curve } => cents as f32 * curve.at(progress),
+        };
+        tuning::bend(self.freq, cents)
+    }
+}
+
+/// Sounds the notes picked by the `Player`.
+pub trait ToneOutput {
+    /// Number of tones the output can sound at once. The player arpeggiates
+    /// the voices of a melody when there are more of them than this.
+    const VOICES: usize = 1;
+
+    /// Starts a note of `freq` Hz on `voice`, replacing whatever that voice
+    /// played.
+    fn tone(&mut self, voice: usize, freq: f32, volume: u32);
+    /// Moves the note on `voice` to `freq` Hz without re-attacking it.
+    fn set_freq(&mut self, voice: usize, freq: f32);
+    /// Ends the note on `voice`, letting it fade out if the output has
+    /// envelopes.
+    fn release(&mut self, voice: usize);
+    /// Silences every voice.
+    fn stop(&mut self);
+    /// Changes the timbre of `voice`, if the output can.
+    fn set_waveform(&mut self, _voice: usize, _waveform: Waveform) {}
+    /// Changes the amplitude envelope of `voice`, if the output can.
+    fn set_envelope(&mut self, _voice: usize, _envelope: Envelope) {}
+    /// Services the output's own interrupt, if it has one.
+    fn handle_event(&mut self) {}
+}
+
+/// Free-running microsecond clock with two compare channels: `play` fires
+/// when the next note event is due, `tick` drives effects such as
+/// arpeggios and vibrato.
+pub trait PlayerClock {
+    fn start(&mut self);
+    fn stop(&mut self);
+    fn now(&self) -> Instant;
+    fn set_play_duration(&mut self, duration: Duration);
+    fn set_tick_duration(&mut self, duration: Duration);
+    fn check_play(&mut self) -> bool;
+    fn check_tick(&mut self) -> bool;
+}
+
+/// Plays melodies from a list, which can be borrowed (`&[Melody]`) or owned
+/// so that it can be changed in place, see `update_list`.
+pub struct Player<C: PlayerClock, B: ToneOutput, L: AsRef<[Melody]> = &'static [Melody]> {
+    list: L,
     state: State,
+    /// Written length of the melody at the state's position, worked out
+    /// once when it starts as it takes decoding the whole melody.
+    duration_ms: u32,
     volume: u32,
-    timer: PlayerTimer<T>,
-    buzzer: PlayerBuzzer<P>,
+    /// Percentage of `volume` actually played, lowered to duck the music.
+    duck: u32,
+    tempo: u32,
+    transpose: i32,
+    tuning: Tuning,
+    mode: PlayMode,
+    shuffle: Shuffle,
+    attach_event_fn: Option<fn(Event)>,
+    sounding: [Option<Pitch>; MAX_VOICES],
+    sweeps: [Sweep; MAX_VOICES],
+    arpeggio: usize,
+    /// When the arpeggio last moved on, in clock ticks.
+    arpeggio_at: u32,
+    timer: C,
+    buzzer: B,
 }
 
-impl<'a, T: timer::Instance, P: pwm::Instance> Player<'a, T, P> {
-    pub fn new(timer: T, pwm: P, pin: Pin<Output<PushPull>>, list: &'a [Melody]) -> Self {
-        let timer = PlayerTimer::new(timer);
-        let buzzer = PlayerBuzzer::new(pwm, pin);
+impl<C: PlayerClock, B: ToneOutput, L: AsRef<[Melody]>> Player<C, B, L> {
+    pub fn new(timer: C, buzzer: B, list: L) -> Self {
         Self {
             list,
             state: State::Stop,
+            duration_ms: 0,
             volume: 100,
+            duck: 100,
+            tempo: 100,
+            transpose: 0,
+            tuning: Tuning::DEFAULT,
+            mode: PlayMode::RepeatOne,
+            shuffle: Shuffle::new(0),
+            attach_event_fn: None,
+            sounding: [None; MAX_VOICES],
+            sweeps: [Sweep::default(); MAX_VOICES],
+            arpeggio: 0,
+            arpeggio_at: 0,
             timer,
             buzzer,
         }
@@ -49,44 +270,170 @@ impl<'a, T: timer::Instance, P: pwm::Instance> Player<'a, T, P> {
         self.volume = self.volume.saturating_sub(volume);
     }
 
+    /// Sets the volume in percent, from the next note on.
+    pub fn set_volume(&mut self, volume: u32) {
+        self.volume = volume.min(100);
+    }
+
     pub fn volume(&self) -> u32 {
         self.volume
     }
 
-    pub fn set_list(&mut self, list: &'a [Melody]) {
+    /// Plays at `percent` of the volume from the next note on, without
+    /// changing `volume`, to duck the music while the room is loud.
+    pub fn set_duck(&mut self, percent: u32) {
+        self.duck = percent.min(100);
+    }
+
+    /// The volume notes sound at, ducked.
+    fn output_volume(&self) -> u32 {
+        self.volume * self.duck / 100
+    }
+
+    /// Speeds playback up by `tempo` percent of the written tempo, from the
+    /// next note on.
+    pub fn tempo_add(&mut self, tempo: u32) {
+        self.tempo = self.tempo.saturating_add(tempo).min(MAX_TEMPO);
+    }
+
+    pub fn tempo_sub(&mut self, tempo: u32) {
+        self.tempo = self.tempo.saturating_sub(tempo).max(MIN_TEMPO);
+    }
+
+    /// Playback speed in percent of the written tempo.
+    pub fn tempo(&self) -> u32 {
+        self.tempo
+    }
+
+    /// Shifts every melody by `semitones` on top of its own transposition,
+    /// from the next note on. Notes pushed out of octaves 1-9 are folded
+    /// back by whole octaves.
+    pub fn set_transpose(&mut self, semitones: i32) {
+        self.transpose = semitones;
+    }
+
+    pub fn transpose(&self) -> i32 {
+        self.transpose
+    }
+
+    /// Sets the reference pitch and temperament, from the next note on.
+    pub fn set_tuning(&mut self, tuning: Tuning) {
+        self.tuning = tuning;
+    }
+
+    pub fn tuning(&self) -> &Tuning {
+        &self.tuning
+    }
+
+    pub fn set_mode(&mut self, mode: PlayMode) {
+        if mode == PlayMode::Shuffle && self.mode != mode {
+            self.shuffle.start(self.pos().unwrap_or(0), self.list.as_ref().len());
+        }
+        self.mode = mode;
+    }
+
+    pub fn mode(&self) -> PlayMode {
+        self.mode
+    }
+
+    /// Seeds the shuffle order, e.g. from the RNG peripheral.
+    pub fn seed_shuffle(&mut self, seed: u32) {
+        self.shuffle = Shuffle::new(seed);
+        self.shuffle.start(self.pos().unwrap_or(0), self.list.as_ref().len());
+    }
+
+    /// Calls `f` with the changes the player makes on its own, see `Event`.
+    pub fn attach_event(&mut self, f: fn(Event)) {
+        self.attach_event_fn = Some(f);
+    }
+
+    /// The melody being played or paused, if any.
+    pub fn current(&self) -> Option<&Melody> {
+        self.pos().and_then(|pos| self.list.as_ref().get(pos))
+    }
+
+    /// Index in the list of the melody being played or paused, if any.
+    pub fn index(&self) -> Option<usize> {
+        self.pos()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        matches!(self.state, State::Pause { .. })
+    }
+
+    pub fn is_playing(&self) -> bool {
+        matches!(self.state, State::Play { .. })
+    }
+
+    pub fn set_list(&mut self, list: L) {
+        self.update_list(|l| *l = list);
+    }
+
+    /// Stops, and lets `f` change the list.
+    pub fn update_list(&mut self, f: impl FnOnce(&mut L)) {
         self.stop();
-        self.list = list;
+        f(&mut self.list);
+        self.shuffle.start(0, self.list.as_ref().len());
+    }
+
+    pub fn list(&self) -> &[Melody] {
+        self.list.as_ref()
     }
 
     pub fn stop(&mut self) {
         self.timer.stop();
-        self.buzzer.stop();
+        self.silence();
         self.state = State::Stop;
     }
 
     pub fn play(&mut self) {
-        if let Some(next_state) = match self.state {
-            State::Stop => Some(State::Play {
-                pos: 0,
-                progress: 0,
-            }),
-            State::Pause { pos, progress } => Some(State::Play { pos, progress }),
-            _ => None,
-        } {
-            self.state = next_state;
-            self.timer.start();
-            self.timer.set_play_duration(DEFAULT_PLAY_DURATION);
+        match self.state {
+            State::Stop => self._start_play(0),
+            State::Pause { pos, mut progress } => {
+                self.timer.start();
+                // the notes the pause cut off sound again for what is left
+                // of them
+                let mut changes = [Change::None; MAX_VOICES];
+                if let Some(&melody) = self.list.as_ref().get(pos) {
+                    for (voice, cursor) in progress.voices.iter_mut().enumerate() {
+                        if let (Gate::Release, Some(step), 1..) =
+                            (cursor.gate, cursor.step, cursor.wait_us)
+                        {
+                            self.begin_step(&melody, voice, cursor, step, cursor.wait_us);
+                            changes[voice] = Change::Attack(self.output_volume());
+                        }
+                    }
+                }
+                self.state = State::Play { pos, progress };
+                if changes.iter().any(|c| matches!(c, Change::Attack(_))) {
+                    self.update_output(changes);
+                }
+                self.schedule();
+            }
+            _ => {}
         }
     }
 
     pub fn pause(&mut self) {
-        if let Some(next_state) = match self.state {
-            State::Play { pos, progress } => Some(State::Pause { pos, progress }),
-            _ => None,
-        } {
+        if let State::Play { pos, mut progress } = self.state {
+            // keep the part of the current wait that already elapsed
+            let elapsed = self.timer.now().ticks().wrapping_sub(progress.scheduled_at);
+            let elapsed = elapsed.min(progress.scheduled_us);
+            for cursor in progress.voices.iter_mut() {
+                cursor.wait_us = cursor.wait_us.saturating_sub(elapsed);
+            }
+            progress.scheduled_us = 0;
+
             self.timer.stop();
-            self.buzzer.stop();
-            self.state = next_state;
+            self.silence();
+            self.state = State::Pause { pos, progress };
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        match self.state {
+            State::Play { .. } => self.pause(),
+            State::Pause { .. } | State::Stop => self.play(),
         }
     }
 
@@ -100,115 +447,601 @@ impl<'a, T: timer::Instance, P: pwm::Instance> Player<'a, T, P> {
         self._start_play(prev_pos);
     }
 
+    /// Jumps to the start of note `index` of the tune, see `seek_ms`.
+    pub fn seek(&mut self, index: usize) {
+        if let Some(melody) = self.current() {
+            self.seek_ms(melody.offset_ms(index));
+        }
+    }
+
+    /// Jumps to the start of bar `bar` of the tune, see `Position::bar`.
+    pub fn seek_bar(&mut self, bar: u32) {
+        if let Some(melody) = self.current() {
+            self.seek_ms(melody.bar_offset_ms(bar));
+        }
+    }
+
+    /// Jumps to `ms` of written time into the current melody, resuming any
+    /// note that spans it. Past the end, the melody ends as it would have.
+    pub fn seek_ms(&mut self, ms: u32) {
+        let (pos, playing) = match self.state {
+            State::Play { pos, .. } => (pos, true),
+            State::Pause { pos, .. } => (pos, false),
+            State::Stop => return,
+        };
+        let Some(&melody) = self.list.as_ref().get(pos) else {
+            return;
+        };
+
+        self.silence();
+        let mut progress = Progress::default();
+        let mut changes = [Change::None; MAX_VOICES];
+        for (voice, cursor) in progress.voices.iter_mut().enumerate() {
+            let Some(step) = Self::find_step(&melody, voice, cursor, ms) else {
+                cursor.gate = Gate::Done;
+                continue;
+            };
+            // time into the step, and how much of it sounds
+            let into_us = (ms - (cursor.end_ms - step.duration_ms)) * 1000;
+            let sound_us = step.duration_ms * 10 * step.gate;
+            if into_us < sound_us {
+                let sound_us = self.scale_us(sound_us - into_us);
+                self.begin_step(&melody, voice, cursor, step, sound_us);
+                // even a tied note starts afresh, nothing sounds before it
+                changes[voice] = Change::Attack(self.output_volume());
+            } else {
+                cursor.wait_us = self.scale_us(step.duration_ms * 1000 - into_us);
+            }
+        }
+
+        if !playing {
+            self.sounding = [None; MAX_VOICES];
+            self.state = State::Pause { pos, progress };
+            return;
+        }
+        if matches!(progress.voices[0].gate, Gate::Done) {
+            self.end_melody(pos);
+            return;
+        }
+        self.state = State::Play { pos, progress };
+        self.update_output(changes);
+        self.schedule();
+    }
+
+    /// Written time reached in the current melody, in milliseconds, e.g. for
+    /// a progress bar along with `duration`.
+    pub fn position(&self) -> u32 {
+        let (State::Play { progress, .. } | State::Pause { progress, .. }) = &self.state else {
+            return 0;
+        };
+        let cursor = &progress.voices[0];
+        let mut left_us = cursor.wait_us;
+        if let State::Play { .. } = self.state {
+            let elapsed = self.timer.now().ticks().wrapping_sub(progress.scheduled_at);
+            left_us = left_us.saturating_sub(elapsed.min(progress.scheduled_us));
+        }
+        if let (Gate::Release, Some(step)) = (cursor.gate, cursor.step) {
+            left_us += self.scale_us(step.duration_ms * 10 * (100 - step.gate));
+        }
+        // back from real to written time
+        let left_ms = (left_us as u64 * self.tempo as u64 / 100_000) as u32;
+        cursor.end_ms.saturating_sub(left_ms)
+    }
+
+    /// Written length of the current melody in milliseconds, 0 if there is
+    /// none.
+    pub fn duration(&self) -> u32 {
+        match self.state {
+            State::Play { .. } | State::Pause { .. } => self.duration_ms,
+            State::Stop => 0,
+        }
+    }
+
+    /// Time signature of the tune at the note being played, if the melody
+    /// has set one by then, e.g. to show the beat along with `bar`.
+    pub fn time_signature(&self) -> Option<(u8, u8)> {
+        self.tune_position().and_then(|pos| pos.time_signature())
+    }
+
+    /// Bar of the tune the note being played is in, 0 if there is none.
+    pub fn bar(&self) -> u32 {
+        self.tune_position().map_or(0, |pos| pos.bar())
+    }
+
+    fn tune_position(&self) -> Option<&Position> {
+        match &self.state {
+            State::Play { progress, .. } | State::Pause { progress, .. } => {
+                Some(&progress.voices[0].pos)
+            }
+            State::Stop => None,
+        }
+    }
+
+    /// Sounds `pitch` on the first voice, as played on a keyboard rather
+    /// than read from a melody, pausing the melody. `volume` is in percent
+    /// of the player's volume; the player's tuning and transposition apply.
+    pub fn note_on(&mut self, pitch: Pitch, volume: u32) {
+        self.pause();
+        let freq = self.tuning.freq(pitch.transpose(self.transpose));
+        self.buzzer.tone(0, freq, self.output_volume() * volume.min(100) / 100);
+    }
+
+    /// Moves the note started by `note_on` to `pitch` without re-attacking
+    /// it, to bend it or to slide to another key.
+    pub fn move_note(&mut self, pitch: Pitch) {
+        let freq = self.tuning.freq(pitch.transpose(self.transpose));
+        self.buzzer.set_freq(0, freq);
+    }
+
+    /// Releases the note started by `note_on`.
+    pub fn note_off(&mut self) {
+        self.buzzer.release(0);
+    }
+
+    /// Services the output's interrupt, see `ToneOutput::handle_event`.
+    pub fn handle_output_event(&mut self) {
+        self.buzzer.handle_event();
+    }
+
     pub fn handle_play_event(&mut self) {
         defmt::debug!("player::tick {}", self.timer.now());
-        if let State::Play { pos, progress } = self.state {
-            let play_fired = self.timer.check_play();
-            let next_fired = self.timer.check_next();
-
-            if let Some(melody) = self.list.get(pos) {
-                if play_fired {
-                    let buzzer = &self.buzzer;
-                    let timer = &self.timer;
-                    if let Some((tone, delay_ms)) = melody.get(progress) {
-                        // play that note for 90% duration, leaving 10% pause
-                        buzzer.tone(tone, self.volume);
-                        timer.set_play_duration((delay_ms * 1_000).micros());
-                        timer.set_next_duration((delay_ms * 900).micros());
-                    } else {
-                        self._start_play(pos);
+        if !self.is_playing() {
+            return;
+        }
+
+        if self.timer.check_tick() {
+            self.handle_tick();
+        }
+        if self.timer.check_play() {
+            self.advance();
+        }
+    }
+
+    /// Moves the voices on by the wait scheduled last, starting and
+    /// releasing the notes that are then due.
+    fn advance(&mut self) {
+        let State::Play { pos, mut progress } = self.state else {
+            return;
+        };
+        let Some(&melody) = self.list.as_ref().get(pos) else {
+            return;
+        };
+
+        let elapsed = progress.scheduled_us;
+        let mut changes = [Change::None; MAX_VOICES];
+        for (voice, cursor) in progress.voices.iter_mut().enumerate() {
+            cursor.wait_us = cursor.wait_us.saturating_sub(elapsed);
+            while cursor.wait_us == 0 {
+                match cursor.gate {
+                    Gate::Attack => match melody.next_step(voice, &mut cursor.pos) {
+                        Some(step) => {
+                            // hold the note for the articulation's share of its duration
+                            let sound_us = self.scale_us(step.duration_ms * 10 * step.gate);
+                            cursor.end_ms += step.duration_ms;
+                            changes[voice] = self.begin_step(&melody, voice, cursor, step, sound_us);
+                        }
+                        None => {
+                            self.sounding[voice] = None;
+                            changes[voice] = Change::Release;
+                            cursor.gate = Gate::Done;
+                        }
+                    },
+                    Gate::Release => {
+                        let step = cursor.step.take();
+                        let gap_us = step.map_or(0, |s| s.duration_ms * 10 * (100 - s.gate));
+                        // a tied note carries on into the next one
+                        if step.is_none_or(|s| s.gate != 100) {
+                            self.sounding[voice] = None;
+                            changes[voice] = Change::Release;
+                        }
+                        cursor.wait_us = self.scale_us(gap_us);
+                        cursor.gate = Gate::Attack;
                     }
-                } else if next_fired {
-                    self.state = State::Play {
-                        pos,
-                        progress: progress + 1,
-                    };
-                    self.buzzer.stop();
+                    Gate::Done => break,
                 }
             }
         }
+
+        if matches!(progress.voices[0].gate, Gate::Done) {
+            self.end_melody(pos);
+            return;
+        }
+
+        self.state = State::Play { pos, progress };
+        self.update_output(changes);
+        self.schedule();
     }
 
-    fn prev_pos(&self) -> usize {
-        let max_pos = self.list.len() - 1;
-        let pos = match self.state {
-            State::Play { pos, .. } => pos,
-            State::Pause { pos, .. } => pos,
-            State::Stop => 0,
+    /// Starts sounding `step`, which `cursor` has just read from `voice`, for
+    /// `sound_us` of real time.
+    fn begin_step(
+        &mut self,
+        melody: &Melody,
+        voice: usize,
+        cursor: &mut Cursor,
+        step: Step,
+        sound_us: u32,
+    ) -> Change {
+        let transpose = melody.transpose() as i32 + self.transpose;
+        let pitch = step.pitch.transpose(transpose);
+        self.sounding[voice] = (!pitch.is_rest()).then_some(pitch);
+        self.sweeps[voice] = Sweep {
+            started_at: self.timer.now().ticks(),
+            length_us: sound_us,
+            ..self.sweep(melody, voice, &cursor.pos, &step, transpose)
         };
-
-        if pos == 0 {
-            max_pos
+        cursor.wait_us = sound_us;
+        cursor.step = Some(step);
+        cursor.gate = Gate::Release;
+        if step.attack {
+            Change::Attack(self.output_volume())
         } else {
-            pos - 1
+            Change::Continue
         }
     }
 
-    fn next_pos(&self) -> usize {
-        let max_pos = self.list.len() - 1;
-        let pos = match self.state {
-            State::Play { pos, .. } => pos,
-            State::Pause { pos, .. } => pos,
-            State::Stop => 0,
+    /// Moves on from the melody at `pos`, which has just ended, as the play
+    /// mode says.
+    fn end_melody(&mut self, pos: usize) {
+        let next_pos = match self.mode {
+            PlayMode::RepeatOne => pos,
+            PlayMode::Once if pos + 1 >= self.list.as_ref().len() => {
+                self.stop();
+                self.emit(Event::End);
+                return;
+            }
+            _ => self.next_pos(),
         };
+        self._start_play(next_pos);
+        if next_pos != pos {
+            self.emit(Event::Advance(next_pos));
+        }
+    }
 
-        if pos == max_pos {
-            0
-        } else {
-            pos + 1
+    fn pos(&self) -> Option<usize> {
+        match self.state {
+            State::Play { pos, .. } | State::Pause { pos, .. } => Some(pos),
+            State::Stop => None,
+        }
+    }
+
+    fn prev_pos(&mut self) -> usize {
+        let max_pos = self.list.as_ref().len().saturating_sub(1);
+        let pos = self.pos().unwrap_or(0);
+
+        match self.mode {
+            PlayMode::Shuffle => self.shuffle.prev(pos),
+            PlayMode::Once if pos == 0 => 0,
+            _ if pos == 0 => max_pos,
+            _ => pos - 1,
+        }
+    }
+
+    fn next_pos(&mut self) -> usize {
+        let max_pos = self.list.as_ref().len().saturating_sub(1);
+        let pos = self.pos().unwrap_or(0);
+
+        match self.mode {
+            PlayMode::Shuffle => self.shuffle.next(pos),
+            _ if pos == max_pos => 0,
+            _ => pos + 1,
+        }
+    }
+
+    fn emit(&self, event: Event) {
+        if let Some(f) = self.attach_event_fn {
+            f(event);
         }
     }
 
     fn _start_play(&mut self, pos: usize) {
         self.stop();
+        self.duration_ms = 0;
+        if let Some(melody) = self.list.as_ref().get(pos) {
+            for voice in 0..MAX_VOICES {
+                self.buzzer.set_waveform(voice, melody.waveform(voice));
+                self.buzzer.set_envelope(voice, melody.envelope(voice));
+            }
+            self.duration_ms = melody.duration_ms();
+        }
         self.state = State::Play {
-            pos: pos,
-            progress: 0,
+            pos,
+            progress: Progress::default(),
         };
         self.timer.start();
         self.timer.set_play_duration(DEFAULT_PLAY_DURATION);
     }
+
+    /// Reads `voice` up to the step that spans `ms`, leaving `cursor` right
+    /// after it.
+    fn find_step(melody: &Melody, voice: usize, cursor: &mut Cursor, ms: u32) -> Option<Step> {
+        loop {
+            let step = melody.next_step(voice, &mut cursor.pos)?;
+            cursor.end_ms += step.duration_ms;
+            if cursor.end_ms > ms {
+                return Some(step);
+            }
+        }
+    }
+
+    /// Converts a written duration to real time at the current tempo.
+    fn scale_us(&self, us: u32) -> u32 {
+        (us as u64 * 100 / self.tempo as u64) as u32
+    }
+
+    /// Sets up the effect of `step`, the note `voice` has just reached at
+    /// `pos`.
+    fn sweep(
+        &self,
+        melody: &Melody,
+        voice: usize,
+        pos: &Position,
+        step: &Step,
+        transpose: i32,
+    ) -> Sweep {
+        let freq = self.tuning.freq(step.pitch.transpose(transpose));
+        let mut effect = step.effect;
+        let mut glide_cents = 0.0;
+        if let Effect::Glide = effect {
+            // peek at the note to slide to
+            let mut next = *pos;
+            let to = melody.next_step(voice, &mut next).map_or(0.0, |next| {
+                self.tuning.freq(next.pitch.transpose(transpose))
+            });
+            if freq > 0.0 && to > 0.0 {
+                glide_cents = tuning::interval(freq, to);
+            } else {
+                effect = Effect::None;
+            }
+        }
+        Sweep {
+            effect,
+            freq,
+            glide_cents,
+            ..Sweep::default()
+        }
+    }
+
+    /// Arms the clock for the earliest pending note event, or handles it
+    /// right away if it is already due, e.g. after a pause that came just
+    /// as it was: a compare set to the current count would not fire until
+    /// the timer wraps around.
+    fn schedule(&mut self) {
+        if let State::Play { progress, .. } = &mut self.state {
+            let wait_us = progress
+                .voices
+                .iter()
+                .filter(|c| !matches!(c.gate, Gate::Done))
+                .map(|c| c.wait_us)
+                .min()
+                .unwrap_or(0);
+            progress.scheduled_us = wait_us;
+            progress.scheduled_at = self.timer.now().ticks();
+            if wait_us == 0 {
+                // leaves every voice waiting, or done
+                self.advance();
+            } else {
+                self.timer.set_play_duration(wait_us.micros());
+            }
+        }
+    }
+
+    /// Pushes the sounding voices to the output, arpeggiating them if the
+    /// output has too few voices. Only voices in `changes` start a new note
+    /// or retune; the others keep sounding.
+    fn update_output(&mut self, changes: [Change; MAX_VOICES]) {
+        if B::VOICES >= MAX_VOICES {
+            for (voice, pitch) in self.sounding.iter().enumerate() {
+                let freq = self.sweeps[voice].freq;
+                match (pitch, changes[voice]) {
+                    (Some(_), Change::Attack(volume)) => self.buzzer.tone(voice, freq, volume),
+                    // drop whatever the previous note's effect left the pitch at
+                    (Some(_), Change::Continue) => self.buzzer.set_freq(voice, freq),
+                    (Some(_), Change::None | Change::Release) => {}
+                    (None, _) => self.buzzer.release(voice),
+                }
+            }
+        } else {
+            self.update_arpeggio(changes);
+        }
+        self.schedule_tick();
+    }
+
+    /// Updates the single output voice for `changes`: a note that starts
+    /// takes it over, and the arpeggio only moves on early when the voice it
+    /// is on falls silent. The other voices are picked up at the next tick.
+    fn update_arpeggio(&mut self, changes: [Change; MAX_VOICES]) {
+        let attack = (1..=MAX_VOICES)
+            .map(|i| (self.arpeggio + i) % MAX_VOICES)
+            .find_map(|voice| match (self.sounding[voice], changes[voice]) {
+                (Some(_), Change::Attack(volume)) => Some((voice, volume)),
+                _ => None,
+            });
+        if let Some((voice, volume)) = attack {
+            self.arpeggio = voice;
+            self.arpeggio_at = self.timer.now().ticks();
+            self.buzzer.tone(0, self.sweeps[voice].freq, volume);
+        } else if self.sounding[self.arpeggio].is_none() {
+            // a rest starting counts as the voice falling silent too
+            if !matches!(changes[self.arpeggio], Change::None) {
+                self.arpeggiate();
+            }
+        } else if let Change::Continue = changes[self.arpeggio] {
+            // drop whatever the previous note's effect left the pitch at
+            self.buzzer.set_freq(0, self.sweeps[self.arpeggio].freq);
+        }
+    }
+
+    /// Moves the arpeggio on when it is due and updates the pitch of the
+    /// sounding notes with effects.
+    fn handle_tick(&mut self) {
+        let now = self.timer.now().ticks();
+        if B::VOICES >= MAX_VOICES {
+            for voice in 0..MAX_VOICES {
+                if self.sounding[voice].is_some() && self.sweeps[voice].is_active() {
+                    self.buzzer.set_freq(voice, self.sweeps[voice].freq(now));
+                }
+            }
+        } else if now.wrapping_sub(self.arpeggio_at) >= ARPEGGIO_DURATION.ticks() {
+            self.arpeggiate();
+        } else if self.sounding[self.arpeggio].is_some() {
+            let sweep = &self.sweeps[self.arpeggio];
+            if sweep.is_active() {
+                self.buzzer.set_freq(0, sweep.freq(now));
+            }
+        }
+        self.schedule_tick();
+    }
+
+    /// Arms the tick for effects if a sounding note has one, or for the
+    /// arpeggio if there is a chord to arpeggiate.
+    fn schedule_tick(&mut self) {
+        let effects =
+            (0..MAX_VOICES).any(|v| self.sounding[v].is_some() && self.sweeps[v].is_active());
+        let chord = B::VOICES < MAX_VOICES && self.sounding.iter().flatten().count() > 1;
+        if effects {
+            self.timer.set_tick_duration(EFFECT_TICK);
+        } else if chord {
+            self.timer.set_tick_duration(ARPEGGIO_DURATION);
+        }
+    }
+
+    /// Sounds the next of the sounding voices on the first output voice.
+    fn arpeggiate(&mut self) {
+        let count = self.sounding.iter().flatten().count();
+        if count == 0 {
+            self.buzzer.release(0);
+            return;
+        }
+
+        self.arpeggio = (self.arpeggio + 1) % MAX_VOICES;
+        while self.sounding[self.arpeggio].is_none() {
+            self.arpeggio = (self.arpeggio + 1) % MAX_VOICES;
+        }
+        let now = self.timer.now().ticks();
+        if self.sounding[self.arpeggio].is_some() {
+            self.buzzer
+                .tone(0, self.sweeps[self.arpeggio].freq(now), self.output_volume());
+        }
+        self.arpeggio_at = now;
+    }
+
+    fn silence(&mut self) {
+        self.sounding = [None; MAX_VOICES];
+        self.buzzer.stop();
+    }
+}
+
+/// Compare value for `volume` percent on a plain buzzer counting up to
+/// `max_duty`, which gets louder towards a 50% duty cycle. It cannot shape a
+/// note over time, so it holds the level an envelope sustains at, scaling
+/// the range from 20% to 50% duty.
+#[cfg_attr(feature = "synth", allow(dead_code))]
+pub fn buzzer_duty(max_duty: u16, volume: u32, envelope: &Envelope) -> u16 {
+    let volume = volume.min(100) * envelope.sustain.min(100) as u32 / 100;
+    let max_duty = max_duty as u32;
+    ((max_duty * 20 + max_duty * 30 * volume / 100) / 100) as u16
 }
 
 mod inner {
-    use super::*;
+    use bsp::hal::timer;
+    #[cfg(not(feature = "synth"))]
+    use bsp::hal::{
+        gpio::{Output, Pin, PushPull},
+        pwm,
+    };
+
+    use super::{Duration, Instant, PlayerClock};
+    #[cfg(not(feature = "synth"))]
+    use super::{buzzer_duty, Envelope, ToneOutput};
 
-    pub(super) struct PlayerBuzzer<T: pwm::Instance>(pwm::Pwm<T>);
+    /// Single-voice output driving the speaker with a plain square wave, at
+    /// the sustain level of the tune's envelope.
+    #[cfg(not(feature = "synth"))]
+    pub struct PlayerBuzzer<T: pwm::Instance>(pwm::Pwm<T>, Envelope);
 
+    #[cfg(not(feature = "synth"))]
     impl<T: pwm::Instance> PlayerBuzzer<T> {
         pub fn new(pwm: T, pin: Pin<Output<PushPull>>) -> Self {
             let buzzer = pwm::Pwm::new(pwm);
             buzzer
                 .set_counter_mode(pwm::CounterMode::UpAndDown)
+                .set_prescaler(pwm::Prescaler::Div1)
                 .set_output_pin(pwm::Channel::C0, pin)
                 .disable();
-            Self(buzzer)
+            Self(buzzer, Envelope::GATE)
+        }
+
+        /// Sets the period for `freq` Hz, picking the fastest PWM clock that
+        /// still fits it so that it is as precise as possible.
+        fn update_freq(&self, freq: f32) {
+            const PRESCALERS: [pwm::Prescaler; 8] = [
+                pwm::Prescaler::Div1,
+                pwm::Prescaler::Div2,
+                pwm::Prescaler::Div4,
+                pwm::Prescaler::Div8,
+                pwm::Prescaler::Div16,
+                pwm::Prescaler::Div32,
+                pwm::Prescaler::Div64,
+                pwm::Prescaler::Div128,
+            ];
+            // counting up and down, a period spans twice the counter top
+            let mut top = 16_000_000.0 / (2.0 * freq);
+            let mut prescaler = 0;
+            while top > 32767.0 && prescaler < PRESCALERS.len() - 1 {
+                top /= 2.0;
+                prescaler += 1;
+            }
+            self.0.set_prescaler(PRESCALERS[prescaler]);
+            self.0.set_max_duty((top + 0.5).min(32767.0) as u16);
+        }
+
+        #[inline(always)]
+        fn update_volume(&self, volume: u32) {
+            let duty = buzzer_duty(self.0.max_duty(), volume, &self.1);
+            self.0.set_duty_on(pwm::Channel::C0, duty);
         }
+    }
 
-        pub fn tone(&self, tone: Tone, volume: u32) {
+    #[cfg(not(feature = "synth"))]
+    impl<T: pwm::Instance> ToneOutput for PlayerBuzzer<T> {
+        fn tone(&mut self, _voice: usize, freq: f32, volume: u32) {
             self.0.disable();
-            if tone != Tone::REST {
-                self.0.set_period(tone.hz());
+            if freq > 0.0 {
+                self.update_freq(freq);
                 self.update_volume(volume);
                 self.0.enable();
             }
         }
 
-        pub fn stop(&self) {
+        fn set_freq(&mut self, _voice: usize, freq: f32) {
+            if freq > 0.0 {
+                // keep the duty cycle, and with it the volume
+                let share = self.0.duty_on(pwm::Channel::C0) as f32 / self.0.max_duty() as f32;
+                self.update_freq(freq);
+                self.0
+                    .set_duty_on(pwm::Channel::C0, (self.0.max_duty() as f32 * share) as u16);
+            }
+        }
+
+        fn release(&mut self, _voice: usize) {
             self.0.disable();
         }
 
-        #[inline(always)]
-        fn update_volume(&self, volume: u32) {
-            let max_duty = self.0.max_duty() as f32;
-            let min_vol = max_duty * 0.2;
-            let max_vol = max_duty * 0.5;
-            let vol = (max_vol - min_vol) * (volume as f32 / 100_f32);
-            self.0.set_duty_on(pwm::Channel::C0, (min_vol + vol) as u16);
+        fn stop(&mut self) {
+            self.0.disable();
+        }
+
+        /// Keeps the tune's envelope, which the arpeggiated accompaniment
+        /// shares, from the next note on.
+        fn set_envelope(&mut self, voice: usize, envelope: Envelope) {
+            if voice == 0 {
+                self.1 = envelope;
+            }
         }
     }
 
-    pub(super) struct PlayerTimer<T: timer::Instance>(T);
+    pub struct PlayerTimer<T: timer::Instance>(T);
 
     impl<T: timer::Instance> PlayerTimer<T> {
         pub fn new(timer: T) -> Self {
@@ -222,57 +1055,59 @@ mod inner {
             Self(timer)
         }
 
-        pub fn start(&self) {
+        #[inline(always)]
+        fn set_duration_for_cc(&self, pos: usize, duration: Duration) {
+            let timer = self.0.as_timer0();
+            let now = self.now();
+            let instant = now + duration;
+            timer.cc[pos].write(|w| unsafe { w.cc().bits(instant.duration_since_epoch().ticks()) });
+        }
+
+        #[inline(always)]
+        fn check_fired_for_cc(&self, pos: usize) -> bool {
+            let timer = self.0.as_timer0();
+            let reg = &timer.events_compare[pos];
+            let fired = reg.read().bits() != 0;
+            if fired {
+                reg.reset();
+            }
+            fired
+        }
+    }
+
+    impl<T: timer::Instance> PlayerClock for PlayerTimer<T> {
+        fn start(&mut self) {
             let timer = self.0.as_timer0();
             timer.tasks_start.write(|w| unsafe { w.bits(1) });
         }
 
-        pub fn stop(&self) {
+        fn stop(&mut self) {
             let timer = self.0.as_timer0();
             timer.tasks_stop.write(|w| unsafe { w.bits(1) });
             timer.tasks_clear.write(|w| unsafe { w.bits(1) });
         }
 
-        pub fn set_play_duration(&self, duration: Duration) {
+        fn set_play_duration(&mut self, duration: Duration) {
             self.set_duration_for_cc(1, duration)
         }
 
-        pub fn set_next_duration(&self, duration: Duration) {
+        fn set_tick_duration(&mut self, duration: Duration) {
             self.set_duration_for_cc(2, duration)
         }
 
-        pub fn check_play(&self) -> bool {
+        fn check_play(&mut self) -> bool {
             self.check_fired_for_cc(1)
         }
 
-        pub fn check_next(&self) -> bool {
+        fn check_tick(&mut self) -> bool {
             self.check_fired_for_cc(2)
         }
 
         #[inline(always)]
-        pub fn now(&self) -> Instant {
+        fn now(&self) -> Instant {
             let timer = self.0.as_timer0();
             timer.tasks_capture[0].write(|w| unsafe { w.bits(1) });
             Instant::from_ticks(timer.cc[0].read().bits())
         }
-
-        #[inline(always)]
-        fn set_duration_for_cc(&self, pos: usize, duration: Duration) {
-            let timer = self.0.as_timer0();
-            let now = self.now();
-            let instant = now + duration;
-            timer.cc[pos].write(|w| unsafe { w.cc().bits(instant.duration_since_epoch().ticks()) });
-        }
-
-        #[inline(always)]
-        fn check_fired_for_cc(&self, pos: usize) -> bool {
-            let timer = self.0.as_timer0();
-            let reg = &timer.events_compare[pos];
-            let fired = reg.read().bits() != 0;
-            if fired {
-                reg.reset();
-            }
-            fired
-        }
     }
 }
@@ -0,0 +1,357 @@
+//! The radio and a timer carrying `ble::Peripheral`'s packets: advertising
+//! until a central connects, then one exchange per connection event, the
+//! central's packet answered by ours after the 150 us inter-frame space.
+pub use self::inner::Radio;
+
+mod inner {
+    use bsp::hal::timer;
+    use bsp::pac::{PPI, RADIO};
+
+    use crate::ble::{self, Packet, Peripheral, MAX_PAYLOAD};
+
+    /// Time between advertising events, and the most added to it at random
+    /// so that advertisers do not keep colliding.
+    const ADV_INTERVAL_US: u32 = 100_000;
+    const ADV_JITTER_US: u32 = 10_000;
+
+    /// How long to listen for a connection request after an advertisement.
+    const ADV_LISTEN_US: u32 = 300;
+
+    /// Lead of enabling the receiver on the start of a packet: its fast
+    /// ramp-up and some margin.
+    const RX_LEAD_US: u32 = 50;
+
+    /// Time from the start of a packet to its ADDRESS event: preamble and
+    /// access address at 1 Mbit/s.
+    const ADDRESS_US: u32 = 40;
+
+    /// Least time to schedule the start of a connection event ahead of.
+    const MIN_LEAD_US: u32 = 20;
+
+    // Compare and capture registers of the timer
+    const CC_RXEN: usize = 0;
+    const CC_TIMEOUT: usize = 1;
+    const CC_ADDRESS: usize = 2;
+    const CC_END: usize = 3;
+    const CC_ADVERTISE: usize = 4;
+    const CC_NOW: usize = 5;
+
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    enum State {
+        /// Waiting for the next advertising event.
+        Idle,
+        /// Sending the advertisement on the advertising channel with this
+        /// index.
+        Advertise(usize),
+        /// Listening for a connection request after it.
+        Listen(usize),
+        /// Waiting for the central's packet of a connection event.
+        Receive,
+        /// Sending the answer to it.
+        Transmit,
+        /// Giving up on a connection event the central was not heard in.
+        Missed,
+    }
+
+    /// Drives the RADIO peripheral and a free-running 1 MHz timer; uses PPI
+    /// channels 0-2 to start receiving and to timestamp packets.
+    pub struct Radio<T: timer::Instance> {
+        radio: RADIO,
+        timer: T,
+        peripheral: Peripheral,
+        /// Advertisements and received packets.
+        packet: Packet,
+        state: State,
+        /// For the random part of the advertising interval.
+        seed: u32,
+    }
+
+    impl<T: timer::Instance> Radio<T> {
+        /// Sets up the radio for Bluetooth LE at 1 Mbit/s; advertising starts
+        /// with the timer's interrupt. The high-frequency clock has to run
+        /// from the crystal oscillator.
+        pub fn new(radio: RADIO, timer: T, peripheral: Peripheral, seed: u32) -> Self {
+            radio.mode.write(|w| w.mode().ble_1mbit());
+            radio.modecnf0.write(|w| w.ru().fast().dtx().center());
+            radio.txpower.write(|w| w.txpower()._0d_bm());
+            // S0 holds the header byte, then comes the 8-bit length
+            radio.pcnf0.write(|w| unsafe {
+                w.lflen()
+                    .bits(8)
+                    .s0len()
+                    .set_bit()
+                    .s1len()
+                    .bits(0)
+                    .plen()
+                    ._8bit()
+            });
+            radio.pcnf1.write(|w| unsafe {
+                w.maxlen()
+                    .bits(MAX_PAYLOAD as u8)
+                    .statlen()
+                    .bits(0)
+                    .balen()
+                    .bits(3)
+                    .endian()
+                    .little()
+                    .whiteen()
+                    .enabled()
+            });
+            radio.crccnf.write(|w| w.len().three().skipaddr().skip());
+            radio
+                .crcpoly
+                .write(|w| unsafe { w.crcpoly().bits(ble::CRC_POLY) });
+            radio.tifs.write(|w| unsafe { w.tifs().bits(150) });
+            radio.txaddress.write(|w| unsafe { w.txaddress().bits(0) });
+            radio.rxaddresses.write(|w| w.addr0().enabled());
+            radio.intenset.write(|w| w.disabled().set());
+
+            let timer0 = timer.as_timer0();
+            timer0.tasks_stop.write(|w| unsafe { w.bits(1) });
+            timer0.tasks_clear.write(|w| unsafe { w.bits(1) });
+            timer0.bitmode.write(|w| w.bitmode()._32bit());
+            timer0.prescaler.write(|w| unsafe { w.prescaler().bits(4) }); // 1 Mhz
+            timer0
+                .intenset
+                .write(|w| w.compare1().set().compare4().set());
+
+            // SAFETY: nothing else uses the PPI
+            let ppi = unsafe { &*PPI::ptr() };
+            let connect = |channel: usize, event: u32, task: u32| {
+                ppi.ch[channel].eep.write(|w| unsafe { w.bits(event) });
+                ppi.ch[channel].tep.write(|w| unsafe { w.bits(task) });
+            };
+            connect(
+                0,
+                &timer0.events_compare[CC_RXEN] as *const _ as u32,
+                &radio.tasks_rxen as *const _ as u32,
+            );
+            connect(
+                1,
+                &radio.events_address as *const _ as u32,
+                &timer0.tasks_capture[CC_ADDRESS] as *const _ as u32,
+            );
+            connect(
+                2,
+                &radio.events_end as *const _ as u32,
+                &timer0.tasks_capture[CC_END] as *const _ as u32,
+            );
+            // receiving on a timer compare is only wanted while connected
+            ppi.chenset.write(|w| w.ch1().set().ch2().set());
+
+            timer0.tasks_start.write(|w| unsafe { w.bits(1) });
+            let radio = Self {
+                radio,
+                timer,
+                peripheral,
+                packet: [0; 2 + MAX_PAYLOAD],
+                state: State::Idle,
+                seed,
+            };
+            radio.set_compare(CC_ADVERTISE, radio.now().wrapping_add(ADV_INTERVAL_US));
+            radio
+        }
+
+        pub fn peripheral(&mut self) -> &mut Peripheral {
+            &mut self.peripheral
+        }
+
+        /// Services the radio's interrupt, raised whenever it is disabled
+        /// after sending or receiving.
+        pub fn handle_radio_event(&mut self) {
+            self.radio.events_disabled.reset();
+            match self.state {
+                State::Advertise(index) => {
+                    // the radio turns to receiving by itself
+                    self.radio
+                        .shorts
+                        .write(|w| w.ready_start().enabled().end_disable().enabled());
+                    self.radio.events_address.reset();
+                    self.radio.events_end.reset();
+                    self.set_compare(CC_TIMEOUT, self.now().wrapping_add(ADV_LISTEN_US));
+                    self.state = State::Listen(index);
+                }
+                State::Listen(index) => {
+                    let received = self.radio.events_end.read().bits() != 0 && self.crc_ok();
+                    let end_us = self.captured(CC_END);
+                    if received && self.peripheral.connect(&self.packet, end_us) {
+                        self.schedule_event();
+                    } else if index + 1 < ble::ADV_CHANNELS.len() {
+                        self.advertise(index + 1);
+                    } else {
+                        self.state = State::Idle;
+                        self.seed = self
+                            .seed
+                            .wrapping_mul(1_664_525)
+                            .wrapping_add(1_013_904_223);
+                        let interval = ADV_INTERVAL_US + (self.seed >> 16) % ADV_JITTER_US;
+                        self.set_compare(CC_ADVERTISE, self.now().wrapping_add(interval));
+                    }
+                }
+                State::Receive => {
+                    // the radio turns to sending by itself, the reply has to
+                    // be in place within the inter-frame space
+                    self.radio
+                        .shorts
+                        .write(|w| w.ready_start().enabled().end_disable().enabled());
+                    let rx = self.crc_ok().then_some(&self.packet);
+                    let start_us = self.captured(CC_ADDRESS).wrapping_sub(ADDRESS_US);
+                    let tx = self.peripheral.exchange(rx, start_us);
+                    self.radio
+                        .packetptr
+                        .write(|w| unsafe { w.bits(tx.as_ptr() as u32) });
+                    self.state = State::Transmit;
+                }
+                State::Transmit => {
+                    self.peripheral.process();
+                    self.schedule_event();
+                }
+                State::Missed => self.schedule_event(),
+                State::Idle => {}
+            }
+        }
+
+        /// Services the timer's interrupt: the start of an advertising
+        /// event, or the end of the time to wait for a packet.
+        pub fn handle_timer_event(&mut self) {
+            if self.take_compare(CC_ADVERTISE) && self.state == State::Idle {
+                self.advertise(0);
+            }
+            if self.take_compare(CC_TIMEOUT) {
+                let heard = self.radio.events_address.read().bits() != 0;
+                match self.state {
+                    State::Listen(_) if !heard => self.disable(),
+                    State::Receive if !heard => {
+                        self.state = State::Missed;
+                        self.disable();
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        /// Sends the advertisement on the channel at `index` of
+        /// `ble::ADV_CHANNELS`, then listens for a connection request into
+        /// the same buffer.
+        fn advertise(&mut self, index: usize) {
+            let (access_address, crc_init) = self.peripheral.access_address();
+            self.tune(ble::ADV_CHANNELS[index], access_address, crc_init);
+            self.peripheral.advertisement(&mut self.packet);
+            self.radio
+                .packetptr
+                .write(|w| unsafe { w.bits(self.packet.as_ptr() as u32) });
+            self.radio.shorts.write(|w| {
+                w.ready_start()
+                    .enabled()
+                    .end_disable()
+                    .enabled()
+                    .disabled_rxen()
+                    .enabled()
+            });
+            self.state = State::Advertise(index);
+            self.radio.tasks_txen.write(|w| unsafe { w.bits(1) });
+        }
+
+        /// Arms the receiver for the next connection event the timer can
+        /// still make, or goes back to advertising once disconnected.
+        fn schedule_event(&mut self) {
+            // SAFETY: nothing else uses the PPI
+            let ppi = unsafe { &*PPI::ptr() };
+            let timing = loop {
+                let Some(timing) = self.peripheral.next_event() else {
+                    ppi.chenclr.write(|w| w.ch0().clear());
+                    self.state = State::Idle;
+                    self.set_compare(CC_ADVERTISE, self.now().wrapping_add(ADV_INTERVAL_US));
+                    return;
+                };
+                let lead = timing
+                    .start_us
+                    .wrapping_sub(RX_LEAD_US)
+                    .wrapping_sub(self.now());
+                if (lead as i32) >= MIN_LEAD_US as i32 {
+                    break timing;
+                }
+            };
+            let (access_address, crc_init) = self.peripheral.access_address();
+            self.tune(timing.channel, access_address, crc_init);
+            self.radio
+                .packetptr
+                .write(|w| unsafe { w.bits(self.packet.as_ptr() as u32) });
+            self.radio.shorts.write(|w| {
+                w.ready_start()
+                    .enabled()
+                    .end_disable()
+                    .enabled()
+                    .disabled_txen()
+                    .enabled()
+            });
+            self.radio.events_address.reset();
+            self.state = State::Receive;
+            self.set_compare(CC_RXEN, timing.start_us.wrapping_sub(RX_LEAD_US));
+            let timeout = timing
+                .start_us
+                .wrapping_add(timing.window_us + ADDRESS_US + RX_LEAD_US);
+            self.set_compare(CC_TIMEOUT, timeout);
+            ppi.chenset.write(|w| w.ch0().set());
+        }
+
+        fn tune(&self, channel: u8, access_address: u32, crc_init: u32) {
+            let radio = &self.radio;
+            radio
+                .frequency
+                .write(|w| unsafe { w.frequency().bits(ble::channel_freq(channel)) });
+            radio
+                .datawhiteiv
+                .write(|w| unsafe { w.datawhiteiv().bits(channel) });
+            // the prefix is the top byte, the base the others
+            radio
+                .base0
+                .write(|w| unsafe { w.bits(access_address << 8) });
+            radio
+                .prefix0
+                .write(|w| unsafe { w.ap0().bits((access_address >> 24) as u8) });
+            radio
+                .crcinit
+                .write(|w| unsafe { w.crcinit().bits(crc_init) });
+        }
+
+        /// Stops whatever the radio is doing without it turning around,
+        /// raising its interrupt.
+        fn disable(&mut self) {
+            self.radio.shorts.reset();
+            if self.radio.state.read().state().is_disabled() {
+                self.handle_radio_event();
+            } else {
+                self.radio.tasks_disable.write(|w| unsafe { w.bits(1) });
+            }
+        }
+
+        fn crc_ok(&self) -> bool {
+            self.radio.crcstatus.read().crcstatus().is_crcok()
+        }
+
+        fn now(&self) -> u32 {
+            let timer0 = self.timer.as_timer0();
+            timer0.tasks_capture[CC_NOW].write(|w| unsafe { w.bits(1) });
+            timer0.cc[CC_NOW].read().bits()
+        }
+
+        fn captured(&self, cc: usize) -> u32 {
+            self.timer.as_timer0().cc[cc].read().bits()
+        }
+
+        /// Whether the compare `cc` fired since last asked.
+        fn take_compare(&self, cc: usize) -> bool {
+            let timer0 = self.timer.as_timer0();
+            let fired = timer0.events_compare[cc].read().bits() != 0;
+            timer0.events_compare[cc].reset();
+            fired
+        }
+
+        fn set_compare(&self, cc: usize, at_us: u32) {
+            let timer0 = self.timer.as_timer0();
+            timer0.events_compare[cc].reset();
+            timer0.cc[cc].write(|w| unsafe { w.cc().bits(at_us) });
+        }
+    }
+}
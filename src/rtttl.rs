@@ -0,0 +1,343 @@
+//! Parser for RTTTL (Nokia ringtone) strings such as
+//! `Nokia:d=4,o=5,b=225:8e6,8d6,4f#,4g#`.
+//!
+//! Every function that does not need a caller-provided buffer is a `const fn`,
+//! so ringtones can be embedded at compile time with the `rtttl!` macro (which
+//! is also what a build script should emit), while `Parser` doubles as an
+//! iterator over byte buffers received at runtime.
+use defmt::Format;
+
+use crate::tone::Tone;
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// One of the `name:defaults:notes` sections is missing.
+    MissingSection,
+    /// The name is not valid UTF-8.
+    BadName,
+    /// A default is not of the form `d=<n>`, `o=<n>` or `b=<n>`.
+    BadDefault,
+    BadDuration,
+    BadOctave,
+    BadTempo,
+    BadNote,
+    /// The output buffer cannot hold all the notes.
+    TooManyNotes,
+}
+
+/// A parse error, located by 1-based line and column in the source.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Error {
+    pub line: u32,
+    pub column: u32,
+    pub kind: ErrorKind,
+}
+
+impl Error {
+    const fn at(src: &[u8], offset: usize, kind: ErrorKind) -> Self {
+        let mut line = 1;
+        let mut column = 1;
+        let mut i = 0;
+        while i < offset && i < src.len() {
+            if src[i] == b'\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+            i += 1;
+        }
+        Self { line, column, kind }
+    }
+
+    /// Aborts const evaluation with a message naming the error kind.
+    pub const fn panic(self) -> ! {
+        match self.kind {
+            ErrorKind::MissingSection => panic!("rtttl: missing section"),
+            ErrorKind::BadName => panic!("rtttl: bad name"),
+            ErrorKind::BadDefault => panic!("rtttl: bad default"),
+            ErrorKind::BadDuration => panic!("rtttl: bad duration"),
+            ErrorKind::BadOctave => panic!("rtttl: bad octave"),
+            ErrorKind::BadTempo => panic!("rtttl: bad tempo"),
+            ErrorKind::BadNote => panic!("rtttl: bad note"),
+            ErrorKind::TooManyNotes => panic!("rtttl: too many notes"),
+        }
+    }
+}
+
+/// The `name:d=..,o=..,b=..` part of a ringtone.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header<'a> {
+    pub name: &'a str,
+    pub duration: u8,
+    pub octave: u8,
+    pub bpm: u32,
+}
+
+impl Header<'_> {
+    /// Length of a whole note, the unit `melody::Melody` works in.
+    pub const fn whole_note_delay_ms(&self) -> u32 {
+        60_000 * 4 / self.bpm
+    }
+}
+
+// `?` is not available in const fn
+macro_rules! tri {
+    ($e:expr) => {
+        match $e {
+            Ok(v) => v,
+            Err(e) => return Err(e),
+        }
+    };
+}
+
+/// Incremental parser over the notes of one ringtone.
+pub struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+    header: Header<'a>,
+}
+
+impl<'a> Parser<'a> {
+    pub const fn new(src: &'a [u8]) -> Result<Self, Error> {
+        let mut pos = skip_space(src, 0);
+
+        // name
+        let name_start = pos;
+        while pos < src.len() && src[pos] != b':' {
+            pos += 1;
+        }
+        if pos == src.len() {
+            return Err(Error::at(src, pos, ErrorKind::MissingSection));
+        }
+        let (_, rest) = src.split_at(name_start);
+        let (name, _) = rest.split_at(pos - name_start);
+        let name = match core::str::from_utf8(trim_end(name)) {
+            Ok(name) => name,
+            Err(_) => return Err(Error::at(src, name_start, ErrorKind::BadName)),
+        };
+        pos += 1;
+
+        // defaults, as specified by Nokia
+        let mut header = Header {
+            name,
+            duration: 4,
+            octave: 6,
+            bpm: 63,
+        };
+        loop {
+            pos = skip_space(src, pos);
+            if pos == src.len() {
+                return Err(Error::at(src, pos, ErrorKind::MissingSection));
+            }
+            if src[pos] == b':' {
+                pos += 1;
+                break;
+            }
+            if src[pos] == b',' {
+                pos += 1;
+                continue;
+            }
+
+            let key_pos = pos;
+            let key = src[pos].to_ascii_lowercase();
+            pos = skip_space(src, pos + 1);
+            if pos == src.len() || src[pos] != b'=' {
+                return Err(Error::at(src, key_pos, ErrorKind::BadDefault));
+            }
+            pos = skip_space(src, pos + 1);
+            let value_pos = pos;
+            let (value, next) = number(src, pos);
+            pos = next;
+            match key {
+                b'd' => header.duration = tri!(duration(src, value_pos, value)),
+                b'o' => {
+                    header.octave = match value {
+                        Some(o @ 1..=9) => o as u8,
+                        _ => return Err(Error::at(src, value_pos, ErrorKind::BadOctave)),
+                    }
+                }
+                b'b' => {
+                    header.bpm = match value {
+                        Some(b @ 1..=900) => b,
+                        _ => return Err(Error::at(src, value_pos, ErrorKind::BadTempo)),
+                    }
+                }
+                _ => return Err(Error::at(src, key_pos, ErrorKind::BadDefault)),
+            }
+        }
+
+        Ok(Self { src, pos, header })
+    }
+
+    pub const fn header(&self) -> &Header<'a> {
+        &self.header
+    }
+
+    /// Parses the next note, or returns `None` at the end of the ringtone.
+    pub const fn next_note(&mut self) -> Result<Option<(Tone, i8)>, Error> {
+        let src = self.src;
+        let mut pos = skip_space(src, self.pos);
+        if pos == src.len() {
+            self.pos = pos;
+            return Ok(None);
+        }
+
+        let duration_pos = pos;
+        let (value, next) = number(src, pos);
+        pos = next;
+        let duration = match value {
+            Some(_) => tri!(duration(src, duration_pos, value)),
+            None => self.header.duration,
+        };
+
+        let note_pos = pos;
+        if pos == src.len() {
+            return Err(Error::at(src, note_pos, ErrorKind::BadNote));
+        }
+        let semitone: Option<u8> = match src[pos].to_ascii_lowercase() {
+            b'c' => Some(0),
+            b'd' => Some(2),
+            b'e' => Some(4),
+            b'f' => Some(5),
+            b'g' => Some(7),
+            b'a' => Some(9),
+            b'b' | b'h' => Some(11),
+            b'p' => None,
+            _ => return Err(Error::at(src, note_pos, ErrorKind::BadNote)),
+        };
+        pos += 1;
+
+        let mut sharp = false;
+        if pos < src.len() && src[pos] == b'#' {
+            sharp = true;
+            pos += 1;
+        }
+
+        // the dot is allowed both before and after the octave
+        let mut dotted = false;
+        if pos < src.len() && src[pos] == b'.' {
+            dotted = true;
+            pos += 1;
+        }
+
+        let octave_pos = pos;
+        let (value, next) = number(src, pos);
+        pos = next;
+        let octave = match value {
+            Some(o @ 1..=9) => o as u8,
+            Some(_) => return Err(Error::at(src, octave_pos, ErrorKind::BadOctave)),
+            None => self.header.octave,
+        };
+
+        if pos < src.len() && src[pos] == b'.' {
+            dotted = true;
+            pos += 1;
+        }
+
+        pos = skip_space(src, pos);
+        if pos < src.len() {
+            if src[pos] != b',' {
+                return Err(Error::at(src, pos, ErrorKind::BadNote));
+            }
+            pos += 1;
+        }
+        self.pos = pos;
+
+        let tone = match semitone {
+            Some(semitone) => {
+                let semitone = semitone + sharp as u8;
+                // B# and E# roll over to the next pitch class
+                let (semitone, octave) = if semitone == 12 {
+                    (0, octave + 1)
+                } else {
+                    (semitone, octave)
+                };
+                match Tone::from_note(semitone, octave) {
+                    Some(tone) => tone,
+                    None => return Err(Error::at(src, octave_pos, ErrorKind::BadOctave)),
+                }
+            }
+            None => Tone::REST,
+        };
+        let duration = duration as i8;
+        Ok(Some((tone, if dotted { -duration } else { duration })))
+    }
+}
+
+impl Iterator for Parser<'_> {
+    type Item = Result<(Tone, i8), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_note() {
+            Ok(note) => note.map(Ok),
+            Err(e) => {
+                // stop after the first error
+                self.pos = self.src.len();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Counts the notes of a ringtone, validating it along the way.
+pub const fn count(src: &[u8]) -> Result<usize, Error> {
+    let mut parser = tri!(Parser::new(src));
+    let mut n = 0;
+    while tri!(parser.next_note()).is_some() {
+        n += 1;
+    }
+    Ok(n)
+}
+
+/// Parses a ringtone into an array of exactly `N` notes, see `count`.
+pub const fn parse_array<const N: usize>(src: &[u8]) -> Result<[(Tone, i8); N], Error> {
+    let mut parser = tri!(Parser::new(src));
+    let mut notes = [(Tone::REST, 0); N];
+    let mut n = 0;
+    while let Some(note) = tri!(parser.next_note()) {
+        if n == N {
+            return Err(Error::at(src, parser.pos, ErrorKind::TooManyNotes));
+        }
+        notes[n] = note;
+        n += 1;
+    }
+    Ok(notes)
+}
+
+const fn duration(src: &[u8], pos: usize, value: Option<u32>) -> Result<u8, Error> {
+    match value {
+        Some(d @ (1 | 2 | 4 | 8 | 16 | 32 | 64)) => Ok(d as u8),
+        _ => Err(Error::at(src, pos, ErrorKind::BadDuration)),
+    }
+}
+
+const fn number(src: &[u8], mut pos: usize) -> (Option<u32>, usize) {
+    let mut value: Option<u32> = None;
+    while pos < src.len() && src[pos].is_ascii_digit() {
+        let digit = (src[pos] - b'0') as u32;
+        value = Some(match value {
+            Some(v) => v.saturating_mul(10).saturating_add(digit),
+            None => digit,
+        });
+        pos += 1;
+    }
+    (value, pos)
+}
+
+const fn skip_space(src: &[u8], mut pos: usize) -> usize {
+    while pos < src.len() && src[pos].is_ascii_whitespace() {
+        pos += 1;
+    }
+    pos
+}
+
+const fn trim_end(mut bytes: &[u8]) -> &[u8] {
+    while let [rest @ .., last] = bytes {
+        if !last.is_ascii_whitespace() {
+            break;
+        }
+        bytes = rest;
+    }
+    bytes
+}
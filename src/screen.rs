@@ -0,0 +1,276 @@
+use bsp::display::nonblocking::GreyscaleImage;
+use fugit::{ExtU64, TimerDurationU64, TimerInstantU64};
+
+use crate::player::{PlayMode, MAX_TEMPO, MIN_TEMPO};
+
+pub type Icon = [[u8; 5]; 5];
+
+/// Fallback icon for melodies that do not bring their own.
+pub const NOTE: Icon = [
+    [0, 0, 9, 9, 0],
+    [0, 0, 9, 0, 9],
+    [0, 0, 9, 0, 0],
+    [9, 9, 9, 0, 0],
+    [9, 9, 9, 0, 0],
+];
+
+/// The Bluetooth rune, shown when a central connects.
+pub const BLUETOOTH: Icon = [
+    [0, 0, 9, 9, 0],
+    [9, 0, 9, 0, 9],
+    [0, 9, 9, 9, 0],
+    [9, 0, 9, 0, 9],
+    [0, 0, 9, 9, 0],
+];
+
+const PAUSE: Icon = [
+    [0, 9, 0, 9, 0],
+    [0, 9, 0, 9, 0],
+    [0, 9, 0, 9, 0],
+    [0, 9, 0, 9, 0],
+    [0, 9, 0, 9, 0],
+];
+
+/// Play mode glyphs: a loop around a dot, a plain loop, play-then-stop and
+/// crossing paths.
+const REPEAT_ONE: Icon = [
+    [0, 9, 9, 9, 0],
+    [9, 0, 0, 0, 9],
+    [9, 0, 9, 0, 9],
+    [9, 0, 0, 0, 9],
+    [0, 9, 9, 9, 0],
+];
+
+const REPEAT_ALL: Icon = [
+    [0, 9, 9, 9, 0],
+    [9, 0, 0, 0, 9],
+    [9, 0, 0, 0, 9],
+    [9, 0, 0, 0, 9],
+    [0, 9, 9, 0, 0],
+];
+
+const ONCE: Icon = [
+    [9, 0, 0, 0, 9],
+    [9, 9, 0, 0, 9],
+    [9, 9, 9, 0, 9],
+    [9, 9, 0, 0, 9],
+    [9, 0, 0, 0, 9],
+];
+
+const SHUFFLE: Icon = [
+    [9, 0, 0, 0, 9],
+    [0, 9, 0, 9, 0],
+    [0, 0, 9, 0, 0],
+    [0, 9, 0, 9, 0],
+    [9, 0, 0, 0, 9],
+];
+
+/// Bar heights of the "now playing" equalizer animation, one row per frame.
+const EQUALIZER: [[u8; 5]; 8] = [
+    [1, 3, 2, 4, 2],
+    [2, 4, 3, 3, 1],
+    [3, 5, 2, 2, 2],
+    [4, 3, 1, 3, 3],
+    [3, 2, 2, 4, 4],
+    [2, 1, 3, 5, 3],
+    [1, 2, 4, 4, 2],
+    [2, 3, 3, 2, 1],
+];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum View {
+    NowPlaying,
+    Paused,
+    Icon(Icon),
+    Volume(u32),
+    Tempo(u32),
+    Mode(PlayMode),
+    /// Share of the melody played, in percent.
+    Progress(u32),
+}
+
+/// Decides what the LED matrix shows.
+///
+/// Transient views (track icon, volume bar, play mode, progress) fall back
+/// to the default view once `timeout_ms` has elapsed; the default view is
+/// the equalizer animation while playing and a pause glyph while paused.
+pub struct Screen<const TIMER_HZ: u32> {
+    view: View,
+    paused: bool,
+    dirty: bool,
+    frame: usize,
+    time: TimerInstantU64<TIMER_HZ>,
+    start_time: TimerInstantU64<TIMER_HZ>,
+    frame_time: TimerInstantU64<TIMER_HZ>,
+    timeout_ms: TimerDurationU64<TIMER_HZ>,
+    frame_ms: TimerDurationU64<TIMER_HZ>,
+}
+
+impl<const TIMER_HZ: u32> Screen<TIMER_HZ> {
+    pub fn new() -> Self {
+        Self {
+            view: View::NowPlaying,
+            paused: false,
+            dirty: true,
+            frame: 0,
+            time: TimerInstantU64::from_ticks(0),
+            start_time: TimerInstantU64::from_ticks(0),
+            frame_time: TimerInstantU64::from_ticks(0),
+            timeout_ms: 1500.millis(),
+            frame_ms: 150.millis(),
+        }
+    }
+
+    pub fn set_timeout_ms(&mut self, timeout_ms: TimerDurationU64<TIMER_HZ>) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    pub fn set_frame_ms(&mut self, frame_ms: TimerDurationU64<TIMER_HZ>) {
+        self.frame_ms = frame_ms;
+    }
+
+    pub fn show_icon(&mut self, icon: &Icon) {
+        self.update_view(View::Icon(*icon));
+    }
+
+    pub fn show_volume(&mut self, volume: u32) {
+        self.update_view(View::Volume(volume.min(100)));
+    }
+
+    /// Shows the playback speed, `MIN_TEMPO` to `MAX_TEMPO` percent.
+    pub fn show_tempo(&mut self, tempo: u32) {
+        self.update_view(View::Tempo(tempo.clamp(MIN_TEMPO, MAX_TEMPO)));
+    }
+
+    /// Shows how far into a melody of `duration` playback is.
+    pub fn show_progress(&mut self, position: u32, duration: u32) {
+        let percent = (position as u64 * 100).checked_div(duration as u64).unwrap_or(0);
+        self.update_view(View::Progress(percent.min(100) as u32));
+    }
+
+    pub fn show_mode(&mut self, mode: PlayMode) {
+        self.update_view(View::Mode(mode));
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.update_view(self.default_view());
+    }
+
+    /// Advances the screen by one tick, returning an image if the matrix
+    /// needs to be redrawn.
+    pub fn tick(&mut self) -> Option<GreyscaleImage> {
+        self.time += TimerDurationU64::from_ticks(1);
+        let now = self.time;
+
+        match self.view {
+            View::Icon(_)
+            | View::Volume(_)
+            | View::Tempo(_)
+            | View::Mode(_)
+            | View::Progress(_) => {
+                if now - self.start_time > self.timeout_ms {
+                    self.update_view(self.default_view());
+                }
+            }
+            View::NowPlaying => {
+                if now - self.frame_time > self.frame_ms {
+                    self.frame = (self.frame + 1) % EQUALIZER.len();
+                    self.frame_time = now;
+                    self.dirty = true;
+                }
+            }
+            View::Paused => {}
+        }
+
+        if self.dirty {
+            self.dirty = false;
+            Some(GreyscaleImage::new(&self.render()))
+        } else {
+            None
+        }
+    }
+
+    fn render(&self) -> Icon {
+        match self.view {
+            View::NowPlaying => {
+                let heights = &EQUALIZER[self.frame];
+                let mut icon = [[0; 5]; 5];
+                for (row, line) in icon.iter_mut().enumerate() {
+                    for (col, led) in line.iter_mut().enumerate() {
+                        if (5 - row) as u8 <= heights[col] {
+                            *led = 9;
+                        }
+                    }
+                }
+                icon
+            }
+            View::Paused => PAUSE,
+            View::Icon(icon) => icon,
+            View::Volume(volume) => {
+                // fill the matrix from the bottom row up, one LED per 4%
+                let lit = (volume as usize * 25).div_ceil(100);
+                let mut icon = [[0; 5]; 5];
+                for n in 0..lit {
+                    icon[4 - n / 5][n % 5] = 9;
+                }
+                icon
+            }
+            View::Tempo(tempo) => {
+                // rising bars, up to the middle one at the written tempo
+                let cols = 1 + [60, 90, 115, 150].iter().filter(|&&t| tempo > t).count();
+                let mut icon = [[0; 5]; 5];
+                for (row, line) in icon.iter_mut().enumerate() {
+                    for (col, led) in line.iter_mut().enumerate().take(cols) {
+                        if row + col >= 4 {
+                            *led = 9;
+                        }
+                    }
+                }
+                icon
+            }
+            View::Progress(percent) => {
+                // fill the matrix in reading order, one LED per 4%
+                let lit = (percent as usize * 25) / 100;
+                let mut icon = [[0; 5]; 5];
+                for n in 0..lit {
+                    icon[n / 5][n % 5] = 9;
+                }
+                // the LED being played glows dimly
+                if lit < 25 {
+                    icon[lit / 5][lit % 5] = 3;
+                }
+                icon
+            }
+            View::Mode(mode) => match mode {
+                PlayMode::RepeatOne => REPEAT_ONE,
+                PlayMode::RepeatAll => REPEAT_ALL,
+                PlayMode::Once => ONCE,
+                PlayMode::Shuffle => SHUFFLE,
+            },
+        }
+    }
+
+    #[inline]
+    fn default_view(&self) -> View {
+        if self.paused {
+            View::Paused
+        } else {
+            View::NowPlaying
+        }
+    }
+
+    #[inline]
+    fn update_view(&mut self, view: View) {
+        self.view = view;
+        self.start_time = self.time;
+        self.frame_time = self.time;
+        self.dirty = true;
+    }
+}
+
+impl<const TIMER_HZ: u32> Default for Screen<TIMER_HZ> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,56 @@
+//! A serial port: the USB one, the UARTE wired to the board's interface chip
+//! which forwards it over USB, or one on the edge connector.
+pub use self::inner::Serial;
+
+mod inner {
+    use core::fmt;
+
+    use bsp::hal::uarte::{self, Baudrate, Parity, Uarte, UarteRx, UarteTx};
+    use embedded_hal::serial::{Read, Write};
+
+    /// Interrupt-driven reception, one byte at a time, and blocking
+    /// transmission.
+    pub struct Serial<T: uarte::Instance> {
+        tx: UarteTx<T>,
+        rx: UarteRx<T>,
+    }
+
+    impl<T: uarte::Instance> Serial<T> {
+        /// Starts receiving at `baudrate`, raising the UARTE interrupt for
+        /// each byte. `tx_buf` sets the size of the DMA transfers out.
+        pub fn new(
+            uarte: T,
+            pins: uarte::Pins,
+            baudrate: Baudrate,
+            tx_buf: &'static mut [u8],
+            rx_buf: &'static mut [u8; 1],
+        ) -> Self {
+            let uarte = Uarte::new(uarte, pins, Parity::EXCLUDED, baudrate);
+            let (tx, mut rx) = uarte.split(tx_buf, rx_buf).unwrap();
+            // SAFETY: only enables an interrupt of the UARTE owned by `rx`
+            unsafe { &*T::ptr() }.intenset.write(|w| w.endrx().set());
+            // the first read only starts the reception
+            rx.read().ok();
+            Self { tx, rx }
+        }
+
+        /// The byte received, if any, listening for the next one.
+        pub fn read(&mut self) -> Option<u8> {
+            loop {
+                match self.rx.read() {
+                    Ok(byte) => return Some(byte),
+                    Err(nb::Error::WouldBlock) => return None,
+                    // a failed transfer ends without starting the next one
+                    Err(nb::Error::Other(_)) => continue,
+                }
+            }
+        }
+    }
+
+    impl<T: uarte::Instance> fmt::Write for Serial<T> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.tx.write_str(s)?;
+            nb::block!(self.tx.flush()).map_err(|_| fmt::Error)
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! Random playback order for `PlayMode::Shuffle`.
+
+/// Visits `0..len` in a random order, every index once per round.
+///
+/// A round walks a full-period linear congruential sequence over the next
+/// power of two at or above `len`, scrambled by an XOR mask and skipping
+/// values out of range. Nothing is stored per melody, and the walk can be
+/// run backwards for `prev`.
+pub struct Shuffle {
+    rng: u32,
+    len: u32,
+    mask: u32,
+    mul: u32,
+    inc: u32,
+    xor: u32,
+    /// Position in the unscrambled sequence.
+    state: u32,
+    /// Indices not yet visited in this round.
+    left: u32,
+}
+
+impl Shuffle {
+    /// `seed` should come from a real source of randomness, such as the RNG
+    /// peripheral; `0` is replaced by a fixed seed.
+    pub const fn new(seed: u32) -> Self {
+        Self {
+            rng: if seed == 0 { 0x9e37_79b9 } else { seed },
+            len: 0,
+            mask: 0,
+            mul: 1,
+            inc: 1,
+            xor: 0,
+            state: 0,
+            left: 0,
+        }
+    }
+
+    /// Starts a round over `0..len` from `current`, which is not visited
+    /// again before every other index has been.
+    pub fn start(&mut self, current: usize, len: usize) {
+        self.len = len as u32;
+        self.mask = (len as u32).next_power_of_two().wrapping_sub(1);
+        // full period modulo a power of two: odd increment, multiplier 1 mod 4
+        self.mul = ((self.random() << 2) | 1) & self.mask.max(3);
+        self.inc = (self.random() | 1) & self.mask.max(1);
+        self.xor = self.random() & self.mask;
+        self.state = current as u32 ^ self.xor;
+        self.left = self.len.saturating_sub(1);
+    }
+
+    /// The index after `current`, starting a new round when this one is
+    /// used up.
+    pub fn next(&mut self, current: usize) -> usize {
+        if self.len == 0 {
+            return 0;
+        }
+        if self.left == 0 || current as u32 != self.state ^ self.xor {
+            self.start(current, self.len as usize);
+        }
+        self.left = self.left.saturating_sub(1);
+        loop {
+            self.state = self.mul.wrapping_mul(self.state).wrapping_add(self.inc) & self.mask;
+            let index = self.state ^ self.xor;
+            if index < self.len {
+                return index as usize;
+            }
+        }
+    }
+
+    /// The index played before `current` in this order.
+    pub fn prev(&mut self, current: usize) -> usize {
+        if self.len == 0 {
+            return 0;
+        }
+        if current as u32 != self.state ^ self.xor {
+            self.start(current, self.len as usize);
+        }
+        self.left = (self.left + 1).min(self.len - 1);
+        let inverse = inverse(self.mul);
+        loop {
+            self.state = inverse.wrapping_mul(self.state.wrapping_sub(self.inc)) & self.mask;
+            let index = self.state ^ self.xor;
+            if index < self.len {
+                return index as usize;
+            }
+        }
+    }
+
+    /// Xorshift32, good enough to pick the parameters of a round.
+    fn random(&mut self) -> u32 {
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng = x;
+        x
+    }
+}
+
+/// Multiplicative inverse of an odd `a` modulo 2^32, by Newton's iteration.
+fn inverse(a: u32) -> u32 {
+    // correct to 3 bits to start with, doubling each step
+    let mut x = a;
+    for _ in 0..4 {
+        x = x.wrapping_mul(2u32.wrapping_sub(a.wrapping_mul(x)));
+    }
+    x
+}
@@ -0,0 +1,529 @@
+//! Append-only record store in a reserved region of flash, holding uploaded
+//! melodies and the playlist across reboots.
+//!
+//! The region is split into two banks, only one of them active. Records are
+//! appended to the active bank and never changed in place: replacing or
+//! deleting a melody appends a newer record with the same id. Each record
+//! carries a CRC-32, so one torn by a reset is skipped. When stale records
+//! take up more room than is left, `Store::open` copies the live ones to the
+//! other bank and switches over once they are all written.
+//!
+//! Records are read where they lie in flash, so uploaded melodies are played
+//! straight from it; a bank is only erased by `open`, before anything
+//! refers to it.
+use defmt::Format;
+use heapless::Vec;
+
+use crate::melody::Melody;
+
+pub use self::inner::Nvmc;
+
+/// Smallest unit of flash that can be erased.
+pub const PAGE_SIZE: usize = 4096;
+const BANK_PAGES: usize = 4;
+const BANK_SIZE: usize = BANK_PAGES * PAGE_SIZE;
+/// Size of the reserved region, two banks.
+pub const REGION_LEN: usize = 2 * BANK_SIZE;
+
+/// Bank header: magic word then a sequence number, the highest being the
+/// active bank. Written last when a bank is filled, to commit it.
+const BANK_MAGIC: u32 = 0x4b42_424d; // "MBBK"
+const BANK_HEADER: usize = 8;
+/// Record header: kind, id and payload length, then the CRC-32 of those
+/// and of the payload. The CRC is written last.
+const RECORD_HEADER: usize = 8;
+const ERASED: u32 = 0xffff_ffff;
+
+/// Ids stored melodies can have, so that a playlist entry fits in a byte.
+const MAX_ID: u8 = 0x7f;
+/// Records that can be live at once: a melody per id and the playlist.
+const MAX_LIVE: usize = MAX_ID as usize + 1;
+
+/// Storage the record store lives in, mapped into memory for reading.
+pub trait Flash {
+    /// The whole region, `REGION_LEN` bytes starting on a page boundary.
+    fn region(&self) -> &'static [u8];
+    /// Erases the page at `offset` in the region to all ones.
+    fn erase_page(&mut self, offset: usize);
+    /// Programs the word at `offset` in the region, which clears bits only.
+    fn write_word(&mut self, offset: usize, word: u32);
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Not enough room left in the bank until the next start compacts it.
+    Full,
+    /// The record cannot fit in a bank at all.
+    TooLarge,
+    /// Every melody id is taken.
+    NoIds,
+    NotFound,
+}
+
+impl Error {
+    /// The reason given in an `error` reply, see `command`.
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Full => "full",
+            Self::TooLarge => "too-large",
+            Self::NoIds => "no-ids",
+            Self::NotFound => "not-found",
+        }
+    }
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// A melody, as written by `Melody::write_record`.
+    Melody = 1,
+    /// The playlist, one `Entry` per byte.
+    Playlist = 2,
+    /// Deletes the melody with the record's id.
+    Delete = 3,
+}
+
+impl Kind {
+    fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            1 => Some(Self::Melody),
+            2 => Some(Self::Playlist),
+            3 => Some(Self::Delete),
+            _ => None,
+        }
+    }
+}
+
+/// A valid record, read in place.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    pub kind: Kind,
+    pub id: u8,
+    pub payload: &'static [u8],
+}
+
+/// Melody of a playlist.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entry {
+    /// Index in the melodies compiled into the firmware.
+    BuiltIn(u8),
+    /// Id of a stored melody.
+    Stored(u8),
+}
+
+impl Entry {
+    pub fn from_u8(byte: u8) -> Self {
+        if byte & 0x80 == 0 {
+            Self::BuiltIn(byte)
+        } else {
+            Self::Stored(byte & MAX_ID)
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Self::BuiltIn(index) => index & MAX_ID,
+            Self::Stored(id) => 0x80 | (id & MAX_ID),
+        }
+    }
+}
+
+/// Iterates over the valid records of a bank, oldest first.
+pub struct Records {
+    bank: &'static [u8],
+    offset: usize,
+}
+
+impl Iterator for Records {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        loop {
+            let (record, next) = read_record(self.bank, self.offset)?;
+            self.offset = next;
+            if record.is_some() {
+                return record;
+            }
+        }
+    }
+}
+
+/// Reads the record at `offset`, returning it if it is valid and the offset
+/// of the next one, or `None` at the end of the records.
+fn read_record(bank: &'static [u8], offset: usize) -> Option<(Option<Record>, usize)> {
+    let head = read_word(bank, offset)?;
+    if head == ERASED {
+        return None;
+    }
+    let [kind, id, len @ ..] = head.to_le_bytes();
+    let len = u16::from_le_bytes(len) as usize;
+    let start = offset + RECORD_HEADER;
+    let next = start + padded(len);
+    if next > bank.len() {
+        return None;
+    }
+    let payload = &bank[start..start + len];
+    let crc = crc32_update(crc32_update(!0, &head.to_le_bytes()), payload);
+    let record = match Kind::from_u8(kind) {
+        Some(kind) if !crc == read_word(bank, offset + 4)? => Some(Record { kind, id, payload }),
+        _ => None,
+    };
+    Some((record, next))
+}
+
+fn read_word(bytes: &[u8], offset: usize) -> Option<u32> {
+    let word = bytes.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+}
+
+/// `len` rounded up to whole words.
+const fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+pub struct Store<F> {
+    flash: F,
+    /// Offset of the active bank in the region.
+    bank: usize,
+    seq: u32,
+    /// Offset in the bank where the next record goes.
+    end: usize,
+    /// The records nothing newer overrides, oldest first.
+    live: Vec<Record, MAX_LIVE>,
+}
+
+impl<F: Flash> Store<F> {
+    /// Opens the store, formatting the region if it holds none and
+    /// compacting it if stale records have piled up.
+    pub fn open(flash: F) -> Self {
+        let region = flash.region();
+        let seq_of = |bank: usize| match read_word(region, bank) {
+            Some(BANK_MAGIC) => read_word(region, bank + 4).filter(|&seq| seq != ERASED),
+            _ => None,
+        };
+        let active = match (seq_of(0), seq_of(BANK_SIZE)) {
+            (Some(a), Some(b)) if b > a => Some((BANK_SIZE, b)),
+            (Some(a), _) => Some((0, a)),
+            (None, Some(b)) => Some((BANK_SIZE, b)),
+            (None, None) => None,
+        };
+
+        let mut store = Self {
+            flash,
+            bank: 0,
+            seq: 0,
+            end: BANK_HEADER,
+            live: Vec::new(),
+        };
+        let Some((bank, seq)) = active else {
+            defmt::info!("store: formatting");
+            store.switch_bank();
+            return store;
+        };
+        store.bank = bank;
+        store.seq = seq;
+
+        // the records end at the first erased word; anything programmed
+        // after that cannot be written over, so the bank counts as full
+        let bank = store.bank_bytes();
+        let mut end = BANK_HEADER;
+        while let Some((record, next)) = read_record(bank, end) {
+            if let Some(record) = record {
+                store.track(record);
+            }
+            end = next;
+        }
+        let clean = bank[end..].iter().all(|&b| b == 0xff);
+        store.end = if clean { end } else { BANK_SIZE };
+
+        let live: usize = store
+            .live
+            .iter()
+            .map(|r| RECORD_HEADER + padded(r.payload.len()))
+            .sum();
+        let stale = end - BANK_HEADER - live;
+        if !clean || stale > store.free() {
+            defmt::info!("store: compacting {} stale bytes", stale);
+            store.switch_bank();
+        }
+        store
+    }
+
+    /// Valid records of the active bank, oldest first, stale ones included.
+    pub fn records(&self) -> Records {
+        Records {
+            bank: self.bank_bytes(),
+            offset: BANK_HEADER,
+        }
+    }
+
+    /// Bytes left for records.
+    pub fn free(&self) -> usize {
+        BANK_SIZE - self.end
+    }
+
+    /// Stored melodies by id, oldest first.
+    pub fn melodies(&self) -> impl Iterator<Item = (u8, &'static [u8])> + '_ {
+        self.live
+            .iter()
+            .filter(|r| r.kind == Kind::Melody)
+            .map(|r| (r.id, r.payload))
+    }
+
+    pub fn melody(&self, id: u8) -> Option<&'static [u8]> {
+        self.melodies().find(|&(i, _)| i == id).map(|(_, payload)| payload)
+    }
+
+    /// The playlist, if one was saved.
+    pub fn playlist(&self) -> Option<impl Iterator<Item = Entry>> {
+        let record = self.live.iter().find(|r| r.kind == Kind::Playlist)?;
+        Some(record.payload.iter().map(|&b| Entry::from_u8(b)))
+    }
+
+    /// Stores a melody record under a new id, which is returned.
+    pub fn add_melody(&mut self, record: &[u8]) -> Result<u8, Error> {
+        let id = (1..=MAX_ID)
+            .find(|&id| self.melody(id).is_none())
+            .ok_or(Error::NoIds)?;
+        self.append(Kind::Melody, id, record)?;
+        Ok(id)
+    }
+
+    /// Replaces the melody with the given id.
+    pub fn replace_melody(&mut self, id: u8, record: &[u8]) -> Result<(), Error> {
+        self.melody(id).ok_or(Error::NotFound)?;
+        self.append(Kind::Melody, id, record)
+    }
+
+    pub fn remove_melody(&mut self, id: u8) -> Result<(), Error> {
+        self.melody(id).ok_or(Error::NotFound)?;
+        self.append(Kind::Delete, id, &[])
+    }
+
+    pub fn set_playlist(&mut self, entries: &[Entry]) -> Result<(), Error> {
+        let bytes: Vec<u8, 256> = entries.iter().map(|e| e.to_u8()).take(256).collect();
+        self.append(Kind::Playlist, 0, &bytes)
+    }
+
+    /// The melodies of the playlist, or the built-in melodies followed by the
+    /// stored ones if there is none. Entries that do not resolve to a melody
+    /// are left out.
+    pub fn entries<'a>(
+        &'a self,
+        builtin: &'a [Melody],
+    ) -> impl Iterator<Item = (Entry, Melody)> + 'a {
+        let playlist = self.playlist();
+        let default = playlist.is_none();
+        let builtins = (0..builtin.len().min(MAX_ID as usize + 1)).map(|i| Entry::BuiltIn(i as u8));
+        let stored = self.melodies().map(|(id, _)| Entry::Stored(id));
+        playlist
+            .into_iter()
+            .flatten()
+            .chain(builtins.chain(stored).filter(move |_| default))
+            .filter_map(move |entry| {
+                let melody = match entry {
+                    Entry::BuiltIn(index) => builtin.get(index as usize).copied(),
+                    Entry::Stored(id) => self.melody(id).and_then(Melody::from_record),
+                };
+                if melody.is_none() {
+                    defmt::warn!("store: no melody for {:?}", entry);
+                }
+                Some(entry).zip(melody)
+            })
+    }
+
+    /// Fills `list` with `entries`, as far as it goes.
+    pub fn load_list<const N: usize>(&self, builtin: &[Melody], list: &mut Vec<Melody, N>) {
+        list.clear();
+        for (_, melody) in self.entries(builtin) {
+            if list.push(melody).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Adds `record`, the newest one, to the live records, dropping those
+    /// it overrides.
+    fn track(&mut self, record: Record) {
+        let overrides = |r: &Record| match record.kind {
+            Kind::Melody | Kind::Delete => r.kind == Kind::Melody && r.id == record.id,
+            Kind::Playlist => r.kind == Kind::Playlist,
+        };
+        if let Some(i) = self.live.iter().position(overrides) {
+            self.live.remove(i);
+        }
+        if record.kind != Kind::Delete && self.live.push(record).is_err() {
+            defmt::warn!(
+                "store: too many records, {:?} {} left out",
+                record.kind,
+                record.id
+            );
+        }
+    }
+
+    fn append(&mut self, kind: Kind, id: u8, payload: &[u8]) -> Result<(), Error> {
+        let len = RECORD_HEADER + padded(payload.len());
+        if payload.len() > u16::MAX as usize || len > BANK_SIZE - BANK_HEADER {
+            return Err(Error::TooLarge);
+        }
+        if len > self.free() {
+            return Err(Error::Full);
+        }
+        let offset = self.bank + self.end;
+        self.write_record(offset, kind as u8, id, payload);
+        self.end += len;
+        let start = offset + RECORD_HEADER;
+        self.track(Record {
+            kind,
+            id,
+            payload: &self.flash.region()[start..start + payload.len()],
+        });
+        Ok(())
+    }
+
+    /// Programs a record at `offset` in the region, CRC last.
+    fn write_record(&mut self, offset: usize, kind: u8, id: u8, payload: &[u8]) {
+        let [len_lo, len_hi] = (payload.len() as u16).to_le_bytes();
+        let head = [kind, id, len_lo, len_hi];
+        self.flash.write_word(offset, u32::from_le_bytes(head));
+        for (i, chunk) in payload.chunks(4).enumerate() {
+            let mut word = [0xff; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.flash
+                .write_word(offset + RECORD_HEADER + 4 * i, u32::from_le_bytes(word));
+        }
+        let crc = !crc32_update(crc32_update(!0, &head), payload);
+        self.flash.write_word(offset + 4, crc);
+    }
+
+    /// Copies the live records to the other bank and makes it the active
+    /// one.
+    fn switch_bank(&mut self) {
+        let to = BANK_SIZE - self.bank;
+        for page in 0..BANK_PAGES {
+            self.flash.erase_page(to + page * PAGE_SIZE);
+        }
+        let region = self.flash.region();
+        let mut end = BANK_HEADER;
+        for i in 0..self.live.len() {
+            let record = self.live[i];
+            self.write_record(to + end, record.kind as u8, record.id, record.payload);
+            let start = to + end + RECORD_HEADER;
+            self.live[i].payload = &region[start..start + record.payload.len()];
+            end += RECORD_HEADER + padded(record.payload.len());
+        }
+        self.seq += 1;
+        self.flash.write_word(to + 4, self.seq);
+        self.flash.write_word(to, BANK_MAGIC);
+        self.bank = to;
+        self.end = end;
+    }
+
+    fn bank_bytes(&self) -> &'static [u8] {
+        &self.flash.region()[self.bank..self.bank + BANK_SIZE]
+    }
+}
+
+/// Continues a CRC-32 (IEEE) over `bytes`; start from `!0` and invert the
+/// result.
+pub fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    crc
+}
+
+mod inner {
+    use core::ptr;
+
+    use bsp::pac::{nvmc, NVMC};
+
+    use super::{Flash, REGION_LEN};
+
+    /// The region sits at the top of the 512 KiB of flash, past the end of
+    /// `FLASH` in `memory.x`.
+    const REGION_START: usize = 0x8_0000 - REGION_LEN;
+
+    // the firmware's build script reads the end of `FLASH` from `memory.x`;
+    // the modules shared with the host tools are built without it
+    const _: () = if let Some(end) = option_env!("MEMORY_X_FLASH_END") {
+        assert!(
+            parse(end) <= REGION_START,
+            "FLASH in memory.x runs into the store's region"
+        );
+    };
+
+    /// Parses a decimal number.
+    const fn parse(digits: &str) -> usize {
+        let digits = digits.as_bytes();
+        let mut n = 0;
+        let mut i = 0;
+        while i < digits.len() {
+            n = n * 10 + (digits[i] - b'0') as usize;
+            i += 1;
+        }
+        n
+    }
+
+    extern "C" {
+        // from cortex-m-rt's link.x: the firmware image ends with the
+        // initial values of .data
+        static __sidata: u32;
+        static __sdata: u32;
+        static __edata: u32;
+    }
+
+    /// The store's region programmed through the NVMC.
+    pub struct Nvmc(());
+
+    impl Nvmc {
+        /// # Safety
+        ///
+        /// The NVMC must not be used elsewhere; the BSP's `Board` does not
+        /// hand it out.
+        pub unsafe fn new() -> Self {
+            // `memory.x` already keeps the image out, this catches a linker
+            // script that does not
+            let image_end = ptr::addr_of!(__sidata) as usize
+                + (ptr::addr_of!(__edata) as usize - ptr::addr_of!(__sdata) as usize);
+            assert!(image_end <= REGION_START, "store: firmware overlaps the store region");
+            Self(())
+        }
+
+        fn regs(&self) -> &nvmc::RegisterBlock {
+            // SAFETY: owned by `self`, see `new`
+            unsafe { &*NVMC::ptr() }
+        }
+
+        fn wait_ready(&self) {
+            while self.regs().ready.read().ready().bit_is_clear() {}
+        }
+    }
+
+    impl Flash for Nvmc {
+        fn region(&self) -> &'static [u8] {
+            // SAFETY: flash is always mapped, and only changed through `self`
+            unsafe { core::slice::from_raw_parts(REGION_START as *const u8, REGION_LEN) }
+        }
+
+        fn erase_page(&mut self, offset: usize) {
+            let regs = self.regs();
+            regs.config.write(|w| w.wen().een());
+            regs.erasepage()
+                .write(|w| unsafe { w.bits((REGION_START + offset) as u32) });
+            self.wait_ready();
+            self.regs().config.write(|w| w.wen().ren());
+        }
+
+        fn write_word(&mut self, offset: usize, word: u32) {
+            self.regs().config.write(|w| w.wen().wen());
+            // SAFETY: a word-aligned address within the region
+            unsafe { ptr::write_volatile((REGION_START + offset) as *mut u32, word) };
+            self.wait_ready();
+            self.regs().config.write(|w| w.wen().ren());
+        }
+    }
+}
@@ -0,0 +1,338 @@
+//! Software synthesizer: oscillators are mixed into a stream of PWM duty
+//! cycles that EasyDMA plays back in sequence mode, so several tones can
+//! sound through the single speaker pin.
+pub use self::inner::SynthBuzzer;
+use crate::{envelope::Envelope, player::MAX_VOICES, waveform::Waveform};
+
+/// PWM counter top; with the 16MHz PWM clock it sets the sample rate.
+pub const COUNTERTOP: u16 = 512;
+pub const SAMPLE_RATE: u32 = 16_000_000 / COUNTERTOP as u32;
+
+/// Full scale of `Adsr` levels.
+const FULL: u32 = 1 << 24;
+
+#[derive(Clone, Copy)]
+struct Oscillator {
+    phase: u32,
+    step: u32,
+    amplitude: i32,
+    waveform: Waveform,
+    noise: u16,
+    envelope: Adsr,
+}
+
+impl Oscillator {
+    #[inline(always)]
+    fn next_sample(&mut self) -> i32 {
+        if self.envelope.is_off() {
+            return 0;
+        }
+        let (phase, wrapped) = self.phase.overflowing_add(self.step);
+        self.phase = phase;
+        if wrapped {
+            self.noise = next_noise(self.noise);
+        }
+        let sample = (self.waveform.sample(phase, self.noise) * self.amplitude) >> 15;
+        (sample * self.envelope.next_level()) >> 15
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Off,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// Envelope generator of one voice, advanced once per sample.
+#[derive(Clone, Copy)]
+struct Adsr {
+    envelope: Envelope,
+    sample_rate: u32,
+    stage: Stage,
+    level: u32,
+    step: u32,
+}
+
+impl Adsr {
+    const fn new(sample_rate: u32) -> Self {
+        Self {
+            envelope: Envelope::DEFAULT,
+            sample_rate,
+            stage: Stage::Off,
+            level: 0,
+            step: 0,
+        }
+    }
+
+    fn set_envelope(&mut self, envelope: Envelope) {
+        self.envelope = envelope;
+    }
+
+    /// Starts a note, rising from the current level so that notes played
+    /// back to back do not click.
+    fn trigger(&mut self) {
+        self.stage = Stage::Attack;
+        self.step = self.step_for(self.envelope.attack_ms, FULL);
+    }
+
+    /// Lets the note fade out over the release time.
+    fn release(&mut self) {
+        if matches!(self.stage, Stage::Attack | Stage::Decay | Stage::Sustain) {
+            self.stage = Stage::Release;
+            self.step = self.step_for(self.envelope.release_ms, self.level);
+        }
+    }
+
+    /// Cuts the note immediately.
+    fn silence(&mut self) {
+        self.stage = Stage::Off;
+        self.level = 0;
+    }
+
+    fn is_off(&self) -> bool {
+        self.stage == Stage::Off
+    }
+
+    /// Advances by one sample and returns the level, `0..=0x8000`.
+    #[inline(always)]
+    fn next_level(&mut self) -> i32 {
+        match self.stage {
+            Stage::Off | Stage::Sustain => {}
+            Stage::Attack => {
+                self.level = self.level.saturating_add(self.step);
+                if self.level >= FULL {
+                    self.level = FULL;
+                    let sustain = self.sustain_level();
+                    self.stage = Stage::Decay;
+                    self.step = self.step_for(self.envelope.decay_ms, FULL - sustain);
+                }
+            }
+            Stage::Decay => {
+                let sustain = self.sustain_level();
+                self.level = self.level.saturating_sub(self.step).max(sustain);
+                if self.level == sustain {
+                    self.stage = Stage::Sustain;
+                }
+            }
+            Stage::Release => {
+                self.level = self.level.saturating_sub(self.step);
+                if self.level == 0 {
+                    self.stage = Stage::Off;
+                }
+            }
+        }
+        (self.level >> 9) as i32
+    }
+
+    #[inline(always)]
+    fn sustain_level(&self) -> u32 {
+        (FULL / 100) * self.envelope.sustain.min(100) as u32
+    }
+
+    /// Per-sample change covering `span` in `ms`.
+    fn step_for(&self, ms: u16, span: u32) -> u32 {
+        let samples = (ms as u32 * self.sample_rate / 1000).max(1);
+        (span / samples).max(1)
+    }
+}
+
+/// Advances a 16-bit Galois LFSR, the noise source of `Waveform::Noise`.
+#[inline(always)]
+fn next_noise(lfsr: u16) -> u16 {
+    let lsb = lfsr & 1;
+    let lfsr = lfsr >> 1;
+    if lsb != 0 {
+        lfsr ^ 0xB400
+    } else {
+        lfsr
+    }
+}
+
+/// Mixes `VOICES` oscillators, each with its own waveform and envelope.
+pub struct Mixer<const VOICES: usize> {
+    voices: [Oscillator; VOICES],
+    sample_rate: u32,
+}
+
+impl<const VOICES: usize> Mixer<VOICES> {
+    pub const fn new(sample_rate: u32) -> Self {
+        Self {
+            voices: [Oscillator {
+                phase: 0,
+                step: 0,
+                amplitude: 0,
+                waveform: Waveform::SQUARE,
+                noise: 1,
+                envelope: Adsr::new(sample_rate),
+            }; VOICES],
+            sample_rate,
+        }
+    }
+
+    /// Starts a note of `freq` Hz on `voice` at `volume` percent.
+    pub fn set_voice(&mut self, voice: usize, freq: f32, volume: u32) {
+        self.set_freq(voice, freq);
+        if let Some(osc) = self.voices.get_mut(voice) {
+            // leave headroom so that all voices at full volume do not clip
+            osc.amplitude = (i16::MAX as i32 / VOICES as i32) * volume.min(100) as i32 / 100;
+            osc.envelope.trigger();
+        }
+    }
+
+    /// Changes the pitch of `voice`, keeping its phase and envelope.
+    pub fn set_freq(&mut self, voice: usize, freq: f32) {
+        if let Some(osc) = self.voices.get_mut(voice) {
+            // phase increment per sample, a full period being 2^32
+            osc.step = (freq / self.sample_rate as f32 * 4_294_967_296.0) as u32;
+        }
+    }
+
+    pub fn set_waveform(&mut self, voice: usize, waveform: Waveform) {
+        if let Some(osc) = self.voices.get_mut(voice) {
+            osc.waveform = waveform;
+        }
+    }
+
+    pub fn set_envelope(&mut self, voice: usize, envelope: Envelope) {
+        if let Some(osc) = self.voices.get_mut(voice) {
+            osc.envelope.set_envelope(envelope);
+        }
+    }
+
+    /// Lets the note on `voice` fade out.
+    pub fn release(&mut self, voice: usize) {
+        if let Some(osc) = self.voices.get_mut(voice) {
+            osc.envelope.release();
+        }
+    }
+
+    pub fn silence(&mut self) {
+        for osc in self.voices.iter_mut() {
+            osc.envelope.silence();
+        }
+    }
+
+    /// Next mixed sample, within `i16` range.
+    #[inline(always)]
+    pub fn next_sample(&mut self) -> i16 {
+        let sum: i32 = self.voices.iter_mut().map(|osc| osc.next_sample()).sum();
+        sum.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+    }
+
+    /// Renders samples as PWM compare values between 0 and `top`.
+    pub fn fill(&mut self, buf: &mut [u16], top: u16) {
+        for duty in buf.iter_mut() {
+            let sample = self.next_sample() as i32 + 0x8000;
+            *duty = ((sample as u32 * top as u32) >> 16) as u16;
+        }
+    }
+}
+
+mod inner {
+    use bsp::hal::{
+        gpio::{Output, Pin, PushPull},
+        pwm,
+    };
+
+    use super::{Mixer, COUNTERTOP, MAX_VOICES, SAMPLE_RATE};
+    use crate::{envelope::Envelope, player::ToneOutput, waveform::Waveform};
+
+    /// Multi-voice `ToneOutput` streaming the mixer through a PWM instance.
+    ///
+    /// The two buffers are played back to back as sequence 0 and 1; each is
+    /// refilled from `handle_event` while the other one plays.
+    pub struct SynthBuzzer<T: pwm::Instance, const N: usize> {
+        pwm: T,
+        mixer: Mixer<MAX_VOICES>,
+        bufs: [&'static mut [u16; N]; 2],
+        running: bool,
+    }
+
+    impl<T: pwm::Instance, const N: usize> SynthBuzzer<T, N> {
+        pub fn new(pwm: T, pin: Pin<Output<PushPull>>, bufs: [&'static mut [u16; N]; 2]) -> Self {
+            pwm.psel.out[0].write(|w| unsafe { w.bits(pin.psel_bits()) });
+            pwm.enable.write(|w| w.enable().enabled());
+            pwm.mode.write(|w| w.updown().up());
+            pwm.prescaler.write(|w| w.prescaler().div_1());
+            pwm.countertop
+                .write(|w| unsafe { w.countertop().bits(COUNTERTOP) });
+            pwm.decoder
+                .write(|w| w.load().common().mode().refresh_count());
+            // play sequence 0 then 1, forever
+            pwm.loop_.write(|w| unsafe { w.cnt().bits(1) });
+            pwm.shorts.write(|w| w.loopsdone_seqstart0().enabled());
+            for (seq, buf) in [&pwm.seq0, &pwm.seq1].into_iter().zip(bufs.iter()) {
+                seq.ptr.write(|w| unsafe { w.bits(buf.as_ptr() as u32) });
+                seq.cnt.write(|w| unsafe { w.bits(N as u32) });
+                seq.refresh.write(|w| unsafe { w.bits(0) });
+                seq.enddelay.write(|w| unsafe { w.bits(0) });
+            }
+            pwm.intenset
+                .write(|w| w.seqend0().set_bit().seqend1().set_bit());
+
+            Self {
+                pwm,
+                mixer: Mixer::new(SAMPLE_RATE),
+                bufs,
+                running: false,
+            }
+        }
+
+        fn start(&mut self) {
+            if !self.running {
+                for buf in self.bufs.iter_mut() {
+                    self.mixer.fill(&mut buf[..], COUNTERTOP);
+                }
+                self.pwm.tasks_seqstart[0].write(|w| unsafe { w.bits(1) });
+                self.running = true;
+            }
+        }
+    }
+
+    impl<T: pwm::Instance, const N: usize> ToneOutput for SynthBuzzer<T, N> {
+        const VOICES: usize = MAX_VOICES;
+
+        fn tone(&mut self, voice: usize, freq: f32, volume: u32) {
+            if freq <= 0.0 {
+                self.mixer.release(voice);
+            } else {
+                self.mixer.set_voice(voice, freq, volume);
+                self.start();
+            }
+        }
+
+        fn set_freq(&mut self, voice: usize, freq: f32) {
+            self.mixer.set_freq(voice, freq);
+        }
+
+        fn release(&mut self, voice: usize) {
+            self.mixer.release(voice);
+        }
+
+        fn set_waveform(&mut self, voice: usize, waveform: Waveform) {
+            self.mixer.set_waveform(voice, waveform);
+        }
+
+        fn set_envelope(&mut self, voice: usize, envelope: Envelope) {
+            self.mixer.set_envelope(voice, envelope);
+        }
+
+        fn stop(&mut self) {
+            self.mixer.silence();
+            self.pwm.tasks_stop.write(|w| unsafe { w.bits(1) });
+            self.running = false;
+        }
+
+        fn handle_event(&mut self) {
+            for (event, buf) in self.pwm.events_seqend.iter().zip(self.bufs.iter_mut()) {
+                if event.read().bits() != 0 {
+                    event.reset();
+                    self.mixer.fill(&mut buf[..], COUNTERTOP);
+                }
+            }
+        }
+    }
+}
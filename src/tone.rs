@@ -1,9 +1,10 @@
 use defmt::Format;
-use bsp::hal::time::Hertz;
+
+use crate::tuning::Tuning;
 
 macro_rules! tones {
     (
-        $($key:ident: $freq:expr),+
+        $($key:ident),+
     ) => {
         #[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
         pub enum Tone {
@@ -13,141 +14,327 @@ macro_rules! tones {
         }
 
         impl Tone {
-            pub fn hz(&self) -> Hertz {
-                match *self {
-                    $(Tone::$key => Hertz($freq),)*
-                }
-            }
-
-            pub fn freq(&self) -> u32 {
-                match *self {
-                    $(Tone::$key => $freq,)*
-                }
-            }
+            /// Every tone in declaration order: `REST`, then each pitch class
+            /// from C to B, octaves 1 to 9.
+            const ALL: &'static [Tone] = &[$(Tone::$key,)*];
         }
     };
 }
 
 tones!(
-    REST: 0,
+    REST,
     // C
-    C1: 33,
-    C2: 65,
-    C3: 131,
-    C4: 262,
-    C5: 523,
-    C6: 1047,
-    C7: 2093,
-    C8: 4186,
-    C9: 8372,
+    C1,
+    C2,
+    C3,
+    C4,
+    C5,
+    C6,
+    C7,
+    C8,
+    C9,
     // C♯/D♭
-    CS1: 35,
-    CS2: 69,
-    CS3: 139,
-    CS4: 277,
-    CS5: 554,
-    CS6: 1109,
-    CS7: 2218,
-    CS8: 4435,
-    CS9: 8869,
+    CS1,
+    CS2,
+    CS3,
+    CS4,
+    CS5,
+    CS6,
+    CS7,
+    CS8,
+    CS9,
     // D
-    D1: 37,
-    D2: 73,
-    D3: 147,
-    D4: 294,
-    D5: 587,
-    D6: 1175,
-    D7: 2349,
-    D8: 4699,
-    D9: 9397,
+    D1,
+    D2,
+    D3,
+    D4,
+    D5,
+    D6,
+    D7,
+    D8,
+    D9,
     // D♯/E♭
-    DS1: 39,
-    DS2: 78,
-    DS3: 156,
-    DS4: 311,
-    DS5: 622,
-    DS6: 1245,
-    DS7: 2489,
-    DS8: 4978,
-    DS9: 9956,
+    DS1,
+    DS2,
+    DS3,
+    DS4,
+    DS5,
+    DS6,
+    DS7,
+    DS8,
+    DS9,
     // E
-    E1: 41,
-    E2: 82,
-    E3: 165,
-    E4: 330,
-    E5: 659,
-    E6: 1319,
-    E7: 2637,
-    E8: 5274,
-    E9: 10548,
+    E1,
+    E2,
+    E3,
+    E4,
+    E5,
+    E6,
+    E7,
+    E8,
+    E9,
     // F
-    F1: 44,
-    F2: 87,
-    F3: 175,
-    F4: 349,
-    F5: 698,
-    F6: 1397,
-    F7: 2794,
-    F8: 5588,
-    F9: 11175,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
     // F♯/G♭
-    FS1: 46,
-    FS2: 92,
-    FS3: 185,
-    FS4: 370,
-    FS5: 740,
-    FS6: 1480,
-    FS7: 2960,
-    FS8: 5920,
-    FS9: 11840,
+    FS1,
+    FS2,
+    FS3,
+    FS4,
+    FS5,
+    FS6,
+    FS7,
+    FS8,
+    FS9,
     // G
-    G1: 49,
-    G2: 98,
-    G3: 196,
-    G4: 392,
-    G5: 784,
-    G6: 1568,
-    G7: 3136,
-    G8: 6272,
-    G9: 12544,
+    G1,
+    G2,
+    G3,
+    G4,
+    G5,
+    G6,
+    G7,
+    G8,
+    G9,
     // G♯/A♭
-    GS1: 52,
-    GS2: 104,
-    GS3: 208,
-    GS4: 415,
-    GS5: 831,
-    GS6: 1661,
-    GS7: 3322,
-    GS8: 6645,
-    GS9: 13290,
+    GS1,
+    GS2,
+    GS3,
+    GS4,
+    GS5,
+    GS6,
+    GS7,
+    GS8,
+    GS9,
     // A
-    A1: 55,
-    A2: 110,
-    A3: 220,
-    A4: 440,
-    A5: 880,
-    A6: 1760,
-    A7: 3520,
-    A8: 7040,
-    A9: 14080,
+    A1,
+    A2,
+    A3,
+    A4,
+    A5,
+    A6,
+    A7,
+    A8,
+    A9,
     // A♯/B♭
-    AS1: 58,
-    AS2: 117,
-    AS3: 233,
-    AS4: 466,
-    AS5: 932,
-    AS6: 1865,
-    AS7: 3729,
-    AS8: 7459,
-    AS9: 14917,
+    AS1,
+    AS2,
+    AS3,
+    AS4,
+    AS5,
+    AS6,
+    AS7,
+    AS8,
+    AS9,
     // B
-    B1: 62,
-    B2: 123,
-    B3: 247,
-    B4: 494,
-    B5: 988,
-    B6: 1976,
-    B7: 3951,
-    B8: 7902,
-    B9: 15804
+    B1,
+    B2,
+    B3,
+    B4,
+    B5,
+    B6,
+    B7,
+    B8,
+    B9
 );
+
+impl Tone {
+    /// Frequency in Hz at concert pitch, see `Tuning` for others.
+    pub const fn freq(&self) -> f32 {
+        Tuning::DEFAULT.freq(self.pitch())
+    }
+
+    pub const fn pitch(&self) -> Pitch {
+        match self.midi() {
+            Some(key) => Pitch::Key(key, 0),
+            None => Pitch::Rest,
+        }
+    }
+}
+
+/// What a note sounds: a key, possibly between the semitones, or a fixed
+/// frequency for effects. `Tone`s convert into keys, e.g.
+/// `Tone::C4.pitch().detune(-14)`.
+#[derive(Format, Debug, Clone, Copy, PartialEq)]
+pub enum Pitch {
+    Rest,
+    /// MIDI note number and offset in cents, tuned by the player's `Tuning`.
+    Key(u8, i16),
+    /// Frequency in Hz, left alone by tuning and transposition.
+    Hz(f32),
+}
+
+impl Pitch {
+    /// Shifts a key by `cents`, e.g. `-50` for a quarter tone down.
+    pub const fn detune(self, cents: i16) -> Pitch {
+        match self {
+            Pitch::Key(key, detune) => Pitch::Key(key, detune.saturating_add(cents)),
+            pitch => pitch,
+        }
+    }
+
+    pub const fn is_rest(&self) -> bool {
+        matches!(self, Pitch::Rest)
+    }
+
+    /// Shifts a key by `semitones`, folding it back into the range of `Tone`
+    /// by whole octaves like `Tone::transpose`.
+    pub const fn transpose(self, semitones: i32) -> Pitch {
+        match self {
+            Pitch::Key(key, cents) => {
+                let mut key = key as i32 + semitones;
+                while key < MIDI_MIN {
+                    key += 12;
+                }
+                while key > MIDI_MAX {
+                    key -= 12;
+                }
+                Pitch::Key(key as u8, cents)
+            }
+            pitch => pitch,
+        }
+    }
+}
+
+impl From<Tone> for Pitch {
+    fn from(tone: Tone) -> Self {
+        tone.pitch()
+    }
+}
+
+/// MIDI note numbers of C1 and B9, the range `Tone` covers. B9 lies beyond
+/// the 0-127 range of MIDI messages.
+const MIDI_MIN: i32 = 24;
+const MIDI_MAX: i32 = 131;
+
+impl Tone {
+    /// Looks up a tone by pitch class (`0` = C, `11` = B) and octave (1-9).
+    pub const fn from_note(semitone: u8, octave: u8) -> Option<Tone> {
+        if semitone >= 12 || octave < 1 || octave > 9 {
+            None
+        } else {
+            Some(Self::ALL[1 + semitone as usize * 9 + (octave - 1) as usize])
+        }
+    }
+
+    /// Pitch class (`0` = C, `11` = B) and octave, `None` for `REST`.
+    pub const fn note(&self) -> Option<(u8, u8)> {
+        match *self as usize {
+            0 => None,
+            index => Some((((index - 1) / 9) as u8, ((index - 1) % 9 + 1) as u8)),
+        }
+    }
+
+    /// Looks up a tone by MIDI note number, `60` being `C4`.
+    pub const fn from_midi(key: u8) -> Option<Tone> {
+        if (key as i32) < MIDI_MIN || key as i32 > MIDI_MAX {
+            return None;
+        }
+        Self::from_note(key % 12, key / 12 - 1)
+    }
+
+    /// MIDI note number, `None` for `REST`.
+    pub const fn midi(&self) -> Option<u8> {
+        match self.note() {
+            Some((semitone, octave)) => Some((octave + 1) * 12 + semitone),
+            None => None,
+        }
+    }
+
+    /// The tone a semitone higher, if there is one.
+    pub const fn next(&self) -> Option<Tone> {
+        self.checked_transpose(1)
+    }
+
+    /// The tone a semitone lower, if there is one.
+    pub const fn prev(&self) -> Option<Tone> {
+        self.checked_transpose(-1)
+    }
+
+    /// Shifts by `semitones`, or `None` if that leaves octaves 1-9. `REST`
+    /// stays a rest.
+    pub const fn checked_transpose(&self, semitones: i32) -> Option<Tone> {
+        let Some(key) = self.midi() else {
+            return Some(Tone::REST);
+        };
+        let key = key as i32 + semitones;
+        if key < MIDI_MIN || key > MIDI_MAX {
+            None
+        } else {
+            Self::from_midi(key as u8)
+        }
+    }
+
+    /// Shifts by `semitones`, folding notes that leave octaves 1-9 back in
+    /// by whole octaves so that they keep their pitch class.
+    pub const fn transpose(&self, semitones: i32) -> Tone {
+        let Some(key) = self.midi() else {
+            return Tone::REST;
+        };
+        let mut key = key as i32 + semitones;
+        while key < MIDI_MIN {
+            key += 12;
+        }
+        while key > MIDI_MAX {
+            key -= 12;
+        }
+        match Self::from_midi(key as u8) {
+            Some(tone) => tone,
+            None => Tone::REST,
+        }
+    }
+
+    /// Shifts by whole octaves, clamping to octaves 1-9.
+    pub const fn octave_shift(&self, octaves: i32) -> Tone {
+        match self.note() {
+            Some((semitone, octave)) => {
+                let octave = octave as i32 + octaves;
+                let octave = if octave < 1 {
+                    1
+                } else if octave > 9 {
+                    9
+                } else {
+                    octave
+                };
+                match Self::from_note(semitone, octave as u8) {
+                    Some(tone) => tone,
+                    None => Tone::REST,
+                }
+            }
+            None => Tone::REST,
+        }
+    }
+}
+
+// The table must list every pitch class in octaves 1-9 in order, and the
+// computed frequencies must rise by a semitone at each step.
+const _: () = {
+    let mut key = MIDI_MIN;
+    while key <= MIDI_MAX {
+        let Some(tone) = Tone::from_midi(key as u8) else {
+            panic!("tone: missing MIDI note");
+        };
+        match tone.midi() {
+            Some(midi) if midi as i32 == key => {}
+            _ => panic!("tone: MIDI round trip"),
+        }
+        if key > MIDI_MIN {
+            let Some(prev) = tone.prev() else {
+                panic!("tone: missing previous semitone");
+            };
+            // 2^(1/12), within a hundredth of a cent
+            let ratio = tone.freq() / prev.freq();
+            if ratio < 1.059_456 || ratio > 1.059_471 {
+                panic!("tone: frequencies are not a semitone apart");
+            }
+        }
+        key += 1;
+    }
+};
@@ -0,0 +1,149 @@
+use defmt::Format;
+
+pub use self::inner::Touch;
+
+/// Weight of a new reading in the baseline, as a power of two.
+const BASELINE_SHIFT: u32 = 4;
+
+/// Tells touches from how long the pad takes to charge through its pull-up:
+/// a finger adds capacitance, so it charges slower. The untouched time is
+/// learnt from the first reading and followed as it drifts.
+///
+/// It is `const`, so the examples at the end of this file are checked at
+/// compile time.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Calibration {
+    /// Untouched charge time, scaled by `1 << BASELINE_SHIFT`; 0 before the
+    /// first reading.
+    baseline: u32,
+    touched: bool,
+    threshold: u32,
+}
+
+impl Calibration {
+    pub const fn new() -> Self {
+        Self {
+            baseline: 0,
+            touched: false,
+            threshold: 50,
+        }
+    }
+
+    /// How much slower than untouched, in percent, the pad has to charge to
+    /// count as touched. It is released at half of that.
+    pub const fn set_threshold(&mut self, threshold: u32) {
+        self.threshold = threshold;
+    }
+
+    /// Whether the pad is touched, given how long it took to charge.
+    pub const fn update(&mut self, charge_time: u32) -> bool {
+        if self.baseline == 0 {
+            self.baseline = charge_time << BASELINE_SHIFT;
+        }
+        let baseline = self.baseline >> BASELINE_SHIFT;
+        let margin = if self.touched {
+            self.threshold / 2
+        } else {
+            self.threshold
+        };
+        self.touched = charge_time > baseline + baseline * margin / 100;
+        if !self.touched {
+            self.baseline = self.baseline - (self.baseline >> BASELINE_SHIFT) + charge_time;
+        }
+        self.touched
+    }
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+mod inner {
+    use bsp::{
+        hal::gpio::{p1::P1_04, Floating, Input},
+        pac::P1,
+    };
+
+    use super::Calibration;
+    use crate::button::Source;
+
+    /// The logo's bit in the port registers.
+    const LOGO: u32 = 1 << 4;
+    /// Busy loops the pad is held low for to empty it.
+    const DISCHARGE_LOOPS: u32 = 64;
+    /// Busy loops waited at most for the pad to charge, a few hundred
+    /// microseconds; a longer time counts as this one.
+    const MAX_CHARGE_LOOPS: u32 = 4096;
+
+    /// The touch logo on P1.04, read as a button: the pad is emptied, then
+    /// timed charging through its pull-up on every read.
+    pub struct Touch {
+        _pin: P1_04<Input<Floating>>,
+        calibration: Calibration,
+    }
+
+    impl Touch {
+        pub fn new(pin: P1_04<Input<Floating>>) -> Self {
+            Self {
+                _pin: pin,
+                calibration: Calibration::new(),
+            }
+        }
+
+        pub fn calibration_mut(&mut self) -> &mut Calibration {
+            &mut self.calibration
+        }
+
+        /// How many busy loops the pad takes to charge.
+        fn charge_time(&mut self) -> u32 {
+            // SAFETY: only drives and reads the pin owned by `_pin`
+            let p1 = unsafe { &*P1::ptr() };
+            p1.outclr.write(|w| unsafe { w.bits(LOGO) });
+            p1.dirset.write(|w| unsafe { w.bits(LOGO) });
+            for _ in 0..DISCHARGE_LOOPS {
+                cortex_m::asm::nop();
+            }
+            p1.dirclr.write(|w| unsafe { w.bits(LOGO) });
+            let mut loops = 0;
+            while loops < MAX_CHARGE_LOOPS && p1.in_.read().bits() & LOGO == 0 {
+                loops += 1;
+            }
+            loops
+        }
+    }
+
+    impl Source for Touch {
+        fn is_active(&mut self) -> bool {
+            let charge_time = self.charge_time();
+            self.calibration.update(charge_time)
+        }
+    }
+}
+
+const _: () = {
+    let mut calibration = Calibration::new();
+    // untouched, drifting a little
+    assert!(!calibration.update(100));
+    assert!(!calibration.update(110));
+    assert!(!calibration.update(95));
+    // a finger on it, held while above half the threshold
+    assert!(calibration.update(300));
+    assert!(calibration.update(130));
+    assert!(!calibration.update(110));
+    // the baseline did not learn from the touch
+    assert!(calibration.baseline >> BASELINE_SHIFT < 110);
+    assert!(calibration.update(160));
+
+    // touched from the start: learnt as the baseline, then found again once
+    // the untouched readings have brought it down
+    let mut calibration = Calibration::new();
+    assert!(!calibration.update(300));
+    let mut i = 0;
+    while i < 64 {
+        calibration.update(100);
+        i += 1;
+    }
+    assert!(calibration.update(300));
+};
@@ -0,0 +1,155 @@
+//! Frequencies of `Tone`s for a reference pitch and tuning system.
+use defmt::Format;
+
+use crate::tone::Pitch;
+
+/// MIDI note number of A4, the reference pitch.
+const A4: i32 = 69;
+
+/// How the octave is divided. Besides equal temperament, the systems are
+/// relative to `Tuning::tonic`.
+#[derive(Format, Debug, Clone, Copy, PartialEq)]
+pub enum Temperament {
+    /// Twelve equal semitones.
+    Equal,
+    /// 5-limit just intonation.
+    Just,
+    /// Stacked pure fifths.
+    Pythagorean,
+    /// Deviation from equal temperament of each degree above the tonic, in
+    /// cents.
+    Cents([f32; 12]),
+}
+
+impl Temperament {
+    const fn cents(&self) -> [f32; 12] {
+        match *self {
+            Self::Equal => [0.0; 12],
+            // 1, 16/15, 9/8, 6/5, 5/4, 4/3, 45/32, 3/2, 8/5, 5/3, 9/5, 15/8
+            Self::Just => [
+                0.0, 11.73, 3.91, 15.64, -13.69, -1.96, -9.78, 1.96, 13.69, -15.64, 17.6, -11.73,
+            ],
+            // 1, 256/243, 9/8, 32/27, 81/64, 4/3, 729/512, 3/2, 128/81, 27/16, 16/9, 243/128
+            Self::Pythagorean => [
+                0.0, -9.78, 3.91, -5.87, 7.82, -1.96, 11.73, 1.96, -7.82, 5.87, -3.91, 9.78,
+            ],
+            Self::Cents(cents) => cents,
+        }
+    }
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq)]
+pub struct Tuning {
+    /// Frequency of A4 in Hz.
+    pub a4: f32,
+    pub temperament: Temperament,
+    /// Pitch class (`0` = C) the temperament is built on.
+    pub tonic: u8,
+}
+
+impl Tuning {
+    /// Concert pitch, A4 = 440 Hz in equal temperament.
+    pub const DEFAULT: Self = Self::new(440.0, Temperament::Equal);
+
+    pub const fn new(a4: f32, temperament: Temperament) -> Self {
+        Self {
+            a4,
+            temperament,
+            tonic: 0,
+        }
+    }
+
+    pub const fn with_tonic(self, tonic: u8) -> Self {
+        Self {
+            tonic: tonic % 12,
+            ..self
+        }
+    }
+
+    /// Frequency of `pitch` in Hz, `0.0` for a rest.
+    pub const fn freq(&self, pitch: Pitch) -> f32 {
+        match pitch {
+            Pitch::Rest => 0.0,
+            Pitch::Key(key, cents) => self.freq_cents(key as i32, cents as f32),
+            Pitch::Hz(hz) => hz,
+        }
+    }
+
+    /// Frequency in Hz of MIDI note `key` detuned by `cents`.
+    const fn freq_cents(&self, key: i32, cents: f32) -> f32 {
+        let deviation = self.temperament.cents();
+        let degree = (key - self.tonic as i32).rem_euclid(12) as usize;
+        let a_degree = (A4 - self.tonic as i32).rem_euclid(12) as usize;
+        // A4 keeps the reference pitch whatever the temperament
+        let cents = (key - A4) as f32 * 100.0 + cents + deviation[degree] - deviation[a_degree];
+        self.a4 * exp2(cents / 1200.0)
+    }
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// `freq` shifted by `cents`.
+pub fn bend(freq: f32, cents: f32) -> f32 {
+    freq * exp2(cents / 1200.0)
+}
+
+/// Interval from `from` to `to` Hz in cents; both must be positive.
+pub fn interval(from: f32, to: f32) -> f32 {
+    1200.0 * log2(to / from)
+}
+
+/// `2^x`, accurate to well under a cent.
+const fn exp2(x: f32) -> f32 {
+    let mut octaves = x as i32;
+    let mut frac = x - octaves as f32;
+    if frac < 0.0 {
+        frac += 1.0;
+        octaves -= 1;
+    }
+
+    // Taylor series of e^(frac * ln 2)
+    let y = frac * core::f32::consts::LN_2;
+    let mut term = 1.0;
+    let mut sum = 1.0;
+    let mut n = 1;
+    while n <= 7 {
+        term = term * y / n as f32;
+        sum += term;
+        n += 1;
+    }
+
+    while octaves > 0 {
+        sum *= 2.0;
+        octaves -= 1;
+    }
+    while octaves < 0 {
+        sum /= 2.0;
+        octaves += 1;
+    }
+    sum
+}
+
+/// Base-2 logarithm of a positive `x`, accurate to well under a cent.
+fn log2(x: f32) -> f32 {
+    // split into exponent and a mantissa in [1, 2)
+    let bits = x.to_bits();
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127;
+    let mantissa = f32::from_bits((bits & 0x007f_ffff) | 0x3f80_0000);
+
+    // ln(m) = 2 atanh((m - 1) / (m + 1)), with the series converging fast
+    // as the argument stays below 1/3
+    let y = (mantissa - 1.0) / (mantissa + 1.0);
+    let mut term = y;
+    let mut sum = 0.0;
+    let mut n = 1;
+    while n <= 9 {
+        sum += term / n as f32;
+        term *= y * y;
+        n += 2;
+    }
+    exponent as f32 + 2.0 * sum / core::f32::consts::LN_2
+}
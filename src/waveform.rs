@@ -0,0 +1,91 @@
+//! Oscillator shapes the synthesizer can play a voice with.
+use defmt::Format;
+
+/// First quarter of a sine period, 64 steps plus the peak.
+const SINE: [i16; 65] = [
+    0, 804, 1608, 2410, 3212, 4011, 4808, 5602, 6393, 7179, 7962, 8739, 9512, 10278, 11039, 11793,
+    12539, 13279, 14010, 14732, 15446, 16151, 16846, 17530, 18204, 18868, 19519, 20159, 20787,
+    21403, 22005, 22594, 23170, 23731, 24279, 24811, 25329, 25832, 26319, 26790, 27245, 27683,
+    28105, 28510, 28898, 29268, 29621, 29956, 30273, 30571, 30852, 31113, 31356, 31580, 31785,
+    31971, 32137, 32285, 32412, 32521, 32609, 32678, 32728, 32757, 32767,
+];
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Waveform {
+    /// Pulse wave, high for the given percentage of each period.
+    Square(u8),
+    Triangle,
+    Sawtooth,
+    Sine,
+    /// Pseudo-random noise, re-sampled once per period of the tone so that
+    /// higher notes sound brighter.
+    Noise,
+}
+
+impl Waveform {
+    /// The classic buzzer sound.
+    pub const SQUARE: Self = Self::Square(50);
+
+    /// Two bytes for storing the waveform, see `from_bytes`.
+    pub const fn to_bytes(self) -> [u8; 2] {
+        match self {
+            Self::Square(duty) => [0, duty],
+            Self::Triangle => [1, 0],
+            Self::Sawtooth => [2, 0],
+            Self::Sine => [3, 0],
+            Self::Noise => [4, 0],
+        }
+    }
+
+    pub const fn from_bytes(bytes: [u8; 2]) -> Option<Self> {
+        match bytes {
+            [0, duty] => Some(Self::Square(duty)),
+            [1, _] => Some(Self::Triangle),
+            [2, _] => Some(Self::Sawtooth),
+            [3, _] => Some(Self::Sine),
+            [4, _] => Some(Self::Noise),
+            _ => None,
+        }
+    }
+
+    /// Amplitude at `phase`, a full period spanning the whole `u32` range.
+    /// `noise` is the current output of the voice's noise generator.
+    #[inline(always)]
+    pub fn sample(self, phase: u32, noise: u16) -> i32 {
+        match self {
+            Self::Square(duty) => {
+                let high = (u32::MAX / 100) * duty.min(100) as u32;
+                if phase < high {
+                    i16::MAX as i32
+                } else {
+                    -(i16::MAX as i32)
+                }
+            }
+            Self::Triangle => {
+                // fold the rising and falling halves onto one ramp
+                let ramp = (phase >> 15) as i32;
+                let ramp = if ramp < 0x1_0000 { ramp } else { 0x1_FFFF - ramp };
+                ramp - 0x8000
+            }
+            Self::Sawtooth => (phase >> 16) as i32 - 0x8000,
+            Self::Sine => {
+                let step = (phase >> 24) as usize;
+                let i = step & 63;
+                match step >> 6 {
+                    0 => SINE[i] as i32,
+                    1 => SINE[64 - i] as i32,
+                    2 => -(SINE[i] as i32),
+                    _ => -(SINE[64 - i] as i32),
+                }
+            }
+            Self::Noise => noise as i16 as i32,
+        }
+    }
+}
+
+impl Default for Waveform {
+    fn default() -> Self {
+        Self::SQUARE
+    }
+}
+